@@ -1,5 +1,5 @@
 use star_engine::network::*;
 
-fn main() {
-    Server::new(BlankCodec).start();
+fn main() -> Result<(), std::io::Error> {
+    Server::default().start()
 }
\ No newline at end of file
@@ -0,0 +1,544 @@
+//! A swarm of headless bots for load testing the server.
+//!
+//! By default the swarm runs its own server in-process, on the loopback transport, along with a small game
+//! that moves each bot's entity around, broadcasts chat and counts item uses. Every bot follows a scripted
+//! behaviour, keeps a `Mirror` of the replicated world, and regularly sends an echo that the game answers
+//! during its tick, to measure the latency the game actually sees. Once the run is over, the swarm reports
+//! the message throughput, how much slower ticks got compared to an idle server, and the echo latency.
+//!
+//! Usage: swarm [options]
+//!        swarm schema [PATH]
+//!   --bots N            How many bots to run (default 100)
+//!   --duration SECS     How long to run for once every bot has connected (default 10)
+//!   --ramp SECS         How long to spread the bots' connections over (default 2)
+//!   --behaviour NAME    walk, chat, items or mixed (default mixed)
+//!   --tick-rate HZ      How often the in-process server ticks (default 20)
+//!   --view RADIUS       Only replicate entities within this distance of each bot
+//!   --tcp ADDRESS       Run the in-process server over TCP on this address instead of the loopback transport
+//!   --connect ADDRESS   Test an already running server over TCP. Tick times aren't measured.
+//!
+//! `swarm schema` writes the protocol schema of the swarm's game as JSON to PATH, or prints it.
+
+use star_engine::ecs::Game;
+use star_engine::network::{ClientID, Message, Server};
+use star_engine::network::client::{ClientConfig, Connection, Mirror};
+use star_engine::network::codec::{self, GameMessage, SerdeCodec};
+use star_engine::network::outbox::Outbox;
+use star_engine::network::opcode::{self, Opcode};
+use star_engine::network::interest::{ControlledEntities, InterestPolicy, Position, Positioned};
+use star_engine::network::replication::{ComponentTypeID, Networked, Replicated};
+use star_engine::network::schema::{Description, Layout, Schema, TraceError, TypeLayout};
+use star_engine::network::transport::{BoxedStream, LoopbackConnector, LoopbackTransport, TcpTransport};
+use specs::{Builder, Component, Entity, VecStorage, World};
+use bytes::{BytesMut, BufMut};
+use serde::{Deserialize, Serialize};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::timer::{Interval, delay_for};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Client to server: move the bot's entity.
+#[derive(Serialize, Deserialize)]
+struct Move {
+    dx: i8,
+    dy: i8,
+}
+
+impl GameMessage for Move {
+    const OPCODE: Opcode = 0x01;
+}
+
+/// Either way: chat, which is broadcast to every bot.
+#[derive(Serialize, Deserialize)]
+struct Chat(String);
+
+impl GameMessage for Chat {
+    const OPCODE: Opcode = 0x02;
+}
+
+/// Client to server: use an item.
+#[derive(Serialize, Deserialize)]
+struct UseItem(u16);
+
+impl GameMessage for UseItem {
+    const OPCODE: Opcode = 0x03;
+}
+
+/// Either way: a timestamp in microseconds, which the game sends straight back.
+#[derive(Serialize, Deserialize)]
+struct Echo(u64);
+
+impl GameMessage for Echo {
+    const OPCODE: Opcode = 0x04;
+}
+
+/// How often each bot acts and reads its messages.
+const BOT_INTERVAL: Duration = Duration::from_millis(50);
+/// How often each bot measures its latency.
+const ECHO_INTERVAL: Duration = Duration::from_millis(250);
+/// How long the idle server ticks for before the bots connect, to measure the baseline tick time.
+const WARMUP: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Behaviour {
+    /// Move around at random.
+    Walk,
+    /// Send random chat messages.
+    Chat,
+    /// Use random items.
+    Items,
+    /// Each bot picks one of the others.
+    Mixed,
+}
+
+impl Behaviour {
+    fn parse(name: &str) -> Option<Behaviour> {
+        match name {
+            "walk" => Some(Behaviour::Walk),
+            "chat" => Some(Behaviour::Chat),
+            "items" => Some(Behaviour::Items),
+            "mixed" => Some(Behaviour::Mixed),
+            _ => None
+        }
+    }
+
+    /// How often a bot with this behaviour acts.
+    fn interval(self) -> Duration {
+        match self {
+            Behaviour::Walk => Duration::from_millis(100),
+            Behaviour::Chat => Duration::from_millis(500),
+            Behaviour::Items | Behaviour::Mixed => Duration::from_secs(1)
+        }
+    }
+
+    fn action<R: Rng>(self, rng: &mut R) -> Message {
+        let message = match self {
+            Behaviour::Walk => codec::encode(&Move { dx: rng.gen_range(-4, 5), dy: rng.gen_range(-4, 5) }),
+            Behaviour::Chat => {
+                let text = (0..rng.gen_range(16, 65)).map(|_| char::from(rng.gen_range(b'a', b'z' + 1))).collect();
+                codec::encode(&Chat(text))
+            },
+            Behaviour::Items => codec::encode(&UseItem(rng.gen_range(0, 64))),
+            Behaviour::Mixed => unreachable!("Mixed bots pick a behaviour when they start")
+        };
+        message.expect("To encode the bot's action")
+    }
+}
+
+/// Where the bots connect to.
+#[derive(Clone)]
+enum Target {
+    Loopback(LoopbackConnector),
+    Tcp(SocketAddr),
+}
+
+struct SwarmConfig {
+    bots: usize,
+    duration: Duration,
+    ramp: Duration,
+    behaviour: Behaviour,
+    tick_rate: f64,
+    view: Option<f32>,
+    tcp: Option<SocketAddr>,
+    connect: Option<SocketAddr>,
+}
+
+impl SwarmConfig {
+    fn from_args() -> Result<SwarmConfig, String> {
+        let mut config = SwarmConfig {
+            bots: 100,
+            duration: Duration::from_secs(10),
+            ramp: Duration::from_secs(2),
+            behaviour: Behaviour::Mixed,
+            tick_rate: 20.0,
+            view: None,
+            tcp: None,
+            connect: None
+        };
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let value = args.next().ok_or_else(|| format!("{} needs a value", arg))?;
+            let invalid = || format!("Invalid value for {}: {}", arg, value);
+            match arg.as_str() {
+                "--bots" => config.bots = value.parse().map_err(|_| invalid())?,
+                "--duration" => config.duration = Duration::from_secs_f64(value.parse().map_err(|_| invalid())?),
+                "--ramp" => config.ramp = Duration::from_secs_f64(value.parse().map_err(|_| invalid())?),
+                "--behaviour" => config.behaviour = Behaviour::parse(&value).ok_or_else(invalid)?,
+                "--tick-rate" => config.tick_rate = value.parse().map_err(|_| invalid())?,
+                "--view" => config.view = Some(value.parse().map_err(|_| invalid())?),
+                "--tcp" => config.tcp = Some(value.parse().map_err(|_| invalid())?),
+                "--connect" => config.connect = Some(value.parse().map_err(|_| invalid())?),
+                _ => return Err(format!("Unknown option {}", arg))
+            }
+        }
+        if config.tick_rate <= 0.0 {
+            return Err("The tick rate has to be positive".to_string());
+        }
+        Ok(config)
+    }
+}
+
+/// What the bots have measured so far.
+#[derive(Default)]
+struct Stats {
+    connected: AtomicUsize,
+    failed: AtomicUsize,
+    sent: AtomicU64,
+    received: AtomicU64,
+    received_bytes: AtomicU64,
+    latencies: Mutex<Vec<Duration>>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct BotPosition([f32; 2]);
+
+impl Component for BotPosition {
+    type Storage = VecStorage<Self>;
+}
+
+impl Replicated for BotPosition {
+    const TYPE_ID: ComponentTypeID = 1;
+
+    fn encode(&self, bytes: &mut BytesMut) {
+        bytes.reserve(8);
+        bytes.put_f32_be(self.0[0]);
+        bytes.put_f32_be(self.0[1]);
+    }
+    fn decode(bytes: &[u8]) -> Option<BotPosition> {
+        if bytes.len() != 8 {
+            return None;
+        }
+        let read = |at: usize| f32::from_bits(u32::from_be_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]));
+        Some(BotPosition([read(0), read(4)]))
+    }
+    fn describe(schema: &mut Schema) -> Result<Description, TraceError> {
+        Ok(Description::big_endian(schema.define("BotPosition", TypeLayout::Tuple(vec![Layout::F32, Layout::F32]))?))
+    }
+}
+
+impl Positioned for BotPosition {
+    fn position(&self) -> Position {
+        self.0
+    }
+}
+
+/// How many items a bot has used.
+#[derive(Clone, Copy, Debug, Default)]
+struct ItemUses(u32);
+
+impl Component for ItemUses {
+    type Storage = VecStorage<Self>;
+}
+
+impl Replicated for ItemUses {
+    const TYPE_ID: ComponentTypeID = 2;
+
+    fn encode(&self, bytes: &mut BytesMut) {
+        bytes.reserve(4);
+        bytes.put_u32_be(self.0);
+    }
+    fn decode(bytes: &[u8]) -> Option<ItemUses> {
+        if bytes.len() != 4 {
+            return None;
+        }
+        Some(ItemUses(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])))
+    }
+    fn describe(schema: &mut Schema) -> Result<Description, TraceError> {
+        Ok(Description::big_endian(schema.define("ItemUses", TypeLayout::Newtype(Layout::U32))?))
+    }
+}
+
+/// Get the entity a bot controls, creating it the first time the bot acts.
+fn bot(world: &mut World, client: ClientID) -> Entity {
+    if let Some(entity) = world.read_resource::<ControlledEntities>().get(client) {
+        return entity;
+    }
+    let entity = world.create_entity()
+        .with(Networked)
+        .with(BotPosition::default())
+        .with(ItemUses::default())
+        .build();
+    world.write_resource::<ControlledEntities>().control(client, entity);
+    entity
+}
+
+/// The game the bots play on the in-process server, which handles their messages as they are decoded.
+fn swarm_codec() -> SerdeCodec {
+    SerdeCodec::new()
+        .with_handler(|world: &mut World, client: ClientID, echo: Echo| {
+            let message = codec::encode(&echo).expect("To encode the echo");
+            world.write_resource::<Outbox>().send(client, message);
+        })
+        .with_handler(|world: &mut World, client: ClientID, step: Move| {
+            let entity = bot(world, client);
+            if let Some(position) = world.write_storage::<BotPosition>().get_mut(entity) {
+                position.0[0] = (position.0[0] + f32::from(step.dx)).clamp(-1000.0, 1000.0);
+                position.0[1] = (position.0[1] + f32::from(step.dy)).clamp(-1000.0, 1000.0);
+            }
+        })
+        .with_handler(|world: &mut World, client: ClientID, _: UseItem| {
+            let entity = bot(world, client);
+            if let Some(uses) = world.write_storage::<ItemUses>().get_mut(entity) {
+                uses.0 += 1;
+            }
+        })
+        .with_handler(|world: &mut World, _: ClientID, chat: Chat| {
+            let message = codec::encode(&chat).expect("To encode the chat");
+            world.write_resource::<Outbox>().broadcast(message);
+        })
+}
+
+fn timestamp(start: Instant) -> u64 {
+    start.elapsed().as_micros() as u64
+}
+
+async fn run_bot(index: usize, target: Target, config: Arc<SwarmConfig>, stats: Arc<Stats>, start: Instant, deadline: Instant) {
+    let mut rng = StdRng::from_entropy();
+    delay_for(config.ramp.mul_f64(index as f64 / config.bots as f64)).await;
+
+    let stream: Result<BoxedStream, std::io::Error> = match target {
+        Target::Loopback(connector) => connector.connect().map(|stream| Box::new(stream) as BoxedStream),
+        Target::Tcp(address) => TcpStream::connect(&address).await.map(|stream| Box::new(stream) as BoxedStream)
+    };
+    let client_config = ClientConfig::new(&format!("star-swarm bot {}", index));
+    let connection = match stream {
+        Ok(stream) => Connection::connect(stream, &client_config).await.map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string())
+    };
+    let mut handle = match connection {
+        Ok(connection) => connection.spawn(),
+        Err(e) => {
+            println!("Bot {} failed to connect: {}", index, e);
+            stats.failed.fetch_add(1, Ordering::SeqCst);
+            return;
+        }
+    };
+    stats.connected.fetch_add(1, Ordering::SeqCst);
+
+    let behaviour = match config.behaviour {
+        Behaviour::Mixed => [Behaviour::Walk, Behaviour::Chat, Behaviour::Items][rng.gen_range(0, 3)],
+        behaviour => behaviour
+    };
+    let mut mirror = Mirror::new().with_component::<BotPosition>().with_component::<ItemUses>();
+    let mut next_action = Instant::now();
+    let mut next_echo = Instant::now();
+    let mut interval = Interval::new_interval(BOT_INTERVAL);
+    while interval.next().await.is_some() {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        let mut outgoing = Vec::new();
+        while let Some(message) = handle.try_recv() {
+            stats.received.fetch_add(1, Ordering::Relaxed);
+            stats.received_bytes.fetch_add(message.bytes.len() as u64, Ordering::Relaxed);
+            match message.bytes.first() {
+                Some(&opcode::SNAPSHOT) => outgoing.extend(mirror.apply(&message)),
+                Some(&first) if first == Echo::OPCODE => if let Ok(Echo(sent)) = codec::decode(&message) {
+                    let latency = timestamp(start).saturating_sub(sent);
+                    stats.latencies.lock().unwrap().push(Duration::from_micros(latency));
+                },
+                _ => {}
+            }
+        }
+        if now >= next_action {
+            outgoing.push(behaviour.action(&mut rng));
+            next_action = now + behaviour.interval();
+        }
+        if now >= next_echo {
+            outgoing.push(codec::encode(&Echo(timestamp(start))).expect("To encode the echo"));
+            next_echo = now + ECHO_INTERVAL;
+        }
+        for message in outgoing {
+            if handle.send(message).is_err() {
+                println!("Bot {} lost its connection", index);
+                return;
+            }
+            stats.sent.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Run every bot on a tokio runtime in the background, until the deadline.
+fn spawn_bots(config: Arc<SwarmConfig>, target: Target, stats: Arc<Stats>, start: Instant, deadline: Instant) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        tokio::runtime::Runtime::new().unwrap().block_on(async move {
+            // Every bot holds a sender, so the receiver ends once they're all done
+            let (done_tx, mut done_rx) = unbounded_channel::<()>();
+            for index in 0..config.bots {
+                let (target, config, stats, done) = (target.clone(), config.clone(), stats.clone(), done_tx.clone());
+                tokio::spawn(async move {
+                    run_bot(index, target, config, stats, start, deadline).await;
+                    drop(done);
+                });
+            }
+            drop(done_tx);
+            done_rx.recv().await;
+        });
+    })
+}
+
+fn percentile(sorted: &[Duration], percentile: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::from_secs(0);
+    }
+    sorted[((sorted.len() - 1) as f64 * percentile).round() as usize]
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Build the swarm's game, only replicating entities within the view radius if there is one.
+fn build_game<'a, 'b>(view: Option<f32>) -> Game<'a, 'b> {
+    let mut builder = Game::new_builder()
+        .with_replicated::<BotPosition>()
+        .and_then(|builder| builder.with_replicated::<ItemUses>())
+        .expect("To register the swarm's components");
+    if let Some(radius) = view {
+        builder = builder.with_interest::<BotPosition>(InterestPolicy::Radius(radius));
+    }
+    builder.build()
+}
+
+fn main() {
+    if let Some(written) = build_game(None).schema_command(&swarm_codec(), std::env::args().skip(1)) {
+        if let Err(e) = written {
+            eprintln!("Couldn't write the schema: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let config = match SwarmConfig::from_args() {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("Usage: swarm [--bots N] [--duration SECS] [--ramp SECS] [--behaviour walk|chat|items|mixed] \
+                       [--tick-rate HZ] [--view RADIUS] [--tcp ADDRESS | --connect ADDRESS]");
+            eprintln!("       swarm schema [PATH]");
+            std::process::exit(1);
+        }
+    };
+    let stats = Arc::new(Stats::default());
+    let start = Instant::now();
+
+    // Without an in-process server, there are no ticks to measure
+    if let Some(address) = config.connect {
+        let deadline = start + config.ramp + config.duration;
+        println!("Running {} bots against {}", config.bots, address);
+        spawn_bots(config.clone(), Target::Tcp(address), stats.clone(), start, deadline).join().unwrap();
+        report(&config, &stats, start.elapsed(), None);
+        return;
+    }
+
+    let mut game = build_game(config.view);
+    let codec = swarm_codec();
+    let (target, server) = match config.tcp {
+        Some(address) => {
+            let server = game.run_server(Server::new(codec).with_transport(TcpTransport::new(address)));
+            (Target::Tcp(address), server)
+        },
+        None => {
+            let (transport, connector) = LoopbackTransport::new();
+            let server = game.run_server(Server::new(codec).with_transport(transport));
+            (Target::Loopback(connector), server)
+        }
+    };
+
+    let tick_interval = Duration::from_secs_f64(1.0 / config.tick_rate);
+    let mut baseline = Vec::new();
+    let mut loaded = Vec::new();
+    let mut overruns = 0;
+    let mut bots = None;
+    let mut load_start = start;
+    let mut next_tick = Instant::now();
+    let mut next_report = Instant::now() + Duration::from_secs(1);
+    loop {
+        if server.is_finished() {
+            match server.join() {
+                Ok(Err(e)) => eprintln!("The server stopped: {}", e),
+                _ => eprintln!("The server stopped")
+            }
+            std::process::exit(1);
+        }
+        let now = Instant::now();
+        if bots.is_none() && now.duration_since(start) >= WARMUP {
+            println!("Starting {} bots", config.bots);
+            load_start = now;
+            let deadline = now + config.ramp + config.duration;
+            bots = Some(spawn_bots(config.clone(), target.clone(), stats.clone(), now, deadline));
+        }
+        if bots.is_some() && now.duration_since(load_start) >= config.ramp + config.duration {
+            break;
+        }
+
+        game.tick().expect("The game failed to tick");
+        let tick_time = now.elapsed();
+        match bots {
+            None => baseline.push(tick_time),
+            // Only count ticks once every bot has had the chance to connect
+            Some(_) if now.duration_since(load_start) >= config.ramp => {
+                loaded.push(tick_time);
+                if tick_time > tick_interval {
+                    overruns += 1;
+                }
+            },
+            Some(_) => {}
+        }
+
+        if now >= next_report {
+            let latencies = stats.latencies.lock().unwrap();
+            println!("{} bots connected, {} messages sent, {} received, last tick took {:.2}ms, {} latency samples",
+                     stats.connected.load(Ordering::SeqCst), stats.sent.load(Ordering::Relaxed),
+                     stats.received.load(Ordering::Relaxed), millis(tick_time), latencies.len());
+            next_report = now + Duration::from_secs(1);
+        }
+
+        next_tick += tick_interval;
+        let now = Instant::now();
+        if next_tick > now {
+            std::thread::sleep(next_tick - now);
+        } else {
+            next_tick = now;
+        }
+    }
+    if let Some(bots) = bots {
+        bots.join().unwrap();
+    }
+
+    baseline.sort();
+    loaded.sort();
+    report(&config, &stats, load_start.elapsed(), Some((&baseline, &loaded, overruns, tick_interval)));
+}
+
+fn report(config: &SwarmConfig,
+          stats: &Stats,
+          elapsed: Duration,
+          ticks: Option<(&[Duration], &[Duration], usize, Duration)>) {
+    let seconds = elapsed.as_secs_f64();
+    println!();
+    println!("Bots: {} of {} connected, {} failed", stats.connected.load(Ordering::SeqCst), config.bots,
+             stats.failed.load(Ordering::SeqCst));
+    println!("Throughput: {:.1} messages/s sent, {:.1} messages/s received ({:.2} MB/s)",
+             stats.sent.load(Ordering::Relaxed) as f64 / seconds,
+             stats.received.load(Ordering::Relaxed) as f64 / seconds,
+             stats.received_bytes.load(Ordering::Relaxed) as f64 / seconds / 1_000_000.0);
+    if let Some((baseline, loaded, overruns, tick_interval)) = ticks {
+        let (idle, busy) = (percentile(baseline, 0.5), percentile(loaded, 0.5));
+        println!("Tick time idle: p50 {:.3}ms, p99 {:.3}ms", millis(idle), millis(percentile(baseline, 0.99)));
+        println!("Tick time under load: p50 {:.3}ms, p99 {:.3}ms, max {:.3}ms ({:.1}x slower at p50)",
+                 millis(busy), millis(percentile(loaded, 0.99)), millis(percentile(loaded, 1.0)),
+                 millis(busy) / millis(idle).max(0.001));
+        println!("Ticks over the {:.1}ms budget: {} of {}", millis(tick_interval), overruns, loaded.len());
+    }
+    let mut latencies = stats.latencies.lock().unwrap();
+    latencies.sort();
+    println!("Echo latency: p50 {:.2}ms, p90 {:.2}ms, p99 {:.2}ms, max {:.2}ms ({} samples)",
+             millis(percentile(&latencies, 0.5)), millis(percentile(&latencies, 0.9)),
+             millis(percentile(&latencies, 0.99)), millis(percentile(&latencies, 1.0)), latencies.len());
+}
@@ -25,7 +25,18 @@
 
 use specs::{World, Dispatcher, DispatcherBuilder, System};
 use crate::network::{Server, ClientMessageCodec};
+use crate::network::codec::SerdeCodec;
+use crate::network::manager::ConnectionManager;
+use crate::network::outbox::Outbox;
+use crate::network::replication::{Replication, Replicated, Networked};
+use crate::network::interest::{ControlledEntities, InterestPolicy, Positioned};
+use crate::network::input::{self, Input, InputQueue, ProcessedInputs};
+use crate::network::rpc::{self, Procedure, Procedures, RpcCalls, RpcError};
+use crate::network::schema::{Schema, TraceError};
+use crate::network::ClientID;
+use std::thread::JoinHandle;
 use crate::script::system::InterpreterSystem;
+use crate::ecs::network::ClientMessageHandler;
 
 pub mod event;
 pub mod events;
@@ -39,7 +50,11 @@ pub struct Game<'a, 'b> {
     dispatcher: Dispatcher<'a, 'b>,
     event_dispatcher: Dispatcher<'a, 'b>,
     interpreter_dispatcher: Vec<InterpreterSystem>,
-    include_builtins: bool
+    include_builtins: bool,
+    clients: Option<ConnectionManager>,
+    codec: Option<Box<dyn ClientMessageHandler>>,
+    input_handlers: Vec<fn(&World, &[ClientID])>,
+    procedures: Procedures
 }
 
 pub struct GameBuilder<'a, 'b> {
@@ -47,40 +62,127 @@ pub struct GameBuilder<'a, 'b> {
     dispatcher: DispatcherBuilder<'a, 'b>,
     event_dispatcher: DispatcherBuilder<'a, 'b>,
     interpreter_dispatcher: Vec<InterpreterSystem>,
-    include_builtins: bool
+    include_builtins: bool,
+    input_handlers: Vec<fn(&World, &[ClientID])>,
+    procedures: Procedures
 }
 
+/// The resource that holds the number of the current tick. It starts at 1 on the first tick.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Tick(pub u32);
+
 
 /// Updaters are payloads of data that can load themselves onto the world.
 pub trait Updater {
     fn update_world(self, world: &mut World);
 }
 
+impl Updater for () {
+    fn update_world(self, _: &mut World) {}
+}
+
 impl<'a, 'b> Game<'a, 'b> {
     pub fn new_builder() -> GameBuilder<'a, 'b> {
         GameBuilder::default()
     }
 
-    //TODO: Add server options
-    pub fn start_server<C>(&mut self, codec: C)
-    where C: ClientMessageCodec + Send + 'static, C::Output: Default + Send + Sync {
-        // Create a new server and serve it
-        let server = Server::new(codec);
-        server.start();
+    /// Run a server with the default settings and the given codec in the background, and connect it to the world.
+    /// Joining the returned thread gives the error that stopped the server.
+    pub fn start_server<C>(&mut self, codec: C) -> JoinHandle<Result<(), std::io::Error>>
+    where C: ClientMessageCodec + Send + 'static, C::Output: Updater + Default + Send + Sync + 'static {
+        self.run_server(Server::new(codec))
+    }
+
+    /// Run a server with the default settings in the background, and connect it to the world. The `SerdeCodec`
+    /// decodes the game's messages and hands them to the handlers registered with it.
+    pub fn start_serde_server(&mut self, codec: SerdeCodec) -> JoinHandle<Result<(), std::io::Error>> {
+        self.start_server(codec)
+    }
+
+    /// Run an already configured server in the background, and connect it to the world.
+    /// The server's codec runs at the start of every tick, on the messages the engine doesn't handle itself, unless
+    /// it's a codec like `BlankCodec` that leaves them in the `Inbox` for the game's systems to read.
+    /// Joining the returned thread gives the error that stopped the server.
+    pub fn run_server<C, M>(&mut self, mut server: Server<C, M>) -> JoinHandle<Result<(), std::io::Error>>
+    where C: ClientMessageCodec<Output=M> + Send + 'static, M: Updater + Default + Send + Sync + 'static {
+        self.clients = Some(server.connections());
+        self.codec = server.take_codec().filter(|codec| codec.wants_messages()).map(|codec| Box::new(codec) as Box<dyn ClientMessageHandler>);
+        self.world.add_resource(server.client_events());
+        if let Some(sessions) = server.sessions() {
+            self.world.add_resource(sessions);
+        }
+        let handle = server.spawn();
 
         // Add the codec as a resource
-        self.world.add_resource(C::Output::default());
+        self.world.add_resource(M::default());
+        self.world.add_resource(Outbox::new());
+        self.world.add_resource(RpcCalls::new());
+        network::sync_clients(self.clients.as_ref().unwrap(), &mut self.world);
+        handle
     }
 
     pub fn tick(&mut self) -> Result<(), ()> {
+        self.world.write_resource::<Tick>().0 += 1;
+        if let Some(clients) = &self.clients {
+            let connected = network::sync_clients(clients, &mut self.world);
+            for handler in &self.input_handlers {
+                handler(&self.world, &connected);
+            }
+            rpc::update(&self.procedures, &mut self.world, &connected);
+            if let Some(codec) = &mut self.codec {
+                network::process_messages(codec.as_mut(), clients, &mut self.world);
+            }
+        }
         self.dispatcher.dispatch(&self.world.res);
         self.event_dispatcher.dispatch(&self.world.res);
         for i in &mut self.interpreter_dispatcher {
             i.run(&self.world).map_err(|_| ())?;
         }
+        if let Some(clients) = &self.clients {
+            network::replicate(clients, &mut self.world);
+            rpc::flush(&self.world);
+            network::flush_outbox(clients, &mut self.world);
+        }
         Ok(())
     }
 
+    /// Describe the protocol: the message types the codec decodes, and the replicated components.
+    /// Pass the codec before it is handed to the server.
+    pub fn schema<C: ClientMessageCodec>(&self, codec: &C) -> Result<Schema, TraceError> {
+        let mut schema = Schema::new();
+        codec.describe(&mut schema)?;
+        if self.world.res.has_value::<Replication>() {
+            self.world.read_resource::<Replication>().describe(&mut schema)?;
+        }
+        Ok(schema)
+    }
+
+    /// Handle the `schema [PATH]` subcommand, which writes the schema as JSON to the path, or prints it.
+    /// Pass the arguments without the program name. Returns `None` if they aren't the subcommand, so the game
+    /// can go on and run as usual.
+    pub fn schema_command<C, I>(&self, codec: &C, args: I) -> Option<Result<(), String>>
+        where C: ClientMessageCodec, I: IntoIterator<Item = String> {
+        let mut args = args.into_iter();
+        if args.next().as_deref() != Some("schema") {
+            return None;
+        }
+        let path = args.next();
+        if let Some(extra) = args.next() {
+            return Some(Err(format!("Unexpected argument {}", extra)));
+        }
+        let schema = match self.schema(codec) {
+            Ok(schema) => schema,
+            Err(e) => return Some(Err(e.to_string()))
+        };
+        Some(match path {
+            Some(path) => schema.write(path).map_err(|e| e.to_string()),
+            None => {
+                println!("{}", schema.to_json());
+                Ok(())
+            }
+        })
+    }
+
     pub fn status(&self) {
         unimplemented!()
     }
@@ -117,6 +219,56 @@ impl<'a, 'b> GameBuilder<'a, 'b> {
         self.interpreter_dispatcher.push(system);
         self
     }
+    /// Register a component and replicate it to clients.
+    /// Only entities that have the `Networked` component are replicated.
+    /// Fails if the component's encoding can't be used, like a `packed!` field with an empty range.
+    pub fn with_replicated<T: Replicated>(mut self) -> Result<Self, TraceError>
+    where T::Storage: Default {
+        self.setup_replication();
+        self.world.write_resource::<Replication>().register::<T>()?;
+        self.world.register::<T>();
+        Ok(self)
+    }
+    /// Only replicate the entities near the entity each client controls, according to the
+    /// `ControlledEntities` resource and the positions from `P`.
+    pub fn with_interest<P: Positioned>(mut self, policy: InterestPolicy) -> Self
+    where P::Storage: Default {
+        self.setup_replication();
+        self.world.register::<P>();
+        self.world.write_resource::<Replication>().set_interest::<P>(policy);
+        self
+    }
+    /// Bit-pack snapshots, to make them smaller at the cost of a little more work to encode them.
+    pub fn with_packed_snapshots(mut self) -> Self {
+        self.setup_replication();
+        self.world.write_resource::<Replication>().set_packed(true);
+        self
+    }
+
+    /// Accept inputs of type `I` from clients. Systems read them from the `InputQueue<I>` resource.
+    pub fn with_input<I: Input>(mut self) -> Self {
+        self.world.add_resource(InputQueue::<I>::new());
+        if !self.world.res.has_value::<ProcessedInputs>() {
+            self.world.add_resource(ProcessedInputs::default());
+        }
+        self.input_handlers.push(input::update::<I>);
+        self
+    }
+
+    /// Answer the calls clients make to `P`. The handler runs at the start of the tick, before the systems.
+    pub fn with_procedure<P, F>(mut self, handler: F) -> Self
+    where P: Procedure, F: Fn(&mut World, ClientID, P::Request) -> Result<P::Response, RpcError> + Send + Sync + 'static {
+        self.procedures.register::<P, F>(handler);
+        self
+    }
+
+    fn setup_replication(&mut self) {
+        if !self.world.res.has_value::<Replication>() {
+            self.world.register::<Networked>();
+            self.world.add_resource(Replication::new());
+            self.world.add_resource(ControlledEntities::new());
+        }
+    }
 
     pub fn build(self) -> Game<'a, 'b> {
         Game {
@@ -124,19 +276,27 @@ impl<'a, 'b> GameBuilder<'a, 'b> {
             dispatcher: self.dispatcher.build(),
             event_dispatcher: self.event_dispatcher.build(),
             interpreter_dispatcher: self.interpreter_dispatcher,
-            include_builtins: self.include_builtins
+            include_builtins: self.include_builtins,
+            clients: None,
+            codec: None,
+            input_handlers: self.input_handlers,
+            procedures: self.procedures
         }
     }
 }
 
 impl<'a, 'b> Default for GameBuilder<'a, 'b> {
     fn default() -> Self {
+        let mut world = World::new();
+        world.add_resource(Tick::default());
         GameBuilder {
-            world: World::new(),
+            world,
             dispatcher: DispatcherBuilder::new(),
             event_dispatcher: DispatcherBuilder::new(),
             interpreter_dispatcher: Vec::new(),
-            include_builtins: true
+            include_builtins: true,
+            input_handlers: Vec::new(),
+            procedures: Procedures::new()
         }
     }
 }
\ No newline at end of file
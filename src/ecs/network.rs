@@ -1,15 +1,152 @@
 use crate::network::*;
+use crate::network::auth::ClientAccounts;
+use crate::network::heartbeat::{ClientConnections, ConnectionStats};
+use crate::network::manager::{self, ConnectionManager};
+use crate::network::outbox::Outbox;
+use crate::network::inbox::Inbox;
+use crate::network::replication::Replication;
+use crate::network::interest::ControlledEntities;
+use crate::network::session::SessionStore;
+use crate::network::lifecycle::{ClientAuthenticated, ClientConnected, ClientDisconnected, ClientEvent, ClientEvents, DisconnectReason};
+use crate::network::opcode;
 use specs::World;
+use crate::ecs::notifier::NotifierQueue;
+use crate::ecs::event::is;
+use crate::network::codec::DecodeError;
+use crate::network::ratelimit::RateLimitViolation;
 use super::Updater;
+use std::time::Instant;
 
 /// Handles messages from the client and makes the appropriate adjustments to the world
 pub trait ClientMessageHandler {
-    fn refresh_messages(&mut self, client_messages: ClientMessages, world: &mut World);
+    fn refresh_messages(&mut self, client_messages: ClientMessages, handshakes: &ClientHandshakes, world: &mut World);
 }
 
 impl<T, U> ClientMessageHandler for T
 where T: ClientMessageCodec<Output=U>, U: Updater {
-    fn refresh_messages(&mut self, client_messages: ClientMessages, world: &mut World) {
-        Updater::update_world(self.process_messages(client_messages), world);
+    fn refresh_messages(&mut self, client_messages: ClientMessages, handshakes: &ClientHandshakes, world: &mut World) {
+        Updater::update_world(self.process_messages(client_messages, handshakes), world);
     }
+}
+
+/// Copy the state of the connected clients (their accounts and connection stats) into the world's resources, and collect
+/// the messages they sent into a fresh `Inbox`. Messages left in the previous inbox are dropped.
+/// Clients that came back under their old ID start over with a full snapshot, and clients whose suspended session
+/// has expired are released from `ControlledEntities`. Lifecycle events
+/// and rate limit violations since the last tick are pushed to the `NotifierQueue`, replacing the previous tick's.
+/// This runs at the start of every tick, and returns the IDs of the connected clients.
+pub fn sync_clients(connections: &ConnectionManager, world: &mut World) -> Vec<ClientID> {
+    // The messages are taken from the clients in the table, so that nothing waits for the connection manager
+    let routes = connections.routes();
+    // The queues are measured before they are emptied
+    let stats = routes.iter()
+        .map(|(id, route)| (*id, ConnectionStats {
+            rtt: route.inbound.rtt(),
+            last_seen: route.inbound.last_seen(),
+            inbound_queue: route.inbound.queued(),
+            outbound_queue: route.sender.len()
+        }))
+        .collect();
+    let received = manager::receive(&routes);
+    let accounts = routes.iter()
+        .filter_map(|(id, route)| route.account.clone().map(|account| (*id, account)))
+        .collect();
+    world.add_resource(ClientAccounts::new(accounts));
+    world.add_resource(ClientConnections::new(stats));
+
+    let mut inbox = Inbox::new();
+    for (id, messages) in received.messages {
+        for message in messages {
+            inbox.push(id, message);
+        }
+    }
+    world.add_resource(inbox);
+    let violations = received.violations;
+
+    let mut lifecycle = if world.res.has_value::<ClientEvents>() {
+        world.read_resource::<ClientEvents>().take()
+    } else {
+        Vec::new()
+    };
+    // A client that resumed or replaced its connection kept its ID, but the new connection hasn't seen any snapshots
+    if world.res.has_value::<Replication>() {
+        let mut replication = world.write_resource::<Replication>();
+        for event in &lifecycle {
+            if let ClientEvent::Connected(ClientConnected { client, resumed: true, .. }) = event {
+                replication.reset(*client);
+            }
+        }
+    }
+    if world.res.has_value::<SessionStore>() {
+        let expired = world.read_resource::<SessionStore>().expire(Instant::now());
+        if !expired.is_empty() && world.res.has_value::<ControlledEntities>() {
+            let mut controlled = world.write_resource::<ControlledEntities>();
+            for (id, _) in &expired {
+                controlled.release(*id);
+            }
+        }
+        lifecycle.extend(expired.into_iter().map(|(client, address)| ClientEvent::Disconnected(ClientDisconnected {
+            client,
+            address,
+            reason: DisconnectReason::SessionExpired,
+            resumable: false
+        })));
+    }
+
+    // Nothing pops the events from the network, so they only last for the tick they were pushed in
+    if world.res.has_value::<NotifierQueue>() {
+        world.write_resource::<NotifierQueue>().retain(|event| {
+            !is::<(ClientConnected, ClientAuthenticated, ClientDisconnected, RateLimitViolation, DecodeError)>(event)
+        });
+    }
+    if !violations.is_empty() || !lifecycle.is_empty() {
+        if !world.res.has_value::<NotifierQueue>() {
+            world.add_resource(NotifierQueue::new());
+        }
+        let mut notifier = world.write_resource::<NotifierQueue>();
+        for event in lifecycle {
+            match event {
+                ClientEvent::Connected(event) => notifier.push_event(event),
+                ClientEvent::Authenticated(event) => notifier.push_event(event),
+                ClientEvent::Disconnected(event) => notifier.push_event(event)
+            }
+        }
+        for violation in violations {
+            notifier.push_event(violation);
+        }
+    }
+    routes.keys().cloned().collect()
+}
+
+/// Hand the messages for the game that are left in the inbox to the codec, and apply what it made of them to the world.
+/// This runs at the start of every tick, once the engine has taken its own messages out of the inbox.
+pub fn process_messages(codec: &mut dyn ClientMessageHandler, connections: &ConnectionManager, world: &mut World) {
+    let handshakes = connections.routes().iter()
+        .map(|(id, route)| (*id, route.handshake.clone()))
+        .collect();
+    let messages = world.write_resource::<Inbox>().take_game();
+    codec.refresh_messages(messages, &handshakes, world);
+}
+
+/// Send each client the changes to the replicated components since the last snapshot it acknowledged.
+/// This runs at the end of every tick, before the outbox is flushed.
+pub fn replicate(connections: &ConnectionManager, world: &mut World) {
+    if !world.res.has_value::<Replication>() {
+        return;
+    }
+    let clients = connections.clients();
+    let mut replication = world.write_resource::<Replication>();
+    for (client, ack) in world.write_resource::<Inbox>().take(opcode::SNAPSHOT_ACK) {
+        replication.handle_ack(client, &ack);
+    }
+    let mut outbox = world.write_resource::<Outbox>();
+    for (client, message) in replication.update(world, &clients) {
+        outbox.send(client, message);
+    }
+}
+
+/// Send every message queued in the `Outbox` to its recipients.
+/// This runs at the end of every tick.
+pub fn flush_outbox(connections: &ConnectionManager, world: &mut World) {
+    world.write_resource::<Outbox>().flush(&connections.routes());
 }
\ No newline at end of file
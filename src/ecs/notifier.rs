@@ -52,6 +52,11 @@ impl NotifierQueue {
     pub fn iter(&self) -> Iter<'_, Box<dyn Event>> {
         self.queue.iter()
     }
+    /// Only keep the events for which `keep` returns true.
+    pub fn retain<F>(&mut self, mut keep: F)
+    where F: FnMut(&dyn Event) -> bool {
+        self.queue.retain(|event| keep(&**event));
+    }
     fn sort(&mut self) {
         self.queue.sort_by_key(|x| x.priority());
         self.needs_sort = false;
@@ -0,0 +1,473 @@
+//! After the handshake, a client has to authenticate before it gets a `ClientID`.
+//!
+//! Authentication is done by an `Authenticator`, which holds a list of pluggable
+//! `AuthProvider`s. Each provider is asked in turn to check the client's `Credentials`,
+//! and the first one that supports them decides whether the client gets in.
+//! Two providers come with the engine:
+//! - `PasswordStore`, a local username and password store with salted hashing.
+//! - `TokenProvider`, which accepts tokens that it has previously issued.
+//!
+//! The resulting `Account` is stored with the client's entry in the `ClientMap`, and is
+//! exposed to the world through the `ClientAccounts` resource. Gameplay, bans and admin rights
+//! should key off of the `AccountID` rather than the `ClientID`, which changes on every connection
+//! unless the client resumes its session (see `session`).
+//!
+//! All integers are big-endian. An authentication request is laid out as:
+//! - 1 byte: the kind of credentials (0 for a password, 1 for a token)
+//! - Password: 1 byte username length, the username, 1 byte password length, the password
+//! - Token: 1 byte token length, the token
+//!
+//! Checking credentials can take a while, since passwords are deliberately slow to hash, so the server does it
+//! on a few threads of its own with `authenticate_in_background`, rather than on the executor. Only a limited
+//! number of logins can wait for those threads, and the rest fail with `AuthError::Unavailable`.
+//!
+//! A wrong password and a username that doesn't exist both fail with `AuthError::InvalidCredentials`, after just
+//! as much hashing, so that clients can't find out which usernames are taken.
+//!
+//! The server answers with a single status byte (0 for success, otherwise an `AuthError` code),
+//! followed by the 8 byte account ID on success.
+
+use bytes::{BytesMut, BufMut};
+use tokio::prelude::*;
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::fmt;
+use lazy_static::lazy_static;
+use tokio::sync::oneshot;
+use hmac::Hmac;
+use sha2::Sha256;
+use rand::Rng;
+use crate::network::ClientID;
+
+/// A unique identifier for an account. Unlike a `ClientID`, this stays the same across connections.
+pub type AccountID = u64;
+
+/// The identity of an authenticated client.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Account {
+    pub id: AccountID,
+    pub name: String,
+}
+
+impl Account {
+    /// Create an account with a new ID. Every account should get its ID from here, whichever provider it
+    /// belongs to, so that no two accounts ever share one.
+    pub fn new(name: &str) -> Account {
+        Account { id: NEXT_ACCOUNT_ID.fetch_add(1, Ordering::Relaxed), name: name.to_string() }
+    }
+}
+
+/// What a client presents to prove who it is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Credentials {
+    Password { username: String, password: String },
+    Token(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthError {
+    /// The provider doesn't handle this kind of credentials.
+    Unsupported,
+    /// No account exists with the given token.
+    UnknownAccount,
+    /// The account exists, but the credentials are wrong.
+    InvalidCredentials,
+    /// The account is already taken, when registering.
+    AccountExists,
+    /// The request could not be parsed.
+    Malformed,
+    /// The server couldn't check the credentials, or had too many to check.
+    Unavailable,
+}
+
+/// A source of truth for who is allowed to connect.
+pub trait AuthProvider: Send + Sync {
+    /// Check the given credentials, returning the account they belong to.
+    /// Providers should return `AuthError::Unsupported` for credentials they don't handle,
+    /// so that the next provider gets a chance.
+    fn authenticate(&self, credentials: &Credentials) -> Result<Account, AuthError>;
+}
+
+/// Holds all of the server's authentication providers.
+#[derive(Default)]
+pub struct Authenticator {
+    providers: Vec<Box<dyn AuthProvider>>,
+}
+
+impl Authenticator {
+    pub fn new() -> Authenticator {
+        Authenticator::default()
+    }
+
+    pub fn with_provider<P: AuthProvider + 'static>(mut self, provider: P) -> Authenticator {
+        self.providers.push(Box::new(provider));
+        self
+    }
+
+    /// Ask each provider in order, until one of them supports the credentials.
+    pub fn authenticate(&self, credentials: &Credentials) -> Result<Account, AuthError> {
+        for provider in &self.providers {
+            match provider.authenticate(credentials) {
+                Err(AuthError::Unsupported) => continue,
+                result => return result
+            }
+        }
+        Err(AuthError::Unsupported)
+    }
+}
+
+static NEXT_ACCOUNT_ID: AtomicU64 = AtomicU64::new(1);
+
+lazy_static! {
+    static ref WORKERS: Mutex<mpsc::SyncSender<Job>> = Mutex::new(spawn_workers());
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The number of threads that check credentials.
+const WORKER_THREADS: usize = 4;
+/// How many logins can wait for the threads, before more are turned away.
+const MAX_QUEUED_JOBS: usize = 256;
+
+fn spawn_workers() -> mpsc::SyncSender<Job> {
+    let (tx, rx) = mpsc::sync_channel::<Job>(MAX_QUEUED_JOBS);
+    let rx = Arc::new(Mutex::new(rx));
+    for i in 0..WORKER_THREADS {
+        let rx = rx.clone();
+        std::thread::Builder::new()
+            .name(format!("authentication-{}", i))
+            .spawn(move || loop {
+                let job = match rx.lock().expect("To get a lock on the authentication jobs").recv() {
+                    Ok(job) => job,
+                    Err(_) => return
+                };
+                job();
+            })
+            .expect("To spawn an authentication thread");
+    }
+    tx
+}
+
+/// Check credentials on the authentication threads, so that hashing passwords doesn't hold up the executor.
+pub async fn authenticate_in_background(authenticator: Arc<Authenticator>, credentials: Credentials) -> Result<Account, AuthError> {
+    let (tx, rx) = oneshot::channel();
+    let job: Job = Box::new(move || {
+        let _ = tx.send(authenticator.authenticate(&credentials));
+    });
+    let sent = WORKERS.lock().expect("To get a lock on the authentication threads").try_send(job).is_ok();
+    if !sent {
+        return Err(AuthError::Unavailable);
+    }
+    // The job is dropped without an answer if a provider panics
+    rx.await.unwrap_or(Err(AuthError::Unavailable))
+}
+
+struct StoredPassword {
+    account: Account,
+    salt: [u8; PasswordStore::SALT_SIZE],
+    hash: [u8; PasswordStore::HASH_SIZE],
+}
+
+/// A local store of usernames and salted password hashes.
+/// Passwords are hashed with PBKDF2-HMAC-SHA256 and a random salt per account.
+pub struct PasswordStore {
+    accounts: HashMap<String, StoredPassword>,
+    rounds: usize,
+    /// Hashed against for usernames that don't exist, so they take as long to check as the ones that do.
+    dummy_salt: [u8; PasswordStore::SALT_SIZE],
+}
+
+impl PasswordStore {
+    const SALT_SIZE: usize = 16;
+    const HASH_SIZE: usize = 32;
+    const DEFAULT_ROUNDS: usize = 10_000;
+
+    pub fn new() -> PasswordStore {
+        PasswordStore::default()
+    }
+
+    /// Set the number of hashing rounds. More rounds make brute-forcing a leaked store
+    /// slower, at the cost of slower logins.
+    pub fn with_rounds(mut self, rounds: usize) -> PasswordStore {
+        self.rounds = rounds;
+        self
+    }
+
+    /// Create a new account with the given username and password.
+    pub fn register(&mut self, username: &str, password: &str) -> Result<Account, AuthError> {
+        if self.accounts.contains_key(username) {
+            return Err(AuthError::AccountExists);
+        }
+        let mut salt = [0u8; PasswordStore::SALT_SIZE];
+        rand::thread_rng().fill(&mut salt);
+        let account = Account::new(username);
+        self.accounts.insert(username.to_string(), StoredPassword {
+            account: account.clone(),
+            salt,
+            hash: self.hash(password, &salt)
+        });
+        Ok(account)
+    }
+
+    /// Change the password of an existing account. A fresh salt is generated.
+    pub fn set_password(&mut self, username: &str, password: &str) -> Result<(), AuthError> {
+        let mut salt = [0u8; PasswordStore::SALT_SIZE];
+        rand::thread_rng().fill(&mut salt);
+        let hash = self.hash(password, &salt);
+        let stored = self.accounts.get_mut(username).ok_or(AuthError::UnknownAccount)?;
+        stored.salt = salt;
+        stored.hash = hash;
+        Ok(())
+    }
+
+    fn hash(&self, password: &str, salt: &[u8]) -> [u8; PasswordStore::HASH_SIZE] {
+        let mut hash = [0u8; PasswordStore::HASH_SIZE];
+        pbkdf2::pbkdf2::<Hmac<Sha256>>(password.as_bytes(), salt, self.rounds, &mut hash);
+        hash
+    }
+}
+
+impl Default for PasswordStore {
+    fn default() -> PasswordStore {
+        let mut dummy_salt = [0u8; PasswordStore::SALT_SIZE];
+        rand::thread_rng().fill(&mut dummy_salt);
+        PasswordStore {
+            accounts: HashMap::new(),
+            rounds: PasswordStore::DEFAULT_ROUNDS,
+            dummy_salt
+        }
+    }
+}
+
+impl AuthProvider for PasswordStore {
+    fn authenticate(&self, credentials: &Credentials) -> Result<Account, AuthError> {
+        match credentials {
+            Credentials::Password { username, password } => {
+                match self.accounts.get(username) {
+                    Some(stored) if constant_time_eq(&self.hash(password, &stored.salt), &stored.hash) => {
+                        Ok(stored.account.clone())
+                    },
+                    Some(_) => Err(AuthError::InvalidCredentials),
+                    None => {
+                        self.hash(password, &self.dummy_salt);
+                        Err(AuthError::InvalidCredentials)
+                    }
+                }
+            },
+            _ => Err(AuthError::Unsupported)
+        }
+    }
+}
+
+/// Accepts opaque tokens that it has issued, such as ones handed out by a web login.
+#[derive(Default)]
+pub struct TokenProvider {
+    tokens: Mutex<HashMap<String, Account>>,
+}
+
+impl TokenProvider {
+    const TOKEN_SIZE: usize = 32;
+
+    pub fn new() -> TokenProvider {
+        TokenProvider::default()
+    }
+
+    /// Generate a new random token for the given account. Accounts that don't already exist elsewhere
+    /// should be created with `Account::new`, so that their ID isn't taken.
+    pub fn issue(&self, account: Account) -> String {
+        let mut bytes = [0u8; TokenProvider::TOKEN_SIZE];
+        rand::thread_rng().fill(&mut bytes);
+        let token = hex::encode(bytes);
+        self.insert(token.clone(), account);
+        token
+    }
+
+    /// Accept a token that was generated elsewhere.
+    pub fn insert(&self, token: String, account: Account) {
+        self.tokens.lock().expect("To get a lock on the token map").insert(token, account);
+    }
+
+    pub fn revoke(&self, token: &str) -> Option<Account> {
+        self.tokens.lock().expect("To get a lock on the token map").remove(token)
+    }
+}
+
+impl AuthProvider for TokenProvider {
+    fn authenticate(&self, credentials: &Credentials) -> Result<Account, AuthError> {
+        match credentials {
+            Credentials::Token(token) => {
+                self.tokens.lock().expect("To get a lock on the token map")
+                    .get(token).cloned().ok_or(AuthError::UnknownAccount)
+            },
+            _ => Err(AuthError::Unsupported)
+        }
+    }
+}
+
+/// The world resource that maps each connected client to its account.
+#[derive(Clone, Debug, Default)]
+pub struct ClientAccounts {
+    accounts: HashMap<ClientID, Account>,
+}
+
+impl ClientAccounts {
+    pub fn new(accounts: HashMap<ClientID, Account>) -> ClientAccounts {
+        ClientAccounts { accounts }
+    }
+    pub fn get(&self, client: ClientID) -> Option<&Account> {
+        self.accounts.get(&client)
+    }
+    /// Find the client that is logged in to the given account, if any.
+    pub fn client_of(&self, account: AccountID) -> Option<ClientID> {
+        self.accounts.iter()
+            .find(|(_, a)| a.id == account)
+            .map(|(client, _)| *client)
+    }
+    pub fn iter(&self) -> impl Iterator<Item=(&ClientID, &Account)> {
+        self.accounts.iter()
+    }
+}
+
+impl Credentials {
+    pub fn encode(&self) -> BytesMut {
+        let mut bytes = BytesMut::with_capacity(64);
+        match self {
+            Credentials::Password { username, password } => {
+                bytes.put_u8(0);
+                put_short_str(&mut bytes, username);
+                put_short_str(&mut bytes, password);
+            },
+            Credentials::Token(token) => {
+                bytes.put_u8(1);
+                put_short_str(&mut bytes, token);
+            }
+        }
+        bytes
+    }
+
+    /// Decode credentials from the start of `bytes`, returning them along with the number of bytes they took up.
+    pub fn decode(bytes: &[u8]) -> Option<(Credentials, usize)> {
+        fn short_str(bytes: &[u8], at: usize) -> Option<(String, usize)> {
+            let len = *bytes.get(at)? as usize;
+            let s = bytes.get(at + 1..at + 1 + len)?;
+            Some((String::from_utf8(s.to_vec()).ok()?, at + 1 + len))
+        }
+        match bytes.first()? {
+            0 => {
+                let (username, at) = short_str(bytes, 1)?;
+                let (password, at) = short_str(bytes, at)?;
+                Some((Credentials::Password { username, password }, at))
+            },
+            1 => {
+                let (token, at) = short_str(bytes, 1)?;
+                Some((Credentials::Token(token), at))
+            },
+            _ => None
+        }
+    }
+}
+
+impl AuthError {
+    /// The code that represents this error on the wire.
+    pub fn code(self) -> u8 {
+        match self {
+            AuthError::Unsupported => 1,
+            AuthError::UnknownAccount => 2,
+            AuthError::InvalidCredentials => 3,
+            AuthError::AccountExists => 4,
+            AuthError::Malformed => 5,
+            AuthError::Unavailable => 6
+        }
+    }
+    pub fn from_code(code: u8) -> AuthError {
+        match code {
+            1 => AuthError::Unsupported,
+            2 => AuthError::UnknownAccount,
+            3 => AuthError::InvalidCredentials,
+            4 => AuthError::AccountExists,
+            6 => AuthError::Unavailable,
+            _ => AuthError::Malformed
+        }
+    }
+}
+
+fn put_short_str(bytes: &mut BytesMut, s: &str) {
+    let s = &s.as_bytes()[..s.len().min(255)];
+    bytes.reserve(1 + s.len());
+    bytes.put_u8(s.len() as u8);
+    bytes.put_slice(s);
+}
+
+async fn read_short_str<S>(stream: &mut S) -> Result<Option<String>, std::io::Error>
+where S: AsyncRead + Unpin {
+    let mut len = [0u8; 1];
+    stream.read_exact(&mut len).await?;
+    let mut s = vec![0u8; len[0] as usize];
+    stream.read_exact(&mut s).await?;
+    Ok(String::from_utf8(s).ok())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Run the server half of authentication. The result is sent to the client before this returns.
+/// The outer error is for I/O failures; the inner one is for rejected credentials.
+pub async fn accept<S>(stream: &mut S, authenticator: &Arc<Authenticator>) -> Result<Result<Account, AuthError>, std::io::Error>
+where S: AsyncRead + AsyncWrite + Unpin {
+    let mut kind = [0u8; 1];
+    stream.read_exact(&mut kind).await?;
+    let credentials = match kind[0] {
+        0 => {
+            let username = read_short_str(stream).await?;
+            let password = read_short_str(stream).await?;
+            username.and_then(|username| password.map(|password| Credentials::Password { username, password }))
+        },
+        1 => read_short_str(stream).await?.map(Credentials::Token),
+        _ => None
+    };
+
+    let result = match credentials {
+        Some(credentials) => authenticate_in_background(authenticator.clone(), credentials).await,
+        None => Err(AuthError::Malformed)
+    };
+    let mut response = BytesMut::with_capacity(9);
+    match &result {
+        Ok(account) => {
+            response.put_u8(0);
+            response.put_u64_be(account.id);
+        },
+        Err(e) => response.put_u8(e.code())
+    }
+    stream.write_all(&response).await?;
+    Ok(result)
+}
+
+/// Run the client half of authentication, returning the ID of the account that was logged in to.
+pub async fn login<S>(stream: &mut S, credentials: &Credentials) -> Result<Result<AccountID, AuthError>, std::io::Error>
+where S: AsyncRead + AsyncWrite + Unpin {
+    stream.write_all(&credentials.encode()).await?;
+    let mut status = [0u8; 1];
+    stream.read_exact(&mut status).await?;
+    if status[0] != 0 {
+        return Ok(Err(AuthError::from_code(status[0])));
+    }
+    let mut id = [0u8; 8];
+    stream.read_exact(&mut id).await?;
+    Ok(Ok(AccountID::from_be_bytes(id)))
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            AuthError::Unsupported => "unsupported credentials",
+            AuthError::UnknownAccount => "unknown account",
+            AuthError::InvalidCredentials => "invalid credentials",
+            AuthError::AccountExists => "account already exists",
+            AuthError::Malformed => "malformed authentication request",
+            AuthError::Unavailable => "authentication is unavailable"
+        };
+        write!(f, "{}", description)
+    }
+}
\ No newline at end of file
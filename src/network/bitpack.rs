@@ -0,0 +1,567 @@
+//! A compact bit-level encoding, for state that is sent every tick, like snapshots and the components in them.
+//!
+//! Values are written to a `BitWriter` and read back from a `BitReader`, least significant bit first, without
+//! padding between them. Only the whole stream is padded, to the next byte. On top of raw bits, there are:
+//! - variable-length integers: 7 bits at a time, each group followed by a bit that says whether another one does.
+//!   Signed integers are zigzag encoded first, so small negative numbers stay small.
+//! - quantized floats: a value in a fixed range is sent as the nearest of evenly spaced steps. `Quantization`
+//!   picks the number of bits from the precision the value needs.
+//! - network IDs relative to a baseline: `IdBaseline` encodes each ID as its distance from the one before it,
+//!   which takes a few bits when IDs are written in order.
+//!
+//! Components choose how each field is encoded by implementing `Packed`, usually with the `packed!` macro, and
+//! use `encode`, `decode` and `describe` in their `Replicated` implementation:
+//!
+//! ```ignore
+//! packed!(Transform {
+//!     x: quantized(-4096.0, 4096.0, 0.01),
+//!     y: quantized(-4096.0, 4096.0, 0.01),
+//!     angle: quantized(-PI, PI, 0.001),
+//!     frame: bits(5),
+//!     health: packed
+//! });
+//! ```
+
+use bytes::BytesMut;
+use std::convert::TryFrom;
+use std::fmt;
+use crate::network::replication::NetworkID;
+use crate::network::schema::{Description, Layout, Schema, TraceError};
+
+#[doc(hidden)]
+pub use lazy_static::lazy_static;
+
+/// Writes values into a stream of bits.
+#[derive(Clone, Debug, Default)]
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl BitWriter {
+    pub fn new() -> BitWriter {
+        BitWriter::default()
+    }
+
+    /// The number of bits written so far.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Forget everything after the first `len` bits.
+    pub fn truncate(&mut self, len: usize) {
+        if len >= self.len {
+            return;
+        }
+        self.bytes.truncate(len.div_ceil(8));
+        if !len.is_multiple_of(8) {
+            let last = self.bytes.len() - 1;
+            self.bytes[last] &= (1 << (len % 8)) - 1;
+        }
+        self.len = len;
+    }
+
+    /// Write the lowest `count` bits of `value`. `count` may be at most 64.
+    pub fn write_bits(&mut self, value: u64, count: usize) {
+        assert!(count <= 64, "Can't write {} bits at once", count);
+        let mut written = 0;
+        while written < count {
+            let offset = self.len % 8;
+            if offset == 0 {
+                self.bytes.push(0);
+            }
+            let chunk = (8 - offset).min(count - written);
+            let bits = (value >> written) as u8 & ((1u16 << chunk) - 1) as u8;
+            let last = self.bytes.len() - 1;
+            self.bytes[last] |= bits << offset;
+            written += chunk;
+            self.len += chunk;
+        }
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_bits(value as u64, 1);
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write_bits(u64::from(*byte), 8);
+        }
+    }
+
+    pub fn write_varint(&mut self, mut value: u64) {
+        loop {
+            self.write_bits(value & 0x7F, 7);
+            value >>= 7;
+            self.write_bool(value != 0);
+            if value == 0 {
+                return;
+            }
+        }
+    }
+
+    pub fn write_signed_varint(&mut self, value: i64) {
+        self.write_varint(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    pub fn write_quantized(&mut self, value: f32, quantization: Quantization) {
+        self.write_bits(quantization.quantize(value), quantization.bits());
+    }
+
+    /// Write all the bits of another writer.
+    pub fn append(&mut self, other: &BitWriter) {
+        for (i, byte) in other.bytes.iter().enumerate() {
+            self.write_bits(u64::from(*byte), (other.len - i * 8).min(8));
+        }
+    }
+
+    /// The bits written, padded with zeroes to a whole number of bytes.
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Reads values from a stream of bits written by a `BitWriter`. Every read returns `None` if the stream is too short.
+#[derive(Clone, Debug)]
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> BitReader<'a> {
+        BitReader { bytes, position: 0 }
+    }
+
+    /// The number of bits left, including the padding at the end.
+    pub fn remaining(&self) -> usize {
+        self.bytes.len() * 8 - self.position
+    }
+
+    pub fn read_bits(&mut self, count: usize) -> Option<u64> {
+        assert!(count <= 64, "Can't read {} bits at once", count);
+        if count > self.remaining() {
+            return None;
+        }
+        let mut value = 0;
+        let mut read = 0;
+        while read < count {
+            let offset = self.position % 8;
+            let chunk = (8 - offset).min(count - read);
+            let bits = (self.bytes[self.position / 8] >> offset) as u64 & ((1 << chunk) - 1);
+            value |= bits << read;
+            read += chunk;
+            self.position += chunk;
+        }
+        Some(value)
+    }
+
+    pub fn read_bool(&mut self) -> Option<bool> {
+        self.read_bits(1).map(|bit| bit != 0)
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Option<Vec<u8>> {
+        if len > self.remaining() / 8 {
+            return None;
+        }
+        (0..len).map(|_| self.read_bits(8).map(|byte| byte as u8)).collect()
+    }
+
+    /// Read a variable-length integer. Encodings longer than any `u64` are rejected.
+    pub fn read_varint(&mut self) -> Option<u64> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let group = self.read_bits(7)?;
+            if shift == 63 && group > 1 {
+                return None;
+            }
+            value |= group << shift;
+            if !self.read_bool()? {
+                return Some(value);
+            }
+            shift += 7;
+            if shift > 63 {
+                return None;
+            }
+        }
+    }
+
+    pub fn read_signed_varint(&mut self) -> Option<i64> {
+        let value = self.read_varint()?;
+        Some((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    pub fn read_quantized(&mut self, quantization: Quantization) -> Option<f32> {
+        self.read_bits(quantization.bits()).map(|step| quantization.dequantize(step))
+    }
+}
+
+/// Why a bit-packed encoding can't be used.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PackError {
+    /// A quantization's range is empty.
+    EmptyRange(f32, f32),
+    /// A quantization's precision isn't positive.
+    Precision(f32),
+    /// Values can't be packed into this many bits.
+    Bits(usize),
+}
+
+impl fmt::Display for PackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PackError::EmptyRange(min, max) => write!(f, "the range {}..{} is empty", min, max),
+            PackError::Precision(precision) => write!(f, "the precision must be positive, not {}", precision),
+            PackError::Bits(bits) => write!(f, "can't pack a value into {} bits", bits)
+        }
+    }
+}
+
+impl std::error::Error for PackError {}
+
+/// An encoding that can't be used can't be described either.
+impl From<PackError> for TraceError {
+    fn from(error: PackError) -> TraceError {
+        TraceError::Invalid(error.to_string())
+    }
+}
+
+/// How a float in a fixed range is rounded to a number of evenly spaced steps.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quantization {
+    min: f32,
+    max: f32,
+    bits: usize,
+}
+
+impl Quantization {
+    /// Quantize values between `min` and `max` with steps no larger than `precision`.
+    /// Decoded values are within half of `precision` of the encoded ones. Panics if `try_new` would fail.
+    pub fn new(min: f32, max: f32, precision: f32) -> Quantization {
+        Quantization::try_new(min, max, precision).unwrap_or_else(|e| panic!("Can't quantize: {}", e))
+    }
+
+    /// Quantize values between `min` and `max` into `bits` bits, which is between 1 and 32.
+    /// Panics if `try_with_bits` would fail.
+    pub fn with_bits(min: f32, max: f32, bits: usize) -> Quantization {
+        Quantization::try_with_bits(min, max, bits).unwrap_or_else(|e| panic!("Can't quantize: {}", e))
+    }
+
+    /// Like `new`, but fails if the precision isn't positive, or the range is empty or needs more than 32 bits.
+    pub fn try_new(min: f32, max: f32, precision: f32) -> Result<Quantization, PackError> {
+        if precision.is_nan() || precision <= 0.0 {
+            return Err(PackError::Precision(precision));
+        }
+        let steps = ((f64::from(max) - f64::from(min)) / f64::from(precision)).ceil().max(1.0);
+        let bits = 64 - (steps as u64).leading_zeros() as usize;
+        Quantization::try_with_bits(min, max, bits)
+    }
+
+    /// Like `with_bits`, but fails if the range is empty or `bits` isn't between 1 and 32.
+    pub fn try_with_bits(min: f32, max: f32, bits: usize) -> Result<Quantization, PackError> {
+        if min.is_nan() || max.is_nan() || min >= max {
+            return Err(PackError::EmptyRange(min, max));
+        }
+        if !(1..=32).contains(&bits) {
+            return Err(PackError::Bits(bits));
+        }
+        Ok(Quantization { min, max, bits })
+    }
+
+    pub fn bits(&self) -> usize {
+        self.bits
+    }
+
+    /// The distance between two steps.
+    pub fn step(&self) -> f32 {
+        ((f64::from(self.max) - f64::from(self.min)) / self.last_step() as f64) as f32
+    }
+
+    /// The step closest to `value`. Values outside the range are clamped to it, and NaN becomes the minimum.
+    pub fn quantize(&self, value: f32) -> u64 {
+        let (min, max) = (f64::from(self.min), f64::from(self.max));
+        let value = f64::from(value);
+        let fraction = if value >= max {
+            1.0
+        } else if value > min {
+            (value - min) / (max - min)
+        } else {
+            0.0
+        };
+        (fraction * self.last_step() as f64).round() as u64
+    }
+
+    pub fn dequantize(&self, step: u64) -> f32 {
+        let (min, max) = (f64::from(self.min), f64::from(self.max));
+        let fraction = step.min(self.last_step()) as f64 / self.last_step() as f64;
+        (min + fraction * (max - min)) as f32
+    }
+
+    /// The layout of quantized values, for the protocol schema.
+    pub fn layout(&self) -> Layout {
+        Layout::Quantized { min: self.min, max: self.max, bits: self.bits, step: self.step() }
+    }
+
+    fn last_step(&self) -> u64 {
+        (1 << self.bits) - 1
+    }
+}
+
+/// Encodes network IDs relative to the one before, so a sorted list of IDs takes a few bits for each.
+///
+/// The index and generation of an ID are encoded separately. The index is the signed distance from the previous
+/// index, and the generation is a single bit when it is the same as the previous one. The first ID is relative
+/// to index 0 of generation 1. The reader has to start from the same baseline as the writer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IdBaseline {
+    previous: NetworkID,
+}
+
+impl IdBaseline {
+    pub fn new() -> IdBaseline {
+        IdBaseline { previous: 1 << 32 }
+    }
+
+    pub fn write(&mut self, writer: &mut BitWriter, id: NetworkID) {
+        let (index, generation) = split(id);
+        let (previous_index, previous_generation) = split(self.previous);
+        writer.write_signed_varint(i64::from(index) - i64::from(previous_index));
+        writer.write_bool(generation != previous_generation);
+        if generation != previous_generation {
+            writer.write_signed_varint(i64::from(generation) - i64::from(previous_generation));
+        }
+        self.previous = id;
+    }
+
+    pub fn read(&mut self, reader: &mut BitReader) -> Option<NetworkID> {
+        let (previous_index, previous_generation) = split(self.previous);
+        let index = i64::from(previous_index).checked_add(reader.read_signed_varint()?)?;
+        let generation = if reader.read_bool()? {
+            i64::from(previous_generation).checked_add(reader.read_signed_varint()?)?
+        } else {
+            i64::from(previous_generation)
+        };
+        let valid = 0..=i64::from(u32::MAX);
+        if !valid.contains(&index) || !valid.contains(&generation) {
+            return None;
+        }
+        self.previous = ((generation as u64) << 32) | index as u64;
+        Some(self.previous)
+    }
+}
+
+impl Default for IdBaseline {
+    fn default() -> IdBaseline {
+        IdBaseline::new()
+    }
+}
+
+/// The index and generation of a network ID.
+fn split(id: NetworkID) -> (u32, u32) {
+    (id as u32, (id >> 32) as u32)
+}
+
+/// A value that can be written to a bit stream.
+pub trait Packed: Sized {
+    fn pack(&self, writer: &mut BitWriter);
+    fn unpack(reader: &mut BitReader) -> Option<Self>;
+    /// The bit-packed layout, for the protocol schema.
+    fn describe(schema: &mut Schema) -> Result<Layout, TraceError>;
+}
+
+impl Packed for bool {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_bool(*self);
+    }
+    fn unpack(reader: &mut BitReader) -> Option<bool> {
+        reader.read_bool()
+    }
+    fn describe(_schema: &mut Schema) -> Result<Layout, TraceError> {
+        Ok(Layout::Bool)
+    }
+}
+
+/// Unsigned integers are variable-length.
+macro_rules! packed_unsigned {
+    ($($ty:ty),*) => {$(
+        impl Packed for $ty {
+            fn pack(&self, writer: &mut BitWriter) {
+                writer.write_varint(*self as u64);
+            }
+            fn unpack(reader: &mut BitReader) -> Option<$ty> {
+                <$ty>::try_from(reader.read_varint()?).ok()
+            }
+            fn describe(_schema: &mut Schema) -> Result<Layout, TraceError> {
+                Ok(Layout::Varint)
+            }
+        }
+    )*}
+}
+
+/// Signed integers are zigzag encoded and variable-length.
+macro_rules! packed_signed {
+    ($($ty:ty),*) => {$(
+        impl Packed for $ty {
+            fn pack(&self, writer: &mut BitWriter) {
+                writer.write_signed_varint(*self as i64);
+            }
+            fn unpack(reader: &mut BitReader) -> Option<$ty> {
+                <$ty>::try_from(reader.read_signed_varint()?).ok()
+            }
+            fn describe(_schema: &mut Schema) -> Result<Layout, TraceError> {
+                Ok(Layout::SignedVarint)
+            }
+        }
+    )*}
+}
+
+packed_unsigned!(u8, u16, u32, u64, usize);
+packed_signed!(i8, i16, i32, i64, isize);
+
+/// Floats that aren't quantized are sent with all their bits.
+impl Packed for f32 {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_bits(u64::from(self.to_bits()), 32);
+    }
+    fn unpack(reader: &mut BitReader) -> Option<f32> {
+        reader.read_bits(32).map(|bits| f32::from_bits(bits as u32))
+    }
+    fn describe(_schema: &mut Schema) -> Result<Layout, TraceError> {
+        Ok(Layout::F32)
+    }
+}
+
+impl Packed for f64 {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_bits(self.to_bits(), 64);
+    }
+    fn unpack(reader: &mut BitReader) -> Option<f64> {
+        reader.read_bits(64).map(f64::from_bits)
+    }
+    fn describe(_schema: &mut Schema) -> Result<Layout, TraceError> {
+        Ok(Layout::F64)
+    }
+}
+
+/// Encode a value into whole bytes, for `Replicated::encode`.
+pub fn encode<T: Packed>(value: &T, bytes: &mut BytesMut) {
+    let mut writer = BitWriter::new();
+    value.pack(&mut writer);
+    bytes.extend_from_slice(&writer.finish());
+}
+
+/// Decode a value encoded with `encode`, for `Replicated::decode`. Nothing but padding may follow it.
+pub fn decode<T: Packed>(bytes: &[u8]) -> Option<T> {
+    let mut reader = BitReader::new(bytes);
+    let value = T::unpack(&mut reader)?;
+    if reader.remaining() >= 8 {
+        return None;
+    }
+    Some(value)
+}
+
+/// Describe a value encoded with `encode`, for `Replicated::describe`.
+pub fn describe<T: Packed>(schema: &mut Schema) -> Result<Description, TraceError> {
+    Ok(Description::bit_packed(T::describe(schema)?))
+}
+
+/// The layout of a field, given a function that gets the field from the struct, since `packed!` doesn't know
+/// the types of the fields.
+#[doc(hidden)]
+pub fn describe_field<S, T: Packed>(schema: &mut Schema, _field: fn(&S) -> &T) -> Result<Layout, TraceError> {
+    T::describe(schema)
+}
+
+/// Check the number of bits of a `bits(count)` field.
+#[doc(hidden)]
+pub fn check_bits(count: usize) -> Result<usize, PackError> {
+    if (1..=64).contains(&count) {
+        Ok(count)
+    } else {
+        Err(PackError::Bits(count))
+    }
+}
+
+/// The value of a `bits(count)` field, clamped to the largest one that fits like quantized values are to their range.
+#[doc(hidden)]
+pub fn clamp_bits(value: u64, count: usize) -> u64 {
+    if count >= 64 {
+        value
+    } else {
+        value.min((1 << count) - 1)
+    }
+}
+
+/// Implement `Packed` for a struct, with the encoding of each of its fields. Every field has to be listed, in any
+/// order, as one of:
+/// - `field: quantized(min, max, precision)`: an `f32` quantized with `Quantization::new(min, max, precision)`
+/// - `field: bits(count)`: an unsigned integer in its lowest `count` bits, between 1 and 64. Values that don't fit
+///   are sent as the largest one that does.
+/// - `field: packed`: any type that implements `Packed`
+///
+/// The struct is described in the protocol schema under its name. Describing it checks the quantizations and the
+/// numbers of bits, which `Replication::register` does, so they can't fail once the game is running.
+#[macro_export]
+macro_rules! packed {
+    (@quantization $min:expr, $max:expr, $precision:expr) => {{
+        $crate::network::bitpack::lazy_static! {
+            static ref QUANTIZATION: $crate::network::bitpack::Quantization =
+                $crate::network::bitpack::Quantization::new($min, $max, $precision);
+        }
+        *QUANTIZATION
+    }};
+    (@pack $writer:ident, $value:expr, quantized($min:expr, $max:expr, $precision:expr)) => {
+        $writer.write_quantized($value, $crate::packed!(@quantization $min, $max, $precision))
+    };
+    (@pack $writer:ident, $value:expr, bits($count:expr)) => {
+        $writer.write_bits($crate::network::bitpack::clamp_bits($value as u64, $count), $count)
+    };
+    (@pack $writer:ident, $value:expr, packed) => {
+        $crate::network::bitpack::Packed::pack(&$value, $writer)
+    };
+    (@unpack $reader:ident, quantized($min:expr, $max:expr, $precision:expr)) => {
+        $reader.read_quantized($crate::packed!(@quantization $min, $max, $precision))?
+    };
+    (@unpack $reader:ident, bits($count:expr)) => {
+        $reader.read_bits($count)? as _
+    };
+    (@unpack $reader:ident, packed) => {
+        $crate::network::bitpack::Packed::unpack($reader)?
+    };
+    (@describe $schema:ident, $name:ident, $field:ident, quantized($min:expr, $max:expr, $precision:expr)) => {
+        $crate::network::bitpack::Quantization::try_new($min, $max, $precision)?.layout()
+    };
+    (@describe $schema:ident, $name:ident, $field:ident, bits($count:expr)) => {
+        $crate::network::schema::Layout::Bits($crate::network::bitpack::check_bits($count)?)
+    };
+    (@describe $schema:ident, $name:ident, $field:ident, packed) => {
+        $crate::network::bitpack::describe_field($schema, |value: &$name| &value.$field)?
+    };
+    ($name:ident { $($field:ident : $kind:ident $(($($args:tt)*))?),* $(,)? }) => {
+        impl $crate::network::bitpack::Packed for $name {
+            fn pack(&self, writer: &mut $crate::network::bitpack::BitWriter) {
+                $($crate::packed!(@pack writer, self.$field, $kind $(($($args)*))?);)*
+            }
+            fn unpack(reader: &mut $crate::network::bitpack::BitReader) -> Option<$name> {
+                Some($name {
+                    $($field: $crate::packed!(@unpack reader, $kind $(($($args)*))?),)*
+                })
+            }
+            fn describe(schema: &mut $crate::network::schema::Schema)
+                -> Result<$crate::network::schema::Layout, $crate::network::schema::TraceError> {
+                let fields = vec![
+                    $($crate::network::schema::Field::new(
+                        stringify!($field),
+                        $crate::packed!(@describe schema, $name, $field, $kind $(($($args)*))?)
+                    ),)*
+                ];
+                schema.define(stringify!($name), $crate::network::schema::TypeLayout::Struct(fields))
+            }
+        }
+    };
+}
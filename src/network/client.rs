@@ -0,0 +1,635 @@
+//! The client half of the engine protocol, for bots, load tests, tools and native game clients.
+//!
+//! A `Connection` is opened over any stream with `Connection::connect`, or over TCP with `Connection::connect_tcp`.
+//! It runs the handshake and authentication, optionally behind TLS, and then sends and receives framed `Message`s,
+//! compressing them if the server agreed to it. The engine's own messages are handled along the way: heartbeats
+//! are answered, round trip times are measured and resume tokens are kept, so `Connection::recv` only ever
+//! returns messages meant for the game. `Connection::spawn` moves the connection onto the tokio runtime and
+//! hands back a `ClientHandle` instead, for clients that send and receive at the same time.
+//! Remote procedures are called on the server with `ClientHandle::call`, and the server's calls are answered
+//! by the handlers registered with `handle`.
+//!
+//! A `Mirror` holds the client's copy of the replicated world. Every `opcode::SNAPSHOT` message is given to
+//! `Mirror::apply`, which keeps a specs `World` in sync with the server's networked entities, using the same
+//! `Replicated` components the server registered, and returns the acknowledgement to send back.
+
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{UnboundedSender, UnboundedReceiver, unbounded_channel};
+use tokio::sync::oneshot;
+use tokio::timer::Timeout;
+use bytes::{Bytes, BytesMut};
+use specs::{Builder, Entity, World};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use crate::network::{Message, frame, heartbeat, opcode, session, tls};
+use crate::network::auth::{self, AccountID, AuthError, Credentials};
+use crate::network::compression::{self, CompressionConfig};
+use crate::network::handshake::{self, Capabilities, ClientHello, Handshake, HandshakeError};
+use crate::network::input::InputSequence;
+use crate::network::replication::{self, ComponentTypeID, NetworkID, Replicated, SnapshotDelta, SnapshotSequence, WorldState};
+use crate::network::rpc::{self, CorrelationID, Envelope, Procedure, ProcedureID, RpcError};
+use crate::network::transport::BoxedStream;
+
+/// How a client connects to the server.
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    pub hello: ClientHello,
+    /// What the client logs in with, if the server requires authentication.
+    pub credentials: Option<Credentials>,
+    /// Set to ask the server for compression.
+    pub compression: Option<CompressionConfig>,
+    /// Set to connect with TLS. The server's certificate has to be valid for this domain.
+    pub tls_domain: Option<String>,
+    /// A PEM encoded certificate to trust as well as the system's root certificates, such as a self-signed one.
+    pub root_certificate: Option<Vec<u8>>,
+}
+
+impl ClientConfig {
+    pub fn new(client_build: &str) -> ClientConfig {
+        ClientConfig {
+            hello: ClientHello::new(client_build, Capabilities::NONE),
+            credentials: None,
+            compression: None,
+            tls_domain: None,
+            root_certificate: None
+        }
+    }
+    pub fn with_credentials(mut self, credentials: Credentials) -> ClientConfig {
+        self.credentials = Some(credentials);
+        self
+    }
+    pub fn with_compression(mut self, compression: CompressionConfig) -> ClientConfig {
+        self.hello.capabilities.insert(Capabilities::COMPRESSION);
+        self.compression = Some(compression);
+        self
+    }
+    pub fn with_tls(mut self, domain: &str) -> ClientConfig {
+        self.tls_domain = Some(domain.to_string());
+        self
+    }
+    pub fn with_root_certificate(mut self, root_certificate: Vec<u8>) -> ClientConfig {
+        self.root_certificate = Some(root_certificate);
+        self
+    }
+}
+
+#[derive(Debug)]
+pub enum ConnectError {
+    Io(std::io::Error),
+    Handshake(HandshakeError),
+    /// The server didn't accept the client's credentials.
+    Auth(AuthError),
+}
+
+impl From<std::io::Error> for ConnectError {
+    fn from(e: std::io::Error) -> ConnectError {
+        ConnectError::Io(e)
+    }
+}
+
+impl From<HandshakeError> for ConnectError {
+    fn from(e: HandshakeError) -> ConnectError {
+        ConnectError::Handshake(e)
+    }
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectError::Io(e) => write!(f, "{}", e),
+            ConnectError::Handshake(e) => write!(f, "handshake failed: {}", e),
+            ConnectError::Auth(e) => write!(f, "authentication failed: {}", e)
+        }
+    }
+}
+
+type Handler = Box<dyn Fn(&[u8]) -> Result<BytesMut, RpcError> + Send + Sync>;
+
+/// What the client has learned from the engine's own messages.
+struct EngineState {
+    start: Instant,
+    rtt: Mutex<Option<Duration>>,
+    token: Mutex<Option<String>>,
+    /// Set once a `ClientHandle` is dropped, to stop its background tasks.
+    closed: AtomicBool,
+    /// The calls to the server that are waiting for a response.
+    calls: Mutex<HashMap<CorrelationID, oneshot::Sender<Result<Bytes, RpcError>>>>,
+    next_call: AtomicU32,
+    /// The handlers for the server's calls, by procedure.
+    procedures: Mutex<HashMap<ProcedureID, Handler>>,
+}
+
+/// What to do with a message from the server.
+enum Incoming {
+    /// Pass it on to the game.
+    Game(Message),
+    /// Send this back to the server.
+    Reply(Message),
+    Handled,
+}
+
+impl EngineState {
+    fn new() -> EngineState {
+        EngineState {
+            start: Instant::now(),
+            rtt: Mutex::new(None),
+            token: Mutex::new(None),
+            closed: AtomicBool::new(false),
+            calls: Mutex::new(HashMap::new()),
+            next_call: AtomicU32::new(0),
+            procedures: Mutex::new(HashMap::new())
+        }
+    }
+
+    fn handle(&self, message: Message) -> Incoming {
+        if let Some(envelope) = rpc::decode(&message) {
+            return self.answer(envelope);
+        }
+        let now = Instant::now();
+        match heartbeat::decode(&message) {
+            Some((opcode::PING, _)) => Incoming::Reply(heartbeat::pong(&message)),
+            Some((_, timestamp)) => {
+                if let Some(rtt) = heartbeat::round_trip(timestamp, self.start, now) {
+                    *self.rtt.lock().expect("To get a lock on the round trip time") = Some(rtt);
+                }
+                Incoming::Handled
+            },
+            None => match session::decode_token(&message) {
+                Some(token) => {
+                    *self.token.lock().expect("To get a lock on the resume token") = Some(token);
+                    Incoming::Handled
+                },
+                None => Incoming::Game(message)
+            }
+        }
+    }
+
+    /// Answer a call from the server, or complete a call to it.
+    fn answer(&self, envelope: Envelope) -> Incoming {
+        match envelope {
+            Envelope::Request { procedure, correlation, payload } => {
+                let result = match self.procedures.lock().expect("To get a lock on the procedures").get(&procedure) {
+                    Some(handler) => handler(&payload[..]),
+                    None => Err(RpcError::UnknownProcedure)
+                };
+                Incoming::Reply(rpc::encode_response(correlation, result))
+            },
+            Envelope::Response { correlation, result } => {
+                // Responses that arrive after their call timed out are dropped
+                if let Some(call) = self.calls.lock().expect("To get a lock on the calls").remove(&correlation) {
+                    let _ = call.send(result);
+                }
+                Incoming::Handled
+            }
+        }
+    }
+
+    fn register<P, F>(&self, handler: F)
+    where P: Procedure, F: Fn(P::Request) -> Result<P::Response, RpcError> + Send + Sync + 'static {
+        let handler: Handler = Box::new(move |payload: &[u8]| rpc::respond::<P, _>(payload, &handler));
+        self.procedures.lock().expect("To get a lock on the procedures").insert(P::ID, handler);
+    }
+
+    /// Pick a correlation ID for a new call, and get the receiver its result arrives on.
+    fn start_call(&self) -> (CorrelationID, oneshot::Receiver<Result<Bytes, RpcError>>) {
+        let correlation = self.next_call.fetch_add(1, Ordering::SeqCst);
+        let (sender, receiver) = oneshot::channel();
+        self.calls.lock().expect("To get a lock on the calls").insert(correlation, sender);
+        (correlation, receiver)
+    }
+
+    fn cancel_call(&self, correlation: CorrelationID) {
+        self.calls.lock().expect("To get a lock on the calls").remove(&correlation);
+    }
+
+    fn rtt(&self) -> Option<Duration> {
+        *self.rtt.lock().expect("To get a lock on the round trip time")
+    }
+
+    fn token(&self) -> Option<String> {
+        self.token.lock().expect("To get a lock on the resume token").clone()
+    }
+}
+
+/// A connection to a server that has passed the handshake and authentication.
+pub struct Connection {
+    stream: BoxedStream,
+    handshake: Handshake,
+    account: Option<AccountID>,
+    /// Set if the server agreed to compression.
+    compression: Option<CompressionConfig>,
+    engine: Arc<EngineState>,
+}
+
+impl Connection {
+    /// Set up a connection over a stream that was just opened to the server.
+    pub async fn connect(mut stream: BoxedStream, config: &ClientConfig) -> Result<Connection, ConnectError> {
+        if let Some(domain) = &config.tls_domain {
+            stream = tls::connect(stream, domain, config.root_certificate.as_ref().map(|pem| &pem[..])).await?;
+        }
+        let handshake = handshake::connect(&mut stream, &config.hello).await?;
+        let account = match &config.credentials {
+            Some(credentials) => Some(auth::login(&mut stream, credentials).await?.map_err(ConnectError::Auth)?),
+            None => None
+        };
+        let compression = config.compression.filter(|_| handshake.capabilities.contains(Capabilities::COMPRESSION));
+        Ok(Connection {
+            stream,
+            handshake,
+            account,
+            compression,
+            engine: Arc::new(EngineState::new())
+        })
+    }
+
+    /// Connect to a server over TCP.
+    pub async fn connect_tcp(address: SocketAddr, config: &ClientConfig) -> Result<Connection, ConnectError> {
+        let stream = TcpStream::connect(&address).await?;
+        Connection::connect(Box::new(stream), config).await
+    }
+
+    /// What the server agreed to in the handshake.
+    pub fn handshake(&self) -> &Handshake {
+        &self.handshake
+    }
+
+    /// The account the client logged in to, if it sent credentials.
+    pub fn account(&self) -> Option<AccountID> {
+        self.account
+    }
+
+    /// The most recent round trip time, once the client has pinged the server.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.engine.rtt()
+    }
+
+    /// The token to resume the session with, if the server keeps sessions.
+    pub fn token(&self) -> Option<String> {
+        self.engine.token()
+    }
+
+    pub async fn send(&mut self, message: Message) -> Result<(), std::io::Error> {
+        let message = compression::encode(message, self.compression.as_ref())?;
+        frame::write_frame(&mut self.stream, &message).await
+    }
+
+    /// Ping the server, to measure the round trip time once the pong arrives.
+    pub async fn ping(&mut self) -> Result<(), std::io::Error> {
+        self.send(heartbeat::ping(self.engine.start, Instant::now())).await
+    }
+
+    /// Answer the calls the server makes to `P`. They are answered while `recv` waits for messages.
+    pub fn handle<P, F>(&self, handler: F)
+    where P: Procedure, F: Fn(P::Request) -> Result<P::Response, RpcError> + Send + Sync + 'static {
+        self.engine.register::<P, F>(handler);
+    }
+
+    /// Wait for the next message for the game. Returns `None` once the server closes the connection.
+    pub async fn recv(&mut self) -> Result<Option<Message>, std::io::Error> {
+        loop {
+            let message = match frame::read_frame(&mut self.stream).await? {
+                Some(message) => compression::decode(message, self.compression.is_some())?,
+                None => return Ok(None)
+            };
+            match self.engine.handle(message) {
+                Incoming::Game(message) => return Ok(Some(message)),
+                Incoming::Reply(reply) => self.send(reply).await?,
+                Incoming::Handled => {}
+            }
+        }
+    }
+
+    /// Run the connection in the background on the tokio runtime, so that messages can be sent and received
+    /// at the same time. This must be called from within the runtime.
+    pub fn spawn(self) -> ClientHandle {
+        let (mut r_socket, mut w_socket) = tokio::io::split(self.stream);
+        let (outgoing_tx, mut outgoing_rx) = unbounded_channel::<Message>();
+        let (mut incoming_tx, incoming_rx) = unbounded_channel();
+        let compression = self.compression;
+        let engine = self.engine.clone();
+
+        let mut replies = outgoing_tx.clone();
+        // The write task stops once both the handle and the read task have dropped their senders
+        tokio::spawn(async move {
+            while let Ok(Some(message)) = frame::read_frame(&mut r_socket).await {
+                if engine.closed.load(Ordering::SeqCst) {
+                    break;
+                }
+                let message = match compression::decode(message, compression.is_some()) {
+                    Ok(message) => message,
+                    Err(_) => break
+                };
+                let sent = match engine.handle(message) {
+                    Incoming::Game(message) => incoming_tx.try_send(message).is_ok(),
+                    Incoming::Reply(reply) => replies.try_send(reply).is_ok(),
+                    Incoming::Handled => true
+                };
+                if !sent {
+                    break;
+                }
+            }
+            // Dropping the senders fails the calls that are still waiting
+            engine.calls.lock().expect("To get a lock on the calls").clear();
+        });
+        tokio::spawn(async move {
+            while let Some(message) = outgoing_rx.recv().await {
+                let message = match compression::encode(message, compression.as_ref()) {
+                    Ok(message) => message,
+                    Err(_) => continue
+                };
+                if frame::write_frame(&mut w_socket, &message).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        ClientHandle {
+            sender: outgoing_tx,
+            receiver: incoming_rx,
+            handshake: self.handshake,
+            account: self.account,
+            engine: self.engine
+        }
+    }
+}
+
+/// A connection running in the background. The connection is closed when the handle is dropped, the next
+/// time anything arrives from the server.
+pub struct ClientHandle {
+    sender: UnboundedSender<Message>,
+    receiver: UnboundedReceiver<Message>,
+    handshake: Handshake,
+    account: Option<AccountID>,
+    engine: Arc<EngineState>,
+}
+
+impl ClientHandle {
+    pub fn handshake(&self) -> &Handshake {
+        &self.handshake
+    }
+    pub fn account(&self) -> Option<AccountID> {
+        self.account
+    }
+    pub fn rtt(&self) -> Option<Duration> {
+        self.engine.rtt()
+    }
+    pub fn token(&self) -> Option<String> {
+        self.engine.token()
+    }
+    /// Queue a message to be sent to the server. Fails once the connection has closed.
+    pub fn send(&mut self, message: Message) -> Result<(), std::io::Error> {
+        self.sender.try_send(message)
+            .map_err(|_| std::io::Error::new(ErrorKind::NotConnected, "The connection to the server is closed"))
+    }
+    /// Wait for the next message for the game. Returns `None` once the server closes the connection.
+    pub async fn recv(&mut self) -> Option<Message> {
+        self.receiver.recv().await
+    }
+    /// Get the next message for the game, if one has already arrived.
+    pub fn try_recv(&mut self) -> Option<Message> {
+        let mut context = Context::from_waker(futures::task::noop_waker_ref());
+        match self.receiver.poll_recv(&mut context) {
+            Poll::Ready(message) => message,
+            Poll::Pending => None
+        }
+    }
+    /// Ping the server, to measure the round trip time once the pong arrives.
+    pub fn ping(&mut self) -> Result<(), std::io::Error> {
+        self.send(heartbeat::ping(self.engine.start, Instant::now()))
+    }
+    /// Answer the calls the server makes to `P`.
+    pub fn handle<P, F>(&self, handler: F)
+    where P: Procedure, F: Fn(P::Request) -> Result<P::Response, RpcError> + Send + Sync + 'static {
+        self.engine.register::<P, F>(handler);
+    }
+    /// Call a procedure on the server and wait for its response, or for `timeout` to pass.
+    /// Any number of calls can wait at the same time.
+    pub async fn call<P: Procedure>(&self, request: &P::Request, timeout: Duration) -> Result<P::Response, RpcError> {
+        let (correlation, result) = self.engine.start_call();
+        if self.sender.clone().try_send(rpc::encode_request::<P>(correlation, request)).is_err() {
+            self.engine.cancel_call(correlation);
+            return Err(RpcError::Disconnected);
+        }
+        match Timeout::new(result, timeout).await {
+            Ok(Ok(result)) => rpc::response::<P>(result),
+            // The connection closed, and took the sender with it
+            Ok(Err(_)) => Err(RpcError::Disconnected),
+            Err(_) => {
+                self.engine.cancel_call(correlation);
+                Err(RpcError::TimedOut)
+            }
+        }
+    }
+}
+
+impl Drop for ClientHandle {
+    fn drop(&mut self) {
+        self.engine.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Whether snapshot `a` was sent after snapshot `b`, allowing for the sequence wrapping around.
+fn is_newer(a: SnapshotSequence, b: SnapshotSequence) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// Applies a change to one type of component, like `apply_component`.
+type Applier = fn(&mut World, Entity, Option<&[u8]>);
+
+/// The client's copy of the replicated world.
+pub struct Mirror {
+    world: World,
+    appliers: HashMap<ComponentTypeID, Applier>,
+    entities: HashMap<NetworkID, Entity>,
+    state: Arc<WorldState>,
+    sequence: SnapshotSequence,
+    last_input: InputSequence,
+    /// The snapshots that were applied, since later snapshots can be relative to any of them.
+    history: VecDeque<(SnapshotSequence, Arc<WorldState>)>,
+    /// The snapshots that have only been partly received, oldest first.
+    partial: VecDeque<PartialSnapshot>,
+}
+
+/// The parts of a snapshot that have arrived so far.
+struct PartialSnapshot {
+    sequence: SnapshotSequence,
+    parts: Vec<Option<SnapshotDelta>>,
+}
+
+impl PartialSnapshot {
+    fn new(sequence: SnapshotSequence, count: u16) -> PartialSnapshot {
+        PartialSnapshot { sequence, parts: vec![None; count as usize] }
+    }
+
+    /// Put the parts together, once every one of them has arrived.
+    fn complete(&mut self) -> Option<SnapshotDelta> {
+        if self.parts.iter().any(Option::is_none) {
+            return None;
+        }
+        let mut parts = self.parts.drain(..).map(Option::unwrap);
+        let mut delta = parts.next()?;
+        for part in parts {
+            delta.merge(part);
+        }
+        Some(delta)
+    }
+}
+
+impl Mirror {
+    /// The server forgets snapshots that are this far behind, so the client can as well.
+    const MAX_HISTORY: usize = 64;
+    /// How many partly received snapshots are kept waiting for their other parts.
+    const MAX_PARTIAL: usize = 4;
+
+    pub fn new() -> Mirror {
+        Mirror::default()
+    }
+
+    /// Mirror a type of replicated component. Components of types that aren't registered are still kept in
+    /// `state`, but don't appear in the world.
+    pub fn with_component<T: Replicated>(mut self) -> Mirror
+    where T::Storage: Default {
+        self.world.register::<T>();
+        self.appliers.insert(T::TYPE_ID, apply_component::<T>);
+        self
+    }
+
+    pub fn world(&self) -> &World {
+        &self.world
+    }
+
+    pub fn world_mut(&mut self) -> &mut World {
+        &mut self.world
+    }
+
+    /// The encoded state of the last snapshot that was applied.
+    pub fn state(&self) -> &WorldState {
+        &self.state
+    }
+
+    /// The sequence number of the last snapshot that was applied, or 0 if none has been.
+    pub fn sequence(&self) -> SnapshotSequence {
+        self.sequence
+    }
+
+    /// The last input the server had processed for this client, as of the last snapshot.
+    pub fn last_input(&self) -> InputSequence {
+        self.last_input
+    }
+
+    /// Get the local entity that mirrors a server entity.
+    pub fn entity(&self, id: NetworkID) -> Option<Entity> {
+        self.entities.get(&id).cloned()
+    }
+
+    /// Apply a snapshot message. Once every part of a snapshot has arrived, in any order, the world is updated and
+    /// the acknowledgement to send back to the server is returned. Other messages, stale snapshots and snapshots
+    /// relative to a baseline the mirror doesn't have are ignored.
+    pub fn apply(&mut self, message: &Message) -> Option<Message> {
+        let part = SnapshotDelta::decode(message)?;
+        let sequence = part.delta.sequence;
+        if self.sequence != 0 && !is_newer(sequence, self.sequence) {
+            return None;
+        }
+        let delta = if part.count == 1 {
+            part.delta
+        } else {
+            let position = match self.partial.iter().position(|partial| partial.sequence == sequence) {
+                Some(position) => position,
+                None => {
+                    if self.partial.len() >= Mirror::MAX_PARTIAL {
+                        self.partial.pop_front();
+                    }
+                    self.partial.push_back(PartialSnapshot::new(sequence, part.count));
+                    self.partial.len() - 1
+                }
+            };
+            let partial = &mut self.partial[position];
+            // A part that doesn't agree with the others about how many there are can't belong with them
+            if partial.parts.len() != part.count as usize {
+                return None;
+            }
+            partial.parts[part.index as usize] = Some(part.delta);
+            let delta = partial.complete()?;
+            self.partial.remove(position);
+            delta
+        };
+        let baseline = if delta.baseline == 0 {
+            Arc::new(WorldState::new())
+        } else {
+            self.history.iter().find(|(sequence, _)| *sequence == delta.baseline)?.1.clone()
+        };
+
+        let state = Arc::new(delta.apply(&baseline));
+        self.update_world(&state);
+        self.state = state.clone();
+        self.sequence = delta.sequence;
+        self.last_input = delta.last_input;
+        self.history.push_back((delta.sequence, state));
+        if self.history.len() > Mirror::MAX_HISTORY {
+            self.history.pop_front();
+        }
+        // Snapshots older than this one will never be applied
+        self.partial.retain(|partial| is_newer(partial.sequence, sequence));
+        Some(replication::encode_ack(delta.sequence))
+    }
+
+    /// Bring the world from the current state to a new one.
+    fn update_world(&mut self, state: &WorldState) {
+        let changes = SnapshotDelta::between(&self.state, state, 0, 0);
+        for id in &changes.removed {
+            if let Some(entity) = self.entities.remove(id) {
+                let _ = self.world.delete_entity(entity);
+            }
+        }
+        for delta in &changes.entities {
+            let world = &mut self.world;
+            let entity = *self.entities.entry(delta.id).or_insert_with(|| world.create_entity().build());
+            for type_id in &delta.removed {
+                if let Some(apply) = self.appliers.get(type_id) {
+                    apply(&mut self.world, entity, None);
+                }
+            }
+            for (type_id, bytes) in &delta.changed {
+                if let Some(apply) = self.appliers.get(type_id) {
+                    apply(&mut self.world, entity, Some(bytes));
+                }
+            }
+        }
+        self.world.maintain();
+    }
+}
+
+impl Default for Mirror {
+    fn default() -> Mirror {
+        Mirror {
+            world: World::new(),
+            appliers: HashMap::new(),
+            entities: HashMap::new(),
+            state: Arc::new(WorldState::new()),
+            sequence: 0,
+            last_input: 0,
+            history: VecDeque::new(),
+            partial: VecDeque::new()
+        }
+    }
+}
+
+/// Insert a decoded component, or remove it if it was removed or can't be decoded.
+fn apply_component<T: Replicated>(world: &mut World, entity: Entity, bytes: Option<&[u8]>) {
+    let mut storage = world.write_storage::<T>();
+    match bytes.and_then(T::decode) {
+        Some(component) => {
+            let _ = storage.insert(entity, component);
+        },
+        None => {
+            storage.remove(entity);
+        }
+    }
+}
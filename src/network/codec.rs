@@ -0,0 +1,218 @@
+//! A ready-made `ClientMessageCodec`, for games whose messages derive serde's `Serialize` and `Deserialize`.
+//!
+//! Each message type implements `GameMessage` to pick its opcode, and is registered with `SerdeCodec::with_handler`
+//! along with the handler that applies it to the world. A message is its opcode followed by the message encoded
+//! with bincode's default options, which `encode` and `decode` take care of: integers are variable-length and
+//! little-endian, and nothing may follow the message.
+//!
+//! Every tick, the messages for the game that are left in the inbox once the engine has taken its own are decoded
+//! and handed to the handlers of their opcodes, in the order each client sent them. A message that can't be decoded,
+//! or whose opcode has no handler, is dropped, and the client's other messages still go through. Each client that sent
+//! bad messages gets one `DecodeError` event per tick in the world's `NotifierQueue`, with why the first of them was
+//! dropped and how many were, so a client that floods the server with garbage doesn't flood the queue as well.
+
+use bincode::Options;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use specs::World;
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use crate::ecs::Updater;
+use crate::ecs::event::Event;
+use crate::ecs::notifier::NotifierQueue;
+use crate::network::{ClientID, ClientHandshakes, ClientMessageCodec, ClientMessages, Message};
+use crate::network::opcode::{self, Opcode};
+use crate::network::schema::{Description, Layout, Schema, TraceError};
+
+/// A message that goes through the `SerdeCodec`. Its opcode has to be below `opcode::RESERVED`.
+pub trait GameMessage: Serialize + DeserializeOwned + Send + Sync + 'static {
+    const OPCODE: Opcode;
+}
+
+/// Why a client's message was dropped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeErrorKind {
+    /// The message didn't even have an opcode.
+    Empty,
+    /// No message type is registered under the opcode.
+    UnknownOpcode(Opcode),
+    /// The message didn't decode as the type registered under its opcode.
+    Malformed(Opcode, String),
+}
+
+/// The event emitted for a client whose messages the codec couldn't decode during a tick.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodeError {
+    pub client: ClientID,
+    /// Why the first of the client's messages was dropped.
+    pub kind: DecodeErrorKind,
+    /// How many of the client's messages were dropped.
+    pub count: usize,
+}
+
+impl Event for DecodeError {
+    fn as_any(&self) -> &dyn Any {
+        self as &dyn Any
+    }
+    fn as_mut_any(&mut self) -> &mut dyn Any {
+        self as &mut dyn Any
+    }
+}
+
+/// Encode a message, to send it to a client or to the server.
+pub fn encode<T: GameMessage>(message: &T) -> Result<Message, bincode::Error> {
+    let mut bytes = vec![T::OPCODE];
+    bincode::options().serialize_into(&mut bytes, message)?;
+    Ok(Message::new(bytes))
+}
+
+pub fn decode<T: GameMessage>(message: &Message) -> Result<T, DecodeErrorKind> {
+    match message.bytes.split_first() {
+        Some((opcode, bytes)) if *opcode == T::OPCODE => deserialize(T::OPCODE, bytes),
+        Some((opcode, _)) => Err(DecodeErrorKind::UnknownOpcode(*opcode)),
+        None => Err(DecodeErrorKind::Empty)
+    }
+}
+
+fn deserialize<T: GameMessage>(opcode: Opcode, bytes: &[u8]) -> Result<T, DecodeErrorKind> {
+    // Nothing in the message can be longer than the message, so a bogus length can't make bincode allocate
+    bincode::options()
+        .with_limit(bytes.len() as u64)
+        .deserialize(bytes)
+        .map_err(|e| DecodeErrorKind::Malformed(opcode, e.to_string()))
+}
+
+/// A decoded message, waiting for its handler to apply it to the world.
+type Delivery = Box<dyn FnOnce(&mut World) + Send + Sync>;
+type Decoder = Box<dyn Fn(ClientID, &[u8]) -> Result<Delivery, DecodeErrorKind> + Send>;
+
+/// A registered message type.
+struct Registration {
+    name: &'static str,
+    decode: Decoder,
+    trace: fn(&mut Schema) -> Result<Layout, TraceError>,
+}
+
+/// Decodes the messages of every registered type, and routes them to their handlers.
+#[derive(Default)]
+pub struct SerdeCodec {
+    registrations: HashMap<Opcode, Registration>,
+}
+
+impl SerdeCodec {
+    pub fn new() -> SerdeCodec {
+        SerdeCodec::default()
+    }
+
+    /// Decode the messages with the opcode of `T`, and apply them to the world with `handler`.
+    /// Registering another type under the same opcode replaces this one.
+    pub fn with_handler<T, F>(mut self, handler: F) -> SerdeCodec
+    where T: GameMessage, F: Fn(&mut World, ClientID, T) + Send + Sync + 'static {
+        assert!(!opcode::is_reserved(T::OPCODE), "Opcode {:#x} is reserved for the engine", T::OPCODE);
+        let handler = Arc::new(handler);
+        let decode: Decoder = Box::new(move |client: ClientID, bytes: &[u8]| {
+            let message = deserialize::<T>(T::OPCODE, bytes)?;
+            let handler = handler.clone();
+            let delivery: Delivery = Box::new(move |world: &mut World| handler(world, client, message));
+            Ok(delivery)
+        });
+        self.registrations.insert(T::OPCODE, Registration {
+            name: std::any::type_name::<T>(),
+            decode,
+            trace: Schema::trace::<T>
+        });
+        self
+    }
+
+    /// The opcodes that have a message type registered under them.
+    pub fn opcodes(&self) -> impl Iterator<Item=Opcode> + '_ {
+        self.registrations.keys().cloned()
+    }
+}
+
+/// What the `SerdeCodec` made of a tick's messages.
+#[derive(Default)]
+pub struct Decoded {
+    deliveries: Vec<Delivery>,
+    errors: Vec<DecodeError>,
+}
+
+impl Decoded {
+    /// The number of messages that were decoded.
+    pub fn len(&self) -> usize {
+        self.deliveries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deliveries.is_empty()
+    }
+
+    pub fn errors(&self) -> &[DecodeError] {
+        &self.errors
+    }
+}
+
+impl ClientMessageCodec for SerdeCodec {
+    type Output = Decoded;
+    fn process_messages(&mut self, client_messages: ClientMessages, _: &ClientHandshakes) -> Decoded {
+        let mut decoded = Decoded::default();
+        for (client, messages) in client_messages {
+            let mut error: Option<DecodeError> = None;
+            for message in messages {
+                let result = match message.bytes.split_first() {
+                    Some((opcode, bytes)) => match self.registrations.get(opcode) {
+                        Some(registration) => (registration.decode)(client, bytes),
+                        None => Err(DecodeErrorKind::UnknownOpcode(*opcode))
+                    },
+                    None => Err(DecodeErrorKind::Empty)
+                };
+                match (result, &mut error) {
+                    (Ok(delivery), _) => decoded.deliveries.push(delivery),
+                    (Err(_), Some(error)) => error.count += 1,
+                    (Err(kind), None) => error = Some(DecodeError { client, kind, count: 1 })
+                }
+            }
+            decoded.errors.extend(error);
+        }
+        decoded
+    }
+
+    fn describe(&self, schema: &mut Schema) -> Result<(), TraceError> {
+        for (opcode, registration) in &self.registrations {
+            let layout = (registration.trace)(schema)?;
+            schema.add_message(*opcode, registration.name, Description::bincode(layout));
+        }
+        Ok(())
+    }
+}
+
+impl Updater for Decoded {
+    fn update_world(self, world: &mut World) {
+        for delivery in self.deliveries {
+            delivery(world);
+        }
+        if !self.errors.is_empty() {
+            if !world.res.has_value::<NotifierQueue>() {
+                world.add_resource(NotifierQueue::new());
+            }
+            let mut notifier = world.write_resource::<NotifierQueue>();
+            for error in self.errors {
+                notifier.push_event(error);
+            }
+        }
+    }
+}
+
+impl fmt::Display for DecodeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeErrorKind::Empty => write!(f, "the message is empty"),
+            DecodeErrorKind::UnknownOpcode(opcode) => write!(f, "no message type has opcode {:#x}", opcode),
+            DecodeErrorKind::Malformed(opcode, e) => write!(f, "the message with opcode {:#x} is malformed: {}", opcode, e)
+        }
+    }
+}
+
+impl std::error::Error for DecodeErrorKind {}
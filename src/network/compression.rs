@@ -0,0 +1,116 @@
+//! Optional LZ4 compression of message payloads, negotiated per connection with `Capabilities::COMPRESSION`.
+//!
+//! Once a client and the server have both agreed to compression, every message between them starts with
+//! a flag byte: 0 if the rest of the message is the payload as is, or 1 if it's LZ4 compressed (with the
+//! uncompressed size prepended as a 4 byte little-endian integer). Messages smaller than the threshold
+//! aren't worth compressing, and are sent as is. Connections without compression never see the flag byte.
+//!
+//! Transports compress and decompress messages at the edges, so the rest of the engine, including
+//! `ClientMessageCodec`s, only ever sees uncompressed messages. A message can also be compressed ahead of
+//! time with `compress`, such as a snapshot that is broadcast to every client, so that it's only compressed once.
+
+use bytes::{Bytes, BytesMut, BufMut};
+use std::io::ErrorKind;
+use crate::network::Message;
+use crate::network::frame::MAX_FRAME_SIZE;
+
+const RAW: u8 = 0;
+const LZ4: u8 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompressionConfig {
+    /// Messages smaller than this many bytes are sent without being compressed.
+    pub threshold: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> CompressionConfig {
+        CompressionConfig {
+            threshold: 256
+        }
+    }
+}
+
+impl CompressionConfig {
+    pub fn with_threshold(mut self, threshold: usize) -> CompressionConfig {
+        self.threshold = threshold;
+        self
+    }
+}
+
+fn invalid_data(msg: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Compress a message's payload, if it isn't already.
+pub fn compress(message: Message) -> Message {
+    if message.compressed {
+        return message;
+    }
+    Message {
+        bytes: Bytes::from(lz4_flex::compress_prepend_size(&message.bytes)),
+        channel: message.channel,
+        compressed: true
+    }
+}
+
+/// Decompress a message's payload, if it's compressed.
+pub fn decompress(message: Message) -> Result<Message, std::io::Error> {
+    if !message.compressed {
+        return Ok(message);
+    }
+    if message.bytes.len() < 4 {
+        return Err(invalid_data("Compressed message is too short"));
+    }
+    let mut size = [0u8; 4];
+    size.copy_from_slice(&message.bytes[..4]);
+    // Don't let a peer make us allocate more than it could have sent uncompressed
+    if u32::from_le_bytes(size) as usize > MAX_FRAME_SIZE {
+        return Err(invalid_data("Compressed message is too large"));
+    }
+    let bytes = lz4_flex::decompress_size_prepended(&message.bytes)
+        .map_err(|_| invalid_data("Compressed message is corrupt"))?;
+    Ok(Message {
+        bytes: Bytes::from(bytes),
+        channel: message.channel,
+        compressed: false
+    })
+}
+
+/// Turn a message into what is actually sent to a peer. `config` is `None` if the connection didn't
+/// negotiate compression.
+pub fn encode(message: Message, config: Option<&CompressionConfig>) -> Result<Message, std::io::Error> {
+    let config = match config {
+        Some(config) => config,
+        None => return decompress(message)
+    };
+    let original_len = message.bytes.len();
+    let message = if !message.compressed && message.bytes.len() >= config.threshold {
+        let compressed = compress(message.clone());
+        // Some payloads, such as ones that are already compressed, only get bigger
+        if compressed.bytes.len() < original_len { compressed } else { message }
+    } else {
+        message
+    };
+
+    let mut bytes = BytesMut::with_capacity(1 + message.bytes.len());
+    bytes.put_u8(if message.compressed { LZ4 } else { RAW });
+    bytes.put_slice(&message.bytes);
+    Ok(Message::on(message.channel, bytes))
+}
+
+/// Turn a message received from a peer back into the message it sent. `negotiated` is whether the connection
+/// negotiated compression.
+pub fn decode(message: Message, negotiated: bool) -> Result<Message, std::io::Error> {
+    if !negotiated {
+        return Ok(message);
+    }
+    let compressed = match message.bytes.first() {
+        Some(&RAW) => false,
+        Some(&LZ4) => true,
+        _ => return Err(invalid_data("Message has an invalid compression flag"))
+    };
+    let mut bytes = message.bytes;
+    bytes.split_to(1);
+    decompress(Message { bytes, channel: message.channel, compressed })
+}
\ No newline at end of file
@@ -0,0 +1,427 @@
+//! Simulated network conditions, to test prediction, reconnection and timeouts without a real bad network.
+//!
+//! A server with a `NetworkSimulator` puts a simulated link in each direction of every client's connection,
+//! between the transport and the client's channels. Each link delays, drops and duplicates what passes through
+//! it according to the client's `NetworkConditions`, which can be set per client and changed at any time.
+//! Heartbeats go through the links as well, so the round trip times in `ClientConnections` include the
+//! simulated latency, and a link that loses everything makes the client time out.
+//!
+//! Stream transports such as TCP never lose or duplicate anything, so their links don't either. A lost message
+//! is resent after a round trip instead, and holds up the messages behind it, like it would over a real
+//! connection. If it keeps getting lost, the link stalls and keeps resending it less and less often, until
+//! it either gets through or the link gives up and closes the connection, like TCP does. UDP links work on whole datagrams, so the UDP transport's own reliability has to deal with
+//! the loss, duplication and reordering. The handshake and authentication are never simulated.
+//!
+//! Links aren't bounded, so on stream transports, a client's outbound queue drains into its link and never fills up.
+
+use tokio::sync::mpsc::{UnboundedSender, UnboundedReceiver, unbounded_channel};
+use tokio::timer::delay;
+use futures::future;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::network::{ClientID, Message};
+
+/// Lost messages on stream transports are resent every round trip this many times, after which the link stalls.
+/// A stalled link backs off between resends, and closes the connection once it has backed off this many times.
+const MAX_RESENDS: u32 = 16;
+/// How long a stalled link first waits to resend. This doubles with every resend, up to `MAX_RESEND_TIMEOUT`.
+const MIN_RESEND_TIMEOUT: Duration = Duration::from_millis(200);
+const MAX_RESEND_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The conditions of one direction of a connection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LinkConditions {
+    /// How long everything takes to arrive.
+    pub latency: Duration,
+    /// Up to this much is randomly added to the latency of each message.
+    pub jitter: Duration,
+    /// The chance of a message being lost, between 0 and 1.
+    pub loss: f64,
+    /// The chance of a datagram arriving twice, between 0 and 1. Ignored by stream transports.
+    pub duplication: f64,
+    /// How many bytes can be sent per second, if there is a cap.
+    pub bandwidth: Option<f64>,
+    /// Whether jitter can make messages overtake each other. Ignored by stream transports, which are always in order.
+    pub reordering: bool,
+}
+
+impl Default for LinkConditions {
+    fn default() -> LinkConditions {
+        LinkConditions {
+            latency: Duration::from_secs(0),
+            jitter: Duration::from_secs(0),
+            loss: 0.0,
+            duplication: 0.0,
+            bandwidth: None,
+            reordering: false
+        }
+    }
+}
+
+/// Turn a probability into one `Rng::gen_bool` takes, treating NaN as never.
+fn chance(probability: f64) -> f64 {
+    if probability.is_nan() {
+        0.0
+    } else {
+        probability.clamp(0.0, 1.0)
+    }
+}
+
+impl LinkConditions {
+    /// A link that delivers everything straight away.
+    pub fn new() -> LinkConditions {
+        LinkConditions::default()
+    }
+    pub fn with_latency(mut self, latency: Duration, jitter: Duration) -> LinkConditions {
+        self.latency = latency;
+        self.jitter = jitter;
+        self
+    }
+    pub fn with_loss(mut self, loss: f64) -> LinkConditions {
+        self.loss = chance(loss);
+        self
+    }
+    pub fn with_duplication(mut self, duplication: f64) -> LinkConditions {
+        self.duplication = chance(duplication);
+        self
+    }
+    /// Cap how many bytes per second go through the link. Anything over the cap queues up behind it.
+    pub fn with_bandwidth(mut self, bytes_per_second: f64) -> LinkConditions {
+        self.bandwidth = Some(bytes_per_second);
+        self
+    }
+    pub fn with_reordering(mut self, reordering: bool) -> LinkConditions {
+        self.reordering = reordering;
+        self
+    }
+}
+
+/// The conditions of both directions of a client's connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NetworkConditions {
+    /// From the client to the server.
+    pub inbound: LinkConditions,
+    /// From the server to the client.
+    pub outbound: LinkConditions,
+}
+
+impl NetworkConditions {
+    /// The same conditions in both directions.
+    pub fn new(link: LinkConditions) -> NetworkConditions {
+        NetworkConditions { inbound: link, outbound: link }
+    }
+    pub fn with_inbound(mut self, inbound: LinkConditions) -> NetworkConditions {
+        self.inbound = inbound;
+        self
+    }
+    pub fn with_outbound(mut self, outbound: LinkConditions) -> NetworkConditions {
+        self.outbound = outbound;
+        self
+    }
+}
+
+/// Which way a link goes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Direction {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Default)]
+struct SimulatorState {
+    default: Option<NetworkConditions>,
+    clients: HashMap<ClientID, NetworkConditions>,
+}
+
+/// Decides the conditions of every client's connection. It can be cloned, and every clone shares the
+/// same conditions, so the game can keep one to change them while the server is running.
+#[derive(Clone, Default)]
+pub struct NetworkSimulator {
+    seed: Option<u64>,
+    state: Arc<Mutex<SimulatorState>>,
+}
+
+impl NetworkSimulator {
+    /// A simulator that leaves every client alone until it is given conditions.
+    pub fn new() -> NetworkSimulator {
+        NetworkSimulator::default()
+    }
+    /// Use these conditions for every client that doesn't have its own.
+    pub fn with_default(self, conditions: NetworkConditions) -> NetworkSimulator {
+        self.set_default(Some(conditions));
+        self
+    }
+    /// Make the randomness repeatable. Each link's randomness is still different, but only depends
+    /// on the seed, the client's ID and the link's direction.
+    pub fn with_seed(mut self, seed: u64) -> NetworkSimulator {
+        self.seed = Some(seed);
+        self
+    }
+    pub fn set_default(&self, conditions: Option<NetworkConditions>) {
+        self.state.lock().expect("To get a lock on the network conditions").default = conditions;
+    }
+    /// Give a client its own conditions, which take effect from its next message.
+    pub fn set(&self, client: ClientID, conditions: NetworkConditions) {
+        self.state.lock().expect("To get a lock on the network conditions").clients.insert(client, conditions);
+    }
+    /// Go back to the default conditions for a client.
+    pub fn clear(&self, client: ClientID) {
+        self.state.lock().expect("To get a lock on the network conditions").clients.remove(&client);
+    }
+    /// The conditions a client's connection is under, if any.
+    pub fn conditions(&self, client: ClientID) -> Option<NetworkConditions> {
+        let state = self.state.lock().expect("To get a lock on the network conditions");
+        state.clients.get(&client).or_else(|| state.default.as_ref()).copied()
+    }
+
+    fn link_conditions(&self, client: ClientID, direction: Direction) -> LinkConditions {
+        match (self.conditions(client), direction) {
+            (Some(conditions), Direction::Inbound) => conditions.inbound,
+            (Some(conditions), Direction::Outbound) => conditions.outbound,
+            (None, _) => LinkConditions::default()
+        }
+    }
+
+    fn rng(&self, client: ClientID, direction: Direction) -> StdRng {
+        match self.seed {
+            Some(seed) => StdRng::seed_from_u64(seed ^ (u64::from(client) << 1) ^ (direction == Direction::Outbound) as u64),
+            None => StdRng::from_entropy()
+        }
+    }
+}
+
+/// Something waiting to come out of a link.
+struct Delayed<T> {
+    at: Instant,
+    /// Breaks ties between things due at the same time, so that they come out in the order they went in.
+    order: u64,
+    item: T,
+}
+
+impl<T> PartialEq for Delayed<T> {
+    fn eq(&self, other: &Delayed<T>) -> bool {
+        (self.at, self.order) == (other.at, other.order)
+    }
+}
+
+impl<T> Eq for Delayed<T> {}
+
+impl<T> PartialOrd for Delayed<T> {
+    fn partial_cmp(&self, other: &Delayed<T>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Delayed<T> {
+    fn cmp(&self, other: &Delayed<T>) -> Ordering {
+        (self.at, self.order).cmp(&(other.at, other.order))
+    }
+}
+
+/// One direction of a client's connection, holding back what goes through it until it is due.
+pub(crate) struct Link<T> {
+    simulator: NetworkSimulator,
+    client: ClientID,
+    direction: Direction,
+    /// Whether the transport is a stream, which never loses, duplicates or reorders anything.
+    stream: bool,
+    rng: StdRng,
+    queue: BinaryHeap<Reverse<Delayed<T>>>,
+    order: u64,
+    /// When the bandwidth cap lets the next message start going through.
+    free_at: Instant,
+    /// When the last message is due, so that the next one can be kept behind it.
+    last_at: Instant,
+    /// On a stalled stream link, what is waiting to be resent, in order, with the size of each.
+    stalled: VecDeque<(T, usize)>,
+    /// When a stalled link next tries to resend, and how many times it has backed off.
+    resend: Option<(Instant, u32)>,
+    /// Whether the link gave up on resending, which closes the connection.
+    closed: bool,
+}
+
+impl<T: Clone> Link<T> {
+    pub fn new(simulator: NetworkSimulator, client: ClientID, direction: Direction, stream: bool, now: Instant) -> Link<T> {
+        Link {
+            rng: simulator.rng(client, direction),
+            simulator,
+            client,
+            direction,
+            stream,
+            queue: BinaryHeap::new(),
+            order: 0,
+            free_at: now,
+            last_at: now,
+            stalled: VecDeque::new(),
+            resend: None,
+            closed: false
+        }
+    }
+
+    /// Send something of the given size through the link.
+    pub fn push(&mut self, item: T, size: usize, now: Instant) {
+        if self.closed {
+            return;
+        }
+        // Nothing overtakes what a stalled stream is resending
+        if !self.stalled.is_empty() {
+            self.stalled.push_back((item, size));
+            return;
+        }
+        let conditions = self.simulator.link_conditions(self.client, self.direction);
+        let mut at = match conditions.bandwidth {
+            Some(bandwidth) if bandwidth > 0.0 => {
+                self.free_at = self.free_at.max(now) + Duration::from_secs_f64(size as f64 / bandwidth);
+                self.free_at
+            },
+            _ => now
+        } + conditions.latency;
+        let loss = chance(conditions.loss);
+        if self.rng.gen_bool(loss) {
+            if !self.stream {
+                return;
+            }
+            let mut resends = 1;
+            while self.rng.gen_bool(loss) {
+                resends += 1;
+                if resends > MAX_RESENDS {
+                    let timeout = (conditions.latency * 2).max(MIN_RESEND_TIMEOUT).min(MAX_RESEND_TIMEOUT);
+                    self.resend = Some((now + timeout, 0));
+                    self.stalled.push_back((item, size));
+                    return;
+                }
+            }
+            at += (conditions.latency * 2) * resends;
+        }
+        let duplicate = !self.stream && self.rng.gen_bool(chance(conditions.duplication));
+        let jitter = self.jitter(&conditions);
+        self.schedule(item.clone(), at + jitter, &conditions);
+        if duplicate {
+            let jitter = self.jitter(&conditions);
+            self.schedule(item, at + jitter, &conditions);
+        }
+    }
+
+    fn jitter(&mut self, conditions: &LinkConditions) -> Duration {
+        if conditions.jitter == Duration::from_secs(0) {
+            return conditions.jitter;
+        }
+        conditions.jitter.mul_f64(self.rng.gen::<f64>())
+    }
+
+    fn schedule(&mut self, item: T, at: Instant, conditions: &LinkConditions) {
+        let at = if self.stream || !conditions.reordering { at.max(self.last_at) } else { at };
+        self.last_at = self.last_at.max(at);
+        self.order += 1;
+        self.queue.push(Reverse(Delayed { at, order: self.order, item }));
+    }
+
+    /// When the next thing in the link is due, or a stalled link next resends.
+    pub fn next_due(&self) -> Option<Instant> {
+        let due = self.queue.peek().map(|Reverse(delayed)| delayed.at);
+        match (due, self.resend) {
+            (Some(due), Some((resend_at, _))) => Some(due.min(resend_at)),
+            (due, resend) => due.or(resend.map(|(resend_at, _)| resend_at))
+        }
+    }
+
+    /// Take the next thing that is due, if there is one.
+    pub fn pop(&mut self, now: Instant) -> Option<T> {
+        match self.resend {
+            Some((resend_at, backoffs)) if resend_at <= now => self.retry(backoffs, now),
+            _ => {}
+        }
+        match self.queue.peek().map(|Reverse(delayed)| delayed.at) {
+            Some(at) if at <= now => self.queue.pop().map(|Reverse(delayed)| delayed.item),
+            _ => None
+        }
+    }
+
+    /// Resend what a stalled link is holding back. If it is lost again, wait twice as long before the next try.
+    fn retry(&mut self, backoffs: u32, now: Instant) {
+        self.resend = None;
+        let conditions = self.simulator.link_conditions(self.client, self.direction);
+        let (item, size) = self.stalled.pop_front().expect("A stalled link to have something to resend");
+        if self.rng.gen_bool(chance(conditions.loss)) {
+            if backoffs + 1 >= MAX_RESENDS {
+                self.closed = true;
+                self.stalled.clear();
+                return;
+            }
+            let timeout = (conditions.latency * 2).max(MIN_RESEND_TIMEOUT) * 2u32.pow(backoffs + 1);
+            self.resend = Some((now + timeout.min(MAX_RESEND_TIMEOUT), backoffs + 1));
+            self.stalled.push_front((item, size));
+            return;
+        }
+        // It got through, and so does everything that was waiting behind it, unless one of them stalls the link again
+        let jitter = self.jitter(&conditions);
+        self.schedule(item, now + conditions.latency + jitter, &conditions);
+        let waiting = std::mem::take(&mut self.stalled);
+        for (item, size) in waiting {
+            self.push(item, size, now);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty() && self.stalled.is_empty()
+    }
+
+    /// Whether the link gave up resending something, and the connection should be closed.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+/// Run a link as a task, between two channels. Whatever is sent to the returned sender comes out of the
+/// returned receiver once it is due. Once the sender is dropped, the receiver ends after the link is empty.
+pub(crate) fn spawn_link<T>(mut link: Link<T>, size: fn(&T) -> usize) -> (UnboundedSender<T>, UnboundedReceiver<T>)
+where T: Clone + Send + 'static {
+    let (tx, mut link_rx) = unbounded_channel();
+    let (mut link_tx, rx) = unbounded_channel();
+    tokio::spawn(async move {
+        let mut open = true;
+        loop {
+            let received = match (open, link.next_due()) {
+                (true, Some(at)) => match future::select(Box::pin(link_rx.recv()), Box::pin(delay(at))).await {
+                    future::Either::Left((received, _)) => Some(received),
+                    future::Either::Right(_) => None
+                },
+                (true, None) => Some(link_rx.recv().await),
+                (false, Some(at)) => {
+                    delay(at).await;
+                    None
+                },
+                (false, None) => return
+            };
+            let now = Instant::now();
+            match received {
+                Some(Some(item)) => {
+                    let size = size(&item);
+                    link.push(item, size, now);
+                },
+                Some(None) => open = false,
+                None => {}
+            }
+            while let Some(item) = link.pop(now) {
+                // Nobody is listening anymore
+                if link_tx.try_send(item).is_err() {
+                    return;
+                }
+            }
+            // Dropping the sender ends the receiver, like a connection that was reset
+            if link.is_closed() {
+                return;
+            }
+        }
+    });
+    (tx, rx)
+}
+
+/// The size of a message, for the bandwidth cap.
+pub(crate) fn message_size(message: &Message) -> usize {
+    message.bytes.len()
+}
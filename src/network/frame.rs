@@ -0,0 +1,108 @@
+//! Messages are sent over stream transports as frames: a 4 byte big-endian length, followed by the payload.
+
+use tokio::prelude::*;
+use bytes::BytesMut;
+use futures::future;
+use std::io::ErrorKind;
+use std::pin::Pin;
+use crate::network::Message;
+use crate::network::pool::BufferPool;
+
+/// The largest payload a peer is allowed to send in one frame.
+pub const MAX_FRAME_SIZE: usize = 1 << 20;
+
+/// Read a single frame. Returns `None` if the stream was closed cleanly between frames.
+pub async fn read_frame<R>(reader: &mut R) -> Result<Option<Message>, std::io::Error>
+where R: AsyncRead + Unpin {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {},
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e)
+    }
+    let len = check_len(u32::from_be_bytes(len) as usize)?;
+    let mut bytes = BytesMut::with_capacity(len);
+    bytes.resize(len, 0);
+    reader.read_exact(&mut bytes).await?;
+    Ok(Some(Message::new(bytes)))
+}
+
+pub async fn write_frame<W>(writer: &mut W, message: &Message) -> Result<(), std::io::Error>
+where W: AsyncWrite + Unpin {
+    writer.write_all(&(message.bytes.len() as u32).to_be_bytes()).await?;
+    writer.write_all(&message.bytes).await?;
+    Ok(())
+}
+
+fn check_len(len: usize) -> Result<usize, std::io::Error> {
+    if len > MAX_FRAME_SIZE {
+        return Err(std::io::Error::new(ErrorKind::InvalidData,
+                                       format!("Frame of {} bytes is larger than the maximum of {}", len, MAX_FRAME_SIZE)));
+    }
+    Ok(len)
+}
+
+/// Reads frames into slabs from a `BufferPool`, so that each payload shares its slab's memory instead of
+/// being allocated on its own. It reads as much as the stream has ready, so several frames can come out of one read.
+pub struct FrameReader {
+    pool: BufferPool,
+    buffer: BytesMut,
+    /// Whether the buffer is a slab of its own for a frame larger than the pool's slabs, which isn't recycled.
+    oversized: bool,
+}
+
+impl FrameReader {
+    pub fn new(pool: BufferPool) -> FrameReader {
+        let buffer = pool.slab(0);
+        FrameReader { pool, buffer, oversized: false }
+    }
+
+    /// Read a single frame. Returns `None` if the stream was closed cleanly between frames.
+    pub async fn read_frame<R>(&mut self, reader: &mut R) -> Result<Option<Message>, std::io::Error>
+    where R: AsyncRead + Unpin {
+        loop {
+            let needed = if self.buffer.len() < 4 {
+                4
+            } else {
+                let len = check_len(u32::from_be_bytes([self.buffer[0], self.buffer[1], self.buffer[2], self.buffer[3]]) as usize)?;
+                if self.buffer.len() >= 4 + len {
+                    self.buffer.advance(4);
+                    return Ok(Some(Message::new(self.buffer.split_to(len).freeze())));
+                }
+                4 + len
+            };
+            self.reserve(needed);
+            let read = future::poll_fn(|cx| Pin::new(&mut *reader).poll_read_buf(cx, &mut self.buffer)).await?;
+            if read == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "The stream closed in the middle of a frame"));
+            }
+        }
+    }
+
+    /// Make sure the buffer can hold `needed` bytes without moving, moving on to a new slab if this one is full.
+    fn reserve(&mut self, needed: usize) {
+        if self.buffer.capacity() >= needed {
+            return;
+        }
+        // Only the start of a frame is left in the old slab, so copying it over is cheap
+        let mut slab = self.pool.slab(needed);
+        slab.extend_from_slice(&self.buffer);
+        let full = std::mem::replace(&mut self.buffer, slab);
+        let oversized = std::mem::replace(&mut self.oversized, needed > self.pool.slab_size());
+        if !oversized {
+            self.pool.recycle(full);
+        }
+    }
+}
+
+impl Drop for FrameReader {
+    fn drop(&mut self) {
+        let buffer = std::mem::replace(&mut self.buffer, BytesMut::new());
+        if !self.oversized {
+            self.pool.recycle(buffer);
+        }
+    }
+}
@@ -0,0 +1,339 @@
+//! Every connection starts with a handshake, before the client is inserted into the `ClientMap`.
+//!
+//! The client opens with a `ClientHello` containing the protocol version it speaks,
+//! its build string and the capabilities it supports. The server answers with a `ServerHello`
+//! that either accepts the client (along with the negotiated version and capabilities)
+//! or rejects it with a reason. A client that speaks the wrong protocol gets a clean
+//! rejection instead of a stream of garbled messages.
+//!
+//! All integers are big-endian. A `ClientHello` is laid out as:
+//! - 4 bytes: the magic `STAR`
+//! - 2 bytes: protocol version
+//! - 4 bytes: capability flags
+//! - 1 byte: length of the build string
+//! - N bytes: the build string (UTF-8)
+
+use bytes::{BytesMut, BufMut, Buf, IntoBuf};
+use tokio::prelude::*;
+use std::fmt;
+use std::time::Duration;
+
+/// The version of the wire protocol.
+pub type ProtocolVersion = u16;
+
+/// The protocol version spoken by this build of the engine.
+pub const PROTOCOL_VERSION: ProtocolVersion = 1;
+
+const MAGIC: &[u8; 4] = b"STAR";
+const HELLO_HEADER_SIZE: usize = 11;
+
+/// A set of optional protocol features, stored as bit flags.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    /// Messages may be compressed. See `compression`.
+    pub const COMPRESSION: Capabilities = Capabilities(1);
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+    pub fn insert(&mut self, other: Capabilities) {
+        self.0 |= other.0;
+    }
+    /// The capabilities present in both sets.
+    pub fn intersection(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+/// The first message a client sends after connecting.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientHello {
+    pub protocol_version: ProtocolVersion,
+    /// A free-form description of the client build, such as `"star-client 0.3.1"`.
+    pub client_build: String,
+    pub capabilities: Capabilities,
+}
+
+/// The reason a client was turned away during the handshake.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RejectReason {
+    /// The client's protocol version is outside of the range the server accepts.
+    UnsupportedVersion { client: ProtocolVersion, min: ProtocolVersion, max: ProtocolVersion },
+    /// The client's hello could not be parsed.
+    Malformed,
+    /// Any other reason, described by a message.
+    Other(String),
+}
+
+/// The server's answer to a `ClientHello`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServerHello {
+    Accepted { protocol_version: ProtocolVersion, capabilities: Capabilities },
+    Rejected(RejectReason),
+}
+
+/// The outcome of a successful handshake. This is stored with the client's
+/// entry in the `ClientMap` so codecs can branch on the protocol version.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Handshake {
+    pub protocol_version: ProtocolVersion,
+    pub client_build: String,
+    /// The capabilities supported by both the client and the server.
+    pub capabilities: Capabilities,
+}
+
+/// Which clients the server is willing to accept.
+#[derive(Clone, Debug)]
+pub struct HandshakeConfig {
+    pub min_protocol_version: ProtocolVersion,
+    pub max_protocol_version: ProtocolVersion,
+    pub capabilities: Capabilities,
+    /// How long a new connection has to finish the handshake and authenticate, before it is dropped.
+    pub timeout: Duration,
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    Io(std::io::Error),
+    /// The server turned the client away.
+    Rejected(RejectReason),
+}
+
+impl Default for HandshakeConfig {
+    fn default() -> HandshakeConfig {
+        HandshakeConfig {
+            min_protocol_version: PROTOCOL_VERSION,
+            max_protocol_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::NONE,
+            timeout: Duration::from_secs(10)
+        }
+    }
+}
+
+impl HandshakeConfig {
+    /// Decide whether to accept a client based on its hello.
+    pub fn negotiate(&self, hello: &ClientHello) -> Result<Handshake, RejectReason> {
+        if hello.protocol_version < self.min_protocol_version
+            || hello.protocol_version > self.max_protocol_version {
+            return Err(RejectReason::UnsupportedVersion {
+                client: hello.protocol_version,
+                min: self.min_protocol_version,
+                max: self.max_protocol_version
+            });
+        }
+        Ok(Handshake {
+            protocol_version: hello.protocol_version,
+            client_build: hello.client_build.clone(),
+            capabilities: hello.capabilities.intersection(self.capabilities)
+        })
+    }
+}
+
+impl ClientHello {
+    pub fn new(client_build: &str, capabilities: Capabilities) -> ClientHello {
+        ClientHello {
+            protocol_version: PROTOCOL_VERSION,
+            client_build: client_build.to_string(),
+            capabilities
+        }
+    }
+
+    pub fn encode(&self) -> BytesMut {
+        // The build string is truncated to fit its one byte length prefix.
+        let build = truncate(&self.client_build, 255).as_bytes();
+        let mut bytes = BytesMut::with_capacity(HELLO_HEADER_SIZE + build.len());
+        bytes.put_slice(MAGIC);
+        bytes.put_u16_be(self.protocol_version);
+        bytes.put_u32_be(self.capabilities.0);
+        bytes.put_u8(build.len() as u8);
+        bytes.put_slice(build);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<ClientHello, RejectReason> {
+        if bytes.len() < HELLO_HEADER_SIZE || &bytes[..4] != MAGIC {
+            return Err(RejectReason::Malformed);
+        }
+        let mut buf = (&bytes[4..]).into_buf();
+        let protocol_version = buf.get_u16_be();
+        let capabilities = Capabilities(buf.get_u32_be());
+        let build_len = buf.get_u8() as usize;
+        let build = &bytes[HELLO_HEADER_SIZE..];
+        if build.len() != build_len {
+            return Err(RejectReason::Malformed);
+        }
+        let client_build = String::from_utf8(build.to_vec()).map_err(|_| RejectReason::Malformed)?;
+        Ok(ClientHello { protocol_version, client_build, capabilities })
+    }
+}
+
+impl ServerHello {
+    pub fn encode(&self) -> BytesMut {
+        let mut bytes = BytesMut::with_capacity(8);
+        match self {
+            ServerHello::Accepted { protocol_version, capabilities } => {
+                bytes.put_u8(0);
+                bytes.put_u16_be(*protocol_version);
+                bytes.put_u32_be(capabilities.0);
+            },
+            ServerHello::Rejected(RejectReason::UnsupportedVersion { client, min, max }) => {
+                bytes.put_u8(1);
+                bytes.put_u16_be(*client);
+                bytes.put_u16_be(*min);
+                bytes.put_u16_be(*max);
+            },
+            ServerHello::Rejected(RejectReason::Malformed) => {
+                bytes.put_u8(2);
+            },
+            ServerHello::Rejected(RejectReason::Other(reason)) => {
+                let reason = truncate(reason, u16::MAX as usize).as_bytes();
+                bytes.reserve(2 + reason.len());
+                bytes.put_u8(3);
+                bytes.put_u16_be(reason.len() as u16);
+                bytes.put_slice(reason);
+            }
+        }
+        bytes
+    }
+
+    /// The number of bytes that follow the status byte, if it can be known from the status alone.
+    fn body_size(status: u8) -> Option<usize> {
+        match status {
+            0 => Some(6),
+            1 => Some(6),
+            2 => Some(0),
+            _ => None
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<ServerHello, RejectReason> {
+        if bytes.is_empty() {
+            return Err(RejectReason::Malformed);
+        }
+        let status = bytes[0];
+        let mut buf = (&bytes[1..]).into_buf();
+        if let Some(size) = ServerHello::body_size(status) {
+            if buf.remaining() < size {
+                return Err(RejectReason::Malformed);
+            }
+        }
+        match status {
+            0 => Ok(ServerHello::Accepted {
+                protocol_version: buf.get_u16_be(),
+                capabilities: Capabilities(buf.get_u32_be())
+            }),
+            1 => Ok(ServerHello::Rejected(RejectReason::UnsupportedVersion {
+                client: buf.get_u16_be(),
+                min: buf.get_u16_be(),
+                max: buf.get_u16_be()
+            })),
+            2 => Ok(ServerHello::Rejected(RejectReason::Malformed)),
+            3 if buf.remaining() >= 2 => {
+                let len = buf.get_u16_be() as usize;
+                let reason = &bytes[3..];
+                if reason.len() != len {
+                    return Err(RejectReason::Malformed);
+                }
+                Ok(ServerHello::Rejected(RejectReason::Other(String::from_utf8_lossy(reason).into_owned())))
+            },
+            _ => Err(RejectReason::Malformed)
+        }
+    }
+}
+
+/// Cut a string down to at most `max` bytes, without splitting a character.
+fn truncate(string: &str, max: usize) -> &str {
+    let mut end = string.len().min(max);
+    while !string.is_char_boundary(end) {
+        end -= 1;
+    }
+    &string[..end]
+}
+
+/// Run the server half of the handshake on a freshly accepted connection.
+/// The rejection (if any) is sent to the client before this returns.
+pub async fn accept<S>(stream: &mut S, config: &HandshakeConfig) -> Result<Handshake, HandshakeError>
+where S: AsyncRead + AsyncWrite + Unpin {
+    let mut header = [0u8; HELLO_HEADER_SIZE];
+    stream.read_exact(&mut header).await?;
+    let mut hello = header.to_vec();
+    if &header[..4] == MAGIC {
+        let mut build = vec![0u8; header[HELLO_HEADER_SIZE - 1] as usize];
+        stream.read_exact(&mut build).await?;
+        hello.extend_from_slice(&build);
+    }
+
+    let result = ClientHello::decode(&hello).and_then(|hello| config.negotiate(&hello));
+    let response = match &result {
+        Ok(handshake) => ServerHello::Accepted {
+            protocol_version: handshake.protocol_version,
+            capabilities: handshake.capabilities
+        },
+        Err(reason) => ServerHello::Rejected(reason.clone())
+    };
+    stream.write_all(&response.encode()).await?;
+    result.map_err(HandshakeError::Rejected)
+}
+
+/// Run the client half of the handshake on a freshly opened connection.
+pub async fn connect<S>(stream: &mut S, hello: &ClientHello) -> Result<Handshake, HandshakeError>
+where S: AsyncRead + AsyncWrite + Unpin {
+    stream.write_all(&hello.encode()).await?;
+
+    let mut status = [0u8; 1];
+    stream.read_exact(&mut status).await?;
+    let mut response = status.to_vec();
+    match ServerHello::body_size(status[0]) {
+        Some(size) => {
+            let mut body = vec![0u8; size];
+            stream.read_exact(&mut body).await?;
+            response.extend_from_slice(&body);
+        },
+        None => {
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len).await?;
+            let mut reason = vec![0u8; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut reason).await?;
+            response.extend_from_slice(&len);
+            response.extend_from_slice(&reason);
+        }
+    }
+
+    match ServerHello::decode(&response).map_err(HandshakeError::Rejected)? {
+        ServerHello::Accepted { protocol_version, capabilities } => Ok(Handshake {
+            protocol_version,
+            client_build: hello.client_build.clone(),
+            capabilities
+        }),
+        ServerHello::Rejected(reason) => Err(HandshakeError::Rejected(reason))
+    }
+}
+
+impl From<std::io::Error> for HandshakeError {
+    fn from(e: std::io::Error) -> HandshakeError {
+        HandshakeError::Io(e)
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::UnsupportedVersion { client, min, max } =>
+                write!(f, "protocol version {} is not supported (expected {} to {})", client, min, max),
+            RejectReason::Malformed => write!(f, "malformed handshake"),
+            RejectReason::Other(reason) => write!(f, "{}", reason)
+        }
+    }
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::Io(e) => write!(f, "{}", e),
+            HandshakeError::Rejected(reason) => write!(f, "rejected: {}", reason)
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,161 @@
+//! Heartbeats keep track of whether clients are still there, and how laggy they are.
+//!
+//! Over stream transports, the server sends each client a `opcode::PING` every `HeartbeatConfig::interval`.
+//! A ping is the opcode followed by an 8 byte big-endian timestamp, which only means something to the
+//! side that sent it. The other side answers with a `opcode::PONG` carrying the same timestamp, which gives
+//! the sender the round trip time. Clients can ping the server the same way. Heartbeats are handled by the
+//! transport, and never reach the game.
+//!
+//! A client that hasn't sent anything, pongs included, for `HeartbeatConfig::timeout` is assumed to be gone,
+//! and is removed from the `ClientMap`. This catches half-open connections, which the OS can take a very long
+//! time to report. The UDP and WebSocket transports have their own keepalives, but report to the same place.
+//!
+//! The round trip time and the last time each client was heard from are exposed to the world through
+//! the `ClientConnections` resource, for lag displays and AFK handling, along with the depth of their queues.
+
+use bytes::{BytesMut, BufMut};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::network::{ClientID, Message};
+use crate::network::opcode::{self, Opcode};
+
+const HEARTBEAT_SIZE: usize = 9;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeartbeatConfig {
+    /// How often clients are pinged.
+    pub interval: Duration,
+    /// Clients that haven't sent anything for this long are disconnected.
+    pub timeout: Duration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> HeartbeatConfig {
+        HeartbeatConfig {
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(10)
+        }
+    }
+}
+
+impl HeartbeatConfig {
+    pub fn with_interval(mut self, interval: Duration) -> HeartbeatConfig {
+        self.interval = interval;
+        self
+    }
+    pub fn with_timeout(mut self, timeout: Duration) -> HeartbeatConfig {
+        self.timeout = timeout;
+        self
+    }
+}
+
+/// Create a `PING`, timestamped with the time since `start`.
+pub fn ping(start: Instant, now: Instant) -> Message {
+    let mut bytes = BytesMut::with_capacity(HEARTBEAT_SIZE);
+    bytes.put_u8(opcode::PING);
+    bytes.put_u64_be(now.duration_since(start).as_micros() as u64);
+    Message::new(bytes)
+}
+
+/// Create the `PONG` that answers a `PING`.
+pub fn pong(ping: &Message) -> Message {
+    let mut bytes = BytesMut::from(&ping.bytes[..]);
+    bytes[0] = opcode::PONG;
+    Message::new(bytes)
+}
+
+/// Make the place where a transport leaves its answer to the client's latest ping, until the write half sends it.
+/// A new answer replaces one that hasn't been sent yet, so a client that pings faster than it reads can't make the
+/// server queue up pongs.
+pub(crate) fn pongs<T>() -> (PongSender<T>, PongReceiver<T>) {
+    let slot = Arc::new(Mutex::new(None));
+    let (notify_tx, notify_rx) = channel(1);
+    (PongSender { slot: slot.clone(), notify: notify_tx }, PongReceiver { slot, notify: notify_rx })
+}
+
+pub(crate) struct PongSender<T> {
+    slot: Arc<Mutex<Option<T>>>,
+    notify: Sender<()>,
+}
+
+impl<T> PongSender<T> {
+    pub(crate) fn send(&mut self, pong: T) {
+        *self.slot.lock().expect("To get a lock on the pong") = Some(pong);
+        // The write half only has to be woken once, however many pongs replace each other before it runs
+        let _ = self.notify.try_send(());
+    }
+}
+
+pub(crate) struct PongReceiver<T> {
+    slot: Arc<Mutex<Option<T>>>,
+    notify: Receiver<()>,
+}
+
+impl<T> PongReceiver<T> {
+    /// Wait for the next pong. Returns `None` once the read half is gone.
+    pub(crate) async fn recv(&mut self) -> Option<T> {
+        loop {
+            self.notify.recv().await?;
+            if let Some(pong) = self.slot.lock().expect("To get a lock on the pong").take() {
+                return Some(pong);
+            }
+        }
+    }
+}
+
+/// Find the opcode and timestamp of a heartbeat, or `None` if the message isn't one.
+pub fn decode(message: &Message) -> Option<(Opcode, u64)> {
+    let bytes = &message.bytes;
+    if bytes.len() != HEARTBEAT_SIZE || (bytes[0] != opcode::PING && bytes[0] != opcode::PONG) {
+        return None;
+    }
+    let mut timestamp = [0u8; 8];
+    timestamp.copy_from_slice(&bytes[1..]);
+    Some((bytes[0], u64::from_be_bytes(timestamp)))
+}
+
+/// Work out the round trip time from the timestamp of a `PONG` that answers one of our pings.
+pub fn round_trip(timestamp: u64, start: Instant, now: Instant) -> Option<Duration> {
+    let sent = start + Duration::from_micros(timestamp);
+    if sent > now {
+        return None;
+    }
+    Some(now.duration_since(sent))
+}
+
+/// How a client's connection is doing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// The smoothed round trip time, once it has been measured.
+    pub rtt: Option<Duration>,
+    /// When anything last arrived from the client.
+    pub last_seen: Instant,
+    /// How many of the client's messages are waiting for the game to read them.
+    pub inbound_queue: usize,
+    /// How many messages are waiting to be sent to the client.
+    pub outbound_queue: usize,
+}
+
+/// The resource that holds the `ConnectionStats` of every connected client, updated every tick.
+#[derive(Clone, Debug, Default)]
+pub struct ClientConnections {
+    connections: HashMap<ClientID, ConnectionStats>,
+}
+
+impl ClientConnections {
+    pub fn new(connections: HashMap<ClientID, ConnectionStats>) -> ClientConnections {
+        ClientConnections { connections }
+    }
+    pub fn get(&self, client: ClientID) -> Option<&ConnectionStats> {
+        self.connections.get(&client)
+    }
+    /// How long it has been since anything arrived from the client.
+    pub fn idle_time(&self, client: ClientID, now: Instant) -> Option<Duration> {
+        self.connections.get(&client).map(|stats| now.saturating_duration_since(stats.last_seen))
+    }
+    pub fn iter(&self) -> impl Iterator<Item=(&ClientID, &ConnectionStats)> {
+        self.connections.iter()
+    }
+}
\ No newline at end of file
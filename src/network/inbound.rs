@@ -0,0 +1,240 @@
+//! The path messages take from a client's transport to the `ClientMap`.
+//!
+//! Each transport passes the messages a client sends to an `InboundSender`, which enforces the
+//! server's rate limits and keeps the client's `InboundState` up to date. The server keeps the
+//! other end of the `InboundState` in the client's `ClientEntry`, so the game can see how many messages
+//! are waiting, which limits the client went over, and when it last heard from the client. However the rate
+//! limits are set, a client can't have more than `queue::QueueConfig::inbound_capacity` messages waiting.
+
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::timer::delay_for;
+use std::fmt;
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use crate::network::{ClientID, Message};
+use crate::network::ratelimit::{RateLimit, RateLimitConfig, RateLimiter, RateLimitViolation, RatePolicy};
+
+/// What the server and a client's transport share about the client's connection.
+#[derive(Debug)]
+pub struct InboundState {
+    /// How many messages are waiting for the game to read them.
+    queued: AtomicUsize,
+    violations: Mutex<Vec<RateLimitViolation>>,
+    /// When anything, including a heartbeat, last arrived from the client.
+    last_seen: Mutex<Instant>,
+    /// The smoothed round trip time, once the transport has measured it.
+    rtt: Mutex<Option<Duration>>,
+}
+
+impl Default for InboundState {
+    fn default() -> InboundState {
+        InboundState {
+            queued: AtomicUsize::new(0),
+            violations: Mutex::new(Vec::new()),
+            last_seen: Mutex::new(Instant::now()),
+            rtt: Mutex::new(None)
+        }
+    }
+}
+
+impl InboundState {
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+    /// Record that the game has read `count` messages.
+    pub(crate) fn received(&self, count: usize) {
+        self.queued.fetch_sub(count, Ordering::SeqCst);
+    }
+    /// Take the violations that have happened since this was last called, one for each limit.
+    pub fn take_violations(&self) -> Vec<RateLimitViolation> {
+        std::mem::take(&mut *self.violations.lock().expect("To get a lock on the rate limit violations"))
+    }
+    pub fn last_seen(&self) -> Instant {
+        *self.last_seen.lock().expect("To get a lock on the last seen time")
+    }
+    pub fn rtt(&self) -> Option<Duration> {
+        *self.rtt.lock().expect("To get a lock on the round trip time")
+    }
+    /// Record that something arrived from the client.
+    pub(crate) fn touch(&self, at: Instant) {
+        let mut last_seen = self.last_seen.lock().expect("To get a lock on the last seen time");
+        if at > *last_seen {
+            *last_seen = at;
+        }
+    }
+    /// Add a round trip time sample to the smoothed round trip time.
+    pub(crate) fn record_rtt(&self, sample: Duration) {
+        let mut rtt = self.rtt.lock().expect("To get a lock on the round trip time");
+        *rtt = Some(match *rtt {
+            Some(rtt) => rtt * 7 / 8 + sample / 8,
+            None => sample
+        });
+    }
+    /// Replace the round trip time, for transports that smooth it themselves.
+    pub(crate) fn set_rtt(&self, rtt: Option<Duration>) {
+        *self.rtt.lock().expect("To get a lock on the round trip time") = rtt;
+    }
+}
+
+/// What to do with a message.
+enum Verdict {
+    Allow,
+    Drop,
+    Wait(Duration),
+    Kick,
+}
+
+/// Passes the messages a client sends on to the client map, enforcing the rate limits.
+pub struct InboundSender {
+    id: ClientID,
+    sender: UnboundedSender<Message>,
+    limiter: Option<RateLimiter>,
+    /// How many messages can wait for the game to read them.
+    capacity: usize,
+    state: Arc<InboundState>,
+}
+
+/// The error inside the `std::io::Error` that `InboundSender` returns when the client should be kicked.
+#[derive(Debug)]
+pub struct Kicked;
+
+impl fmt::Display for Kicked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Kicked for going over the rate limits")
+    }
+}
+
+impl std::error::Error for Kicked {}
+
+fn kicked() -> std::io::Error {
+    std::io::Error::new(ErrorKind::ConnectionAborted, Kicked)
+}
+
+fn closed() -> std::io::Error {
+    std::io::Error::new(ErrorKind::ConnectionAborted, "The client was removed from the client map")
+}
+
+impl InboundSender {
+    pub(crate) fn new(id: ClientID,
+                      sender: UnboundedSender<Message>,
+                      config: Option<RateLimitConfig>,
+                      capacity: usize,
+                      state: Arc<InboundState>) -> InboundSender {
+        InboundSender {
+            id,
+            sender,
+            limiter: config.map(|config| RateLimiter::new(config, Instant::now())),
+            capacity,
+            state
+        }
+    }
+
+    fn check(&mut self, len: usize, queued: usize, report: bool) -> Verdict {
+        // The game drains the queue every tick, so check again soon. If the rate limits have a lower
+        // queue depth, they get to decide what happens instead, as long as the message isn't let in.
+        let full = queued >= self.capacity;
+        let limited = self.limiter.as_ref().is_some_and(|limiter| queued >= limiter.config().max_queue_depth);
+        if full && !limited {
+            return Verdict::Wait(Duration::from_millis(10));
+        }
+        let limiter = match &mut self.limiter {
+            Some(limiter) => limiter,
+            None => return Verdict::Allow
+        };
+        let now = Instant::now();
+        let limit = match limiter.check(len, queued, now) {
+            Ok(()) => return Verdict::Allow,
+            Err(limit) => limit
+        };
+        let policy = limiter.config().policy;
+        // Violations are counted until the game takes them, so a flood only makes one event per limit
+        let first = report && {
+            let mut violations = self.state.violations.lock().expect("To get a lock on the rate limit violations");
+            match violations.iter_mut().find(|violation| violation.limit == limit) {
+                Some(violation) => {
+                    violation.count += 1;
+                    false
+                },
+                None => {
+                    violations.push(RateLimitViolation { client: self.id, limit, policy, count: 1 });
+                    true
+                }
+            }
+        };
+        match policy {
+            RatePolicy::Drop => Verdict::Drop,
+            RatePolicy::Warn => {
+                if first {
+                    println!("Client with ID {} went over the {:?} limit", self.id, limit);
+                }
+                if full {
+                    return Verdict::Wait(Duration::from_millis(10));
+                }
+                limiter.take(len, now);
+                Verdict::Allow
+            },
+            RatePolicy::Kick => Verdict::Kick,
+            RatePolicy::Throttle => match limit {
+                // The game drains the queue every tick, so check again soon
+                RateLimit::QueueDepth => Verdict::Wait(Duration::from_millis(10)),
+                _ => Verdict::Wait(limiter.time_until(len, now))
+            }
+        }
+    }
+
+    pub fn state(&self) -> &Arc<InboundState> {
+        &self.state
+    }
+
+    fn forward(&mut self, message: Message) -> Result<(), std::io::Error> {
+        self.state.queued.fetch_add(1, Ordering::SeqCst);
+        self.sender.try_send(message).map_err(|_| {
+            self.state.received(1);
+            closed()
+        })
+    }
+
+    /// Pass a message on, waiting if the client is being throttled.
+    /// Returns an error if the client should be disconnected.
+    pub async fn send(&mut self, message: Message) -> Result<(), std::io::Error> {
+        self.state.touch(Instant::now());
+        let mut report = true;
+        loop {
+            match self.check(message.bytes.len(), self.state.queued(), report) {
+                Verdict::Allow => return self.forward(message),
+                Verdict::Drop => return Ok(()),
+                Verdict::Kick => return Err(kicked()),
+                Verdict::Wait(duration) => {
+                    // Only report the violation once, however long the message waits
+                    report = false;
+                    delay_for(duration).await;
+                }
+            }
+        }
+    }
+
+    /// Pass a message on without waiting. Messages from throttled clients are dropped.
+    /// Returns an error if the client should be disconnected.
+    pub fn try_send(&mut self, message: Message) -> Result<(), std::io::Error> {
+        self.state.touch(Instant::now());
+        match self.check(message.bytes.len(), self.state.queued(), true) {
+            Verdict::Allow => self.forward(message),
+            Verdict::Drop | Verdict::Wait(_) => Ok(()),
+            Verdict::Kick => Err(kicked())
+        }
+    }
+
+    /// Check a message the transport answers itself, like a ping, against the rate limits. It never reaches the
+    /// game, so only the message and byte rates apply. Returns whether to answer it, or an error if the client
+    /// should be disconnected.
+    pub fn admit(&mut self, len: usize) -> Result<bool, std::io::Error> {
+        self.state.touch(Instant::now());
+        match self.check(len, 0, true) {
+            Verdict::Allow => Ok(true),
+            Verdict::Drop | Verdict::Wait(_) => Ok(false),
+            Verdict::Kick => Err(kicked())
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,66 @@
+//! Messages received from clients are collected into the `Inbox` resource at the start of every tick.
+//!
+//! Engine subsystems take the messages with their reserved opcodes out of the inbox,
+//! and whatever is left over is for the game's codec.
+//!
+//! The inbox is replaced every tick. Messages that are kept for longer should be `Message::detach`ed, so that a
+//! small message doesn't keep the whole pooled buffer it was read into alive.
+
+use crate::network::{ClientID, ClientMessages, Message};
+use crate::network::opcode::{self, Opcode};
+
+/// The messages received from clients since the last tick.
+#[derive(Default)]
+pub struct Inbox {
+    messages: ClientMessages,
+}
+
+impl Inbox {
+    pub fn new() -> Inbox {
+        Inbox::default()
+    }
+
+    pub fn push(&mut self, client: ClientID, message: Message) {
+        self.messages.entry(client).or_default().push(message);
+    }
+
+    /// Remove and return every message with the given opcode, in the order they were received.
+    pub fn take(&mut self, opcode: Opcode) -> Vec<(ClientID, Message)> {
+        let mut taken = Vec::new();
+        for (client, messages) in self.messages.iter_mut() {
+            let mut i = 0;
+            while i < messages.len() {
+                if messages[i].bytes.first() == Some(&opcode) {
+                    taken.push((*client, messages.remove(i)));
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        taken
+    }
+
+    /// Remove and return the messages for the game, leaving the ones with reserved opcodes for the engine.
+    pub fn take_game(&mut self) -> ClientMessages {
+        let mut taken = ClientMessages::new();
+        for (client, messages) in self.messages.iter_mut() {
+            let (engine, game): (Vec<Message>, Vec<Message>) = std::mem::take(messages)
+                .into_iter()
+                .partition(|message| message.bytes.first().is_some_and(|opcode| opcode::is_reserved(*opcode)));
+            *messages = engine;
+            if !game.is_empty() {
+                taken.insert(*client, game);
+            }
+        }
+        taken
+    }
+
+    /// Remove and return every message that is left.
+    pub fn drain(&mut self) -> ClientMessages {
+        std::mem::take(&mut self.messages)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.values().all(|messages| messages.is_empty())
+    }
+}
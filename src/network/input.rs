@@ -0,0 +1,284 @@
+//! Client inputs, such as movement, using an item or clicking on something, go through the input pipeline.
+//!
+//! Every input carries a sequence number assigned by the client and the tick it was meant for.
+//! The server validates each input and queues it for the tick in which it applies, and every
+//! snapshot sent back to the client contains the sequence number up to which every input has been
+//! processed, either applied or rejected. Inputs that arrive out of order don't count until the ones
+//! before them have arrived. Clients use this to predict the results of their inputs and reconcile
+//! them with the server.
+//!
+//! Games define their inputs by implementing `Input` and registering them with `GameBuilder::with_input`.
+//! Systems read the inputs for the current tick from the `InputQueue` resource.
+//!
+//! Inputs are sent in messages that start with `opcode::INPUT` and a 1 byte count. Each input is then
+//! laid out as a 4 byte sequence number, a 4 byte tick, a 2 byte length and the encoded input.
+//! Integers are big-endian. Clients usually resend inputs until they see them acknowledged in a
+//! snapshot, so inputs that have already been received are ignored.
+
+use bytes::{BytesMut, BufMut, Buf, IntoBuf};
+use specs::World;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use crate::network::{ClientID, Message};
+use crate::network::opcode;
+use crate::network::inbox::Inbox;
+use crate::ecs::Tick;
+
+/// The sequence number of an input. Sequence 0 means "no input".
+pub type InputSequence = u32;
+
+/// An action taken by a client.
+pub trait Input: Send + Sync + Sized + 'static {
+    fn encode(&self, bytes: &mut BytesMut);
+    fn decode(bytes: &[u8]) -> Option<Self>;
+
+    /// Check that the client is allowed to send this input. Inputs that fail validation are dropped.
+    fn validate(&self, _client: ClientID) -> bool {
+        true
+    }
+}
+
+/// An input along with the sequence number and tick the client gave it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InputCommand<I> {
+    pub sequence: InputSequence,
+    pub tick: u32,
+    pub input: I,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputRejection {
+    /// An input with the same sequence number was already received.
+    Duplicate,
+    /// The sequence number is too far ahead of the inputs that have been processed.
+    OutOfWindow,
+    /// The input was meant for a tick too far in the past.
+    TooLate,
+    /// The input was meant for a tick too far in the future.
+    TooEarly,
+    /// The input failed `Input::validate`.
+    Invalid,
+}
+
+/// The resource that maps each client to the sequence number up to which all of its inputs
+/// have been processed. Snapshots include this value.
+#[derive(Clone, Debug, Default)]
+pub struct ProcessedInputs {
+    sequences: HashMap<ClientID, InputSequence>,
+}
+
+impl ProcessedInputs {
+    pub fn get(&self, client: ClientID) -> InputSequence {
+        self.sequences.get(&client).cloned().unwrap_or(0)
+    }
+    fn set(&mut self, client: ClientID, sequence: InputSequence) {
+        self.sequences.insert(client, sequence);
+    }
+    fn retain(&mut self, clients: &[ClientID]) {
+        self.sequences.retain(|client, _| clients.contains(client));
+    }
+}
+
+/// Which of a client's inputs have been received and processed.
+#[derive(Clone, Debug, Default)]
+struct Sequences {
+    /// Every input up to and including this one has been applied or rejected.
+    processed: InputSequence,
+    /// The inputs after `processed` that have been received.
+    received: BTreeSet<InputSequence>,
+    /// The received inputs that are still waiting for their tick.
+    pending: BTreeSet<InputSequence>,
+}
+
+impl Sequences {
+    fn finish(&mut self, sequence: InputSequence) {
+        self.pending.remove(&sequence);
+        while self.received.contains(&(self.processed + 1)) && !self.pending.contains(&(self.processed + 1)) {
+            self.processed += 1;
+            self.received.remove(&self.processed);
+        }
+    }
+}
+
+/// The resource that holds the inputs waiting to be applied.
+pub struct InputQueue<I> {
+    pending: BTreeMap<u32, Vec<(ClientID, InputCommand<I>)>>,
+    current: Vec<(ClientID, InputCommand<I>)>,
+    sequences: HashMap<ClientID, Sequences>,
+    max_late: u32,
+    max_early: u32,
+}
+
+impl<I: Input> InputQueue<I> {
+    const DEFAULT_MAX_LATE: u32 = 10;
+    const DEFAULT_MAX_EARLY: u32 = 30;
+    /// How far past the processed inputs a client's sequence numbers can get, which bounds what is remembered about them.
+    const MAX_AHEAD: InputSequence = 1024;
+
+    pub fn new() -> InputQueue<I> {
+        InputQueue::default()
+    }
+
+    /// Set how many ticks late or early an input can be. Late inputs are applied on the current tick,
+    /// early inputs are held until their tick comes.
+    pub fn with_window(mut self, max_late: u32, max_early: u32) -> InputQueue<I> {
+        self.max_late = max_late;
+        self.max_early = max_early;
+        self
+    }
+
+    /// The inputs to apply during the current tick, in the order they were received.
+    pub fn current(&self) -> impl Iterator<Item=(ClientID, &I)> {
+        self.current.iter().map(|(client, command)| (*client, &command.input))
+    }
+
+    /// The inputs to apply during the current tick, with their sequence numbers and ticks.
+    pub fn current_commands(&self) -> &[(ClientID, InputCommand<I>)] {
+        &self.current
+    }
+
+    /// The sequence number up to which all of a client's inputs have been applied or rejected.
+    pub fn processed(&self, client: ClientID) -> InputSequence {
+        self.sequences.get(&client).map(|sequences| sequences.processed).unwrap_or(0)
+    }
+
+    /// Validate an input and queue it for the tick it applies to. This is what a
+    /// `ClientMessageCodec` should call if it decodes inputs itself.
+    /// Inputs that are rejected for any reason other than being a duplicate still count as processed.
+    pub fn submit(&mut self, client: ClientID, command: InputCommand<I>, current_tick: u32) -> Result<(), InputRejection> {
+        let sequences = self.sequences.entry(client).or_default();
+        if command.sequence <= sequences.processed || sequences.received.contains(&command.sequence) {
+            return Err(InputRejection::Duplicate);
+        }
+        if command.sequence - sequences.processed > InputQueue::<I>::MAX_AHEAD {
+            return Err(InputRejection::OutOfWindow);
+        }
+        sequences.received.insert(command.sequence);
+        let rejection = if command.tick.wrapping_add(self.max_late) < current_tick {
+            Some(InputRejection::TooLate)
+        } else if command.tick > current_tick.wrapping_add(self.max_early) {
+            Some(InputRejection::TooEarly)
+        } else if !command.input.validate(client) {
+            Some(InputRejection::Invalid)
+        } else {
+            None
+        };
+        if let Some(rejection) = rejection {
+            sequences.finish(command.sequence);
+            return Err(rejection);
+        }
+        sequences.pending.insert(command.sequence);
+        let tick = command.tick.max(current_tick);
+        self.pending.entry(tick).or_default().push((client, command));
+        Ok(())
+    }
+
+    /// Move the inputs for the given tick into `current`, and forget about disconnected clients.
+    pub fn advance(&mut self, tick: u32, clients: &[ClientID]) {
+        self.current.clear();
+        // Anything left from before this tick is applied now, rather than being lost
+        let due: Vec<u32> = self.pending.range(..=tick).map(|(tick, _)| *tick).collect();
+        for due_tick in due {
+            self.current.extend(self.pending.remove(&due_tick).unwrap());
+        }
+        self.current.retain(|(client, _)| clients.contains(client));
+        self.sequences.retain(|client, _| clients.contains(client));
+        for (client, command) in &self.current {
+            if let Some(sequences) = self.sequences.get_mut(client) {
+                sequences.finish(command.sequence);
+            }
+        }
+        for inputs in self.pending.values_mut() {
+            inputs.retain(|(client, _)| clients.contains(client));
+        }
+    }
+}
+
+impl<I: Input> Default for InputQueue<I> {
+    fn default() -> InputQueue<I> {
+        InputQueue {
+            pending: BTreeMap::new(),
+            current: Vec::new(),
+            sequences: HashMap::new(),
+            max_late: InputQueue::<I>::DEFAULT_MAX_LATE,
+            max_early: InputQueue::<I>::DEFAULT_MAX_EARLY
+        }
+    }
+}
+
+/// Encode a batch of inputs into a single message. At most 255 inputs fit in one message.
+pub fn encode_inputs<I: Input>(commands: &[InputCommand<I>]) -> Message {
+    let commands = &commands[..commands.len().min(255)];
+    let mut bytes = BytesMut::with_capacity(2 + commands.len() * 16);
+    bytes.put_u8(opcode::INPUT);
+    bytes.put_u8(commands.len() as u8);
+    for command in commands {
+        let mut input = BytesMut::new();
+        command.input.encode(&mut input);
+        bytes.reserve(10 + input.len());
+        bytes.put_u32_be(command.sequence);
+        bytes.put_u32_be(command.tick);
+        bytes.put_u16_be(input.len() as u16);
+        bytes.put_slice(&input);
+    }
+    Message::new(bytes)
+}
+
+pub fn decode_inputs<I: Input>(message: &Message) -> Option<Vec<InputCommand<I>>> {
+    let bytes = &message.bytes;
+    if bytes.len() < 2 || bytes[0] != opcode::INPUT {
+        return None;
+    }
+    let count = bytes[1] as usize;
+    let mut buf = (&bytes[2..]).into_buf();
+    let mut commands = Vec::with_capacity(count);
+    for _ in 0..count {
+        if buf.remaining() < 10 {
+            return None;
+        }
+        let sequence = buf.get_u32_be();
+        let tick = buf.get_u32_be();
+        let len = buf.get_u16_be() as usize;
+        if buf.remaining() < len {
+            return None;
+        }
+        let mut input = vec![0u8; len];
+        buf.copy_to_slice(&mut input);
+        commands.push(InputCommand { sequence, tick, input: I::decode(&input)? });
+    }
+    Some(commands)
+}
+
+/// Take the inputs out of the inbox and queue them, then make the inputs for this tick current.
+/// They are recorded as processed right away, since the snapshot at the end of this tick will include their effects.
+/// This runs at the start of every tick, after the inbox has been filled.
+pub fn update<I: Input>(world: &World, clients: &[ClientID]) {
+    let tick = world.read_resource::<Tick>().0;
+    let mut queue = world.write_resource::<InputQueue<I>>();
+    for (client, message) in world.write_resource::<Inbox>().take(opcode::INPUT) {
+        // A malformed batch is dropped as a whole
+        for command in decode_inputs::<I>(&message).unwrap_or_default() {
+            let _ = queue.submit(client, command, tick);
+        }
+    }
+    queue.advance(tick, clients);
+
+    let mut processed = world.write_resource::<ProcessedInputs>();
+    processed.retain(clients);
+    for client in clients {
+        processed.set(*client, queue.processed(*client));
+    }
+}
+
+impl fmt::Display for InputRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            InputRejection::Duplicate => "input was already received",
+            InputRejection::OutOfWindow => "input is too far ahead of the processed inputs",
+            InputRejection::TooLate => "input is too late",
+            InputRejection::TooEarly => "input is too early",
+            InputRejection::Invalid => "input is invalid"
+        };
+        write!(f, "{}", description)
+    }
+}
\ No newline at end of file
@@ -0,0 +1,124 @@
+//! Area of interest filtering decides which networked entities are relevant to each client.
+//!
+//! Each client can control an entity (usually their mob), recorded in the `ControlledEntities` resource.
+//! When replication has an `InterestPolicy`, a client is only sent the entities that are close
+//! enough to its controlled entity, which saves bandwidth on large maps and keeps clients from
+//! learning about things they shouldn't see. Entities without a position are always relevant.
+//!
+//! When an entity becomes relevant to a client or stops being relevant, the client is sent a
+//! `opcode::VIEW_ENTER` or `opcode::VIEW_LEAVE` message before the snapshot. Each is laid out as the opcode,
+//! followed by a 2 byte big-endian count and that many 8 byte `NetworkID`s.
+
+use bytes::{BytesMut, BufMut, Buf, IntoBuf};
+use specs::{Component, Entity, Join, World};
+use std::collections::{HashMap, HashSet};
+use crate::network::{ClientID, Message};
+use crate::network::opcode::{self, Opcode};
+use crate::network::replication::{network_id, NetworkID, Networked, WorldState};
+
+/// A 2D position in world space.
+pub type Position = [f32; 2];
+
+/// A component that gives an entity a position, for the purposes of interest filtering.
+pub trait Positioned: Component + Send + Sync {
+    fn position(&self) -> Position;
+}
+
+/// How far a client can see from its controlled entity.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InterestPolicy {
+    /// Entities within a circle of the given radius are relevant.
+    Radius(f32),
+    /// Entities within a rectangle centered on the controlled entity are relevant.
+    ViewRect { half_width: f32, half_height: f32 },
+}
+
+/// The resource that records which entity each client controls.
+#[derive(Clone, Debug, Default)]
+pub struct ControlledEntities {
+    entities: HashMap<ClientID, Entity>,
+}
+
+/// The positions of every networked entity that has one.
+pub type Positions = HashMap<NetworkID, Position>;
+
+impl ControlledEntities {
+    pub fn new() -> ControlledEntities {
+        ControlledEntities::default()
+    }
+    /// Make a client control an entity, replacing the entity it controlled before.
+    pub fn control(&mut self, client: ClientID, entity: Entity) {
+        self.entities.insert(client, entity);
+    }
+    pub fn release(&mut self, client: ClientID) -> Option<Entity> {
+        self.entities.remove(&client)
+    }
+    pub fn get(&self, client: ClientID) -> Option<Entity> {
+        self.entities.get(&client).cloned()
+    }
+}
+
+impl InterestPolicy {
+    pub fn contains(&self, viewer: Position, target: Position) -> bool {
+        let (dx, dy) = (target[0] - viewer[0], target[1] - viewer[1]);
+        match *self {
+            InterestPolicy::Radius(radius) => dx * dx + dy * dy <= radius * radius,
+            InterestPolicy::ViewRect { half_width, half_height } => dx.abs() <= half_width && dy.abs() <= half_height
+        }
+    }
+
+    /// Find the entities in `state` that are relevant to a viewer. The viewer's own entity is always relevant,
+    /// and so are entities without a position. A client without a viewer position only sees
+    /// entities without a position.
+    pub fn relevant(&self, viewer: Option<NetworkID>, state: &WorldState, positions: &Positions) -> HashSet<NetworkID> {
+        let viewer_position = viewer.and_then(|viewer| positions.get(&viewer));
+        state.keys()
+            .filter(|id| {
+                if Some(**id) == viewer {
+                    return true;
+                }
+                match (positions.get(id), viewer_position) {
+                    (None, _) => true,
+                    (Some(position), Some(viewer_position)) => self.contains(*viewer_position, *position),
+                    (Some(_), None) => false
+                }
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+/// Collect the position of every networked entity with a `P` component.
+pub fn capture_positions<P: Positioned>(world: &World) -> Positions {
+    let entities = world.entities();
+    let networked = world.read_storage::<Networked>();
+    let positions = world.read_storage::<P>();
+    (&entities, &networked, &positions).join()
+        .map(|(entity, _, position)| (network_id(entity), position.position()))
+        .collect()
+}
+
+/// Create a `VIEW_ENTER` or `VIEW_LEAVE` message.
+pub fn encode_view_change(opcode: Opcode, ids: &[NetworkID]) -> Message {
+    let mut bytes = BytesMut::with_capacity(3 + 8 * ids.len());
+    bytes.put_u8(opcode);
+    bytes.put_u16_be(ids.len() as u16);
+    for id in ids {
+        bytes.put_u64_be(*id);
+    }
+    Message::new(bytes)
+}
+
+/// Decode a `VIEW_ENTER` or `VIEW_LEAVE` message into its opcode and entities.
+pub fn decode_view_change(message: &Message) -> Option<(Opcode, Vec<NetworkID>)> {
+    let bytes = &message.bytes;
+    if bytes.len() < 3 || (bytes[0] != opcode::VIEW_ENTER && bytes[0] != opcode::VIEW_LEAVE) {
+        return None;
+    }
+    let mut buf = (&bytes[1..]).into_buf();
+    let count = buf.get_u16_be() as usize;
+    if buf.remaining() != count * 8 {
+        return None;
+    }
+    Some((bytes[0], (0..count).map(|_| buf.get_u64_be()).collect()))
+}
\ No newline at end of file
@@ -0,0 +1,159 @@
+//! Events for clients joining and leaving, so that gameplay and admin logging can react to them.
+//!
+//! Transports record what happens to their clients in the server's `ClientEvents`, and `ecs::network::sync_clients`
+//! pushes the events to the world's `NotifierQueue` at the start of the next tick, in the order they happened:
+//! - `ClientConnected` when a client passes the handshake and gets its `ClientID`.
+//! - `ClientAuthenticated` right after, if the client logged in to an account.
+//! - `ClientDisconnected` when the client's connection closes, with the reason. If the client's session was kept
+//!   so that it can resume it, another `ClientDisconnected` follows once the session expires.
+//!
+//! Like the other events from the network, they stay in the `NotifierQueue` for one tick, and are removed when
+//! the next tick's events are pushed.
+
+use std::any::Any;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use crate::ecs::event::Event;
+use crate::network::ClientID;
+use crate::network::auth::Account;
+use crate::network::inbound::Kicked;
+use crate::network::queue::SlowConsumer;
+
+/// Why a client left.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The client closed the connection.
+    Closed,
+    /// Nothing was heard from the client for too long.
+    TimedOut,
+    /// The client went over a rate limit with `RatePolicy::Kick`.
+    Kicked,
+    /// The game removed the client from the `ClientMap`.
+    Removed,
+    /// The client didn't read its messages fast enough, and its outbound queue overflowed.
+    SlowConsumer,
+    /// The connection failed.
+    Error(String),
+    /// The client's session was kept after it disconnected, but it didn't come back in time.
+    SessionExpired,
+}
+
+impl DisconnectReason {
+    /// Work out why a client left from the error that ended its connection.
+    pub fn from_error(e: &std::io::Error) -> DisconnectReason {
+        if e.kind() == std::io::ErrorKind::TimedOut {
+            DisconnectReason::TimedOut
+        } else if e.get_ref().is_some_and(|inner| inner.is::<Kicked>()) {
+            DisconnectReason::Kicked
+        } else if e.get_ref().is_some_and(|inner| inner.is::<SlowConsumer>()) {
+            DisconnectReason::SlowConsumer
+        } else {
+            DisconnectReason::Error(e.to_string())
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientConnected {
+    pub client: ClientID,
+    pub address: SocketAddr,
+    /// Whether the client got its ID back by resuming its session.
+    pub resumed: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientAuthenticated {
+    pub client: ClientID,
+    pub address: SocketAddr,
+    pub account: Account,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientDisconnected {
+    pub client: ClientID,
+    pub address: SocketAddr,
+    pub reason: DisconnectReason,
+    /// Whether the client's session is being kept, in which case the client may come back with the same ID.
+    pub resumable: bool,
+}
+
+macro_rules! impl_event {
+    ($($event:ty),*) => {
+        $(impl Event for $event {
+            fn as_any(&self) -> &dyn Any {
+                self as &dyn Any
+            }
+            fn as_mut_any(&mut self) -> &mut dyn Any {
+                self as &mut dyn Any
+            }
+        })*
+    }
+}
+
+impl_event!(ClientConnected, ClientAuthenticated, ClientDisconnected);
+
+/// Any of the lifecycle events.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientEvent {
+    Connected(ClientConnected),
+    Authenticated(ClientAuthenticated),
+    Disconnected(ClientDisconnected),
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+    events: VecDeque<ClientEvent>,
+    /// How many events were dropped since the events were last taken.
+    dropped: usize,
+}
+
+/// The lifecycle events that haven't reached the world yet. It can be cloned, and every clone
+/// shares the same events.
+///
+/// If nothing takes the events, such as when the server runs without a `Game`, only the latest
+/// `ClientEvents::DEFAULT_CAPACITY` are kept.
+#[derive(Clone, Debug)]
+pub struct ClientEvents {
+    capacity: usize,
+    pending: Arc<Mutex<Pending>>,
+}
+
+impl Default for ClientEvents {
+    fn default() -> ClientEvents {
+        ClientEvents {
+            capacity: ClientEvents::DEFAULT_CAPACITY,
+            pending: Arc::default()
+        }
+    }
+}
+
+impl ClientEvents {
+    pub const DEFAULT_CAPACITY: usize = 4096;
+
+    pub fn new() -> ClientEvents {
+        ClientEvents::default()
+    }
+    /// Set how many events are kept until they are taken. Older events are dropped to make room for new ones.
+    pub fn with_capacity(mut self, capacity: usize) -> ClientEvents {
+        self.capacity = capacity.max(1);
+        self
+    }
+    pub(crate) fn push(&self, event: ClientEvent) {
+        let mut pending = self.pending.lock().expect("To get a lock on the client events");
+        if pending.events.len() >= self.capacity {
+            if pending.dropped == 0 {
+                println!("Nothing is taking the client events, so the oldest ones are being dropped");
+            }
+            pending.events.pop_front();
+            pending.dropped += 1;
+        }
+        pending.events.push_back(event);
+    }
+    /// Take the events that have happened since this was last called.
+    pub fn take(&self) -> Vec<ClientEvent> {
+        let mut pending = self.pending.lock().expect("To get a lock on the client events");
+        pending.dropped = 0;
+        pending.events.drain(..).collect()
+    }
+}
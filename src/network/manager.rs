@@ -0,0 +1,429 @@
+//! The connection manager, which owns every connected client so that the transports and the game never wait on each other.
+//!
+//! The manager is a task of its own, and everything that changes the set of connected clients is a command sent to it:
+//! transports register clients once they have passed the handshake (and authentication) and unregister them when their
+//! connection closes, and the game removes the clients it wants gone. Commands are handled in the order they were sent,
+//! so a client that reconnects right after its connection dropped always finds the session its old connection left behind.
+//!
+//! Sending to clients and taking what they sent doesn't go through the manager at all, so the game never waits for it.
+//! Once it has handled the commands that are waiting, the manager publishes a new `RoutingTable` with each client's
+//! queues, handshake and account, and anyone can load the latest one without taking a lock. A loaded table is a
+//! snapshot: a client that disconnects after it was loaded is still in it, and sending to them fails with
+//! `queue::SendError::Closed`. Commands that arrive together share one new table, so a burst of connections doesn't
+//! copy the table once for each of them.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use arc_swap::ArcSwap;
+use tokio::sync::mpsc::{UnboundedSender, UnboundedReceiver, unbounded_channel};
+use tokio::sync::oneshot;
+use crate::network::{ClientEntry, ClientID, ClientMap, ClientMessages, Message};
+use crate::network::auth::Account;
+use crate::network::handshake::Handshake;
+use crate::network::inbound::{InboundSender, InboundState};
+use crate::network::lifecycle::{ClientAuthenticated, ClientConnected, ClientDisconnected, ClientEvent, ClientEvents, DisconnectReason};
+use crate::network::queue::{self, OutboundReceiver, OutboundSender, QueueConfig};
+use crate::network::ratelimit::{RateLimitConfig, RateLimitViolation};
+use crate::network::session::{self, SessionStore};
+
+/// How to reach a connected client.
+pub struct Route {
+    pub address: SocketAddr,
+    /// Queues messages to send to the client.
+    pub sender: OutboundSender,
+    /// The result of the client's handshake.
+    pub handshake: Handshake,
+    /// The account the client logged in to, if the server requires authentication.
+    pub account: Option<Account>,
+    /// How many of the client's messages are waiting to be read, and which rate limits it went over.
+    pub inbound: Arc<InboundState>,
+    /// Receives messages that the client has sent.
+    pub(crate) receiver: Arc<Mutex<UnboundedReceiver<Message>>>,
+}
+
+impl Route {
+    pub(crate) fn new(entry: &ClientEntry) -> Route {
+        Route {
+            address: entry.address,
+            sender: entry.sender.clone(),
+            handshake: entry.handshake.clone(),
+            account: entry.account.clone(),
+            inbound: entry.inbound.clone(),
+            receiver: entry.receiver.clone()
+        }
+    }
+}
+
+/// The route to every connected client, as of the last change the manager made.
+pub type RoutingTable = HashMap<ClientID, Arc<Route>>;
+
+/// What the connected clients have sent since the messages were last collected.
+#[derive(Default)]
+pub struct Received {
+    pub messages: ClientMessages,
+    /// The rate limits that clients went over.
+    pub violations: Vec<RateLimitViolation>,
+}
+
+/// A client that has passed the handshake, on its way into the client map.
+struct Registration {
+    address: SocketAddr,
+    handshake: Handshake,
+    account: Option<Account>,
+    rate_limit: Option<RateLimitConfig>,
+    queues: QueueConfig,
+    sessions: Option<SessionStore>,
+    events: ClientEvents,
+}
+
+/// A client whose connection has closed, on its way out of the client map.
+struct Unregistration {
+    id: ClientID,
+    address: SocketAddr,
+    outbound: OutboundReceiver,
+    reason: DisconnectReason,
+    sessions: Option<SessionStore>,
+    events: ClientEvents,
+}
+
+enum Command {
+    Register(Registration, oneshot::Sender<(ClientID, InboundSender, OutboundReceiver)>),
+    Unregister(Unregistration, oneshot::Sender<()>),
+    Remove(ClientID, oneshot::Sender<Option<ClientEntry>>),
+}
+
+/// The most commands the manager handles before it publishes the routing table and replies to them.
+const MAX_BATCH: usize = 256;
+
+/// The manager's answer to a command. The command is sent straight away, so the answer can be dropped without waiting for it.
+/// Fails if the manager has stopped, which only happens if it panicked.
+pub struct Reply<T> {
+    receiver: oneshot::Receiver<T>,
+}
+
+impl<T> Future for Reply<T> {
+    type Output = Result<T, io::Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.receiver).poll(cx)
+            .map(|reply| reply.map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "The connection manager has stopped")))
+    }
+}
+
+/// A handle to the connection manager. It can be cloned, and every clone talks to the same manager.
+/// The manager stops once every handle is gone.
+#[derive(Clone)]
+pub struct ConnectionManager {
+    commands: UnboundedSender<Command>,
+    routes: Arc<ArcSwap<RoutingTable>>,
+}
+
+impl Default for ConnectionManager {
+    fn default() -> ConnectionManager {
+        ConnectionManager::new()
+    }
+}
+
+impl ConnectionManager {
+    /// Start a connection manager on a thread of its own. It doesn't need a runtime, since it never waits on anything
+    /// but its commands.
+    pub fn new() -> ConnectionManager {
+        let (commands, receiver) = unbounded_channel();
+        let routes = Arc::new(ArcSwap::from_pointee(RoutingTable::new()));
+        let manager = Manager {
+            clients: ClientMap::new(),
+            table: RoutingTable::new(),
+            routes: routes.clone(),
+            changed: false,
+            nonce: 0
+        };
+        std::thread::Builder::new()
+            .name("connection manager".to_string())
+            .spawn(move || futures::executor::block_on(manager.run(receiver)))
+            .expect("To start the connection manager");
+        ConnectionManager { commands, routes }
+    }
+
+    /// Load the route to every connected client, without taking a lock.
+    pub fn routes(&self) -> Arc<RoutingTable> {
+        self.routes.load_full()
+    }
+
+    /// The IDs of the connected clients.
+    pub fn clients(&self) -> Vec<ClientID> {
+        self.routes.load().keys().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.load().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Take the messages that the clients in the latest routing table have sent, along with the rate limits they went over.
+    pub fn receive(&self) -> Received {
+        receive(&self.routes.load())
+    }
+
+    /// Take a client out of the client map, which closes its connection once the entry and every routing table
+    /// that has it are dropped. The client doesn't get to resume its session.
+    pub fn remove(&self, id: ClientID) -> Reply<Option<ClientEntry>> {
+        self.send(|reply| Command::Remove(id, reply))
+    }
+
+    /// Give a client that has passed the handshake (and authentication) an ID, and add them to the client map.
+    /// If the client's account has a suspended session, the client gets that session's ID and pending messages back.
+    /// With sessions, an account that is still connected has its old connection replaced, and the client gets its ID.
+    /// Replies with the ID, the sender for messages received from the client and the receiver for messages to send to it.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn register(&self,
+                           address: SocketAddr,
+                           handshake: Handshake,
+                           account: Option<Account>,
+                           rate_limit: Option<RateLimitConfig>,
+                           queues: QueueConfig,
+                           sessions: Option<&SessionStore>,
+                           events: &ClientEvents) -> Reply<(ClientID, InboundSender, OutboundReceiver)>
+    {
+        let registration = Registration {
+            address,
+            handshake,
+            account,
+            rate_limit,
+            queues,
+            sessions: sessions.cloned(),
+            events: events.clone()
+        };
+        self.send(|reply| Command::Register(registration, reply))
+    }
+
+    /// Remove a client whose connection has closed from the client map. If `sessions` is given, the client's
+    /// session is suspended so that it can be resumed, along with the messages left in `outbound`.
+    pub(crate) fn unregister(&self,
+                             sessions: Option<&SessionStore>,
+                             events: &ClientEvents,
+                             id: ClientID,
+                             address: SocketAddr,
+                             outbound: OutboundReceiver,
+                             reason: DisconnectReason) -> Reply<()>
+    {
+        let unregistration = Unregistration {
+            id,
+            address,
+            outbound,
+            reason,
+            sessions: sessions.cloned(),
+            events: events.clone()
+        };
+        self.send(|reply| Command::Unregister(unregistration, reply))
+    }
+
+    fn send<T, F>(&self, command: F) -> Reply<T>
+    where F: FnOnce(oneshot::Sender<T>) -> Command {
+        let (reply, receiver) = oneshot::channel();
+        // The manager only stops once every handle is gone, and this is one of them
+        let _ = self.commands.clone().try_send(command(reply));
+        Reply { receiver }
+    }
+}
+
+/// Take the messages that the clients in `routes` have sent, along with the rate limits they went over.
+/// The receivers are only locked for as long as it takes to empty them, and never by the manager while it waits.
+pub fn receive(routes: &RoutingTable) -> Received {
+    let mut received = Received::default();
+    for (id, route) in routes {
+        let messages = take_unread(&route.receiver);
+        route.inbound.received(messages.len());
+        if !messages.is_empty() {
+            received.messages.insert(*id, messages);
+        }
+        received.violations.extend(route.inbound.take_violations());
+    }
+    received
+}
+
+/// Take the messages a client sent that have already arrived.
+fn take_unread(receiver: &Mutex<UnboundedReceiver<Message>>) -> Vec<Message> {
+    // The receiver is polled without a real task, since we only want what has already arrived.
+    let mut context = Context::from_waker(futures::task::noop_waker_ref());
+    let mut receiver = receiver.lock().expect("To get a lock on the client's messages");
+    let mut messages = Vec::new();
+    while let Poll::Ready(Some(message)) = receiver.poll_recv(&mut context) {
+        messages.push(message);
+    }
+    messages
+}
+
+/// Move the messages a client sent that the game hasn't read yet to the channel of its new connection.
+fn move_unread(receiver: &Mutex<UnboundedReceiver<Message>>, sender: &mut UnboundedSender<Message>) {
+    for message in take_unread(receiver) {
+        let _ = sender.try_send(message);
+    }
+}
+
+/// Copy the messages a client sent that the game hasn't read yet out of their slabs, since a suspended session
+/// can keep them for its whole grace period.
+fn detach_unread(mut entry: ClientEntry) -> ClientEntry {
+    let (mut tx, rx) = unbounded_channel();
+    for message in take_unread(&entry.receiver) {
+        let _ = tx.try_send(message.detach());
+    }
+    entry.receiver = Arc::new(Mutex::new(rx));
+    entry
+}
+
+/// The manager's own state, which nothing else can touch.
+struct Manager {
+    clients: ClientMap,
+    /// The routing table as of the last command, which is published once the waiting commands are handled.
+    table: RoutingTable,
+    routes: Arc<ArcSwap<RoutingTable>>,
+    /// Whether the table has changed since it was last published.
+    changed: bool,
+    nonce: ClientID,
+}
+
+impl Manager {
+    async fn run(mut self, mut commands: UnboundedReceiver<Command>) {
+        let mut context = Context::from_waker(futures::task::noop_waker_ref());
+        while let Some(command) = commands.recv().await {
+            let mut replies = vec![self.handle(command)];
+            while replies.len() < MAX_BATCH {
+                match commands.poll_recv(&mut context) {
+                    Poll::Ready(Some(command)) => replies.push(self.handle(command)),
+                    _ => break
+                }
+            }
+            // Published before replying, so that whoever gets a reply sees the changes in the routing table
+            if self.changed {
+                self.routes.store(Arc::new(self.table.clone()));
+                self.changed = false;
+            }
+            for reply in replies {
+                reply();
+            }
+        }
+    }
+
+    /// Handle a command, and return what sends its reply.
+    fn handle(&mut self, command: Command) -> Box<dyn FnOnce()> {
+        // If the reply can't be sent, whoever asked isn't waiting for it
+        match command {
+            Command::Register(registration, reply) => {
+                let registered = self.register(registration);
+                Box::new(move || { let _ = reply.send(registered); })
+            },
+            Command::Unregister(unregistration, reply) => {
+                self.unregister(unregistration);
+                Box::new(move || { let _ = reply.send(()); })
+            },
+            Command::Remove(id, reply) => {
+                let entry = self.clients.remove(&id);
+                if entry.is_some() {
+                    self.table.remove(&id);
+                    self.changed = true;
+                }
+                Box::new(move || { let _ = reply.send(entry); })
+            }
+        }
+    }
+
+    fn register(&mut self, registration: Registration) -> (ClientID, InboundSender, OutboundReceiver) {
+        let Registration { address, handshake, account, rate_limit, queues, sessions, events } = registration;
+        let now = Instant::now();
+        let (mut tx2, rx2) = unbounded_channel();
+        let resumed = match (&sessions, &account) {
+            (Some(sessions), Some(account)) => sessions.resume(account, now),
+            _ => None
+        };
+        // An account that logs in again while its old connection is still open takes over the old connection's entry
+        let replaced = match (&sessions, &account, &resumed) {
+            (Some(_), Some(account), None) => self.clients.iter()
+                .find(|(_, entry)| entry.account.as_ref().map(|existing| existing.id) == Some(account.id))
+                .map(|(id, _)| *id),
+            _ => None
+        };
+        let replaced = replaced.and_then(|id| {
+            self.table.remove(&id);
+            self.changed = true;
+            self.clients.remove(&id).map(|entry| (id, entry))
+        });
+        let resumed_session = resumed.is_some() || replaced.is_some();
+        let (id, tx, rx, inbound) = match (resumed, replaced) {
+            (Some(session), _) => {
+                // The old connection's sender is gone, so the messages the game hasn't read yet are moved to a new channel
+                move_unread(&session.entry.receiver, &mut tx2);
+                session.entry.inbound.touch(now);
+                println!("Client with ID {} has resumed its session", session.id);
+                (session.id, session.entry.sender, session.outbound, session.entry.inbound)
+            },
+            (None, Some((id, entry))) => {
+                // Dropping the old entry's sender closes the old connection, and what was still waiting to be sent to it is lost
+                move_unread(&entry.receiver, &mut tx2);
+                entry.inbound.touch(now);
+                println!("Client with ID {} logged in again, replacing its old connection", id);
+                let (tx, rx) = queue::outbound(queues);
+                (id, tx, rx, entry.inbound)
+            },
+            (None, None) => {
+                let (tx, rx) = queue::outbound(queues);
+                let id = self.nonce;
+                self.nonce = self.nonce.wrapping_add(1);
+                (id, tx, rx, Arc::new(InboundState::default()))
+            }
+        };
+        if let (Some(sessions), Some(account)) = (&sessions, &account) {
+            let _ = tx.try_send(session::token_message(&sessions.issue(account)));
+        }
+        events.push(ClientEvent::Connected(ClientConnected { client: id, address, resumed: resumed_session }));
+        if let Some(account) = &account {
+            events.push(ClientEvent::Authenticated(ClientAuthenticated { client: id, address, account: account.clone() }));
+        }
+        let entry = ClientEntry {
+            address,
+            sender: tx,
+            receiver: Arc::new(Mutex::new(rx2)),
+            handshake,
+            account,
+            inbound: inbound.clone()
+        };
+        self.table.insert(id, Arc::new(Route::new(&entry)));
+        self.clients.insert(id, entry);
+        self.changed = true;
+        (id, InboundSender::new(id, tx2, rate_limit, queues.inbound_capacity, inbound), rx)
+    }
+
+    fn unregister(&mut self, unregistration: Unregistration) {
+        let Unregistration { id, address, outbound, reason, sessions, events } = unregistration;
+        // A connection that was taken over by a new login no longer owns the entry under its ID
+        if self.clients.get(&id).is_some_and(|entry| !entry.sender.feeds(&outbound)) {
+            println!("The replaced connection of client with ID {} has closed", id);
+            return;
+        }
+        let entry = self.clients.remove(&id);
+        let removed = self.table.remove(&id).is_some();
+        // If the entry is already gone, the game removed the client on purpose
+        let reason = if removed { reason } else { DisconnectReason::Removed };
+        let suspended = match (entry, &sessions) {
+            (Some(entry), Some(sessions)) => sessions.suspend(id, detach_unread(entry), outbound, Instant::now()),
+            _ => false
+        };
+        events.push(ClientEvent::Disconnected(ClientDisconnected { client: id, address, reason, resumable: suspended }));
+        match sessions {
+            Some(sessions) if suspended => {
+                println!("Client with ID {} has disconnected, keeping its session for {:?}", id, sessions.grace_period());
+            },
+            _ => println!("Client with ID {} has disconnected!", id)
+        }
+        // Published after this, so that anyone who sees the client gone also sees its session and event
+        self.changed |= removed;
+    }
+}
@@ -1,26 +1,127 @@
-use tokio::net::{TcpListener, TcpStream};
-use std::net::{SocketAddr, IpAddr};
+use std::net::SocketAddr;
 use std::collections::HashMap;
-use bytes::{BytesMut};
+use bytes::Bytes;
 use std::sync::{Arc, Mutex};
 use tokio::sync::mpsc::{UnboundedSender, UnboundedReceiver, unbounded_channel};
-use tokio::net::tcp::split::{ReadHalf, WriteHalf};
+use tokio_io::split::{ReadHalf, WriteHalf};
 use tokio::prelude::*;
 use futures::future;
-use std::str::FromStr;
 use std::io::ErrorKind;
+use std::thread::JoinHandle;
+use std::pin::Pin;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::timer::Interval;
 
+pub mod handshake;
+pub mod auth;
+pub mod outbox;
+pub mod inbox;
+pub mod opcode;
+pub mod frame;
+pub mod replication;
+pub mod bitpack;
+pub mod interest;
+pub mod input;
+pub mod udp;
+pub mod websocket;
+pub mod transport;
+pub mod tls;
+pub mod compression;
+pub mod ratelimit;
+pub mod inbound;
+pub mod heartbeat;
+pub mod session;
+pub mod lifecycle;
+pub mod client;
+pub mod conditions;
+pub mod queue;
+pub mod manager;
+pub mod pool;
+pub mod rpc;
+pub mod codec;
+pub mod schema;
+
+use handshake::{Capabilities, Handshake, HandshakeConfig};
+use auth::{Account, Authenticator};
+use udp::{UdpConfig, UdpListener};
+use websocket::{WebSocketConfig, WebSocketListener};
+use transport::{BoxedStream, TcpTransport, Transport};
+use tls::TlsAcceptor;
+use compression::CompressionConfig;
+use ratelimit::RateLimitConfig;
+use inbound::{InboundSender, InboundState};
+use heartbeat::{HeartbeatConfig, PongReceiver, PongSender};
+use session::SessionStore;
+use conditions::{Direction, Link, NetworkSimulator};
+use queue::{OutboundReceiver, OutboundSender, QueueConfig};
+use manager::ConnectionManager;
+use pool::BufferPool;
+use frame::FrameReader;
+use lifecycle::{ClientEvents, DisconnectReason};
+use codec::{Decoded, SerdeCodec};
+
+#[derive(Clone, Debug)]
 pub struct Message {
-    pub bytes: BytesMut
+    /// The payload, which is frozen so that cloning a message for each of its recipients doesn't copy it.
+    pub bytes: Bytes,
+    /// How the message should be delivered. Stream transports such as TCP deliver
+    /// everything reliably and in order, and ignore this.
+    pub channel: Channel,
+    /// Whether `bytes` is LZ4 compressed. Transports decompress messages before handing them to the engine,
+    /// so this is only set on messages that were compressed ahead of time with `compression::compress`.
+    pub compressed: bool
 }
 
-/// A wrapper type that maps clients to their address and the channel
-/// to communicate with them.
-pub type ClientMap = HashMap<ClientID, (SocketAddr, UnboundedSender<Message>, UnboundedReceiver<Message>)>;
+/// The delivery guarantees of a message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Channel {
+    /// Always delivered, in the order it was sent.
+    ReliableOrdered,
+    /// Always delivered, but possibly out of order.
+    ReliableUnordered,
+    /// May be lost, and is dropped if a newer message on this channel has already arrived.
+    UnreliableSequenced,
+}
+
+impl Message {
+    /// Create a message on the reliable ordered channel.
+    pub fn new<B: Into<Bytes>>(bytes: B) -> Message {
+        Message { bytes: bytes.into(), channel: Channel::ReliableOrdered, compressed: false }
+    }
+    pub fn on<B: Into<Bytes>>(channel: Channel, bytes: B) -> Message {
+        Message { bytes: bytes.into(), channel, compressed: false }
+    }
+
+    /// Copy a small payload out of the pooled slab it was read into, so that keeping the message doesn't keep the
+    /// whole slab alive. Payloads larger than `pool::DETACH_LIMIT` are left where they are.
+    pub fn detach(self) -> Message {
+        if self.bytes.len() > pool::DETACH_LIMIT {
+            return self;
+        }
+        Message { bytes: Bytes::from(&self.bytes[..]), ..self }
+    }
+}
+
+/// Everything the server keeps about a connected client.
+pub struct ClientEntry {
+    pub address: SocketAddr,
+    /// Queues messages to send to the client.
+    pub sender: OutboundSender,
+    /// Receives messages that the client has sent. The game takes them from the routing table while the entry is in
+    /// the client map, so it is shared with the client's `manager::Route`.
+    pub receiver: Arc<Mutex<UnboundedReceiver<Message>>>,
+    /// The result of the client's handshake.
+    pub handshake: Handshake,
+    /// The account the client logged in to, if the server requires authentication.
+    pub account: Option<Account>,
+    /// How many of the client's messages are waiting to be read, and which rate limits it went over.
+    pub inbound: Arc<InboundState>,
+}
 
-/// A wrapper type that puts a client map in an arc mutex pointer so
-/// it can be accessed and modified by multiple threads.
-pub type SharedClientMap = Arc<Mutex<ClientMap>>;
+/// A wrapper type that maps clients to their address and the channel
+/// to communicate with them. The `manager::ConnectionManager` owns it.
+pub type ClientMap = HashMap<ClientID, ClientEntry>;
 
 /// A client identifier number, used to represent the UID (Unique Identifier) for each client.
 pub type ClientID = u32;
@@ -29,136 +130,506 @@ pub type ClientID = u32;
 /// messages to a certain client.
 pub type ClientMessages = HashMap<ClientID, Vec<Message>>;
 
+/// A wrapper type for a map of client IDs to the result of their handshake.
+/// Codecs use this to branch on each client's protocol version.
+pub type ClientHandshakes = HashMap<ClientID, Handshake>;
+
+/// A transport's listener, which serves its clients until it fails.
+type Listener = Pin<Box<dyn Future<Output=Result<(), std::io::Error>> + Send>>;
+
 /// A client future that processes a client connection and
 /// communicates with a server.
 pub struct Client {
-    socket: TcpStream,
+    socket: BoxedStream,
     id: ClientID,
-    server_tx: UnboundedSender<Message>,
-    server_rx: UnboundedReceiver<Message>,
-    shared_client_map: SharedClientMap,
+    address: SocketAddr,
+    server_tx: InboundSender,
+    server_rx: OutboundReceiver,
+    connections: ConnectionManager,
+    /// Set if the client negotiated compression.
+    compression: Option<CompressionConfig>,
+    heartbeat: HeartbeatConfig,
+    sessions: Option<SessionStore>,
+    events: ClientEvents,
+    simulator: Option<NetworkSimulator>,
+    buffers: BufferPool,
 }
 
 pub trait ClientMessageCodec {
     type Output;
-    fn process_messages(&mut self, client_messages: ClientMessages) -> Self::Output;
+    fn process_messages(&mut self, client_messages: ClientMessages, handshakes: &ClientHandshakes) -> Self::Output;
+
+    /// Add the messages the codec understands to the protocol schema.
+    fn describe(&self, _schema: &mut schema::Schema) -> Result<(), schema::TraceError> {
+        Ok(())
+    }
+
+    /// Whether the game hands the codec its messages every tick. If not, they are left in the inbox for systems to read.
+    fn wants_messages(&self) -> bool {
+        true
+    }
 }
 
+/// A codec that ignores every message, for games that read them from the `inbox::Inbox` themselves.
+/// Most games want the `codec::SerdeCodec` instead, which is what `Server::default` uses.
 pub struct BlankCodec;
 
 impl ClientMessageCodec for BlankCodec {
     type Output = ();
-    fn process_messages(&mut self, _: ClientMessages) {}
+    fn process_messages(&mut self, _: ClientMessages, _: &ClientHandshakes) {}
+    fn wants_messages(&self) -> bool {
+        false
+    }
 }
 
 pub struct Server<C, M>
 where C: ClientMessageCodec<Output=M> + Send + 'static, M: 'static {
-    shared_client_map: SharedClientMap,
-    codec: C
+    connections: ConnectionManager,
+    handshake_config: HandshakeConfig,
+    authenticator: Option<Arc<Authenticator>>,
+    transport: Box<dyn Transport>,
+    tls: Option<TlsAcceptor>,
+    compression: Option<CompressionConfig>,
+    rate_limit: Option<RateLimitConfig>,
+    queues: QueueConfig,
+    heartbeat: HeartbeatConfig,
+    sessions: Option<SessionStore>,
+    events: ClientEvents,
+    simulator: Option<NetworkSimulator>,
+    buffers: BufferPool,
+    udp_config: Option<UdpConfig>,
+    websocket_config: Option<WebSocketConfig>,
+    /// Taken out by the game, which runs it at the start of every tick.
+    codec: Option<C>
 }
 
 impl<C, M> Server<C, M>
 where C: ClientMessageCodec<Output=M> + Send + 'static, M: 'static {
     pub fn new(codec: C) -> Server<C, M> {
         Server {
-            codec,
-            shared_client_map: Arc::new(Mutex::new(HashMap::new()))
+            codec: Some(codec),
+            connections: ConnectionManager::new(),
+            handshake_config: HandshakeConfig::default(),
+            authenticator: None,
+            transport: Box::new(TcpTransport::default()),
+            tls: None,
+            compression: None,
+            rate_limit: None,
+            queues: QueueConfig::default(),
+            heartbeat: HeartbeatConfig::default(),
+            sessions: None,
+            events: ClientEvents::new(),
+            simulator: None,
+            buffers: BufferPool::default(),
+            udp_config: None,
+            websocket_config: None
         }
     }
-    pub fn start(mut self) {
-        tokio::runtime::Runtime::new().unwrap().block_on(
+    /// Set which protocol versions and capabilities the server accepts.
+    pub fn with_handshake_config(mut self, handshake_config: HandshakeConfig) -> Server<C, M> {
+        self.handshake_config = handshake_config;
+        self
+    }
+    /// Require clients to authenticate after the handshake. Without an authenticator,
+    /// every client that passes the handshake is let in without an account.
+    pub fn with_authenticator(mut self, authenticator: Authenticator) -> Server<C, M> {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+    /// Accept the main connections from a different transport, instead of TCP on port 4343.
+    pub fn with_transport<T: Transport + 'static>(mut self, transport: T) -> Server<C, M> {
+        self.transport = Box::new(transport);
+        self
+    }
+    /// Encrypt connections from the main transport. Use `TlsConfig::load` to get the acceptor.
+    pub fn with_tls(mut self, tls: TlsAcceptor) -> Server<C, M> {
+        self.tls = Some(tls);
+        self
+    }
+    /// Compress large messages to and from clients that support it, on every transport.
+    pub fn with_compression(mut self, compression: CompressionConfig) -> Server<C, M> {
+        self.compression = Some(compression);
+        self
+    }
+    /// Limit how many messages and bytes each client can send, on every transport.
+    pub fn with_rate_limit(mut self, rate_limit: RateLimitConfig) -> Server<C, M> {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+    /// Change how many messages can wait for each client, and what happens when a client falls too far behind.
+    pub fn with_queues(mut self, queues: QueueConfig) -> Server<C, M> {
+        self.queues = queues;
+        self
+    }
+    /// Change how often clients on the main transport are pinged, and how long they can go quiet before being dropped.
+    pub fn with_heartbeat(mut self, heartbeat: HeartbeatConfig) -> Server<C, M> {
+        self.heartbeat = heartbeat;
+        self
+    }
+    /// Keep the sessions of authenticated clients whose connection drops, so that they can resume them.
+    /// Add the same store to the authenticator to let clients log in again with their resume token.
+    pub fn with_sessions(mut self, sessions: SessionStore) -> Server<C, M> {
+        self.sessions = Some(sessions);
+        self
+    }
+    /// Simulate bad network conditions between the server and its clients, on every transport.
+    /// Keep a clone of the simulator to change the conditions while the server is running.
+    pub fn with_network_conditions(mut self, simulator: NetworkSimulator) -> Server<C, M> {
+        self.simulator = Some(simulator);
+        self
+    }
+    /// Change the pool that clients on the main transport read their messages into.
+    pub fn with_buffer_pool(mut self, buffers: BufferPool) -> Server<C, M> {
+        self.buffers = buffers;
+        self
+    }
+    /// Also accept clients over UDP. They share the client map with TCP clients.
+    pub fn with_udp(mut self, udp_config: UdpConfig) -> Server<C, M> {
+        self.udp_config = Some(udp_config);
+        self
+    }
+    /// Also accept clients over WebSockets, so that browsers can connect. They share the client map with TCP clients.
+    pub fn with_websocket(mut self, websocket_config: WebSocketConfig) -> Server<C, M> {
+        self.websocket_config = Some(websocket_config);
+        self
+    }
+    /// Get a handle to the manager of the connected clients.
+    pub fn connections(&self) -> ConnectionManager {
+        self.connections.clone()
+    }
+    /// Get a handle to the sessions of disconnected clients, if the server keeps them.
+    pub fn sessions(&self) -> Option<SessionStore> {
+        self.sessions.clone()
+    }
+    /// Get a handle to the events for clients joining and leaving.
+    pub fn client_events(&self) -> ClientEvents {
+        self.events.clone()
+    }
+    /// Take the codec out of the server, to run it on the game's thread. The server itself never calls it.
+    pub fn take_codec(&mut self) -> Option<C> {
+        self.codec.take()
+    }
+    /// Run the server on this thread until a listener fails, and return its error.
+    pub fn start(mut self) -> Result<(), std::io::Error> {
+        tokio::runtime::Runtime::new()?.block_on(
             async move {
-                // TODO: Do something with this error
-                let _ = self.serve().await;
+                if self.compression.is_some() {
+                    self.handshake_config.capabilities.insert(Capabilities::COMPRESSION);
+                }
+                let mut transports: Vec<Listener> = Vec::new();
+                if let Some(config) = self.udp_config.clone() {
+                    transports.push(Box::pin(UdpListener {
+                        config,
+                        handshake_config: self.handshake_config.clone(),
+                        authenticator: self.authenticator.clone(),
+                        compression: self.compression,
+                        rate_limit: self.rate_limit.clone(),
+                        queues: self.queues,
+                        sessions: self.sessions.clone(),
+                        events: self.events.clone(),
+                        simulator: self.simulator.clone(),
+                        connections: self.connections.clone()
+                    }.serve()));
+                }
+                if let Some(config) = self.websocket_config.clone() {
+                    transports.push(Box::pin(WebSocketListener {
+                        config,
+                        handshake_config: self.handshake_config.clone(),
+                        authenticator: self.authenticator.clone(),
+                        compression: self.compression,
+                        rate_limit: self.rate_limit.clone(),
+                        queues: self.queues,
+                        sessions: self.sessions.clone(),
+                        events: self.events.clone(),
+                        simulator: self.simulator.clone(),
+                        connections: self.connections.clone()
+                    }.serve()));
+                }
+                // The first listener to fail stops the others, since clients would only reach part of the server
+                let transport = std::mem::replace(&mut self.transport, Box::new(TcpTransport::default()));
+                future::try_join(self.serve(transport), future::try_join_all(transports)).await.map(|_| ())
             }
-        );
+        )
+    }
+    /// Start the server on a background thread, so that the game can keep ticking.
+    /// Joining the thread returns the error that stopped the server.
+    pub fn spawn(self) -> JoinHandle<Result<(), std::io::Error>> {
+        std::thread::spawn(move || self.start())
+    }
+}
+
+/// A server with the `SerdeCodec`, which decodes nothing until message types are registered with it.
+impl Default for Server<SerdeCodec, Decoded> {
+    fn default() -> Server<SerdeCodec, Decoded> {
+        Server::new(SerdeCodec::new())
     }
 }
 
 impl<C, M> Server<C, M>
 where C: ClientMessageCodec<Output=M> + Send + 'static, M: 'static {
-    async fn serve(&mut self) -> Result<(), std::io::Error> {
-        let mut client_nonce: ClientID = 0;
-        let mut listener = TcpListener::bind(
-            SocketAddr::new(IpAddr::from_str("0.0.0.0").unwrap(), 4343)).await?;
+    async fn serve(&self, mut transport: Box<dyn Transport>) -> Result<(), std::io::Error> {
         #[allow(irrefutable_let_patterns)]
-        while let (stream, address) = listener.accept().await? {
-            let id = client_nonce;
-            let (tx, rx) = unbounded_channel();
-            let (tx2, rx2) = unbounded_channel();
-            self.insert_client(id, address, tx, rx2);
-            let client_map = self.shared_client_map.clone();
+        while let (mut stream, address) = transport.accept().await? {
+            let connections = self.connections.clone();
+            let handshake_config = self.handshake_config.clone();
+            let authenticator = self.authenticator.clone();
+            let tls = self.tls.clone();
+            let compression = self.compression;
+            let rate_limit = self.rate_limit.clone();
+            let queues = self.queues;
+            let heartbeat = self.heartbeat;
+            let sessions = self.sessions.clone();
+            let events = self.events.clone();
+            let simulator = self.simulator.clone();
+            let buffers = self.buffers.clone();
             tokio::spawn(async move {
-                Client::new(stream, id, tx2, rx, client_map)
-                    .process().await;
+                let timeout = handshake_config.timeout;
+                let admitted = async move {
+                    // Encrypt the connection before anything else is sent over it
+                    if let Some(tls) = tls {
+                        stream = match tls.accept(stream).await {
+                            Ok(stream) => stream,
+                            Err(e) => {
+                                println!("Client at {} failed to set up TLS: {}", address, e);
+                                return None;
+                            }
+                        };
+                    }
+                    // The client only makes it into the client map once it has passed the handshake
+                    let handshake = match handshake::accept(&mut stream, &handshake_config).await {
+                        Ok(handshake) => handshake,
+                        Err(e) => {
+                            println!("Client at {} failed the handshake: {}", address, e);
+                            return None;
+                        }
+                    };
+                    // ...and has authenticated, if the server requires it
+                    let account = match authenticator {
+                        Some(authenticator) => match auth::accept(&mut stream, &authenticator).await {
+                            Ok(Ok(account)) => Some(account),
+                            Ok(Err(e)) => {
+                                println!("Client at {} failed to authenticate: {}", address, e);
+                                return None;
+                            },
+                            Err(e) => {
+                                println!("Client at {} disconnected during authentication: {}", address, e);
+                                return None;
+                            }
+                        },
+                        None => None
+                    };
+                    Some((stream, handshake, account))
+                };
+                // The heartbeat only starts once the client is registered, so until then it has a deadline instead
+                let (stream, handshake, account) = match admitted.timeout(timeout).await {
+                    Ok(Some(admitted)) => admitted,
+                    Ok(None) => return,
+                    Err(_) => {
+                        println!("Client at {} took too long to connect", address);
+                        return;
+                    }
+                };
+                let compression = compression.filter(|_| handshake.capabilities.contains(Capabilities::COMPRESSION));
+                let (id, tx, rx) = match connections.register(address, handshake, account, rate_limit, queues,
+                                                              sessions.as_ref(), &events).await {
+                    Ok(registered) => registered,
+                    Err(e) => {
+                        println!("Failed to register client at {}: {}", address, e);
+                        return;
+                    }
+                };
+                let client = Client {
+                    socket: stream,
+                    id,
+                    address,
+                    server_tx: tx,
+                    server_rx: rx,
+                    connections,
+                    compression,
+                    heartbeat,
+                    sessions,
+                    events,
+                    simulator,
+                    buffers
+                };
+                client.process().await;
             });
-            client_nonce += 1;
         }
 
         Ok(())
     }
-    fn insert_client(&mut self,
-                     id: ClientID,
-                     address: SocketAddr,
-                     tx: UnboundedSender<Message>,
-                     rx: UnboundedReceiver<Message>)
-    {
-        self.shared_client_map.lock().expect("To get a lock on the shared client map")
-            .insert(id, (address, tx, rx));
+}
+
+/// Where a client's messages come from: straight from the socket, or out of a simulated link.
+enum Incoming {
+    Socket(ReadHalf<BoxedStream>, FrameReader, bool),
+    Simulated(UnboundedReceiver<Message>),
+}
+
+impl Incoming {
+    async fn next(&mut self) -> Result<Option<Message>, std::io::Error> {
+        match self {
+            Incoming::Socket(r_socket, frames, compressed) => match frames.read_frame(r_socket).await? {
+                Some(message) => compression::decode(message, *compressed).map(Some),
+                None => Ok(None)
+            },
+            Incoming::Simulated(link_rx) => Ok(link_rx.recv().await)
+        }
     }
 }
 
+/// Where a client's messages go: straight to the socket, or into a simulated link.
+enum Outgoing {
+    Socket(WriteHalf<BoxedStream>, Option<CompressionConfig>),
+    Simulated(UnboundedSender<Message>),
+}
 
-impl Client {
-    fn new(socket: TcpStream,
-               id: ClientID,
-               server_tx: UnboundedSender<Message>,
-               server_rx: UnboundedReceiver<Message>,
-               shared_client_map: SharedClientMap) -> Client
-    {
-        Client {
-            socket,
-            id,
-            server_tx,
-            server_rx,
-            shared_client_map
+impl Outgoing {
+    async fn send(&mut self, message: Message) -> Result<(), std::io::Error> {
+        match self {
+            Outgoing::Socket(w_socket, config) => {
+                frame::write_frame(w_socket, &compression::encode(message, config.as_ref())?).await
+            },
+            Outgoing::Simulated(link_tx) => link_tx.try_send(message)
+                .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "The simulated link has closed"))
         }
     }
 }
 
 impl Client {
-    const BUFFER_SIZE: usize = 4096;
-    async fn read(mut r_socket: ReadHalf<'_>, mut server_tx: UnboundedSender<Message>) -> Result<(), std::io::Error> {
+    async fn read(mut incoming: Incoming,
+                  mut server_tx: InboundSender,
+                  mut pong_tx: PongSender<Message>,
+                  heartbeat: HeartbeatConfig,
+                  start: Instant) -> Result<(), std::io::Error> {
         loop {
-            let mut buffer = BytesMut::with_capacity(Client::BUFFER_SIZE);
-            println!("Reading");
-            r_socket.read(&mut buffer).await?;
-            // This checks if the buffer is just a bunch of zeros (and therefore blank).
-            if buffer.iter().all(|&x| x == 0) {
-                break;
+            let message = incoming.next().timeout(heartbeat.timeout).await
+                .map_err(|_| std::io::Error::new(ErrorKind::TimedOut, "The client stopped responding"))??;
+            let message = match message {
+                Some(message) => message,
+                None => return Ok(())
+            };
+            // Heartbeats are answered here, and never reach the game
+            let now = Instant::now();
+            match heartbeat::decode(&message) {
+                // Pings count towards the rate limits, since the server has to answer them
+                Some((opcode::PING, _)) => if server_tx.admit(message.bytes.len())? {
+                    pong_tx.send(heartbeat::pong(&message));
+                },
+                Some((_, timestamp)) => {
+                    server_tx.state().touch(now);
+                    if let Some(rtt) = heartbeat::round_trip(timestamp, start, now) {
+                        server_tx.state().record_rtt(rtt);
+                    }
+                },
+                None => server_tx.send(message).await?
             }
-            server_tx.send(Message { bytes: buffer }).await
-                .map_err(|e| std::io::Error::new(ErrorKind::ConnectionAborted, e))?;
         }
-        Ok(())
     }
-    async fn write(mut w_socket: WriteHalf<'_>, mut server_rx: UnboundedReceiver<Message>) -> Result<(), std::io::Error> {
-        while let Some(Message{ bytes }) = server_rx.recv().await {
-            w_socket.write(&bytes).await?;
+    async fn write(mut outgoing: Outgoing,
+                   server_rx: &mut OutboundReceiver,
+                   mut heartbeat_rx: UnboundedReceiver<Message>,
+                   mut pong_rx: PongReceiver<Message>) -> Result<(), std::io::Error> {
+        loop {
+            let heartbeat = future::select(Box::pin(heartbeat_rx.recv()), Box::pin(pong_rx.recv()));
+            let message = match future::select(Box::pin(server_rx.recv()), heartbeat).await {
+                future::Either::Left((message, _)) => message,
+                future::Either::Right((future::Either::Left((heartbeat, _)), _)) |
+                future::Either::Right((future::Either::Right((heartbeat, _)), _)) => heartbeat
+            };
+            match message {
+                Some(message) => outgoing.send(message).await?,
+                None if server_rx.overflowed() => return Err(queue::slow_consumer()),
+                // The client was removed from the client map
+                None => return Ok(())
+            }
+        }
+    }
+    /// Move messages between the socket and the simulated links.
+    /// Only finishes early if the socket fails, or once the links are gone.
+    async fn simulate(mut incoming: Incoming,
+                      mut inbound_tx: UnboundedSender<Message>,
+                      mut outgoing: Outgoing,
+                      mut outbound_rx: UnboundedReceiver<Message>) -> Result<(), std::io::Error> {
+        let receive = async move {
+            while let Some(message) = incoming.next().await? {
+                if inbound_tx.try_send(message).is_err() {
+                    return Ok(());
+                }
+            }
+            // The connection closed, but what's still in the link has to arrive first
+            drop(inbound_tx);
+            future::pending::<Result<(), std::io::Error>>().await
+        };
+        let transmit = async move {
+            while let Some(message) = outbound_rx.recv().await {
+                outgoing.send(message).await?;
+            }
+            Ok::<(), std::io::Error>(())
+        };
+        match future::select(Box::pin(receive), Box::pin(transmit)).await {
+            future::Either::Left((result, _)) | future::Either::Right((result, _)) => result
+        }
+    }
+    async fn ping(mut heartbeat_tx: UnboundedSender<Message>, interval: Duration, start: Instant) {
+        let mut interval = Interval::new_interval(interval);
+        while interval.next().await.is_some() {
+            if heartbeat_tx.try_send(heartbeat::ping(start, Instant::now())).is_err() {
+                break;
+            }
         }
-        Ok(())
     }
-    async fn process(mut self) {
+    async fn process(self) {
 
-        let (r_socket, w_socket) = self.socket.split();
-        let _ = future::join(
-            Client::read(r_socket, self.server_tx),
-            Client::write(w_socket, self.server_rx)
-        ).await;
+        let (r_socket, w_socket) = tokio::io::split(self.socket);
+        let (heartbeat_tx, heartbeat_rx) = unbounded_channel();
+        let (pong_tx, pong_rx) = heartbeat::pongs();
+        let mut server_rx = self.server_rx;
+        let start = Instant::now();
+        let incoming = Incoming::Socket(r_socket, FrameReader::new(self.buffers), self.compression.is_some());
+        let outgoing = Outgoing::Socket(w_socket, self.compression);
+        // With simulated conditions, the socket is read and written through the links instead
+        let (incoming, outgoing, links) = match self.simulator {
+            Some(simulator) => {
+                let (inbound_tx, inbound_rx) = conditions::spawn_link(
+                    Link::new(simulator.clone(), self.id, Direction::Inbound, true, start), conditions::message_size);
+                let (outbound_tx, outbound_rx) = conditions::spawn_link(
+                    Link::new(simulator, self.id, Direction::Outbound, true, start), conditions::message_size);
+                (Incoming::Simulated(inbound_rx), Outgoing::Simulated(outbound_tx),
+                 Some(Client::simulate(incoming, inbound_tx, outgoing, outbound_rx)))
+            },
+            None => (incoming, outgoing, None)
+        };
+        let read = Client::read(incoming, self.server_tx, pong_tx, self.heartbeat, start);
+        let read = async move {
+            match links {
+                Some(links) => match future::select(Box::pin(read), Box::pin(links)).await {
+                    future::Either::Left((result, _)) | future::Either::Right((result, _)) => result
+                },
+                None => read.await
+            }
+        };
+        // Stop as soon as any part is done, since the write half only ends once the client is removed
+        let reason = match future::select(
+            Box::pin(read),
+            Box::pin(future::select(
+                Box::pin(Client::write(outgoing, &mut server_rx, heartbeat_rx, pong_rx)),
+                Box::pin(Client::ping(heartbeat_tx, self.heartbeat.interval, start))
+            ))
+        ).await {
+            future::Either::Left((Ok(()), _)) => DisconnectReason::Closed,
+            future::Either::Left((Err(e), _)) | future::Either::Right((future::Either::Left((Err(e), _)), _)) => {
+                println!("Lost the connection to client with ID {}: {}", self.id, e);
+                DisconnectReason::from_error(&e)
+            },
+            // The write half only finishes cleanly once the client is removed from the client map
+            future::Either::Right(_) => DisconnectReason::Removed
+        };
 
-        // The client has exited, so remove their information from the client map
-        self.shared_client_map.lock().expect("To get a lock on the shared client map")
-            .remove(&self.id);
-        println!("Client with ID {} has disconnected!", self.id);
+        // The client has exited, so remove their information from the client map.
+        // Stream transports have no way to say goodbye, so every client that wasn't kicked gets the chance to resume.
+        let sessions = self.sessions.as_ref()
+            .filter(|_| reason != DisconnectReason::Kicked && reason != DisconnectReason::SlowConsumer);
+        let _ = self.connections.unregister(sessions, &self.events, self.id, self.address, server_rx, reason).await;
     }
 }
\ No newline at end of file
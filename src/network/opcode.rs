@@ -0,0 +1,49 @@
+//! The first byte of every `Message` is its opcode, which says what kind of message it is.
+//!
+//! Opcodes from `RESERVED` upwards belong to the engine, and messages that use them are
+//! handled before the rest reach the game. Game messages must use opcodes below `RESERVED`.
+
+pub type Opcode = u8;
+
+/// The first opcode reserved for the engine.
+pub const RESERVED: Opcode = 0xE0;
+
+/// Server to client: a delta-compressed snapshot of the replicated components.
+pub const SNAPSHOT: Opcode = 0xE0;
+/// Client to server: acknowledges that a snapshot was received.
+pub const SNAPSHOT_ACK: Opcode = 0xE1;
+/// Server to client: entities that have become relevant to the client.
+pub const VIEW_ENTER: Opcode = 0xE2;
+/// Server to client: entities that are no longer relevant to the client.
+pub const VIEW_LEAVE: Opcode = 0xE3;
+/// Client to server: a batch of inputs.
+pub const INPUT: Opcode = 0xE4;
+/// Either way: a heartbeat, which the other side answers with a `PONG`.
+pub const PING: Opcode = 0xE5;
+/// Either way: the answer to a `PING`, carrying the same payload.
+pub const PONG: Opcode = 0xE6;
+/// Server to client: the token to resume the client's session with, if its connection drops.
+pub const SESSION: Opcode = 0xE7;
+/// Either way: a call to a remote procedure.
+pub const RPC_REQUEST: Opcode = 0xE8;
+/// Either way: the answer to an `RPC_REQUEST`.
+pub const RPC_RESPONSE: Opcode = 0xE9;
+
+/// The name of every opcode the engine uses.
+pub const ENGINE: &[(&str, Opcode)] = &[
+    ("SNAPSHOT", SNAPSHOT),
+    ("SNAPSHOT_ACK", SNAPSHOT_ACK),
+    ("VIEW_ENTER", VIEW_ENTER),
+    ("VIEW_LEAVE", VIEW_LEAVE),
+    ("INPUT", INPUT),
+    ("PING", PING),
+    ("PONG", PONG),
+    ("SESSION", SESSION),
+    ("RPC_REQUEST", RPC_REQUEST),
+    ("RPC_RESPONSE", RPC_RESPONSE),
+];
+
+/// Whether a message with the given opcode is handled by the engine rather than the game.
+pub fn is_reserved(opcode: Opcode) -> bool {
+    opcode >= RESERVED
+}
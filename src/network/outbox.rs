@@ -0,0 +1,106 @@
+//! Systems talk back to clients through the `Outbox` resource.
+//!
+//! Messages can be queued for a single client, for every client, or for a named group
+//! of clients. Nothing is sent right away: at the end of every tick, the queue is flushed
+//! into the write task of each recipient.
+
+use std::collections::{HashMap, HashSet};
+use crate::network::{ClientID, Message};
+use crate::network::manager::RoutingTable;
+
+/// Who a queued message is for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Recipient {
+    Client(ClientID),
+    All,
+    Group(String),
+}
+
+/// A queue of messages waiting to be sent to clients, along with the named groups they can be sent to.
+#[derive(Default)]
+pub struct Outbox {
+    queue: Vec<(Recipient, Message)>,
+    groups: HashMap<String, HashSet<ClientID>>,
+}
+
+impl Outbox {
+    pub fn new() -> Outbox {
+        Outbox::default()
+    }
+
+    /// Queue a message for a single client.
+    pub fn send(&mut self, client: ClientID, message: Message) {
+        self.queue.push((Recipient::Client(client), message));
+    }
+
+    /// Queue a message for every connected client.
+    pub fn broadcast(&mut self, message: Message) {
+        self.queue.push((Recipient::All, message));
+    }
+
+    /// Queue a message for every client in a group. If the group doesn't exist
+    /// when the outbox is flushed, the message is dropped.
+    pub fn multicast(&mut self, group: &str, message: Message) {
+        self.queue.push((Recipient::Group(group.to_string()), message));
+    }
+
+    pub fn join_group(&mut self, group: &str, client: ClientID) {
+        self.groups.entry(group.to_string()).or_default().insert(client);
+    }
+
+    pub fn leave_group(&mut self, group: &str, client: ClientID) {
+        if let Some(members) = self.groups.get_mut(group) {
+            members.remove(&client);
+            if members.is_empty() {
+                self.groups.remove(group);
+            }
+        }
+    }
+
+    pub fn group(&self, group: &str) -> Option<&HashSet<ClientID>> {
+        self.groups.get(group)
+    }
+
+    /// The number of messages waiting to be flushed.
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Hand every queued message to the write task of its recipients.
+    /// Clients that have disconnected are removed from all groups.
+    pub fn flush(&mut self, routes: &RoutingTable) {
+        for members in self.groups.values_mut() {
+            members.retain(|client| routes.contains_key(client));
+        }
+        self.groups.retain(|_, members| !members.is_empty());
+
+        for (recipient, message) in self.queue.drain(..) {
+            match recipient {
+                Recipient::Client(client) => {
+                    if let Some(route) = routes.get(&client) {
+                        // If this fails, the client is disconnecting and will be removed from the map.
+                        let _ = route.sender.try_send(message);
+                    }
+                },
+                Recipient::All => {
+                    for route in routes.values() {
+                        let _ = route.sender.try_send(message.clone());
+                    }
+                },
+                Recipient::Group(group) => {
+                    if let Some(members) = self.groups.get(&group) {
+                        for client in members {
+                            if let Some(route) = routes.get(client) {
+                                let _ = route.sender.try_send(message.clone());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
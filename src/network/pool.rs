@@ -0,0 +1,95 @@
+//! Pooled receive buffers, so that reading messages doesn't allocate for every one of them.
+//!
+//! Stream transports read into slabs: large buffers that frames are read into back to back. Each frame's payload
+//! is split off its slab and frozen into the `Bytes` of a `Message` without being copied, so the message shares
+//! the slab's memory. Once a slab is full, it goes back to the `BufferPool`, and is handed out again as soon as
+//! every message that was split off it has been dropped. A message's payload never changes once it is frozen, so
+//! broadcasting it only clones a reference for each client.
+//!
+//! A frame that is larger than a slab is read into a slab of its own, which isn't reused, so the pool never holds on
+//! to more than `max_slabs` slabs of the usual size. Since a message keeps its whole slab alive, messages that are
+//! kept after the tick they arrived in should be `Message::detach`ed first, which copies small payloads out of their slab.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use bytes::BytesMut;
+
+/// The largest payload `Message::detach` copies out of its slab. Larger payloads take up enough of their slab to keep it.
+pub const DETACH_LIMIT: usize = 4 * 1024;
+
+/// Slabs waiting to be reused. It can be cloned, and every clone shares the same slabs.
+#[derive(Clone, Debug)]
+pub struct BufferPool {
+    /// How large each slab is.
+    slab_size: usize,
+    /// How many unused slabs are kept around.
+    max_slabs: usize,
+    slabs: Arc<Mutex<VecDeque<BytesMut>>>,
+}
+
+impl Default for BufferPool {
+    fn default() -> BufferPool {
+        BufferPool {
+            slab_size: 64 * 1024,
+            max_slabs: 256,
+            slabs: Arc::default()
+        }
+    }
+}
+
+impl BufferPool {
+    pub fn new() -> BufferPool {
+        BufferPool::default()
+    }
+
+    pub fn with_slab_size(mut self, slab_size: usize) -> BufferPool {
+        self.slab_size = slab_size.max(64);
+        self
+    }
+
+    pub fn with_max_slabs(mut self, max_slabs: usize) -> BufferPool {
+        self.max_slabs = max_slabs;
+        self
+    }
+
+    pub fn slab_size(&self) -> usize {
+        self.slab_size
+    }
+
+    /// Get an empty slab with room for at least `size` bytes, and a whole slab's worth if `size` is smaller.
+    /// A slab for more than a slab's worth is allocated on its own, and shouldn't be recycled.
+    pub fn slab(&self, size: usize) -> BytesMut {
+        if size > self.slab_size {
+            return BytesMut::with_capacity(size);
+        }
+        let size = self.slab_size;
+        let slab = self.slabs.lock().expect("To get a lock on the buffer pool").pop_front();
+        match slab {
+            Some(mut slab) => {
+                slab.clear();
+                // If the messages split off this slab are all gone, this reuses its memory instead of allocating
+                slab.reserve(size);
+                slab
+            },
+            None => BytesMut::with_capacity(size)
+        }
+    }
+
+    /// Give a slab back to the pool once it is full. The messages split off it can still be in use.
+    /// Only slabs that were handed out for a slab's worth of bytes or less belong here.
+    pub fn recycle(&self, slab: BytesMut) {
+        let mut slabs = self.slabs.lock().expect("To get a lock on the buffer pool");
+        if slabs.len() < self.max_slabs {
+            slabs.push_back(slab);
+        }
+    }
+
+    /// How many slabs are waiting to be reused.
+    pub fn len(&self) -> usize {
+        self.slabs.lock().expect("To get a lock on the buffer pool").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,318 @@
+//! Bounded queues for each client's messages, so that one slow client can't make the server run out of memory.
+//!
+//! The messages on their way to a client wait in its outbound queue until the transport can write them. When a
+//! client stops reading, the queue fills up, and the `QueueConfig`'s `OverflowPolicy` decides what gives:
+//! - `DropOldestUnreliable` makes room by dropping the oldest `Channel::UnreliableSequenced` message.
+//! - `Coalesce` makes room by dropping the queued messages that the new one makes pointless. A snapshot replaces the
+//!   queued snapshots, since the next one is sent relative to whatever the client acknowledged, and any other
+//!   unreliable message replaces the queued unreliable messages with the same opcode.
+//! - `Disconnect` disconnects the client.
+//!
+//! A snapshot is no use to the client unless every part of it arrives, so once one part is dropped, the other parts
+//! of the same snapshot are dropped with it, including the ones that haven't been queued yet.
+//!
+//! Reliable messages can never be dropped, so a client whose queue is still full of them is always disconnected,
+//! with `DisconnectReason::SlowConsumer`. Unreliable messages that don't fit are dropped, unless the policy is
+//! `Disconnect`. A client that is disconnected this way has already lost messages, so it doesn't keep its session.
+//!
+//! The messages a client sends wait for the game to read them, and are capped at `QueueConfig::inbound_capacity`.
+//! Once a client has that many waiting, its transport stops reading until the next tick, or drops them if it can't wait.
+//!
+//! The depth of both queues is in each client's `heartbeat::ConnectionStats`.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use crate::network::{Channel, Message};
+use crate::network::opcode;
+
+/// What happens when a client's outbound queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// The oldest unreliable message in the queue is dropped.
+    DropOldestUnreliable,
+    /// Unreliable messages replace the queued messages they make pointless.
+    Coalesce,
+    /// The client is disconnected.
+    Disconnect,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueueConfig {
+    /// How many messages can wait to be sent to a client.
+    pub capacity: usize,
+    /// How many messages a client can send before the game reads them.
+    pub inbound_capacity: usize,
+    pub policy: OverflowPolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> QueueConfig {
+        QueueConfig {
+            capacity: 4096,
+            inbound_capacity: 4096,
+            policy: OverflowPolicy::DropOldestUnreliable
+        }
+    }
+}
+
+impl QueueConfig {
+    pub fn with_capacity(mut self, capacity: usize) -> QueueConfig {
+        self.capacity = capacity.max(1);
+        self
+    }
+    pub fn with_inbound_capacity(mut self, inbound_capacity: usize) -> QueueConfig {
+        self.inbound_capacity = inbound_capacity.max(1);
+        self
+    }
+    pub fn with_policy(mut self, policy: OverflowPolicy) -> QueueConfig {
+        self.policy = policy;
+        self
+    }
+}
+
+/// Why a message wasn't queued.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendError {
+    /// The client's queue was full, so the message was dropped.
+    Dropped,
+    /// The client's queue was full, so the client is being disconnected.
+    Overflowed,
+    /// The client is gone.
+    Closed,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Dropped => write!(f, "The client's queue is full, so the message was dropped"),
+            SendError::Overflowed => write!(f, "The client's queue overflowed"),
+            SendError::Closed => write!(f, "The client has disconnected")
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+/// The error inside the `std::io::Error` that transports return when a client's outbound queue overflowed.
+#[derive(Debug)]
+pub struct SlowConsumer;
+
+impl fmt::Display for SlowConsumer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Disconnected for not keeping up with its messages")
+    }
+}
+
+impl std::error::Error for SlowConsumer {}
+
+pub(crate) fn slow_consumer() -> std::io::Error {
+    std::io::Error::new(ErrorKind::ConnectionAborted, SlowConsumer)
+}
+
+struct QueueState {
+    messages: VecDeque<Message>,
+    senders: usize,
+    receiver: bool,
+    overflowed: bool,
+    /// The sequence number of the last snapshot that had a part dropped, whose other parts are dropped as well.
+    broken_snapshot: Option<[u8; 4]>,
+    /// The receiver's task, if it is waiting for a message.
+    waker: Option<Waker>,
+}
+
+impl QueueState {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Make room for a message, if the policy allows it. Returns whether there is room now.
+    fn make_room(&mut self, message: &Message, config: &QueueConfig) -> bool {
+        match config.policy {
+            OverflowPolicy::DropOldestUnreliable => {
+                let oldest = self.messages.iter().position(|queued| queued.channel == Channel::UnreliableSequenced);
+                if let Some(dropped) = oldest.and_then(|oldest| self.messages.remove(oldest)) {
+                    if let Some(sequence) = snapshot_of(&dropped) {
+                        self.drop_snapshot(sequence);
+                    }
+                }
+            },
+            OverflowPolicy::Coalesce => self.messages.retain(|queued| !supersedes(message, queued)),
+            OverflowPolicy::Disconnect => {}
+        }
+        self.messages.len() < config.capacity
+    }
+
+    /// Drop the queued parts of a snapshot, and the parts that are still to come.
+    fn drop_snapshot(&mut self, sequence: [u8; 4]) {
+        self.messages.retain(|queued| snapshot_of(queued) != Some(sequence));
+        self.broken_snapshot = Some(sequence);
+    }
+}
+
+/// The sequence number of the snapshot a message is a part of, if it is one.
+fn snapshot_of(message: &Message) -> Option<[u8; 4]> {
+    if message.channel != Channel::UnreliableSequenced || message.compressed || message.bytes.len() < 5
+        || message.bytes[0] != opcode::SNAPSHOT {
+        return None;
+    }
+    let mut sequence = [0u8; 4];
+    sequence.copy_from_slice(&message.bytes[1..5]);
+    Some(sequence)
+}
+
+/// Whether sending `message` makes sending `queued` pointless.
+fn supersedes(message: &Message, queued: &Message) -> bool {
+    if message.channel != Channel::UnreliableSequenced || queued.channel != Channel::UnreliableSequenced
+        || message.compressed || queued.compressed {
+        return false;
+    }
+    match (message.bytes.first(), queued.bytes.first()) {
+        // The parts of the same snapshot are all needed
+        (Some(&opcode::SNAPSHOT), Some(&opcode::SNAPSHOT)) => message.bytes.get(1..5) != queued.bytes.get(1..5),
+        (Some(opcode), Some(queued)) => opcode == queued,
+        _ => false
+    }
+}
+
+/// Queues messages for a client. It can be cloned, and every clone sends to the same queue.
+pub struct OutboundSender {
+    config: QueueConfig,
+    state: Arc<Mutex<QueueState>>,
+}
+
+/// Takes the messages for a client out of its queue, for the transport to send.
+pub struct OutboundReceiver {
+    state: Arc<Mutex<QueueState>>,
+}
+
+/// Create a client's outbound queue.
+pub fn outbound(config: QueueConfig) -> (OutboundSender, OutboundReceiver) {
+    let state = Arc::new(Mutex::new(QueueState {
+        messages: VecDeque::new(),
+        senders: 1,
+        receiver: true,
+        overflowed: false,
+        broken_snapshot: None,
+        waker: None
+    }));
+    (OutboundSender { config, state: state.clone() }, OutboundReceiver { state })
+}
+
+impl OutboundSender {
+    /// Whether this sender feeds `receiver`.
+    pub(crate) fn feeds(&self, receiver: &OutboundReceiver) -> bool {
+        Arc::ptr_eq(&self.state, &receiver.state)
+    }
+    /// Queue a message for the client, applying the overflow policy if the queue is full.
+    pub fn try_send(&self, message: Message) -> Result<(), SendError> {
+        let mut state = self.state.lock().expect("To get a lock on the client's queue");
+        if !state.receiver || state.overflowed {
+            return Err(SendError::Closed);
+        }
+        let snapshot = snapshot_of(&message);
+        if snapshot.is_some() && snapshot == state.broken_snapshot {
+            return Err(SendError::Dropped);
+        }
+        if state.messages.len() >= self.config.capacity && !state.make_room(&message, &self.config) {
+            if message.channel == Channel::UnreliableSequenced && self.config.policy != OverflowPolicy::Disconnect {
+                if let Some(sequence) = snapshot {
+                    state.drop_snapshot(sequence);
+                }
+                return Err(SendError::Dropped);
+            }
+            // The client is going, so there's no point keeping its messages around
+            state.overflowed = true;
+            state.messages.clear();
+            state.wake();
+            return Err(SendError::Overflowed);
+        }
+        state.messages.push_back(message);
+        state.wake();
+        Ok(())
+    }
+
+    /// How many messages are waiting to be sent.
+    pub fn len(&self) -> usize {
+        self.state.lock().expect("To get a lock on the client's queue").messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Clone for OutboundSender {
+    fn clone(&self) -> OutboundSender {
+        self.state.lock().expect("To get a lock on the client's queue").senders += 1;
+        OutboundSender { config: self.config, state: self.state.clone() }
+    }
+}
+
+impl Drop for OutboundSender {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().expect("To get a lock on the client's queue");
+        state.senders -= 1;
+        if state.senders == 0 {
+            state.wake();
+        }
+    }
+}
+
+impl OutboundReceiver {
+    /// Take the next message, or `None` once every sender is gone or the queue has overflowed.
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        let mut state = self.state.lock().expect("To get a lock on the client's queue");
+        if let Some(message) = state.messages.pop_front() {
+            return Poll::Ready(Some(message));
+        }
+        if state.senders == 0 || state.overflowed {
+            return Poll::Ready(None);
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    pub async fn recv(&mut self) -> Option<Message> {
+        Recv { receiver: self }.await
+    }
+
+    /// Whether the queue overflowed, in which case the client should be disconnected.
+    pub fn overflowed(&self) -> bool {
+        self.state.lock().expect("To get a lock on the client's queue").overflowed
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().expect("To get a lock on the client's queue").messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Drop for OutboundReceiver {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().expect("To get a lock on the client's queue");
+        state.receiver = false;
+        state.messages.clear();
+    }
+}
+
+struct Recv<'a> {
+    receiver: &'a mut OutboundReceiver,
+}
+
+impl Future for Recv<'_> {
+    type Output = Option<Message>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        self.receiver.poll_recv(cx)
+    }
+}
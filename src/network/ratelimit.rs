@@ -0,0 +1,197 @@
+//! Per-client rate limiting, so that a single client can't flood the server.
+//!
+//! Every message a client sends goes through an `inbound::InboundSender` on its way to the `ClientMap`. If the server
+//! has a `RateLimitConfig`, the sender checks the message against two token buckets, one for messages and
+//! one for bytes, and against the number of messages that are waiting for the game to read them. When a
+//! client goes over a limit, the config's `RatePolicy` decides what happens to the message. On the next tick, a
+//! `RateLimitViolation` event is pushed to the world's `NotifierQueue` for each limit the client went over,
+//! counting the messages that went over it.
+
+use std::any::Any;
+use std::time::{Duration, Instant};
+use crate::ecs::event::Event;
+use crate::network::ClientID;
+
+/// What happens to a message that goes over a limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RatePolicy {
+    /// The message is thrown away.
+    Drop,
+    /// The message waits until the client is back under the limit. Transports that can't wait,
+    /// such as UDP, drop the message instead.
+    Throttle,
+    /// The message is let through anyway, and only the event is emitted.
+    Warn,
+    /// The client is disconnected.
+    Kick,
+}
+
+#[derive(Clone, Debug)]
+pub struct RateLimitConfig {
+    pub messages_per_second: f64,
+    /// How many messages a client can send at once, after being quiet for a while.
+    pub message_burst: f64,
+    pub bytes_per_second: f64,
+    /// How many bytes a client can send at once, after being quiet for a while.
+    pub byte_burst: f64,
+    /// How many of a client's messages can be waiting for the game to read them.
+    pub max_queue_depth: usize,
+    pub policy: RatePolicy,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> RateLimitConfig {
+        RateLimitConfig {
+            messages_per_second: 60.0,
+            message_burst: 120.0,
+            bytes_per_second: 64.0 * 1024.0,
+            byte_burst: 256.0 * 1024.0,
+            max_queue_depth: 1024,
+            policy: RatePolicy::Throttle
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn with_messages_per_second(mut self, rate: f64, burst: f64) -> RateLimitConfig {
+        self.messages_per_second = rate;
+        self.message_burst = burst;
+        self
+    }
+    pub fn with_bytes_per_second(mut self, rate: f64, burst: f64) -> RateLimitConfig {
+        self.bytes_per_second = rate;
+        self.byte_burst = burst;
+        self
+    }
+    pub fn with_max_queue_depth(mut self, max_queue_depth: usize) -> RateLimitConfig {
+        self.max_queue_depth = max_queue_depth;
+        self
+    }
+    pub fn with_policy(mut self, policy: RatePolicy) -> RateLimitConfig {
+        self.policy = policy;
+        self
+    }
+}
+
+/// Tokens refill at a steady rate up to a capacity, and each use takes some of them.
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket.
+    pub fn new(rate: f64, capacity: f64, now: Instant) -> TokenBucket {
+        TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            last_refill: now
+        }
+    }
+    fn refill(&mut self, now: Instant) {
+        if now > self.last_refill {
+            let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+            self.last_refill = now;
+        }
+    }
+    pub fn available(&mut self, now: Instant) -> f64 {
+        self.refill(now);
+        self.tokens
+    }
+    /// Whether `amount` tokens are available. Anything larger than the capacity only needs a full bucket.
+    pub fn has(&mut self, amount: f64, now: Instant) -> bool {
+        self.available(now) >= amount.min(self.capacity)
+    }
+    /// Take tokens, even if there aren't enough of them. The bucket never goes below empty.
+    pub fn take(&mut self, amount: f64, now: Instant) {
+        self.refill(now);
+        self.tokens = (self.tokens - amount).max(0.0);
+    }
+    /// How long until `amount` tokens are available.
+    pub fn time_until(&mut self, amount: f64, now: Instant) -> Duration {
+        let missing = amount.min(self.capacity) - self.available(now);
+        if missing <= 0.0 || self.rate <= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(missing / self.rate)
+        }
+    }
+}
+
+/// Which limit a client went over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimit {
+    MessageRate,
+    ByteRate,
+    QueueDepth,
+}
+
+/// The event emitted once a tick for each limit a client went over.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RateLimitViolation {
+    pub client: ClientID,
+    pub limit: RateLimit,
+    /// What was done about it.
+    pub policy: RatePolicy,
+    /// How many messages went over the limit since the last tick.
+    pub count: usize,
+}
+
+impl Event for RateLimitViolation {
+    fn as_any(&self) -> &dyn Any {
+        self as &dyn Any
+    }
+    fn as_mut_any(&mut self) -> &mut dyn Any {
+        self as &mut dyn Any
+    }
+}
+
+/// The state of a client's limits.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    messages: TokenBucket,
+    bytes: TokenBucket,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig, now: Instant) -> RateLimiter {
+        RateLimiter {
+            messages: TokenBucket::new(config.messages_per_second, config.message_burst, now),
+            bytes: TokenBucket::new(config.bytes_per_second, config.byte_burst, now),
+            config
+        }
+    }
+    pub fn config(&self) -> &RateLimitConfig {
+        &self.config
+    }
+    /// Check whether a message of `len` bytes can be let through while `queued` messages are waiting.
+    /// If it can, its tokens are taken.
+    pub fn check(&mut self, len: usize, queued: usize, now: Instant) -> Result<(), RateLimit> {
+        if queued >= self.config.max_queue_depth {
+            return Err(RateLimit::QueueDepth);
+        }
+        if !self.messages.has(1.0, now) {
+            return Err(RateLimit::MessageRate);
+        }
+        if !self.bytes.has(len as f64, now) {
+            return Err(RateLimit::ByteRate);
+        }
+        self.take(len, now);
+        Ok(())
+    }
+    /// Take the tokens for a message of `len` bytes, whether or not there are enough of them.
+    pub fn take(&mut self, len: usize, now: Instant) {
+        self.messages.take(1.0, now);
+        self.bytes.take(len as f64, now);
+    }
+    /// How long until the rate limits would let a message of `len` bytes through.
+    pub fn time_until(&mut self, len: usize, now: Instant) -> Duration {
+        self.messages.time_until(1.0, now).max(self.bytes.time_until(len as f64, now))
+    }
+}
\ No newline at end of file
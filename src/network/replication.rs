@@ -0,0 +1,680 @@
+//! Replication keeps each client's view of the world in sync with the server.
+//!
+//! Components opt in to replication by implementing `Replicated` and being registered
+//! with `GameBuilder::with_replicated`. Only entities marked with the `Networked` component
+//! are replicated. Every tick, the state of all replicated components is captured, and each
+//! client is sent the difference between that state and the last state it acknowledged.
+//! Entity creation and deletion are part of the difference, so the client never has to
+//! be told about them separately.
+//!
+//! A snapshot is sent as one or more messages. All integers are big-endian, and each message is laid out as:
+//! - 1 byte: `opcode::SNAPSHOT`
+//! - 4 bytes: the sequence number of the snapshot
+//! - 4 bytes: the sequence number of the baseline it is relative to, or 0 for none
+//! - 4 bytes: the sequence number of the last input processed for the client, or 0 for none
+//! - 1 byte: flags (`PACKED` if the rest is bit-packed)
+//! - 2 bytes: the index of this message among the snapshot's messages, starting from 0
+//! - 2 bytes: the number of messages the snapshot was split into
+//! - 2 bytes: the number of removed entities, followed by their 8 byte `NetworkID`s
+//! - 2 bytes: the number of updated entities, each of which is:
+//!   - 8 bytes: the `NetworkID`
+//!   - 2 bytes: the number of changed components, each of which is a 2 byte `ComponentTypeID`
+//!     followed by a 2 byte length and the encoded component
+//!   - 2 bytes: the number of removed components, each of which is a 2 byte `ComponentTypeID`
+//!
+//! If replication is set to pack snapshots, the rest of the message is bit-packed with the `bitpack` module instead.
+//! The counts, `ComponentTypeID`s and component lengths are variable-length integers, and the `NetworkID`s of each
+//! list are relative to the previous one in the same message. The components themselves are unchanged, so
+//! components that want to be smaller pack their own fields.
+//!
+//! Clients acknowledge a snapshot once they have received every part of it, by sending
+//! `opcode::SNAPSHOT_ACK` followed by the 4 byte sequence number. A snapshot with a part missing
+//! is never applied, and the next one is sent relative to the last snapshot the client acknowledged.
+//!
+//! If replication has an `InterestPolicy`, each client is only sent the entities relevant
+//! to it. See the `interest` module for details.
+
+use bytes::{Bytes, BytesMut, BufMut, Buf, IntoBuf};
+use specs::{Component, Entity, Join, NullStorage, World};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use crate::network::{Channel, ClientID, Message};
+use crate::network::opcode;
+use crate::network::bitpack::{BitReader, BitWriter, IdBaseline, Packed};
+use crate::network::interest::{self, ControlledEntities, InterestPolicy, Positioned, Positions};
+use crate::network::input::{InputSequence, ProcessedInputs};
+use crate::network::schema::{Description, Schema, TraceError};
+
+/// Identifies a type of replicated component on the wire.
+pub type ComponentTypeID = u16;
+
+/// Identifies an entity on the wire. This is made from the entity's index and generation,
+/// so a recycled entity index is never confused with the entity that used it before.
+pub type NetworkID = u64;
+
+/// The sequence number of a snapshot. Sequence 0 means "no snapshot".
+pub type SnapshotSequence = u32;
+
+/// The encoded replicated components of a single entity.
+pub type EntityState = BTreeMap<ComponentTypeID, Bytes>;
+
+/// The encoded replicated components of every networked entity.
+pub type WorldState = BTreeMap<NetworkID, EntityState>;
+
+/// A component that is synced to clients.
+pub trait Replicated: Component + Send + Sync {
+    /// A unique ID for this type of component. It must be the same on the server and the client.
+    const TYPE_ID: ComponentTypeID;
+
+    fn encode(&self, bytes: &mut BytesMut);
+    fn decode(bytes: &[u8]) -> Option<Self> where Self: Sized;
+
+    /// Describe how the component is encoded, for the protocol schema. Components that don't are listed as opaque.
+    fn describe(_schema: &mut Schema) -> Result<Description, TraceError> where Self: Sized {
+        Ok(Description::opaque())
+    }
+}
+
+/// Marks an entity as replicated to clients.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Networked;
+
+impl Component for Networked {
+    type Storage = NullStorage<Self>;
+}
+
+/// Get the network ID of an entity.
+pub fn network_id(entity: Entity) -> NetworkID {
+    (u64::from(entity.gen().id() as u32) << 32) | u64::from(entity.id())
+}
+
+/// The changes to a single entity between two snapshots.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EntityDelta {
+    pub id: NetworkID,
+    pub changed: Vec<(ComponentTypeID, Bytes)>,
+    pub removed: Vec<ComponentTypeID>,
+}
+
+/// The changes between two snapshots.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SnapshotDelta {
+    pub sequence: SnapshotSequence,
+    pub baseline: SnapshotSequence,
+    /// The last input the server processed for the client this snapshot is for.
+    pub last_input: InputSequence,
+    /// Entities that no longer exist (or are no longer networked).
+    pub removed: Vec<NetworkID>,
+    /// Entities that were created or had components change.
+    pub entities: Vec<EntityDelta>,
+}
+
+/// One message of a snapshot.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SnapshotPart {
+    pub delta: SnapshotDelta,
+    /// Which of the snapshot's messages this is, starting from 0.
+    pub index: u16,
+    /// How many messages the snapshot was split into.
+    pub count: u16,
+}
+
+/// A snapshot that was sent to a client, but not yet acknowledged.
+struct PendingSnapshot {
+    sequence: SnapshotSequence,
+    state: Arc<WorldState>,
+}
+
+/// What the server knows about a single client's view of the world.
+struct ClientReplication {
+    baseline_sequence: SnapshotSequence,
+    baseline: Arc<WorldState>,
+    pending: VecDeque<PendingSnapshot>,
+    /// The entities that were relevant to the client in the last snapshot.
+    relevant: HashSet<NetworkID>,
+}
+
+/// Filters which entities are sent to each client.
+struct Interest {
+    policy: InterestPolicy,
+    positions: fn(&World) -> Positions,
+}
+
+/// Adds the description of one type of component to a schema.
+type Describer = fn(&mut Schema) -> Result<(), TraceError>;
+
+/// The resource that drives replication.
+pub struct Replication {
+    capturers: Vec<fn(&World, &mut WorldState)>,
+    describers: Vec<Describer>,
+    interest: Option<Interest>,
+    clients: HashMap<ClientID, ClientReplication>,
+    sequence: SnapshotSequence,
+    max_message_size: usize,
+    packed: bool,
+}
+
+impl Replication {
+    const MAX_PENDING: usize = 64;
+    const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024;
+    /// Opcode, sequence, baseline, last input, flags, part index and part count
+    const HEADER_SIZE: usize = 1 + 4 + 4 + 4 + 1 + 2 + 2;
+    /// The largest encoded component that can be replicated, since its length is sent in 2 bytes.
+    pub const MAX_COMPONENT_SIZE: usize = u16::MAX as usize;
+    pub const PACKED: u8 = 2;
+
+    pub fn new() -> Replication {
+        Replication::default()
+    }
+
+    /// Start replicating a type of component.
+    /// Replicate a type of component. Fails if its encoding can't be used, which describing it checks.
+    pub fn register<T: Replicated>(&mut self) -> Result<(), TraceError> {
+        T::describe(&mut Schema::new())?;
+        // Entities have at most one of each, and the number of changed components is sent in 2 bytes
+        assert!(self.capturers.len() < u16::MAX as usize, "Too many types of replicated components");
+        self.capturers.push(capture::<T>);
+        self.describers.push(describe::<T>);
+        Ok(())
+    }
+
+    /// Add every replicated component to the protocol schema.
+    pub fn describe(&self, schema: &mut Schema) -> Result<(), TraceError> {
+        self.describers.iter().try_for_each(|describe| describe(schema))
+    }
+
+    /// Only send each client the entities near the entity it controls, using the positions from `P`.
+    pub fn set_interest<P: Positioned>(&mut self, policy: InterestPolicy) {
+        self.interest = Some(Interest { policy, positions: interest::capture_positions::<P> });
+    }
+
+    /// Bit-pack the snapshots sent from now on. Clients decode either kind.
+    pub fn set_packed(&mut self, packed: bool) {
+        self.packed = packed;
+    }
+
+    /// Set the largest message a snapshot will be split into. Single entities larger than this
+    /// are still sent in one message.
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Replication {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Capture the state of every networked entity in the world.
+    pub fn capture(&self, world: &World) -> WorldState {
+        let mut state = WorldState::new();
+        let entities = world.entities();
+        let networked = world.read_storage::<Networked>();
+        for (entity, _) in (&entities, &networked).join() {
+            state.insert(network_id(entity), EntityState::new());
+        }
+        for capturer in &self.capturers {
+            capturer(world, &mut state);
+        }
+        state
+    }
+
+    /// Capture the world, and create the snapshot messages for every client.
+    /// Clients that aren't in `clients` are forgotten.
+    pub fn update(&mut self, world: &World, clients: &[ClientID]) -> Vec<(ClientID, Message)> {
+        let state = Arc::new(self.capture(world));
+        self.sequence = self.sequence.wrapping_add(1).max(1);
+        let sequence = self.sequence;
+        let max_message_size = self.max_message_size;
+        let packed = self.packed;
+
+        self.clients.retain(|client, _| clients.contains(client));
+        let positions = self.interest.as_ref().map(|interest| (interest.positions)(world));
+        let controlled = if world.res.has_value::<ControlledEntities>() {
+            world.read_resource::<ControlledEntities>().clone()
+        } else {
+            ControlledEntities::new()
+        };
+        let processed_inputs = if world.res.has_value::<ProcessedInputs>() {
+            Some(world.read_resource::<ProcessedInputs>())
+        } else {
+            None
+        };
+
+        let mut messages = Vec::new();
+        for client in clients {
+            let replication = self.clients.entry(*client).or_insert_with(ClientReplication::new);
+            let client_state = match (&self.interest, &positions) {
+                (Some(interest), Some(positions)) => {
+                    let viewer = controlled.get(*client).map(network_id);
+                    let relevant = interest.policy.relevant(viewer, &state, positions);
+                    let entered: Vec<NetworkID> = relevant.difference(&replication.relevant).cloned().collect();
+                    // Entities that were deleted aren't leaving the view; the snapshot removes them.
+                    let left: Vec<NetworkID> = replication.relevant.difference(&relevant)
+                        .filter(|id| state.contains_key(id))
+                        .cloned()
+                        .collect();
+                    for (opcode, ids) in [(opcode::VIEW_ENTER, entered), (opcode::VIEW_LEAVE, left)] {
+                        for chunk in ids.chunks(u16::MAX as usize) {
+                            messages.push((*client, interest::encode_view_change(opcode, chunk)));
+                        }
+                    }
+                    let client_state = state.iter()
+                        .filter(|(id, _)| relevant.contains(id))
+                        .map(|(id, components)| (*id, components.clone()))
+                        .collect();
+                    replication.relevant = relevant;
+                    Arc::new(client_state)
+                },
+                _ => state.clone()
+            };
+
+            let mut delta = SnapshotDelta::between(&replication.baseline, &client_state, replication.baseline_sequence, sequence);
+            delta.last_input = processed_inputs.as_ref().map(|p| p.get(*client)).unwrap_or(0);
+            let encoded = if packed { delta.encode_packed(max_message_size) } else { delta.encode(max_message_size) };
+            for message in encoded {
+                messages.push((*client, message));
+            }
+            replication.pending.push_back(PendingSnapshot { sequence, state: client_state });
+            if replication.pending.len() > Replication::MAX_PENDING {
+                replication.pending.pop_front();
+            }
+        }
+        messages
+    }
+
+    /// Start a client over with a full snapshot, because it came back under the same ID on a new connection.
+    pub fn reset(&mut self, client: ClientID) {
+        self.clients.remove(&client);
+    }
+
+    /// Handle a client's acknowledgement of a snapshot. Future snapshots sent to the
+    /// client will be relative to it.
+    pub fn acknowledge(&mut self, client: ClientID, sequence: SnapshotSequence) {
+        if let Some(replication) = self.clients.get_mut(&client) {
+            if let Some(index) = replication.pending.iter().position(|p| p.sequence == sequence) {
+                let acknowledged = replication.pending.drain(..=index).next_back().unwrap();
+                replication.baseline_sequence = acknowledged.sequence;
+                replication.baseline = acknowledged.state;
+            }
+        }
+    }
+
+    /// Handle an acknowledgement message from a client. Malformed messages are ignored.
+    pub fn handle_ack(&mut self, client: ClientID, message: &Message) {
+        if let Some(sequence) = decode_ack(message) {
+            self.acknowledge(client, sequence);
+        }
+    }
+}
+
+impl Default for Replication {
+    fn default() -> Replication {
+        Replication {
+            capturers: Vec::new(),
+            describers: Vec::new(),
+            interest: None,
+            clients: HashMap::new(),
+            sequence: 0,
+            max_message_size: Replication::DEFAULT_MAX_MESSAGE_SIZE,
+            packed: false
+        }
+    }
+}
+
+impl ClientReplication {
+    fn new() -> ClientReplication {
+        ClientReplication {
+            baseline_sequence: 0,
+            baseline: Arc::new(WorldState::new()),
+            pending: VecDeque::new(),
+            relevant: HashSet::new()
+        }
+    }
+}
+
+fn describe<T: Replicated>(schema: &mut Schema) -> Result<(), TraceError> {
+    let description = T::describe(schema)?;
+    schema.add_component(T::TYPE_ID, std::any::type_name::<T>(), description);
+    Ok(())
+}
+
+fn capture<T: Replicated>(world: &World, state: &mut WorldState) {
+    let entities = world.entities();
+    let networked = world.read_storage::<Networked>();
+    let components = world.read_storage::<T>();
+    for (entity, _, component) in (&entities, &networked, &components).join() {
+        let mut bytes = BytesMut::new();
+        component.encode(&mut bytes);
+        if bytes.len() > Replication::MAX_COMPONENT_SIZE {
+            println!("Not replicating a {} of {} bytes, since components can be at most {} bytes",
+                     std::any::type_name::<T>(), bytes.len(), Replication::MAX_COMPONENT_SIZE);
+            continue;
+        }
+        state.entry(network_id(entity)).or_default()
+            .insert(T::TYPE_ID, bytes.freeze());
+    }
+}
+
+impl EntityDelta {
+    fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.removed.is_empty()
+    }
+
+    fn encoded_size(&self) -> usize {
+        8 + 2 + self.changed.iter().map(|(_, bytes)| 4 + bytes.len()).sum::<usize>()
+            + 2 + 2 * self.removed.len()
+    }
+
+    fn pack(&self, writer: &mut BitWriter, ids: &mut IdBaseline) {
+        ids.write(writer, self.id);
+        (self.changed.len() as u16).pack(writer);
+        for (type_id, component) in &self.changed {
+            type_id.pack(writer);
+            component.len().pack(writer);
+            writer.write_bytes(component);
+        }
+        (self.removed.len() as u16).pack(writer);
+        for type_id in &self.removed {
+            type_id.pack(writer);
+        }
+    }
+
+    fn unpack(reader: &mut BitReader, ids: &mut IdBaseline) -> Option<EntityDelta> {
+        let id = ids.read(reader)?;
+        let changed_count = u16::unpack(reader)? as usize;
+        let mut changed = Vec::with_capacity(changed_count);
+        for _ in 0..changed_count {
+            let type_id = ComponentTypeID::unpack(reader)?;
+            let len = usize::unpack(reader)?;
+            changed.push((type_id, Bytes::from(reader.read_bytes(len)?)));
+        }
+        let removed_count = u16::unpack(reader)?;
+        let removed = (0..removed_count).map(|_| ComponentTypeID::unpack(reader)).collect::<Option<_>>()?;
+        Some(EntityDelta { id, changed, removed })
+    }
+}
+
+impl SnapshotDelta {
+    /// Find the differences between two states.
+    pub fn between(baseline: &WorldState,
+                   current: &WorldState,
+                   baseline_sequence: SnapshotSequence,
+                   sequence: SnapshotSequence) -> SnapshotDelta
+    {
+        let removed = baseline.keys()
+            .filter(|id| !current.contains_key(id))
+            .cloned()
+            .collect();
+        let empty = EntityState::new();
+        let entities = current.iter()
+            .map(|(id, components)| {
+                let old = baseline.get(id).unwrap_or(&empty);
+                EntityDelta {
+                    id: *id,
+                    changed: components.iter()
+                        .filter(|(type_id, bytes)| old.get(type_id) != Some(bytes))
+                        .map(|(type_id, bytes)| (*type_id, bytes.clone()))
+                        .collect(),
+                    removed: old.keys()
+                        .filter(|type_id| !components.contains_key(type_id))
+                        .cloned()
+                        .collect()
+                }
+            })
+            // New entities are always sent, even if they have no components, so that the client creates them.
+            .filter(|delta| !delta.is_empty() || !baseline.contains_key(&delta.id))
+            .collect();
+        SnapshotDelta { sequence, baseline: baseline_sequence, last_input: 0, removed, entities }
+    }
+
+    /// Apply this delta to the state it is relative to.
+    pub fn apply(&self, baseline: &WorldState) -> WorldState {
+        let mut state = baseline.clone();
+        for id in &self.removed {
+            state.remove(id);
+        }
+        for delta in &self.entities {
+            let components = state.entry(delta.id).or_default();
+            for type_id in &delta.removed {
+                components.remove(type_id);
+            }
+            for (type_id, bytes) in &delta.changed {
+                components.insert(*type_id, bytes.clone());
+            }
+        }
+        state
+    }
+
+    /// Merge a later part of the same snapshot into this one.
+    pub fn merge(&mut self, part: SnapshotDelta) {
+        self.removed.extend(part.removed);
+        self.entities.extend(part.entities);
+    }
+
+    /// Encode this delta into as many messages as it takes to keep each one under `max_message_size`.
+    pub fn encode(&self, max_message_size: usize) -> Vec<Message> {
+        let mut parts = Vec::new();
+        let mut removed = &self.removed[..];
+        let mut entities = &self.entities[..];
+        loop {
+            let mut size = Replication::HEADER_SIZE + 4;
+            let removed_count = removed.len().min((max_message_size.saturating_sub(size) / 8).max(1))
+                .min(u16::MAX as usize);
+            size += 8 * removed_count;
+            let mut entity_count = 0;
+            while entity_count < entities.len() && entity_count < u16::MAX as usize {
+                let entity_size = entities[entity_count].encoded_size();
+                // Always make progress, even if a single entity doesn't fit
+                if size + entity_size > max_message_size && (entity_count > 0 || removed_count > 0) {
+                    break;
+                }
+                size += entity_size;
+                entity_count += 1;
+            }
+
+            let last = removed_count == removed.len() && entity_count == entities.len();
+            let mut bytes = BytesMut::with_capacity(size);
+            self.put_header(&mut bytes, 0);
+            bytes.put_u16_be(removed_count as u16);
+            for id in &removed[..removed_count] {
+                bytes.put_u64_be(*id);
+            }
+            bytes.put_u16_be(entity_count as u16);
+            for delta in &entities[..entity_count] {
+                bytes.put_u64_be(delta.id);
+                bytes.put_u16_be(delta.changed.len() as u16);
+                for (type_id, component) in &delta.changed {
+                    bytes.put_u16_be(*type_id);
+                    bytes.put_u16_be(component.len() as u16);
+                    bytes.put_slice(component);
+                }
+                bytes.put_u16_be(delta.removed.len() as u16);
+                for type_id in &delta.removed {
+                    bytes.put_u16_be(*type_id);
+                }
+            }
+            parts.push(bytes);
+
+            removed = &removed[removed_count..];
+            entities = &entities[entity_count..];
+            if last {
+                return self.number_parts(parts);
+            }
+        }
+    }
+
+    /// Encode this delta like `encode`, but with the rest of each message after the header bit-packed.
+    pub fn encode_packed(&self, max_message_size: usize) -> Vec<Message> {
+        // Leave room for the header and the two counts, which take at most 3 bytes each
+        let budget = max_message_size.saturating_sub(Replication::HEADER_SIZE + 6) * 8;
+        let mut parts = Vec::new();
+        let mut removed = &self.removed[..];
+        let mut entities = &self.entities[..];
+        loop {
+            // Each message starts from a fresh baseline, so that it can be decoded even if the others are lost
+            let mut removed_bits = BitWriter::new();
+            let mut ids = IdBaseline::new();
+            let mut removed_count = 0;
+            while removed_count < removed.len() && removed_count < u16::MAX as usize {
+                let mark = removed_bits.len();
+                ids.write(&mut removed_bits, removed[removed_count]);
+                if removed_bits.len() > budget && removed_count > 0 {
+                    removed_bits.truncate(mark);
+                    break;
+                }
+                removed_count += 1;
+            }
+            let mut entity_bits = BitWriter::new();
+            let mut ids = IdBaseline::new();
+            let mut entity_count = 0;
+            while entity_count < entities.len() && entity_count < u16::MAX as usize {
+                let mark = entity_bits.len();
+                entities[entity_count].pack(&mut entity_bits, &mut ids);
+                // Always make progress, even if a single entity doesn't fit
+                if removed_bits.len() + entity_bits.len() > budget && (entity_count > 0 || removed_count > 0) {
+                    entity_bits.truncate(mark);
+                    break;
+                }
+                entity_count += 1;
+            }
+
+            let last = removed_count == removed.len() && entity_count == entities.len();
+            let mut writer = BitWriter::new();
+            (removed_count as u16).pack(&mut writer);
+            writer.append(&removed_bits);
+            (entity_count as u16).pack(&mut writer);
+            writer.append(&entity_bits);
+            let body = writer.finish();
+            let mut bytes = BytesMut::with_capacity(Replication::HEADER_SIZE + body.len());
+            self.put_header(&mut bytes, Replication::PACKED);
+            bytes.put_slice(&body);
+            parts.push(bytes);
+
+            removed = &removed[removed_count..];
+            entities = &entities[entity_count..];
+            if last {
+                return self.number_parts(parts);
+            }
+        }
+    }
+
+    /// Write the header, leaving the part index and count to `number_parts`.
+    fn put_header(&self, bytes: &mut BytesMut, flags: u8) {
+        bytes.put_u8(opcode::SNAPSHOT);
+        bytes.put_u32_be(self.sequence);
+        bytes.put_u32_be(self.baseline);
+        bytes.put_u32_be(self.last_input);
+        bytes.put_u8(flags);
+        bytes.put_u16_be(0);
+        bytes.put_u16_be(0);
+    }
+
+    /// Fill in the index and count of every part, now that it is known how many there are.
+    fn number_parts(&self, parts: Vec<BytesMut>) -> Vec<Message> {
+        if parts.len() > u16::MAX as usize {
+            println!("Snapshot {} would take {} messages, so it wasn't sent", self.sequence, parts.len());
+            return Vec::new();
+        }
+        let count = parts.len() as u16;
+        parts.into_iter()
+            .enumerate()
+            .map(|(index, mut bytes)| {
+                bytes[Replication::HEADER_SIZE - 4..Replication::HEADER_SIZE - 2].copy_from_slice(&(index as u16).to_be_bytes());
+                bytes[Replication::HEADER_SIZE - 2..Replication::HEADER_SIZE].copy_from_slice(&count.to_be_bytes());
+                // Snapshots can be lost, since the next one is sent relative to whatever the client acknowledged
+                Message::on(Channel::UnreliableSequenced, bytes)
+            })
+            .collect()
+    }
+
+    /// Decode a single snapshot message.
+    pub fn decode(message: &Message) -> Option<SnapshotPart> {
+        let bytes = &message.bytes;
+        if bytes.len() < Replication::HEADER_SIZE + 2 || bytes[0] != opcode::SNAPSHOT {
+            return None;
+        }
+        let mut buf = (&bytes[1..]).into_buf();
+        let sequence = buf.get_u32_be();
+        let baseline = buf.get_u32_be();
+        let last_input = buf.get_u32_be();
+        let flags = buf.get_u8();
+        let index = buf.get_u16_be();
+        let count = buf.get_u16_be();
+        if index >= count {
+            return None;
+        }
+        let body = &bytes[Replication::HEADER_SIZE..];
+        let (removed, entities) = if flags & Replication::PACKED != 0 {
+            SnapshotDelta::decode_packed(body)?
+        } else {
+            SnapshotDelta::decode_body(body)?
+        };
+        Some(SnapshotPart { delta: SnapshotDelta { sequence, baseline, last_input, removed, entities }, index, count })
+    }
+
+    fn decode_body(bytes: &[u8]) -> Option<(Vec<NetworkID>, Vec<EntityDelta>)> {
+        let mut buf = bytes.into_buf();
+        let removed_count = buf.get_u16_be() as usize;
+        if buf.remaining() < removed_count * 8 + 2 {
+            return None;
+        }
+        let removed = (0..removed_count).map(|_| buf.get_u64_be()).collect();
+
+        let entity_count = buf.get_u16_be() as usize;
+        let mut entities = Vec::with_capacity(entity_count);
+        for _ in 0..entity_count {
+            if buf.remaining() < 10 {
+                return None;
+            }
+            let id = buf.get_u64_be();
+            let changed_count = buf.get_u16_be() as usize;
+            let mut changed = Vec::with_capacity(changed_count);
+            for _ in 0..changed_count {
+                if buf.remaining() < 4 {
+                    return None;
+                }
+                let type_id = buf.get_u16_be();
+                let len = buf.get_u16_be() as usize;
+                if buf.remaining() < len {
+                    return None;
+                }
+                let mut component = vec![0u8; len];
+                buf.copy_to_slice(&mut component);
+                changed.push((type_id, Bytes::from(component)));
+            }
+            if buf.remaining() < 2 {
+                return None;
+            }
+            let removed_components = buf.get_u16_be() as usize;
+            if buf.remaining() < removed_components * 2 {
+                return None;
+            }
+            let removed = (0..removed_components).map(|_| buf.get_u16_be()).collect();
+            entities.push(EntityDelta { id, changed, removed });
+        }
+        Some((removed, entities))
+    }
+
+    fn decode_packed(bytes: &[u8]) -> Option<(Vec<NetworkID>, Vec<EntityDelta>)> {
+        let mut reader = BitReader::new(bytes);
+        let mut ids = IdBaseline::new();
+        let removed_count = u16::unpack(&mut reader)?;
+        let removed = (0..removed_count).map(|_| ids.read(&mut reader)).collect::<Option<_>>()?;
+        let mut ids = IdBaseline::new();
+        let entity_count = u16::unpack(&mut reader)?;
+        let entities = (0..entity_count).map(|_| EntityDelta::unpack(&mut reader, &mut ids)).collect::<Option<_>>()?;
+        Some((removed, entities))
+    }
+}
+
+/// Create the message a client sends to acknowledge a snapshot.
+pub fn encode_ack(sequence: SnapshotSequence) -> Message {
+    let mut bytes = BytesMut::with_capacity(5);
+    bytes.put_u8(opcode::SNAPSHOT_ACK);
+    bytes.put_u32_be(sequence);
+    Message::on(Channel::UnreliableSequenced, bytes)
+}
+
+pub fn decode_ack(message: &Message) -> Option<SnapshotSequence> {
+    let bytes = &message.bytes;
+    if bytes.len() != 5 || bytes[0] != opcode::SNAPSHOT_ACK {
+        return None;
+    }
+    Some((&bytes[1..]).into_buf().get_u32_be())
+}
\ No newline at end of file
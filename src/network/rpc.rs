@@ -0,0 +1,345 @@
+//! Remote procedure calls, for exchanges that need an answer, such as opening a shop, querying an inventory
+//! or fetching a character sheet. Calls go both ways: clients call procedures on the server, and the server
+//! calls procedures on clients.
+//!
+//! A procedure is a request type and a response type, registered under the same ID on both sides by
+//! implementing `Procedure`. Every call carries a correlation ID chosen by the caller, which its response
+//! carries back, so any number of calls can be in flight at once. A call fails with `RpcError::TimedOut` if
+//! no response arrives in time, and an error returned by the handler is sent back to the caller.
+//!
+//! On the server, handlers are registered with `GameBuilder::with_procedure` and run at the start of every tick,
+//! after the inbox has been filled, with mutable access to the world. Systems call procedures on clients through
+//! the `RpcCalls` resource, and collect the results on a later tick. On the client, `ClientHandle::call` waits for
+//! the response, and `ClientHandle::handle` registers the handlers for the server's calls.
+//!
+//! Requests start with `opcode::RPC_REQUEST`, a 2 byte procedure ID and a 4 byte correlation ID, followed by the
+//! encoded request. Responses start with `opcode::RPC_RESPONSE`, the 4 byte correlation ID and a status byte.
+//! A status of 0 is followed by the encoded response. The other statuses are the `RpcError`s: 1 for
+//! `UnknownProcedure`, 2 for `Malformed`, 3 for `Failed`, which is followed by a UTF-8 error message, 4 for
+//! `TimedOut` and 5 for `Disconnected`.
+//! Integers are big-endian.
+
+use bytes::{Bytes, BytesMut, BufMut};
+use specs::World;
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::time::{Duration, Instant};
+use crate::network::{ClientID, Message};
+use crate::network::opcode;
+use crate::network::inbox::Inbox;
+use crate::network::outbox::Outbox;
+
+/// The ID a procedure is registered under. It has to be the same on the server and the client.
+pub type ProcedureID = u16;
+
+/// Matches a response to the call it answers. It is unique among the caller's calls in flight.
+pub type CorrelationID = u32;
+
+/// The request or response of a procedure.
+pub trait Payload: Send + Sync + Sized + 'static {
+    fn encode(&self, bytes: &mut BytesMut);
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+/// For requests and responses that don't carry anything.
+impl Payload for () {
+    fn encode(&self, _: &mut BytesMut) {}
+    fn decode(_: &[u8]) -> Option<()> {
+        Some(())
+    }
+}
+
+/// A procedure that can be called remotely.
+pub trait Procedure: 'static {
+    const ID: ProcedureID;
+    type Request: Payload;
+    type Response: Payload;
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RpcError {
+    /// No response arrived before the call's timeout.
+    TimedOut,
+    /// The connection closed before the response arrived.
+    Disconnected,
+    /// The other side has no handler for the procedure.
+    UnknownProcedure,
+    /// The request or the response could not be decoded.
+    Malformed,
+    /// The handler refused the request, for the given reason.
+    Failed(String),
+}
+
+impl RpcError {
+    pub(crate) const OK: u8 = 0;
+    const UNKNOWN_PROCEDURE: u8 = 1;
+    const MALFORMED: u8 = 2;
+    pub(crate) const FAILED: u8 = 3;
+    const TIMED_OUT: u8 = 4;
+    const DISCONNECTED: u8 = 5;
+}
+
+/// A decoded request or response.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Envelope {
+    Request {
+        procedure: ProcedureID,
+        correlation: CorrelationID,
+        payload: Bytes,
+    },
+    Response {
+        correlation: CorrelationID,
+        result: Result<Bytes, RpcError>,
+    },
+}
+
+pub fn encode_request<P: Procedure>(correlation: CorrelationID, request: &P::Request) -> Message {
+    let mut bytes = BytesMut::with_capacity(16);
+    bytes.put_u8(opcode::RPC_REQUEST);
+    bytes.put_u16_be(P::ID);
+    bytes.put_u32_be(correlation);
+    request.encode(&mut bytes);
+    Message::new(bytes)
+}
+
+pub fn encode_response(correlation: CorrelationID, result: Result<BytesMut, RpcError>) -> Message {
+    let mut bytes = BytesMut::with_capacity(16);
+    bytes.put_u8(opcode::RPC_RESPONSE);
+    bytes.put_u32_be(correlation);
+    let (status, payload) = match result {
+        Ok(payload) => (RpcError::OK, payload),
+        Err(RpcError::UnknownProcedure) => (RpcError::UNKNOWN_PROCEDURE, BytesMut::new()),
+        Err(RpcError::Malformed) => (RpcError::MALFORMED, BytesMut::new()),
+        Err(RpcError::TimedOut) => (RpcError::TIMED_OUT, BytesMut::new()),
+        Err(RpcError::Disconnected) => (RpcError::DISCONNECTED, BytesMut::new()),
+        Err(RpcError::Failed(reason)) => (RpcError::FAILED, BytesMut::from(reason.into_bytes()))
+    };
+    bytes.reserve(1 + payload.len());
+    bytes.put_u8(status);
+    bytes.put_slice(&payload);
+    Message::new(bytes)
+}
+
+/// Decode a request or a response. The payload shares the message's memory.
+pub fn decode(message: &Message) -> Option<Envelope> {
+    let bytes = &message.bytes;
+    match bytes.first() {
+        Some(&opcode::RPC_REQUEST) if bytes.len() >= 7 => Some(Envelope::Request {
+            procedure: u16::from_be_bytes([bytes[1], bytes[2]]),
+            correlation: u32::from_be_bytes([bytes[3], bytes[4], bytes[5], bytes[6]]),
+            payload: bytes.slice_from(7)
+        }),
+        Some(&opcode::RPC_RESPONSE) if bytes.len() >= 6 => {
+            let payload = bytes.slice_from(6);
+            let result = match bytes[5] {
+                RpcError::OK => Ok(payload),
+                RpcError::UNKNOWN_PROCEDURE => Err(RpcError::UnknownProcedure),
+                RpcError::MALFORMED => Err(RpcError::Malformed),
+                RpcError::TIMED_OUT => Err(RpcError::TimedOut),
+                RpcError::DISCONNECTED => Err(RpcError::Disconnected),
+                _ => Err(RpcError::Failed(String::from_utf8_lossy(&payload).into_owned()))
+            };
+            Some(Envelope::Response {
+                correlation: u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]),
+                result
+            })
+        },
+        _ => None
+    }
+}
+
+/// Decode a request, let the handler answer it, and encode the answer.
+pub(crate) fn respond<P, F>(payload: &[u8], handler: F) -> Result<BytesMut, RpcError>
+where P: Procedure, F: FnOnce(P::Request) -> Result<P::Response, RpcError> {
+    let request = P::Request::decode(payload).ok_or(RpcError::Malformed)?;
+    let response = handler(request)?;
+    let mut bytes = BytesMut::new();
+    response.encode(&mut bytes);
+    Ok(bytes)
+}
+
+/// Decode the result of a call.
+pub(crate) fn response<P: Procedure>(result: Result<Bytes, RpcError>) -> Result<P::Response, RpcError> {
+    P::Response::decode(&result?).ok_or(RpcError::Malformed)
+}
+
+type Handler = Box<dyn Fn(&mut World, ClientID, &[u8]) -> Result<BytesMut, RpcError> + Send + Sync>;
+
+/// The server's handlers, by procedure.
+#[derive(Default)]
+pub struct Procedures {
+    handlers: HashMap<ProcedureID, Handler>,
+}
+
+impl Procedures {
+    pub fn new() -> Procedures {
+        Procedures::default()
+    }
+
+    /// Handle the calls clients make to `P`. A procedure can only have one handler, so this replaces any earlier one.
+    pub fn register<P, F>(&mut self, handler: F)
+    where P: Procedure, F: Fn(&mut World, ClientID, P::Request) -> Result<P::Response, RpcError> + Send + Sync + 'static {
+        self.handlers.insert(P::ID, Box::new(move |world: &mut World, client: ClientID, payload: &[u8]| {
+            respond::<P, _>(payload, |request| handler(world, client, request))
+        }));
+    }
+
+    pub fn contains(&self, procedure: ProcedureID) -> bool {
+        self.handlers.contains_key(&procedure)
+    }
+
+    /// Run the handler for a request, and encode its response.
+    pub fn handle(&self, world: &mut World, client: ClientID, procedure: ProcedureID, correlation: CorrelationID, payload: &[u8]) -> Message {
+        let result = match self.handlers.get(&procedure) {
+            Some(handler) => handler(world, client, payload),
+            None => Err(RpcError::UnknownProcedure)
+        };
+        encode_response(correlation, result)
+    }
+}
+
+/// A call the server made to a client, to collect the result of from `RpcCalls`.
+pub struct Call<P> {
+    correlation: CorrelationID,
+    procedure: PhantomData<fn() -> P>,
+}
+
+impl<P> Clone for Call<P> {
+    fn clone(&self) -> Call<P> {
+        *self
+    }
+}
+
+impl<P> Copy for Call<P> {}
+
+impl<P> fmt::Debug for Call<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Call").field("correlation", &self.correlation).finish()
+    }
+}
+
+/// The resource that systems call procedures on clients with.
+///
+/// Calls are sent at the end of the tick, and their results are collected with `RpcCalls::result` once they
+/// arrive. A result that isn't collected within `RpcCalls::RESULT_LIFETIME` of arriving is dropped.
+#[derive(Default)]
+pub struct RpcCalls {
+    next: CorrelationID,
+    /// The client and deadline of every call waiting for a response.
+    pending: HashMap<CorrelationID, (ClientID, Instant)>,
+    /// The results waiting to be collected, and when they arrived.
+    completed: HashMap<CorrelationID, (Result<Bytes, RpcError>, Instant)>,
+    queue: Vec<(ClientID, Message)>,
+}
+
+impl RpcCalls {
+    pub const RESULT_LIFETIME: Duration = Duration::from_secs(60);
+
+    pub fn new() -> RpcCalls {
+        RpcCalls::default()
+    }
+
+    /// Call a procedure on a client. The call fails if no response arrives within `timeout`.
+    pub fn call<P: Procedure>(&mut self, client: ClientID, request: &P::Request, timeout: Duration) -> Call<P> {
+        let correlation = self.next;
+        self.next = self.next.wrapping_add(1);
+        self.pending.insert(correlation, (client, Instant::now() + timeout));
+        self.queue.push((client, encode_request::<P>(correlation, request)));
+        Call { correlation, procedure: PhantomData }
+    }
+
+    /// Take the result of a call, once it has one.
+    pub fn result<P: Procedure>(&mut self, call: Call<P>) -> Option<Result<P::Response, RpcError>> {
+        self.completed.remove(&call.correlation).map(|(result, _)| response::<P>(result))
+    }
+
+    pub fn is_pending<P>(&self, call: Call<P>) -> bool {
+        self.pending.contains_key(&call.correlation)
+    }
+
+    /// The number of calls waiting for a response.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Record a response from a client. Responses to calls that have timed out, or that were made to another
+    /// client, are ignored.
+    pub fn complete(&mut self, client: ClientID, correlation: CorrelationID, result: Result<Bytes, RpcError>, now: Instant) {
+        if self.pending.get(&correlation).map(|(callee, _)| *callee) == Some(client) {
+            self.pending.remove(&correlation);
+            self.completed.insert(correlation, (result, now));
+        }
+    }
+
+    /// Fail the calls that are past their deadline or whose client has disconnected, and drop old results.
+    pub fn expire(&mut self, clients: &[ClientID], now: Instant) {
+        let completed = &mut self.completed;
+        self.pending.retain(|correlation, (client, deadline)| {
+            let error = if !clients.contains(client) {
+                RpcError::Disconnected
+            } else if *deadline <= now {
+                RpcError::TimedOut
+            } else {
+                return true;
+            };
+            completed.insert(*correlation, (Err(error), now));
+            false
+        });
+        self.completed.retain(|_, (_, arrived)| now.duration_since(*arrived) < RpcCalls::RESULT_LIFETIME);
+    }
+
+    /// Move the calls made during this tick into the outbox.
+    pub fn flush(&mut self, outbox: &mut Outbox) {
+        for (client, message) in self.queue.drain(..) {
+            outbox.send(client, message);
+        }
+    }
+}
+
+/// Take the requests and responses out of the inbox. Requests are handed to their handlers and answered through
+/// the outbox, and responses complete the server's calls. Calls that are out of time fail.
+/// This runs at the start of every tick, after the inbox has been filled.
+pub fn update(procedures: &Procedures, world: &mut World, clients: &[ClientID]) {
+    let now = Instant::now();
+    let (requests, responses) = {
+        let mut inbox = world.write_resource::<Inbox>();
+        (inbox.take(opcode::RPC_REQUEST), inbox.take(opcode::RPC_RESPONSE))
+    };
+
+    {
+        let mut calls = world.write_resource::<RpcCalls>();
+        for (client, message) in responses {
+            if let Some(Envelope::Response { correlation, result }) = decode(&message) {
+                calls.complete(client, correlation, result, now);
+            }
+        }
+        calls.expire(clients, now);
+    }
+
+    for (client, message) in requests {
+        if let Some(Envelope::Request { procedure, correlation, payload }) = decode(&message) {
+            let response = procedures.handle(world, client, procedure, correlation, &payload);
+            world.write_resource::<Outbox>().send(client, response);
+        }
+    }
+}
+
+/// Send the calls systems made during this tick. This runs at the end of every tick, before the outbox is flushed.
+pub fn flush(world: &World) {
+    world.write_resource::<RpcCalls>().flush(&mut world.write_resource::<Outbox>());
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::TimedOut => write!(f, "the call timed out"),
+            RpcError::Disconnected => write!(f, "the connection closed before the call was answered"),
+            RpcError::UnknownProcedure => write!(f, "the procedure is unknown"),
+            RpcError::Malformed => write!(f, "the call could not be decoded"),
+            RpcError::Failed(reason) => write!(f, "{}", reason)
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
@@ -0,0 +1,822 @@
+//! A machine-readable description of the protocol, so that clients written in other languages can generate their
+//! bindings instead of reverse-engineering byte layouts.
+//!
+//! A `Schema` lists the protocol version, the engine's messages with their opcodes and layouts, the game's message
+//! types with their opcodes, and the replicated components with their type IDs. `Game::schema` builds one from the
+//! codec's registered messages and the registered components, and `Schema::to_json` turns it into JSON.
+//!
+//! Games export it with `Game::schema_command`, which handles a `schema PATH` subcommand, so that the build of a
+//! client in another language can run the game's binary with `schema protocol.json` and generate its bindings from
+//! the file before it compiles them.
+//!
+//! The layouts of serde types are worked out by `Schema::trace`, which deserializes the type from a stand-in
+//! format that records what the type asks for. Every enum is deserialized once for each of its variants, so all
+//! of them end up described. Structs and enums are described once in `Schema::types`, and referred to by name.
+//! This works for any type bincode can decode, apart from structs that contain themselves. Serde only knows the
+//! names of types without their modules, so two different types with the same name can't both be described,
+//! and fail with `TraceError::Collision`.
+//!
+//! Each message and component says how it is encoded:
+//! - `bincode`: bincode's default options. Integers are variable-length and little-endian, and strings, bytes,
+//!   sequences and maps are prefixed with their length. Options start with a 0 or 1 byte, and enums with the
+//!   index of their variant.
+//! - `big-endian`: fixed-width, big-endian integers, laid out one after the other. This is how the engine's messages
+//!   are encoded, and their fields use the layouts that only appear there: `list`, which is a count in the given
+//!   layout followed by that many elements, `struct`, which is a struct laid out in place, `rest`, which is
+//!   whatever is left of the message, `text`, which is the rest as UTF-8, `magic`, which is bytes that are always
+//!   the same, `little_endian`, for the odd integer that isn't big-endian, and `switch`, which is laid out
+//!   according to an earlier field of the same struct, like a status byte. Snapshots can be `bit_packed` after
+//!   their header, in which case the `NetworkID`s are `relative_id`s.
+//! - `bit-packed`: the `bitpack` module's encoding, with fields one after the other, least significant bit first and
+//!   without padding. Bools are one bit and floats are all their bits. The layouts that only appear there are
+//!   `bits`, an unsigned integer in that many bits, `varint` and `signed_varint`, and `quantized`, a float in a
+//!   range sent as one of evenly spaced steps, with the distance between them.
+//! - `opaque`: not described.
+//!
+//! The payloads of RPC requests and successful responses are encoded by each procedure's `Payload`, which isn't
+//! described.
+//!
+//! `Schema::formats` describes what is sent around the messages, in the order a connection uses them:
+//! - `client_hello` and `server_hello`: the handshake, which comes first (see `handshake`).
+//! - `credentials` and `auth_result`: authentication, which follows if the server requires it (see `auth`).
+//! - `frame`: how stream transports, like TCP, send each message after that. WebSocket clients send the handshake,
+//!   authentication and messages in a binary frame each instead, and UDP clients in packets of their own.
+//! - `compressed_message`: every message, inside its frame, once both sides have agreed to compression.
+use serde::Serialize;
+use serde::de::{self, DeserializeOwned, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess, SeqAccess, VariantAccess, Visitor};
+use serde::de::value::U32Deserializer;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+use crate::network::opcode::{self, Opcode};
+use crate::network::handshake::{ProtocolVersion, PROTOCOL_VERSION};
+use crate::network::replication::{ComponentTypeID, Replication};
+use crate::network::rpc::RpcError;
+
+/// How a value is laid out on the wire.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Layout {
+    Unit,
+    Bool,
+    I8,
+    I16,
+    I32,
+    I64,
+    I128,
+    U8,
+    U16,
+    U32,
+    U64,
+    U128,
+    F32,
+    F64,
+    Char,
+    String,
+    Bytes,
+    Option(Box<Layout>),
+    Seq(Box<Layout>),
+    Map {
+        key: Box<Layout>,
+        value: Box<Layout>,
+    },
+    Tuple(Vec<Layout>),
+    /// A struct or enum from `Schema::types`.
+    Named(String),
+    /// A count, followed by that many elements.
+    List {
+        count: Box<Layout>,
+        element: Box<Layout>,
+    },
+    /// A struct laid out in place, for the parts of engine messages that don't have a type of their own.
+    Struct(Vec<Field>),
+    /// Whatever is left of the message.
+    Rest,
+    /// Whatever is left of the message, as UTF-8 text.
+    Text,
+    /// These bytes, as they are.
+    Magic(String),
+    /// An integer in little-endian order, among big-endian ones.
+    LittleEndian(Box<Layout>),
+    /// Laid out as the first case whose value equals the earlier field of the same struct, after it's masked
+    /// with `mask`, or as `default` if none do.
+    Switch {
+        field: String,
+        mask: u64,
+        cases: Vec<Case>,
+        default: Box<Layout>,
+    },
+    /// Whatever is left of the message, bit-packed and padded with zeroes to a whole byte.
+    BitPacked(Box<Layout>),
+    /// A bit-packed `NetworkID`, relative to the one before it in the same list. See `bitpack::IdBaseline`.
+    RelativeId,
+    /// An unsigned integer in this many bits.
+    Bits(usize),
+    /// 7 bits at a time, each group followed by a bit that says whether another one does.
+    Varint,
+    /// A zigzag encoded `Varint`.
+    SignedVarint,
+    /// A float between `min` and `max`, sent as the number of steps from `min` in `bits` bits.
+    Quantized {
+        min: f32,
+        max: f32,
+        bits: usize,
+        step: f32,
+    },
+}
+
+/// How a struct, or a variant of an enum, is laid out.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TypeLayout {
+    Unit,
+    Newtype(Layout),
+    Tuple(Vec<Layout>),
+    Struct(Vec<Field>),
+    Enum(Vec<Variant>),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Field {
+    pub name: String,
+    pub layout: Layout,
+}
+
+impl Field {
+    pub fn new(name: &str, layout: Layout) -> Field {
+        Field { name: name.to_string(), layout }
+    }
+}
+
+/// A layout that a `Layout::Switch` picks when its field has this value.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Case {
+    pub value: u64,
+    pub name: String,
+    pub layout: Layout,
+}
+
+impl Case {
+    pub fn new(value: u64, name: &str, layout: Layout) -> Case {
+        Case { value, name: name.to_string(), layout }
+    }
+}
+
+/// A variant of an enum. Its index is its position in the enum.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Variant {
+    pub name: String,
+    pub layout: TypeLayout,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Encoding {
+    Bincode,
+    BigEndian,
+    BitPacked,
+    Opaque,
+}
+
+/// How a message or component is encoded, and its layout.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Description {
+    pub encoding: Encoding,
+    pub layout: Layout,
+}
+
+impl Description {
+    pub fn bincode(layout: Layout) -> Description {
+        Description { encoding: Encoding::Bincode, layout }
+    }
+    pub fn big_endian(layout: Layout) -> Description {
+        Description { encoding: Encoding::BigEndian, layout }
+    }
+    pub fn bit_packed(layout: Layout) -> Description {
+        Description { encoding: Encoding::BitPacked, layout }
+    }
+    /// For encodings that aren't described. The layout is just bytes.
+    pub fn opaque() -> Description {
+        Description { encoding: Encoding::Opaque, layout: Layout::Bytes }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct OpcodeSchema {
+    pub opcode: Opcode,
+    pub name: String,
+    /// The layout of what follows the opcode.
+    #[serde(flatten)]
+    pub description: Description,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MessageSchema {
+    pub opcode: Opcode,
+    pub name: String,
+    #[serde(flatten)]
+    pub description: Description,
+}
+
+/// Something that is sent outside of a message, like the handshake.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FormatSchema {
+    pub name: String,
+    #[serde(flatten)]
+    pub description: Description,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ComponentSchema {
+    pub type_id: ComponentTypeID,
+    pub name: String,
+    #[serde(flatten)]
+    pub description: Description,
+}
+
+/// The description of the protocol.
+#[derive(Clone, Debug, Serialize)]
+pub struct Schema {
+    pub protocol_version: ProtocolVersion,
+    /// The opcodes the engine has reserved for its own messages, and the layouts of the messages.
+    /// What their fields mean is documented with the modules that use them.
+    pub engine_opcodes: Vec<OpcodeSchema>,
+    /// What is sent around the messages, and before them.
+    pub formats: Vec<FormatSchema>,
+    pub messages: Vec<MessageSchema>,
+    pub components: Vec<ComponentSchema>,
+    /// The structs and enums the layouts refer to by name.
+    pub types: BTreeMap<String, TypeLayout>,
+    #[serde(skip)]
+    trace: TraceState,
+}
+
+/// What `Schema::trace` keeps track of between passes.
+#[derive(Clone, Debug, Default)]
+struct TraceState {
+    /// The variants of every enum seen so far, and the layouts of the ones that have been traced.
+    enums: HashMap<&'static str, (&'static [&'static str], Vec<Option<TypeLayout>>)>,
+    /// The types being traced, innermost last, with the variant that was picked if they are enums.
+    stack: Vec<(&'static str, Option<u32>)>,
+    pass: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TraceError {
+    /// The type can only be decoded from a self-describing format, which bincode isn't.
+    NotSupported(&'static str),
+    /// Two different types have the same name.
+    Collision(String),
+    /// The struct contains itself.
+    Recursive(String),
+    /// Some of the enum's variants could never be reached.
+    Unreachable(String),
+    /// The type refused the values it was given while being traced.
+    Custom(String),
+    /// The type's encoding can't be used, like a quantization with an empty range.
+    Invalid(String),
+}
+
+impl Default for Schema {
+    fn default() -> Schema {
+        Schema {
+            protocol_version: PROTOCOL_VERSION,
+            engine_opcodes: opcode::ENGINE.iter()
+                .map(|(name, opcode)| OpcodeSchema {
+                    opcode: *opcode,
+                    name: name.to_string(),
+                    description: Description::big_endian(engine_layout(*opcode))
+                })
+                .collect(),
+            formats: formats(),
+            messages: Vec::new(),
+            components: Vec::new(),
+            types: BTreeMap::new(),
+            trace: TraceState::default()
+        }
+    }
+}
+
+fn list(count: Layout, element: Layout) -> Layout {
+    Layout::List { count: Box::new(count), element: Box::new(element) }
+}
+
+fn switch(field: &str, mask: u64, cases: Vec<Case>, default: Layout) -> Layout {
+    Layout::Switch { field: field.to_string(), mask, cases, default: Box::new(default) }
+}
+
+/// The removed and updated entities of a snapshot, as the `replication` module lays them out. Bit-packed snapshots
+/// have the same fields, with variable-length counts, type IDs and lengths, and relative `NetworkID`s.
+fn snapshot_delta(packed: bool) -> Layout {
+    let (count, id, type_id) = if packed {
+        (Layout::Varint, Layout::RelativeId, Layout::Varint)
+    } else {
+        (Layout::U16, Layout::U64, Layout::U16)
+    };
+    Layout::Struct(vec![
+        Field::new("removed", list(count.clone(), id.clone())),
+        Field::new("entities", list(count.clone(), Layout::Struct(vec![
+            Field::new("id", id),
+            // Each component is encoded as `Schema::components` describes the type
+            Field::new("changed", list(count.clone(), Layout::Struct(vec![
+                Field::new("type_id", type_id.clone()),
+                Field::new("component", list(count.clone(), Layout::U8)),
+            ]))),
+            Field::new("removed", list(count, type_id)),
+        ]))),
+    ])
+}
+
+/// The layout of an engine message after its opcode.
+fn engine_layout(opcode: Opcode) -> Layout {
+    let fields = match opcode {
+        opcode::SNAPSHOT => vec![
+            Field::new("sequence", Layout::U32),
+            Field::new("baseline", Layout::U32),
+            Field::new("last_input", Layout::U32),
+            Field::new("flags", Layout::U8),
+            Field::new("part_index", Layout::U16),
+            Field::new("part_count", Layout::U16),
+            Field::new("delta", switch("flags", u64::from(Replication::PACKED), vec![
+                Case::new(u64::from(Replication::PACKED), "packed", Layout::BitPacked(Box::new(snapshot_delta(true)))),
+            ], snapshot_delta(false))),
+        ],
+        opcode::SNAPSHOT_ACK => vec![Field::new("sequence", Layout::U32)],
+        opcode::VIEW_ENTER | opcode::VIEW_LEAVE => vec![Field::new("entities", list(Layout::U16, Layout::U64))],
+        opcode::INPUT => vec![Field::new("inputs", list(Layout::U8, Layout::Struct(vec![
+            Field::new("sequence", Layout::U32),
+            Field::new("tick", Layout::U32),
+            Field::new("input", list(Layout::U16, Layout::U8)),
+        ])))],
+        opcode::PING | opcode::PONG => vec![Field::new("timestamp", Layout::U64)],
+        opcode::SESSION => vec![Field::new("token", Layout::Text)],
+        opcode::RPC_REQUEST => vec![
+            Field::new("procedure", Layout::U16),
+            Field::new("correlation", Layout::U32),
+            Field::new("request", Layout::Rest),
+        ],
+        opcode::RPC_RESPONSE => vec![
+            Field::new("correlation", Layout::U32),
+            Field::new("status", Layout::U8),
+            Field::new("result", switch("status", 0xFF, vec![
+                Case::new(u64::from(RpcError::OK), "ok", Layout::Rest),
+                Case::new(u64::from(RpcError::FAILED), "failed", Layout::Text),
+            ], Layout::Unit)),
+        ],
+        _ => vec![Field::new("contents", Layout::Rest)]
+    };
+    Layout::Struct(fields)
+}
+
+/// What is sent around the messages, and before them. All of it is big-endian.
+fn formats() -> Vec<FormatSchema> {
+    let short_bytes = || list(Layout::U8, Layout::U8);
+    let formats = vec![
+        ("client_hello", vec![
+            Field::new("magic", Layout::Magic("STAR".to_string())),
+            Field::new("protocol_version", Layout::U16),
+            Field::new("capabilities", Layout::U32),
+            Field::new("client_build", short_bytes()),
+        ]),
+        ("server_hello", vec![
+            Field::new("status", Layout::U8),
+            Field::new("body", switch("status", 0xFF, vec![
+                Case::new(0, "accepted", Layout::Struct(vec![
+                    Field::new("protocol_version", Layout::U16),
+                    Field::new("capabilities", Layout::U32),
+                ])),
+                Case::new(1, "unsupported_version", Layout::Struct(vec![
+                    Field::new("client", Layout::U16),
+                    Field::new("min", Layout::U16),
+                    Field::new("max", Layout::U16),
+                ])),
+                Case::new(2, "malformed", Layout::Unit),
+                Case::new(3, "other", Layout::Struct(vec![Field::new("reason", list(Layout::U16, Layout::U8))])),
+            ], Layout::Unit)),
+        ]),
+        ("credentials", vec![
+            Field::new("kind", Layout::U8),
+            Field::new("credentials", switch("kind", 0xFF, vec![
+                Case::new(0, "password", Layout::Struct(vec![
+                    Field::new("username", short_bytes()),
+                    Field::new("password", short_bytes()),
+                ])),
+                Case::new(1, "token", Layout::Struct(vec![Field::new("token", short_bytes())])),
+            ], Layout::Unit)),
+        ]),
+        // Any other status is an `AuthError` code
+        ("auth_result", vec![
+            Field::new("status", Layout::U8),
+            Field::new("account", switch("status", 0xFF, vec![Case::new(0, "ok", Layout::U64)], Layout::Unit)),
+        ]),
+        ("frame", vec![
+            Field::new("length", Layout::U32),
+            Field::new("message", Layout::Rest),
+        ]),
+        ("compressed_message", vec![
+            Field::new("compressed", Layout::U8),
+            Field::new("message", switch("compressed", 0xFF, vec![
+                Case::new(1, "lz4", Layout::Struct(vec![
+                    Field::new("uncompressed_size", Layout::LittleEndian(Box::new(Layout::U32))),
+                    Field::new("block", Layout::Rest),
+                ])),
+            ], Layout::Rest)),
+        ]),
+    ];
+    formats.into_iter()
+        .map(|(name, fields)| FormatSchema { name: name.to_string(), description: Description::big_endian(Layout::Struct(fields)) })
+        .collect()
+}
+
+impl Schema {
+    const MAX_PASSES: usize = 1024;
+
+    pub fn new() -> Schema {
+        Schema::default()
+    }
+
+    pub fn add_message(&mut self, opcode: Opcode, name: &str, description: Description) {
+        self.messages.retain(|message| message.opcode != opcode);
+        self.messages.push(MessageSchema { opcode, name: name.to_string(), description });
+        self.messages.sort_by_key(|message| message.opcode);
+    }
+
+    pub fn add_component(&mut self, type_id: ComponentTypeID, name: &str, description: Description) {
+        self.components.retain(|component| component.type_id != type_id);
+        self.components.push(ComponentSchema { type_id, name: name.to_string(), description });
+        self.components.sort_by_key(|component| component.type_id);
+    }
+
+    /// Describe a struct or enum by hand, and get the layout that refers to it.
+    /// Fails if a different type was already described under the same name.
+    pub fn define(&mut self, name: &str, layout: TypeLayout) -> Result<Layout, TraceError> {
+        match self.types.get(name) {
+            Some(existing) if *existing != layout => return Err(TraceError::Collision(name.to_string())),
+            Some(_) => {},
+            None => {
+                self.types.insert(name.to_string(), layout);
+            }
+        }
+        Ok(Layout::Named(name.to_string()))
+    }
+
+    /// Work out the layout of a serde type, adding the structs and enums in it to `types`.
+    pub fn trace<T: DeserializeOwned>(&mut self) -> Result<Layout, TraceError> {
+        self.trace.pass = 0;
+        loop {
+            let mut layout = Layout::Unit;
+            self.trace.stack.clear();
+            if let Err(e) = T::deserialize(Tracer { schema: self, layout: &mut layout }) {
+                // Forget the enums this type left unfinished, so that they don't hold up the next one
+                self.trace.enums.retain(|_, (_, layouts)| layouts.iter().all(Option::is_some));
+                return Err(e);
+            }
+            self.trace.pass += 1;
+
+            let incomplete = self.trace.enums.iter()
+                .find(|(_, (_, layouts))| layouts.iter().any(Option::is_none))
+                .map(|(name, _)| *name);
+            match incomplete {
+                None => {
+                    self.finish_enums()?;
+                    return Ok(layout);
+                },
+                Some(name) if self.trace.pass >= Schema::MAX_PASSES => return Err(TraceError::Unreachable(name.to_string())),
+                Some(_) => {}
+            }
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("A schema is always valid JSON")
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), std::io::Error> {
+        std::fs::write(path, self.to_json())
+    }
+
+    fn finish_enums(&mut self) -> Result<(), TraceError> {
+        let enums: Vec<(&'static str, TypeLayout)> = self.trace.enums.iter()
+            .map(|(name, (variants, layouts))| {
+                let variants = variants.iter().zip(layouts)
+                    .map(|(variant, layout)| Variant {
+                        name: variant.to_string(),
+                        layout: layout.clone().expect("Every variant to have been traced")
+                    })
+                    .collect();
+                (*name, TypeLayout::Enum(variants))
+            })
+            .collect();
+        for (name, layout) in enums {
+            self.define(name, layout)?;
+        }
+        Ok(())
+    }
+
+    /// Pick the variant of an enum to trace next. Variants that haven't been traced yet come first, unless the
+    /// enum is inside itself, in which case a variant that is already being traced would never finish.
+    fn pick_variant(&mut self, name: &'static str, variants: &'static [&'static str]) -> Result<u32, TraceError> {
+        let (known, layouts) = self.trace.enums.entry(name).or_insert_with(|| (variants, vec![None; variants.len()]));
+        if *known != variants {
+            return Err(TraceError::Collision(name.to_string()));
+        }
+        let outer: Vec<u32> = self.trace.stack.iter()
+            .filter(|(traced, _)| *traced == name)
+            .filter_map(|(_, variant)| *variant)
+            .collect();
+        let index = if outer.is_empty() {
+            layouts.iter().position(Option::is_none).unwrap_or(self.trace.pass % variants.len().max(1))
+        } else {
+            let free = |index: &usize| !outer.contains(&(*index as u32));
+            match (0..layouts.len()).filter(free).find(|index| layouts[*index].is_some()) {
+                Some(index) => index,
+                None => (0..layouts.len()).find(free).ok_or_else(|| TraceError::Recursive(name.to_string()))?
+            }
+        };
+        if index >= variants.len() {
+            return Err(TraceError::Unreachable(name.to_string()));
+        }
+        Ok(index as u32)
+    }
+
+    fn enter(&mut self, name: &'static str) -> Result<(), TraceError> {
+        if self.trace.stack.iter().any(|(traced, _)| *traced == name) {
+            return Err(TraceError::Recursive(name.to_string()));
+        }
+        self.trace.stack.push((name, None));
+        Ok(())
+    }
+
+    /// Record the layout of a variant of an enum. An enum with the same name and variants that was traced before
+    /// has to have laid the variant out the same way.
+    fn record_variant(&mut self, name: &'static str, index: u32, layout: TypeLayout) -> Result<(), TraceError> {
+        let recorded = &mut self.trace.enums.get_mut(name).expect("The enum to have been seen").1[index as usize];
+        match recorded {
+            Some(existing) if *existing != layout => Err(TraceError::Collision(name.to_string())),
+            _ => {
+                *recorded = Some(layout);
+                Ok(())
+            }
+        }
+    }
+
+    fn leave(&mut self, name: &'static str, layout: TypeLayout) -> Result<Layout, TraceError> {
+        self.trace.stack.pop();
+        self.define(name, layout)
+    }
+}
+
+/// Deserializes any type from made-up values, and records the layout the type asked for.
+struct Tracer<'a> {
+    schema: &'a mut Schema,
+    layout: &'a mut Layout,
+}
+
+macro_rules! trace_primitive {
+    ($method:ident, $layout:ident, $visit:ident, $value:expr) => {
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+            *self.layout = Layout::$layout;
+            visitor.$visit($value)
+        }
+    };
+}
+
+impl<'de, 'a> de::Deserializer<'de> for Tracer<'a> {
+    type Error = TraceError;
+
+    trace_primitive!(deserialize_bool, Bool, visit_bool, false);
+    trace_primitive!(deserialize_i8, I8, visit_i8, 0);
+    trace_primitive!(deserialize_i16, I16, visit_i16, 0);
+    trace_primitive!(deserialize_i32, I32, visit_i32, 0);
+    trace_primitive!(deserialize_i64, I64, visit_i64, 0);
+    trace_primitive!(deserialize_i128, I128, visit_i128, 0);
+    trace_primitive!(deserialize_u8, U8, visit_u8, 0);
+    trace_primitive!(deserialize_u16, U16, visit_u16, 0);
+    trace_primitive!(deserialize_u32, U32, visit_u32, 0);
+    trace_primitive!(deserialize_u64, U64, visit_u64, 0);
+    trace_primitive!(deserialize_u128, U128, visit_u128, 0);
+    trace_primitive!(deserialize_f32, F32, visit_f32, 0.0);
+    trace_primitive!(deserialize_f64, F64, visit_f64, 0.0);
+    trace_primitive!(deserialize_char, Char, visit_char, '\0');
+    trace_primitive!(deserialize_str, String, visit_str, "");
+    trace_primitive!(deserialize_string, String, visit_string, String::new());
+    trace_primitive!(deserialize_bytes, Bytes, visit_bytes, &[]);
+    trace_primitive!(deserialize_byte_buf, Bytes, visit_byte_buf, Vec::new());
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        *self.layout = Layout::Unit;
+        visitor.visit_unit()
+    }
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, TraceError> {
+        Err(TraceError::NotSupported("deserialize_any"))
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, _: V) -> Result<V::Value, TraceError> {
+        Err(TraceError::NotSupported("deserialize_identifier"))
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, TraceError> {
+        Err(TraceError::NotSupported("deserialize_ignored_any"))
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        let mut inner = Layout::Unit;
+        let value = visitor.visit_some(Tracer { schema: self.schema, layout: &mut inner })?;
+        *self.layout = Layout::Option(Box::new(inner));
+        Ok(value)
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(self, name: &'static str, visitor: V) -> Result<V::Value, TraceError> {
+        *self.layout = self.schema.define(name, TypeLayout::Unit)?;
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, name: &'static str, visitor: V) -> Result<V::Value, TraceError> {
+        self.schema.enter(name)?;
+        let mut inner = Layout::Unit;
+        let value = visitor.visit_newtype_struct(Tracer { schema: &mut *self.schema, layout: &mut inner })?;
+        *self.layout = self.schema.leave(name, TypeLayout::Newtype(inner))?;
+        Ok(value)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        let mut layouts = Vec::new();
+        let value = visitor.visit_seq(SeqTracer { schema: self.schema, layouts: &mut layouts, remaining: 1 })?;
+        *self.layout = Layout::Seq(Box::new(layouts.pop().unwrap_or(Layout::Unit)));
+        Ok(value)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, TraceError> {
+        let mut layouts = Vec::new();
+        let value = visitor.visit_seq(SeqTracer { schema: self.schema, layouts: &mut layouts, remaining: len })?;
+        *self.layout = Layout::Tuple(layouts);
+        Ok(value)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, name: &'static str, len: usize, visitor: V) -> Result<V::Value, TraceError> {
+        self.schema.enter(name)?;
+        let mut layouts = Vec::new();
+        let value = visitor.visit_seq(SeqTracer { schema: &mut *self.schema, layouts: &mut layouts, remaining: len })?;
+        *self.layout = self.schema.leave(name, TypeLayout::Tuple(layouts))?;
+        Ok(value)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        let (mut key, mut entry) = (Layout::Unit, Layout::Unit);
+        let value = visitor.visit_map(MapTracer { schema: self.schema, key: &mut key, value: &mut entry, remaining: 1 })?;
+        *self.layout = Layout::Map { key: Box::new(key), value: Box::new(entry) };
+        Ok(value)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, name: &'static str, fields: &'static [&'static str], visitor: V)
+        -> Result<V::Value, TraceError> {
+        self.schema.enter(name)?;
+        let mut layouts = Vec::new();
+        let value = visitor.visit_seq(SeqTracer { schema: &mut *self.schema, layouts: &mut layouts, remaining: fields.len() })?;
+        let fields = fields.iter().zip(layouts).map(|(name, layout)| Field::new(name, layout)).collect();
+        *self.layout = self.schema.leave(name, TypeLayout::Struct(fields))?;
+        Ok(value)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, name: &'static str, variants: &'static [&'static str], visitor: V)
+        -> Result<V::Value, TraceError> {
+        let index = self.schema.pick_variant(name, variants)?;
+        self.schema.trace.stack.push((name, Some(index)));
+        let mut layout = None;
+        let value = visitor.visit_enum(EnumTracer { schema: &mut *self.schema, index, layout: &mut layout })?;
+        self.schema.trace.stack.pop();
+        if let Some(layout) = layout {
+            self.schema.record_variant(name, index, layout)?;
+        }
+        *self.layout = Layout::Named(name.to_string());
+        Ok(value)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+/// Hands out the elements of a sequence, tuple or struct.
+struct SeqTracer<'a> {
+    schema: &'a mut Schema,
+    layouts: &'a mut Vec<Layout>,
+    remaining: usize,
+}
+
+impl<'de, 'a> SeqAccess<'de> for SeqTracer<'a> {
+    type Error = TraceError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, TraceError> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        let mut layout = Layout::Unit;
+        let value = seed.deserialize(Tracer { schema: &mut *self.schema, layout: &mut layout })?;
+        self.layouts.push(layout);
+        Ok(Some(value))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+/// Hands out a single entry of a map.
+struct MapTracer<'a> {
+    schema: &'a mut Schema,
+    key: &'a mut Layout,
+    value: &'a mut Layout,
+    remaining: usize,
+}
+
+impl<'de, 'a> MapAccess<'de> for MapTracer<'a> {
+    type Error = TraceError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, TraceError> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        seed.deserialize(Tracer { schema: &mut *self.schema, layout: &mut *self.key }).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, TraceError> {
+        seed.deserialize(Tracer { schema: &mut *self.schema, layout: &mut *self.value })
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+/// Picks the variant `index` of an enum, and records its layout.
+struct EnumTracer<'a> {
+    schema: &'a mut Schema,
+    index: u32,
+    layout: &'a mut Option<TypeLayout>,
+}
+
+impl<'de, 'a> EnumAccess<'de> for EnumTracer<'a> {
+    type Error = TraceError;
+    type Variant = Self;
+
+    fn variant_seed<S: DeserializeSeed<'de>>(self, seed: S) -> Result<(S::Value, Self), TraceError> {
+        let index: U32Deserializer<TraceError> = self.index.into_deserializer();
+        Ok((seed.deserialize(index)?, self))
+    }
+}
+
+impl<'de, 'a> VariantAccess<'de> for EnumTracer<'a> {
+    type Error = TraceError;
+
+    fn unit_variant(self) -> Result<(), TraceError> {
+        *self.layout = Some(TypeLayout::Unit);
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, TraceError> {
+        let mut inner = Layout::Unit;
+        let value = seed.deserialize(Tracer { schema: self.schema, layout: &mut inner })?;
+        *self.layout = Some(TypeLayout::Newtype(inner));
+        Ok(value)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, TraceError> {
+        let mut layouts = Vec::new();
+        let value = visitor.visit_seq(SeqTracer { schema: self.schema, layouts: &mut layouts, remaining: len })?;
+        *self.layout = Some(TypeLayout::Tuple(layouts));
+        Ok(value)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, fields: &'static [&'static str], visitor: V) -> Result<V::Value, TraceError> {
+        let mut layouts = Vec::new();
+        let value = visitor.visit_seq(SeqTracer { schema: self.schema, layouts: &mut layouts, remaining: fields.len() })?;
+        let fields = fields.iter().zip(layouts).map(|(name, layout)| Field::new(name, layout)).collect();
+        *self.layout = Some(TypeLayout::Struct(fields));
+        Ok(value)
+    }
+}
+
+impl de::Error for TraceError {
+    fn custom<T: fmt::Display>(message: T) -> TraceError {
+        TraceError::Custom(message.to_string())
+    }
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceError::NotSupported(method) => write!(f, "the type needs a self-describing format ({})", method),
+            TraceError::Collision(name) => write!(f, "two different types are called {}", name),
+            TraceError::Recursive(name) => write!(f, "{} contains itself", name),
+            TraceError::Unreachable(name) => write!(f, "some variants of {} could not be reached", name),
+            TraceError::Custom(message) => write!(f, "{}", message),
+            TraceError::Invalid(message) => write!(f, "invalid encoding: {}", message)
+        }
+    }
+}
+
+impl std::error::Error for TraceError {}
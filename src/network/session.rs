@@ -0,0 +1,186 @@
+//! Session resume, so that players on flaky connections don't lose their character when their connection drops.
+//!
+//! When a server has a `SessionStore`, every authenticated client is sent an `opcode::SESSION` message
+//! with a resume token, made of the opcode followed by the token's ASCII bytes. If the client's connection
+//! drops, its `ClientEntry` is taken out of the `ClientMap` and suspended for the store's grace period,
+//! along with the messages that were still waiting to be sent to it. If the same account logs in again
+//! before the grace period is over, the client gets its old `ClientID` back, so anything the game keyed
+//! off of it, such as the entity in `ControlledEntities`, is still there. The messages that were waiting are
+//! sent first, and replication starts over with a full snapshot. If the account logs in while its old connection
+//! is still open, which happens when the server hasn't noticed the old connection is dead yet, the new connection
+//! takes over the old one's `ClientID` the same way, and the old connection is closed.
+//!
+//! Clients can log in again with their usual credentials, or with the resume token, since the store is also
+//! an `AuthProvider` for the tokens it issued. Add it to the server's `Authenticator` for that to work.
+//! Each token works once: every login and resume is sent a new one, which replaces the old.
+//! Clients without an account can't be told apart, so they never get a session. Neither do clients that
+//! explicitly close their connection, or that the game removes from the `ClientMap`.
+//!
+//! Once a session expires, its ID is released from `ControlledEntities` at the start of the next tick, and
+//! a `lifecycle::ClientDisconnected` event with `DisconnectReason::SessionExpired` is pushed.
+
+use bytes::{BytesMut, BufMut};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use rand::Rng;
+use crate::network::{ClientEntry, ClientID, Message};
+use crate::network::auth::{Account, AccountID, AuthError, AuthProvider, Credentials};
+use crate::network::opcode;
+use crate::network::queue::OutboundReceiver;
+
+const TOKEN_SIZE: usize = 32;
+
+/// A disconnected client, waiting to be resumed.
+pub(crate) struct SuspendedSession {
+    pub id: ClientID,
+    pub entry: ClientEntry,
+    /// The messages that hadn't been sent to the client yet.
+    pub outbound: OutboundReceiver,
+    suspended_at: Instant,
+}
+
+#[derive(Default)]
+struct Sessions {
+    /// The resume token of every account with a session, and the account it belongs to.
+    tokens: HashMap<String, Account>,
+    issued: HashMap<AccountID, String>,
+    suspended: HashMap<AccountID, SuspendedSession>,
+    /// Sessions whose grace period turned out to be over when their account logged in again, before they
+    /// were dropped. They are left for `expire` to report.
+    expired: Vec<(ClientID, SocketAddr)>,
+}
+
+impl Sessions {
+    fn forget(&mut self, account: AccountID) {
+        if let Some(token) = self.issued.remove(&account) {
+            self.tokens.remove(&token);
+        }
+    }
+}
+
+/// Keeps the sessions of disconnected clients for a grace period. It can be cloned, and every clone
+/// shares the same sessions.
+#[derive(Clone)]
+pub struct SessionStore {
+    grace_period: Duration,
+    sessions: Arc<Mutex<Sessions>>,
+}
+
+impl Default for SessionStore {
+    fn default() -> SessionStore {
+        SessionStore {
+            grace_period: Duration::from_secs(30),
+            sessions: Arc::default()
+        }
+    }
+}
+
+impl SessionStore {
+    pub fn new() -> SessionStore {
+        SessionStore::default()
+    }
+
+    /// Set how long a disconnected client's session is kept.
+    pub fn with_grace_period(mut self, grace_period: Duration) -> SessionStore {
+        self.grace_period = grace_period;
+        self
+    }
+
+    pub fn grace_period(&self) -> Duration {
+        self.grace_period
+    }
+
+    /// The IDs of the clients whose sessions are waiting to be resumed.
+    pub fn suspended(&self) -> Vec<ClientID> {
+        self.sessions.lock().expect("To get a lock on the sessions")
+            .suspended.values().map(|session| session.id).collect()
+    }
+
+    /// Generate a new resume token for an account, replacing the one it had. Tokens are issued every time
+    /// the account logs in or resumes, so a token that leaked stops working once the client uses it.
+    pub(crate) fn issue(&self, account: &Account) -> String {
+        let mut sessions = self.sessions.lock().expect("To get a lock on the sessions");
+        sessions.forget(account.id);
+        let mut bytes = [0u8; TOKEN_SIZE];
+        rand::thread_rng().fill(&mut bytes);
+        let token = hex::encode(bytes);
+        sessions.tokens.insert(token.clone(), account.clone());
+        sessions.issued.insert(account.id, token.clone());
+        token
+    }
+
+    /// Keep a disconnected client's entry until it comes back or the grace period is over.
+    /// Returns false if the client doesn't have an account, in which case nothing is kept.
+    pub(crate) fn suspend(&self, id: ClientID, entry: ClientEntry, outbound: OutboundReceiver, now: Instant) -> bool {
+        let account = match &entry.account {
+            Some(account) => account.id,
+            None => return false
+        };
+        self.sessions.lock().expect("To get a lock on the sessions")
+            .suspended.insert(account, SuspendedSession { id, entry, outbound, suspended_at: now });
+        true
+    }
+
+    /// Take the suspended session of an account that has logged in again, if its grace period isn't over.
+    pub(crate) fn resume(&self, account: &Account, now: Instant) -> Option<SuspendedSession> {
+        let mut sessions = self.sessions.lock().expect("To get a lock on the sessions");
+        match sessions.suspended.remove(&account.id) {
+            Some(session) if now.duration_since(session.suspended_at) < self.grace_period => Some(session),
+            // The session is dropped now, so that `expire` doesn't forget the token the new login is about to get
+            Some(session) => {
+                sessions.forget(account.id);
+                sessions.expired.push((session.id, session.entry.address));
+                None
+            },
+            None => None
+        }
+    }
+
+    /// Drop the sessions whose grace period is over, and return the IDs and last addresses of their clients.
+    pub fn expire(&self, now: Instant) -> Vec<(ClientID, SocketAddr)> {
+        let mut sessions = self.sessions.lock().expect("To get a lock on the sessions");
+        let grace_period = self.grace_period;
+        let expired: Vec<AccountID> = sessions.suspended.iter()
+            .filter(|(_, session)| now.duration_since(session.suspended_at) >= grace_period)
+            .map(|(account, _)| *account)
+            .collect();
+        let mut clients = std::mem::take(&mut sessions.expired);
+        clients.extend(expired.into_iter()
+            .filter_map(|account| {
+                sessions.forget(account);
+                sessions.suspended.remove(&account).map(|session| (session.id, session.entry.address))
+            }));
+        clients
+    }
+}
+
+impl AuthProvider for SessionStore {
+    fn authenticate(&self, credentials: &Credentials) -> Result<Account, AuthError> {
+        match credentials {
+            // Tokens this store didn't issue may belong to another provider
+            Credentials::Token(token) => {
+                self.sessions.lock().expect("To get a lock on the sessions")
+                    .tokens.get(token).cloned().ok_or(AuthError::Unsupported)
+            },
+            _ => Err(AuthError::Unsupported)
+        }
+    }
+}
+
+/// Create the message that gives a client its resume token.
+pub fn token_message(token: &str) -> Message {
+    let mut bytes = BytesMut::with_capacity(1 + token.len());
+    bytes.put_u8(opcode::SESSION);
+    bytes.put_slice(token.as_bytes());
+    Message::new(bytes)
+}
+
+/// Get the resume token out of an `opcode::SESSION` message.
+pub fn decode_token(message: &Message) -> Option<String> {
+    match message.bytes.split_first() {
+        Some((&opcode::SESSION, token)) => String::from_utf8(token.to_vec()).ok(),
+        _ => None
+    }
+}
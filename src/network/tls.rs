@@ -0,0 +1,123 @@
+//! TLS encryption for client connections, since passwords and admin commands are sent over them.
+//!
+//! The server loads its certificate and private key from the PEM files named in a `TlsConfig`. With TLS
+//! enabled, connections are encrypted before the handshake. If the config allows plaintext as well, the
+//! server looks at the first byte a client sends to tell the two apart: TLS connections always start
+//! with a handshake record (0x16), which plaintext connections never do, since they start with `handshake::MAGIC`.
+
+use tokio::prelude::*;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use crate::network::transport::BoxedStream;
+
+/// The first byte of every TLS connection.
+const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    /// The PEM encoded certificate chain.
+    pub certificate: PathBuf,
+    /// The PEM encoded PKCS #8 private key.
+    pub key: PathBuf,
+    /// Also accept clients that don't use TLS.
+    pub allow_plaintext: bool,
+}
+
+impl TlsConfig {
+    pub fn new<P: Into<PathBuf>>(certificate: P, key: P) -> TlsConfig {
+        TlsConfig {
+            certificate: certificate.into(),
+            key: key.into(),
+            allow_plaintext: false
+        }
+    }
+    pub fn with_plaintext(mut self, allow_plaintext: bool) -> TlsConfig {
+        self.allow_plaintext = allow_plaintext;
+        self
+    }
+    /// Read the certificate and key, ready to be given to the server.
+    pub fn load(&self) -> Result<TlsAcceptor, std::io::Error> {
+        let certificate = std::fs::read(&self.certificate)?;
+        let key = std::fs::read(&self.key)?;
+        TlsAcceptor::from_pem(&certificate, &key, self.allow_plaintext)
+    }
+}
+
+fn tls_error(e: native_tls::Error) -> std::io::Error {
+    std::io::Error::other(e)
+}
+
+/// Encrypts connections on the server side.
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    acceptor: tokio_tls::TlsAcceptor,
+    allow_plaintext: bool,
+}
+
+impl TlsAcceptor {
+    pub fn from_pem(certificate: &[u8], key: &[u8], allow_plaintext: bool) -> Result<TlsAcceptor, std::io::Error> {
+        let identity = native_tls::Identity::from_pkcs8(certificate, key).map_err(tls_error)?;
+        let acceptor = native_tls::TlsAcceptor::new(identity).map_err(tls_error)?;
+        Ok(TlsAcceptor {
+            acceptor: acceptor.into(),
+            allow_plaintext
+        })
+    }
+
+    /// Run the server side of the TLS handshake, or let a plaintext connection through if that's allowed.
+    pub async fn accept(&self, mut stream: BoxedStream) -> Result<BoxedStream, std::io::Error> {
+        let mut first = [0u8; 1];
+        stream.read_exact(&mut first).await?;
+        let stream = PrefixedStream { prefix: Some(first[0]), stream };
+        if first[0] == TLS_HANDSHAKE_RECORD {
+            Ok(Box::new(self.acceptor.accept(stream).await.map_err(tls_error)?))
+        } else if self.allow_plaintext {
+            Ok(Box::new(stream))
+        } else {
+            Err(std::io::Error::new(ErrorKind::InvalidData, "The server requires TLS"))
+        }
+    }
+}
+
+/// Run the client side of the TLS handshake. The server's certificate has to be valid for `domain`,
+/// and signed by either a system root certificate or `root_certificate`, if one is given.
+pub async fn connect(stream: BoxedStream, domain: &str, root_certificate: Option<&[u8]>) -> Result<BoxedStream, std::io::Error> {
+    let mut builder = native_tls::TlsConnector::builder();
+    if let Some(root_certificate) = root_certificate {
+        builder.add_root_certificate(native_tls::Certificate::from_pem(root_certificate).map_err(tls_error)?);
+    }
+    let connector = tokio_tls::TlsConnector::from(builder.build().map_err(tls_error)?);
+    Ok(Box::new(connector.connect(domain, stream).await.map_err(tls_error)?))
+}
+
+/// A stream that gives back a byte that was already read from it.
+struct PrefixedStream {
+    prefix: Option<u8>,
+    stream: BoxedStream,
+}
+
+impl AsyncRead for PrefixedStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize, std::io::Error>> {
+        if !buf.is_empty() {
+            if let Some(prefix) = self.prefix.take() {
+                buf[0] = prefix;
+                return Poll::Ready(Ok(1));
+            }
+        }
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for PrefixedStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, std::io::Error>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
\ No newline at end of file
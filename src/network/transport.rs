@@ -0,0 +1,195 @@
+//! Transports are where the server gets its client connections from.
+//!
+//! The server accepts connections from a `Transport` and runs the handshake, authentication and framing
+//! on top of whatever stream it hands back, so it doesn't care where the bytes come from. `TcpTransport`
+//! is the default. `LoopbackTransport` connects clients entirely in memory, which lets tests and
+//! headless setups run simulated clients without opening any sockets.
+
+use tokio::net::TcpListener;
+use tokio::prelude::*;
+use tokio::sync::mpsc::{UnboundedSender, UnboundedReceiver, unbounded_channel};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::task::{Context, Poll, Waker};
+
+/// A connection to a client, in either direction.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T> Stream for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+pub type BoxedStream = Box<dyn Stream>;
+
+/// The future returned by `Transport::accept`.
+pub type Accept<'a> = Pin<Box<dyn Future<Output=Result<(BoxedStream, SocketAddr), std::io::Error>> + Send + 'a>>;
+
+/// A source of client connections.
+pub trait Transport: Send {
+    /// Wait for the next client to connect, and return its stream and address.
+    fn accept(&mut self) -> Accept<'_>;
+}
+
+/// Accepts clients over TCP. The listener is bound the first time a client is accepted.
+pub struct TcpTransport {
+    address: SocketAddr,
+    listener: Option<TcpListener>,
+}
+
+impl TcpTransport {
+    pub fn new(address: SocketAddr) -> TcpTransport {
+        TcpTransport {
+            address,
+            listener: None
+        }
+    }
+}
+
+impl Default for TcpTransport {
+    fn default() -> TcpTransport {
+        TcpTransport::new(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 4343))
+    }
+}
+
+impl Transport for TcpTransport {
+    fn accept(&mut self) -> Accept<'_> {
+        Box::pin(async move {
+            if self.listener.is_none() {
+                self.listener = Some(TcpListener::bind(self.address).await?);
+            }
+            let listener = self.listener.as_mut().unwrap();
+            let (stream, address) = listener.accept().await?;
+            Ok((Box::new(stream) as BoxedStream, address))
+        })
+    }
+}
+
+/// One direction of a loopback connection.
+#[derive(Default)]
+struct Pipe {
+    buffer: VecDeque<u8>,
+    /// Set when either end of the connection is dropped or shut down.
+    closed: bool,
+    reader: Option<Waker>,
+}
+
+impl Pipe {
+    fn close(&mut self) {
+        self.closed = true;
+        if let Some(waker) = self.reader.take() {
+            waker.wake();
+        }
+    }
+}
+
+type SharedPipe = Arc<Mutex<Pipe>>;
+
+/// One end of an in-memory connection. Writes never block, and reads wait until the other end writes
+/// something. Reads return end of file once the other end is dropped and everything it wrote has been read.
+pub struct LoopbackStream {
+    incoming: SharedPipe,
+    outgoing: SharedPipe,
+}
+
+impl LoopbackStream {
+    /// Create both ends of a connection.
+    pub fn pair() -> (LoopbackStream, LoopbackStream) {
+        let a: SharedPipe = Arc::default();
+        let b: SharedPipe = Arc::default();
+        (LoopbackStream { incoming: a.clone(), outgoing: b.clone() },
+         LoopbackStream { incoming: b, outgoing: a })
+    }
+}
+
+impl AsyncRead for LoopbackStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize, std::io::Error>> {
+        let mut pipe = self.incoming.lock().expect("To get a lock on the loopback pipe");
+        if pipe.buffer.is_empty() {
+            if pipe.closed || buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            pipe.reader = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let len = buf.len().min(pipe.buffer.len());
+        for (byte, received) in buf.iter_mut().zip(pipe.buffer.drain(..len)) {
+            *byte = received;
+        }
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for LoopbackStream {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, std::io::Error>> {
+        let mut pipe = self.outgoing.lock().expect("To get a lock on the loopback pipe");
+        if pipe.closed {
+            return Poll::Ready(Err(std::io::Error::new(ErrorKind::BrokenPipe, "The other end of the loopback stream is closed")));
+        }
+        pipe.buffer.extend(buf);
+        if let Some(waker) = pipe.reader.take() {
+            waker.wake();
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        self.outgoing.lock().expect("To get a lock on the loopback pipe").close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for LoopbackStream {
+    fn drop(&mut self) {
+        self.outgoing.lock().expect("To get a lock on the loopback pipe").close();
+        self.incoming.lock().expect("To get a lock on the loopback pipe").close();
+    }
+}
+
+/// Accepts clients that connect in memory through a `LoopbackConnector`.
+pub struct LoopbackTransport {
+    connections: UnboundedReceiver<(LoopbackStream, SocketAddr)>,
+}
+
+/// Connects simulated clients to a `LoopbackTransport`. It can be cloned and used from any thread.
+#[derive(Clone)]
+pub struct LoopbackConnector {
+    connections: UnboundedSender<(LoopbackStream, SocketAddr)>,
+    next_port: Arc<AtomicU16>,
+}
+
+impl LoopbackTransport {
+    /// Create a loopback transport, along with the connector that clients use to reach it.
+    pub fn new() -> (LoopbackTransport, LoopbackConnector) {
+        let (tx, rx) = unbounded_channel();
+        (LoopbackTransport { connections: rx },
+         LoopbackConnector { connections: tx, next_port: Arc::new(AtomicU16::new(1)) })
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn accept(&mut self) -> Accept<'_> {
+        Box::pin(async move {
+            match self.connections.recv().await {
+                Some((stream, address)) => Ok((Box::new(stream) as BoxedStream, address)),
+                None => Err(std::io::Error::new(ErrorKind::NotConnected, "Every loopback connector has been dropped"))
+            }
+        })
+    }
+}
+
+impl LoopbackConnector {
+    /// Open a connection to the server. Each connection gets its own made up localhost address.
+    pub fn connect(&self) -> Result<LoopbackStream, std::io::Error> {
+        let (client, server) = LoopbackStream::pair();
+        let port = self.next_port.fetch_add(1, Ordering::SeqCst);
+        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+        self.connections.clone().try_send((server, address))
+            .map_err(|_| std::io::Error::new(ErrorKind::ConnectionRefused, "The loopback transport has been dropped"))?;
+        Ok(client)
+    }
+}
\ No newline at end of file
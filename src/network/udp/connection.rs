@@ -0,0 +1,441 @@
+//! The reliability layer of a single UDP connection.
+//!
+//! A `Connection` turns messages into packets and packets back into messages. It doesn't own a socket:
+//! the caller feeds it the packets that arrive with `receive`, and sends whatever `poll` returns.
+//! This keeps it usable from both the server and the client, and makes it easy to test.
+//!
+//! Acknowledgements are per packet. Every packet carries the sequence number of the latest packet
+//! received from the other side, along with a bitfield of the 32 before it. When a packet is acknowledged,
+//! the reliable segments it carried are delivered. Reliable segments that go unacknowledged for too long
+//! are sent again in a later packet.
+
+use bytes::{Bytes, BytesMut};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use crate::network::{Channel, Message};
+use super::packet::{self, DataHeader, PacketKind, Segment, sequence_greater_than};
+
+/// Packets are kept under this size by default, so that they aren't fragmented by the IP layer on typical networks.
+pub const DEFAULT_MTU: usize = 1200;
+
+/// The largest number of fragments a single message can be split into.
+pub const MAX_FRAGMENTS: usize = 255;
+
+#[derive(Clone, Debug)]
+pub struct ConnectionConfig {
+    /// The largest packet that will be sent, in bytes.
+    pub mtu: usize,
+    /// If nothing has been sent for this long, an empty packet is sent to keep the connection alive.
+    pub keepalive_interval: Duration,
+    /// The shortest time to wait for an acknowledgement before sending a reliable segment again.
+    pub min_resend_interval: Duration,
+    /// How many bytes the messages that are still missing fragments can take up. Packets that would start
+    /// another message past this are ignored, and not acknowledged, so their reliable segments come again later.
+    pub max_pending_bytes: usize,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> ConnectionConfig {
+        ConnectionConfig {
+            mtu: DEFAULT_MTU,
+            keepalive_interval: Duration::from_millis(250),
+            min_resend_interval: Duration::from_millis(50),
+            max_pending_bytes: 1 << 20
+        }
+    }
+}
+
+/// A segment waiting to be sent, or waiting to be acknowledged.
+struct OutgoingSegment {
+    segment: Segment,
+    last_sent: Option<Instant>,
+}
+
+/// A message being put back together from its fragments.
+struct Assembly {
+    fragments: Vec<Option<Bytes>>,
+    remaining: usize,
+    /// The most bytes the message can take up, which counts towards `ConnectionConfig::max_pending_bytes`.
+    reserved: usize,
+}
+
+/// A packet that was sent, and the reliable segments it carried.
+struct SentPacket {
+    sent_at: Instant,
+    segments: Vec<(Channel, u16, u8)>,
+}
+
+#[derive(Default)]
+struct ChannelSender {
+    next_sequence: u16,
+    outgoing: Vec<OutgoingSegment>,
+}
+
+#[derive(Default)]
+struct ChannelReceiver {
+    /// For ordered channels, the next message to deliver. For unordered channels, every message
+    /// before this one has been delivered. For sequenced channels, the last message delivered.
+    next: u16,
+    /// Messages after `next` that have been delivered, for unordered channels.
+    delivered: HashSet<u16>,
+    /// Messages that are complete, but can't be delivered yet, for ordered channels.
+    complete: HashMap<u16, Bytes>,
+    assemblies: HashMap<u16, Assembly>,
+    /// The bytes reserved by `assemblies`.
+    reserved: usize,
+    received_any: bool,
+}
+
+pub struct Connection {
+    config: ConnectionConfig,
+    local_sequence: u16,
+    remote_sequence: u16,
+    ack_bits: u32,
+    received_any: bool,
+    needs_ack: bool,
+    sent_packets: HashMap<u16, SentPacket>,
+    senders: HashMap<Channel, ChannelSender>,
+    receivers: HashMap<Channel, ChannelReceiver>,
+    rtt: Option<Duration>,
+    last_sent: Option<Instant>,
+    last_received: Instant,
+}
+
+impl Assembly {
+    fn new(fragment_count: u8, fragment_size: usize) -> Assembly {
+        Assembly {
+            fragments: vec![None; fragment_count as usize],
+            remaining: fragment_count as usize,
+            reserved: fragment_count as usize * fragment_size
+        }
+    }
+
+    /// Add a fragment, returning the whole message once every fragment has arrived.
+    fn insert(&mut self, segment: &Segment) -> Option<Bytes> {
+        if segment.fragment_count as usize != self.fragments.len() {
+            return None;
+        }
+        let slot = &mut self.fragments[segment.fragment as usize];
+        if slot.is_none() {
+            *slot = Some(segment.payload.clone());
+            self.remaining -= 1;
+        }
+        if self.remaining > 0 {
+            return None;
+        }
+        let mut bytes = BytesMut::with_capacity(self.fragments.iter().flatten().map(|f| f.len()).sum());
+        for fragment in self.fragments.iter().flatten() {
+            bytes.extend_from_slice(fragment);
+        }
+        Some(bytes.freeze())
+    }
+}
+
+impl ChannelReceiver {
+    /// How far ahead of the next expected message a segment can be before it's dropped.
+    const WINDOW: u16 = 1024;
+    /// How far behind the latest sequenced message an unfinished one can be before it's given up on.
+    const SEQUENCED_WINDOW: u16 = 32;
+
+    fn receive(&mut self, channel: Channel, segment: &Segment, fragment_size: usize, delivered: &mut Vec<Message>) {
+        let sequence = segment.sequence;
+        match channel {
+            Channel::ReliableOrdered => {
+                if sequence_greater_than(self.next, sequence) || sequence.wrapping_sub(self.next) >= Self::WINDOW {
+                    return;
+                }
+                if let Some(bytes) = self.assemble(segment, fragment_size) {
+                    self.complete.insert(sequence, bytes);
+                }
+                while let Some(bytes) = self.complete.remove(&self.next) {
+                    delivered.push(Message::on(channel, bytes));
+                    self.next = self.next.wrapping_add(1);
+                }
+            },
+            Channel::ReliableUnordered => {
+                if sequence_greater_than(self.next, sequence) || sequence.wrapping_sub(self.next) >= Self::WINDOW
+                    || self.delivered.contains(&sequence) {
+                    return;
+                }
+                if let Some(bytes) = self.assemble(segment, fragment_size) {
+                    delivered.push(Message::on(channel, bytes));
+                    self.delivered.insert(sequence);
+                    while self.delivered.remove(&self.next) {
+                        self.next = self.next.wrapping_add(1);
+                    }
+                }
+            },
+            Channel::UnreliableSequenced => {
+                if self.received_any && !sequence_greater_than(sequence, self.next) {
+                    return;
+                }
+                // The fragments they're missing were lost, and won't be sent again
+                self.drop_assemblies(|pending| sequence_greater_than(sequence, pending)
+                    && sequence.wrapping_sub(pending) >= Self::SEQUENCED_WINDOW);
+                if let Some(bytes) = self.assemble(segment, fragment_size) {
+                    delivered.push(Message::on(channel, bytes));
+                    self.next = sequence;
+                    self.received_any = true;
+                    // Older messages can never be delivered now
+                    self.drop_assemblies(|pending| !sequence_greater_than(pending, sequence));
+                }
+            }
+        }
+    }
+
+    fn assemble(&mut self, segment: &Segment, fragment_size: usize) -> Option<Bytes> {
+        // Messages that fit in one segment share its payload
+        if segment.fragment_count == 1 {
+            return Some(segment.payload.clone());
+        }
+        if !self.assemblies.contains_key(&segment.sequence) {
+            let assembly = Assembly::new(segment.fragment_count, fragment_size);
+            self.reserved += assembly.reserved;
+            self.assemblies.insert(segment.sequence, assembly);
+        }
+        let bytes = self.assemblies.get_mut(&segment.sequence).unwrap().insert(segment)?;
+        self.drop_assemblies(|pending| pending == segment.sequence);
+        Some(bytes)
+    }
+
+    /// Whether the segment would start putting a new message together.
+    fn starts_assembly(&self, segment: &Segment) -> bool {
+        segment.fragment_count > 1 && !self.assemblies.contains_key(&segment.sequence)
+    }
+
+    fn drop_assemblies<F: Fn(u16) -> bool>(&mut self, dropped: F) {
+        let reserved = &mut self.reserved;
+        self.assemblies.retain(|pending, assembly| {
+            if dropped(*pending) {
+                *reserved -= assembly.reserved;
+                return false;
+            }
+            true
+        });
+    }
+}
+
+impl Connection {
+    pub fn new(config: ConnectionConfig, now: Instant) -> Connection {
+        let channels = [Channel::ReliableOrdered, Channel::ReliableUnordered, Channel::UnreliableSequenced];
+        Connection {
+            config,
+            local_sequence: 0,
+            remote_sequence: 0,
+            ack_bits: 0,
+            received_any: false,
+            needs_ack: false,
+            sent_packets: HashMap::new(),
+            senders: channels.iter().map(|channel| (*channel, ChannelSender::default())).collect(),
+            receivers: channels.iter().map(|channel| (*channel, ChannelReceiver::default())).collect(),
+            rtt: None,
+            last_sent: None,
+            last_received: now
+        }
+    }
+
+    /// The largest payload that fits in a single segment, leaving room for the salt clients send.
+    fn max_fragment_size(&self) -> usize {
+        self.config.mtu - packet::PREFIX_SIZE - packet::SALT_SIZE - packet::DATA_HEADER_SIZE - packet::SEGMENT_HEADER_SIZE
+    }
+
+    /// The largest message that can be sent.
+    pub fn max_message_size(&self) -> usize {
+        self.max_fragment_size() * MAX_FRAGMENTS
+    }
+
+    /// The smoothed round trip time, once a packet has been acknowledged.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// When the last packet from the other side arrived.
+    pub fn last_received(&self) -> Instant {
+        self.last_received
+    }
+
+    /// The number of reliable segments that are waiting to be acknowledged.
+    pub fn unacknowledged(&self) -> usize {
+        self.senders.values().map(|sender| sender.outgoing.len()).sum()
+    }
+
+    /// Queue a message to be sent on its channel. Messages are split into fragments if they don't fit into one packet.
+    pub fn send(&mut self, message: Message) -> Result<(), std::io::Error> {
+        if message.bytes.len() > self.max_message_size() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                                           format!("Message of {} bytes is too large to send over UDP", message.bytes.len())));
+        }
+        let fragment_size = self.max_fragment_size();
+        let sender = self.senders.get_mut(&message.channel).unwrap();
+        let sequence = sender.next_sequence;
+        sender.next_sequence = sender.next_sequence.wrapping_add(1);
+
+        let bytes = message.bytes;
+        let fragment_count = bytes.len().div_ceil(fragment_size).max(1);
+        for fragment in 0..fragment_count {
+            let start = fragment * fragment_size;
+            let end = (start + fragment_size).min(bytes.len());
+            sender.outgoing.push(OutgoingSegment {
+                segment: Segment {
+                    channel: message.channel,
+                    sequence,
+                    fragment: fragment as u8,
+                    fragment_count: fragment_count as u8,
+                    payload: bytes.slice(start, end)
+                },
+                last_sent: None
+            });
+        }
+        Ok(())
+    }
+
+    /// Handle the contents of a `Data` packet, returning the messages that are ready to be delivered.
+    /// Malformed packets are ignored.
+    pub fn receive(&mut self, contents: &[u8], now: Instant) -> Vec<Message> {
+        let mut delivered = Vec::new();
+        let (header, segments) = match packet::decode_data(contents) {
+            Some(data) => data,
+            None => return delivered
+        };
+        self.last_received = now;
+        let fragment_size = self.max_fragment_size();
+        let reserved: usize = self.receivers.values().map(|receiver| receiver.reserved).sum();
+        let starting: usize = segments.iter()
+            .filter(|segment| self.receivers[&segment.channel].starts_assembly(segment))
+            .map(|segment| segment.fragment_count as usize * fragment_size)
+            .sum();
+        // Messages that are already being put back together can always be finished, so there's no deadlock
+        if reserved > 0 && reserved + starting > self.config.max_pending_bytes {
+            return delivered;
+        }
+        self.needs_ack = true;
+        self.record_received(header.sequence);
+        // Until the other side has received something, there is nothing for it to acknowledge
+        if let Some(ack) = header.ack {
+            self.process_acks(ack, header.ack_bits, now);
+        }
+
+        for segment in &segments {
+            let receiver = self.receivers.get_mut(&segment.channel).unwrap();
+            receiver.receive(segment.channel, segment, fragment_size, &mut delivered);
+        }
+        delivered
+    }
+
+    fn record_received(&mut self, sequence: u16) {
+        if !self.received_any {
+            self.received_any = true;
+            self.remote_sequence = sequence;
+            self.ack_bits = 0;
+        } else if sequence_greater_than(sequence, self.remote_sequence) {
+            let shift = u32::from(sequence.wrapping_sub(self.remote_sequence));
+            self.ack_bits = if shift > 32 {
+                0
+            } else {
+                // The old latest packet becomes bit `shift - 1`
+                self.ack_bits.checked_shl(shift).unwrap_or(0) | (1 << (shift - 1))
+            };
+            self.remote_sequence = sequence;
+        } else {
+            let distance = u32::from(self.remote_sequence.wrapping_sub(sequence));
+            if (1..=32).contains(&distance) {
+                self.ack_bits |= 1 << (distance - 1);
+            }
+        }
+    }
+
+    fn process_acks(&mut self, ack: u16, ack_bits: u32, now: Instant) {
+        let acked = std::iter::once(ack).chain(
+            (0..32).filter(|bit| ack_bits & (1 << bit) != 0)
+                .map(|bit| ack.wrapping_sub(bit as u16 + 1)));
+        for sequence in acked {
+            let packet = match self.sent_packets.remove(&sequence) {
+                Some(packet) => packet,
+                None => continue
+            };
+            if sequence == ack {
+                let sample = now.duration_since(packet.sent_at);
+                self.rtt = Some(match self.rtt {
+                    Some(rtt) => rtt.mul_f32(0.9) + sample.mul_f32(0.1),
+                    None => sample
+                });
+            }
+            for (channel, sequence, fragment) in packet.segments {
+                self.senders.get_mut(&channel).unwrap().outgoing.retain(|outgoing| {
+                    outgoing.segment.sequence != sequence || outgoing.segment.fragment != fragment
+                });
+            }
+        }
+    }
+
+    fn resend_interval(&self) -> Duration {
+        self.rtt.map(|rtt| rtt * 2).unwrap_or(Duration::from_millis(200))
+            .max(self.config.min_resend_interval)
+    }
+
+    /// Build the packets that should be sent right now. This includes new segments, reliable segments
+    /// that are due to be sent again, and an empty packet if the other side is owed an acknowledgement.
+    pub fn poll(&mut self, now: Instant) -> Vec<BytesMut> {
+        let resend_interval = self.resend_interval();
+        let mut due = Vec::new();
+        for (channel, sender) in self.senders.iter_mut() {
+            match channel {
+                Channel::UnreliableSequenced => {
+                    due.extend(sender.outgoing.drain(..).map(|outgoing| outgoing.segment));
+                },
+                _ => {
+                    for outgoing in sender.outgoing.iter_mut() {
+                        let is_due = outgoing.last_sent
+                            .map(|last_sent| now.duration_since(last_sent) >= resend_interval)
+                            .unwrap_or(true);
+                        if is_due {
+                            outgoing.last_sent = Some(now);
+                            due.push(outgoing.segment.clone());
+                        }
+                    }
+                }
+            }
+        }
+
+        let keepalive_due = self.last_sent
+            .map(|last_sent| now.duration_since(last_sent) >= self.config.keepalive_interval)
+            .unwrap_or(true);
+        if due.is_empty() && !self.needs_ack && !keepalive_due {
+            return Vec::new();
+        }
+
+        let mut packets = Vec::new();
+        let mut segments = due.into_iter().peekable();
+        loop {
+            let mut bytes = packet::begin_packet(PacketKind::Data, self.config.mtu);
+            let sequence = self.local_sequence;
+            self.local_sequence = self.local_sequence.wrapping_add(1);
+            let ack = if self.received_any { Some(self.remote_sequence) } else { None };
+            DataHeader { sequence, ack, ack_bits: self.ack_bits }.encode(&mut bytes);
+
+            let mut reliable = Vec::new();
+            while let Some(segment) = segments.peek() {
+                if bytes.len() + segment.encoded_size() + packet::SALT_SIZE > self.config.mtu {
+                    break;
+                }
+                let segment = segments.next().unwrap();
+                if segment.channel != Channel::UnreliableSequenced {
+                    reliable.push((segment.channel, segment.sequence, segment.fragment));
+                }
+                segment.encode(&mut bytes);
+            }
+            self.sent_packets.insert(sequence, SentPacket { sent_at: now, segments: reliable });
+            packets.push(bytes);
+            if segments.peek().is_none() {
+                break;
+            }
+        }
+
+        // Packets more than 32 behind can never be acknowledged, so their segments will be resent instead
+        let local_sequence = self.local_sequence;
+        self.sent_packets.retain(|sequence, _| local_sequence.wrapping_sub(*sequence) <= 33);
+        self.needs_ack = false;
+        self.last_sent = Some(now);
+        packets
+    }
+}
\ No newline at end of file
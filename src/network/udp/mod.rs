@@ -0,0 +1,509 @@
+//! A UDP transport, for messages that shouldn't suffer from TCP's head-of-line blocking.
+//!
+//! UDP has no connections, so they are emulated. A client connects by sending a `Connect` packet
+//! containing its `ClientHello`, followed by its credentials if the server requires authentication.
+//! Since the source address of a datagram is easy to forge, the server first answers with a `Challenge` packet
+//! holding a salt, which the client puts in front of its next `Connect` and of every `Data` and `Disconnect`
+//! packet after it. Only a client that can receive at its address learns the salt, so nobody else can connect
+//! in its name or end its connection. Salts are good for `CHALLENGE_LIFETIME` to twice that, and are derived
+//! from the address with a secret key, so the server keeps nothing for clients that haven't answered them.
+//! The first `Connect` can start with any 8 bytes in place of the salt, which keeps it bigger than the
+//! `Challenge`, so the server can't be used to amplify an attack on a forged address.
+//!
+//! The server then answers with an `Accept` packet containing the `ServerHello` (and the 8 byte account ID,
+//! if the client authenticated), or a `Reject` packet. A `Reject` packet starts with a byte saying
+//! which stage failed: 0 for the handshake, followed by the `ServerHello`, or 1 for authentication,
+//! followed by the `AuthError` code. Clients should keep sending `Connect` until they get an answer.
+//!
+//! From then on, `Data` packets carry messages on the three `Channel`s. The connection is closed when
+//! either side sends `Disconnect`, or when nothing has been heard from the client for `UdpConfig::timeout`.
+//! Only clients that time out can resume their session, since a `Disconnect` means the client is done.
+//! Connected clients are put in the `ClientMap` just like TCP clients, so the rest of the engine doesn't
+//! need to know which transport they use.
+//!
+//! New clients connect on a task of their own, so the handshake and authentication don't hold up the packets of the
+//! clients that are already connected. At most `UdpConfig::max_pending_connects` clients connect at once, and
+//! the `Connect` packets of any others are dropped until there's room, like lost ones.
+//!
+//! With a `NetworkSimulator`, the `Data` packets of connected clients go through simulated links. The links
+//! are only checked every `UdpConfig::send_interval`, so that's as precise as their timing gets.
+
+pub mod packet;
+pub mod connection;
+
+use tokio::net::UdpSocket;
+use tokio::net::udp::split::{UdpSocketRecvHalf, UdpSocketSendHalf};
+use tokio::sync::mpsc::{Receiver, Sender, UnboundedSender, UnboundedReceiver, channel, unbounded_channel};
+use tokio::timer::Interval;
+use bytes::{BytesMut, BufMut};
+use futures::future;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use crate::network::ClientID;
+use crate::network::manager::ConnectionManager;
+use crate::network::handshake::{Capabilities, ClientHello, HandshakeConfig, RejectReason, ServerHello};
+use crate::network::compression::{self, CompressionConfig};
+use crate::network::ratelimit::RateLimitConfig;
+use crate::network::queue::{OutboundReceiver, QueueConfig};
+use crate::network::inbound::InboundSender;
+use crate::network::session::SessionStore;
+use crate::network::lifecycle::{ClientEvents, DisconnectReason};
+use crate::network::conditions::{Direction, Link, NetworkSimulator};
+use crate::network::auth::{self, AuthError, Authenticator, Credentials};
+use self::connection::{Connection, ConnectionConfig};
+use self::packet::PacketKind;
+
+#[derive(Clone, Debug)]
+pub struct UdpConfig {
+    pub address: SocketAddr,
+    pub connection: ConnectionConfig,
+    /// Clients that haven't sent anything for this long are disconnected.
+    pub timeout: Duration,
+    /// How often outgoing messages are packed and sent.
+    pub send_interval: Duration,
+    /// How many clients can be going through the handshake and authentication at once.
+    pub max_pending_connects: usize,
+    /// Once this many segments are waiting to be acknowledged by a client, its messages are left in its queue,
+    /// so that a client that stops acknowledging them runs into the queue's overflow policy.
+    pub max_unacknowledged: usize,
+}
+
+impl UdpConfig {
+    pub fn new(address: SocketAddr) -> UdpConfig {
+        UdpConfig {
+            address,
+            connection: ConnectionConfig::default(),
+            timeout: Duration::from_secs(10),
+            send_interval: Duration::from_millis(10),
+            max_pending_connects: 64,
+            max_unacknowledged: 1024
+        }
+    }
+}
+
+/// How long a salt from a `Challenge` can be used for, at least.
+pub const CHALLENGE_LIFETIME: Duration = Duration::from_secs(30);
+
+/// Makes the salts for `Challenge` packets.
+struct Challenges {
+    keys: RandomState,
+    started: Instant,
+}
+
+impl Challenges {
+    fn new() -> Challenges {
+        Challenges {
+            keys: RandomState::new(),
+            started: Instant::now()
+        }
+    }
+
+    fn salt(&self, address: SocketAddr, epoch: u64) -> u64 {
+        let mut hasher = self.keys.build_hasher();
+        address.hash(&mut hasher);
+        epoch.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn epoch(&self, now: Instant) -> u64 {
+        now.duration_since(self.started).as_secs() / CHALLENGE_LIFETIME.as_secs()
+    }
+
+    /// The `Challenge` packet for a client at the address.
+    fn issue(&self, address: SocketAddr, now: Instant) -> BytesMut {
+        let mut challenge = packet::begin_packet(PacketKind::Challenge, packet::PREFIX_SIZE + packet::SALT_SIZE);
+        challenge.put_u64_be(self.salt(address, self.epoch(now)));
+        challenge
+    }
+
+    /// Whether the salt was recently sent to the address.
+    fn check(&self, address: SocketAddr, salt: u64, now: Instant) -> bool {
+        let epoch = self.epoch(now);
+        salt == self.salt(address, epoch) || (epoch > 0 && salt == self.salt(address, epoch - 1))
+    }
+}
+
+/// A connected UDP client.
+struct Peer {
+    id: ClientID,
+    /// The salt the client was challenged with, which its packets must carry.
+    salt: u64,
+    connection: Connection,
+    /// Messages received from the client, on their way to the `ClientMap`.
+    inbound: InboundSender,
+    /// Messages from the `ClientMap`, on their way to the client.
+    outbound: OutboundReceiver,
+    /// The answer to the client's `Connect`, in case it has to be sent again.
+    accept: BytesMut,
+    /// Set if the client negotiated compression.
+    compression: Option<CompressionConfig>,
+    /// Set if the network is simulated.
+    links: Option<PeerLinks>,
+}
+
+/// The simulated links of a peer, which delay its `Data` packets.
+struct PeerLinks {
+    inbound: Link<BytesMut>,
+    outbound: Link<BytesMut>,
+}
+
+impl Peer {
+    /// Pass the messages in a `Data` packet on to the `ClientMap`.
+    /// Returns true if the client went over a rate limit that kicks it.
+    fn receive(&mut self, contents: &[u8], now: Instant) -> bool {
+        for message in self.connection.receive(contents, now) {
+            match compression::decode(message, self.compression.is_some()) {
+                Ok(message) => if let Err(e) = self.inbound.try_send(message) {
+                    println!("Disconnecting client with ID {}: {}", self.id, e);
+                    return true;
+                },
+                Err(e) => println!("Dropping message from client with ID {}: {}", self.id, e)
+            }
+        }
+        false
+    }
+}
+
+type Peers = Arc<Mutex<HashMap<SocketAddr, Peer>>>;
+
+/// Everything the UDP listener needs from the server.
+pub struct UdpListener {
+    pub config: UdpConfig,
+    pub handshake_config: HandshakeConfig,
+    pub authenticator: Option<Arc<Authenticator>>,
+    pub compression: Option<CompressionConfig>,
+    pub rate_limit: Option<RateLimitConfig>,
+    pub queues: QueueConfig,
+    pub sessions: Option<SessionStore>,
+    pub events: ClientEvents,
+    pub simulator: Option<NetworkSimulator>,
+    pub connections: ConnectionManager,
+}
+
+impl UdpListener {
+    pub async fn serve(self) -> Result<(), std::io::Error> {
+        let socket = UdpSocket::bind(self.config.address).await?;
+        let (recv_half, send_half) = socket.split();
+        let peers: Peers = Arc::new(Mutex::new(HashMap::new()));
+        // Answers to `Connect` packets are sent by the sending task, since it owns the send half
+        let (reply_tx, reply_rx) = unbounded_channel();
+        let (connect_tx, connect_rx) = channel(self.config.max_pending_connects.max(1));
+        let challenges = Challenges::new();
+
+        // If any of the tasks stops, the listener does
+        future::try_join3(
+            self.receive(recv_half, peers.clone(), &challenges, connect_tx, reply_tx.clone()),
+            self.accept(connect_rx, peers.clone(), reply_tx),
+            self.send(send_half, peers, reply_rx)
+        ).await?;
+        Ok(())
+    }
+
+    async fn receive(&self,
+                     mut socket: UdpSocketRecvHalf,
+                     peers: Peers,
+                     challenges: &Challenges,
+                     mut connect_tx: Sender<(SocketAddr, u64, Vec<u8>)>,
+                     mut reply_tx: UnboundedSender<(SocketAddr, BytesMut)>) -> Result<(), std::io::Error>
+    {
+        let mut buffer = vec![0u8; 65536];
+        loop {
+            let (len, address) = socket.recv_from(&mut buffer).await?;
+            let (kind, contents) = match packet::parse_packet(&buffer[..len]) {
+                Some(packet) => packet,
+                None => continue
+            };
+            // Everything the server is sent starts with the salt
+            let (salt, contents) = match packet::split_salt(contents) {
+                Some(packet) => packet,
+                None => continue
+            };
+            let now = Instant::now();
+            let mut peers = peers.lock().expect("To get a lock on the UDP peers");
+            let salted = peers.get(&address).map(|peer| peer.salt == salt).unwrap_or(false);
+            if kind == PacketKind::Connect {
+                if salted || challenges.check(address, salt, now) {
+                    // If too many clients are connecting, this is dropped, and the client sends it again later
+                    let _ = connect_tx.try_send((address, salt, contents.to_vec()));
+                } else {
+                    let _ = reply_tx.try_send((address, challenges.issue(address, now)));
+                }
+                continue;
+            }
+            // Anything else from the address is forged, or from before the client last connected
+            if !salted {
+                continue;
+            }
+            match kind {
+                PacketKind::Data => {
+                    let kicked = match peers.get_mut(&address) {
+                        Some(peer) => match &mut peer.links {
+                            // Simulated packets are handled by the sending task once they are due
+                            Some(links) => {
+                                links.inbound.push(BytesMut::from(contents), contents.len(), now);
+                                false
+                            },
+                            None => peer.receive(contents, now)
+                        },
+                        None => false
+                    };
+                    if kicked {
+                        if let Some(peer) = peers.remove(&address) {
+                            let _ = reply_tx.try_send((address, packet::begin_packet(PacketKind::Disconnect, 0)));
+                            self.remove_client(address, peer, DisconnectReason::Kicked);
+                        }
+                    }
+                },
+                PacketKind::Disconnect => {
+                    if let Some(peer) = peers.remove(&address) {
+                        self.remove_client(address, peer, DisconnectReason::Closed);
+                    }
+                },
+                // Connections were handled above, and only clients are sent the rest
+                PacketKind::Connect | PacketKind::Challenge | PacketKind::Accept | PacketKind::Reject => {}
+            }
+        }
+    }
+
+    /// Connect the clients that send `Connect` packets, all at the same time. A client that is still connecting doesn't
+    /// start over when it sends `Connect` again, and a client that has connected gets its `Accept` packet again.
+    async fn accept(&self,
+                    mut connect_rx: Receiver<(SocketAddr, u64, Vec<u8>)>,
+                    peers: Peers,
+                    mut reply_tx: UnboundedSender<(SocketAddr, BytesMut)>) -> Result<(), std::io::Error>
+    {
+        let mut connecting = FuturesUnordered::new();
+        let mut pending = HashSet::new();
+        loop {
+            // An empty set of connections is already finished, so it's only waited on while it has some
+            let next = if connecting.is_empty() {
+                future::Either::Left(connect_rx.recv().await)
+            } else {
+                match future::select(Box::pin(connect_rx.recv()), connecting.next()).await {
+                    future::Either::Left((request, _)) => future::Either::Left(request),
+                    future::Either::Right((connected, _)) => future::Either::Right(connected)
+                }
+            };
+            match next {
+                future::Either::Left(Some((address, salt, contents))) => {
+                    let accepted = peers.lock().expect("To get a lock on the UDP peers")
+                        .get(&address).map(|peer| peer.accept.clone());
+                    match accepted {
+                        // The client didn't get our answer, so send it again
+                        Some(accept) => {
+                            let _ = reply_tx.try_send((address, accept));
+                        },
+                        None => if pending.len() < self.config.max_pending_connects && pending.insert(address) {
+                            connecting.push(async move {
+                                let connected = self.connect(address, salt, &contents, Instant::now()).await;
+                                (address, connected)
+                            });
+                        }
+                    }
+                },
+                // The receiving task has stopped
+                future::Either::Left(None) => return Ok(()),
+                future::Either::Right(Some((address, connected))) => {
+                    pending.remove(&address);
+                    let reply = match connected {
+                        Ok(peer) => {
+                            let accept = peer.accept.clone();
+                            peers.lock().expect("To get a lock on the UDP peers").insert(address, peer);
+                            accept
+                        },
+                        Err(reject) => reject
+                    };
+                    let _ = reply_tx.try_send((address, reply));
+                },
+                future::Either::Right(None) => {}
+            }
+        }
+    }
+
+    /// Run the handshake and authentication for a new client. Returns the rejection packet if either fails.
+    async fn connect(&self, address: SocketAddr, salt: u64, contents: &[u8], now: Instant) -> Result<Peer, BytesMut> {
+        let hello_len = contents.get(10).map(|len| 11 + *len as usize).unwrap_or(0);
+        let hello = ClientHello::decode(contents.get(..hello_len).unwrap_or(contents));
+        let handshake = match hello.and_then(|hello| self.handshake_config.negotiate(&hello)) {
+            Ok(handshake) => handshake,
+            Err(reason) => {
+                println!("Client at {} failed the handshake: {}", address, reason);
+                return Err(reject_handshake(reason));
+            }
+        };
+        let account = match &self.authenticator {
+            Some(authenticator) => {
+                let result = match Credentials::decode(&contents[hello_len..]) {
+                    Some((credentials, _)) => auth::authenticate_in_background(authenticator.clone(), credentials).await,
+                    None => Err(AuthError::Malformed)
+                };
+                match result {
+                    Ok(account) => Some(account),
+                    Err(e) => {
+                        println!("Client at {} failed to authenticate: {}", address, e);
+                        return Err(reject_auth(e));
+                    }
+                }
+            },
+            None => None
+        };
+
+        let mut accept = packet::begin_packet(PacketKind::Accept, 32);
+        accept.extend_from_slice(&ServerHello::Accepted {
+            protocol_version: handshake.protocol_version,
+            capabilities: handshake.capabilities
+        }.encode());
+        if let Some(account) = &account {
+            accept.put_u64_be(account.id);
+        }
+        let compression = self.compression.filter(|_| handshake.capabilities.contains(Capabilities::COMPRESSION));
+        let registered = self.connections.register(address, handshake, account, self.rate_limit.clone(), self.queues,
+                                                   self.sessions.as_ref(), &self.events).await;
+        let (id, inbound, outbound) = match registered {
+            Ok(registered) => registered,
+            Err(e) => {
+                println!("Failed to register client at {}: {}", address, e);
+                return Err(reject_handshake(RejectReason::Other(e.to_string())));
+            }
+        };
+        let links = self.simulator.as_ref().map(|simulator| PeerLinks {
+            inbound: Link::new(simulator.clone(), id, Direction::Inbound, false, now),
+            outbound: Link::new(simulator.clone(), id, Direction::Outbound, false, now)
+        });
+        Ok(Peer {
+            id,
+            salt,
+            connection: Connection::new(self.config.connection.clone(), now),
+            inbound,
+            outbound,
+            accept,
+            compression,
+            links
+        })
+    }
+
+    async fn send(&self,
+                  mut socket: UdpSocketSendHalf,
+                  peers: Peers,
+                  mut reply_rx: UnboundedReceiver<(SocketAddr, BytesMut)>) -> Result<(), std::io::Error>
+    {
+        let mut interval = Interval::new_interval(self.config.send_interval);
+        loop {
+            interval.next().await;
+            for (address, packet) in self.poll_peers(&peers, &mut reply_rx, Instant::now()) {
+                // A packet that can't be sent is as good as lost, which the connections already deal with
+                if let Err(e) = socket.send_to(&packet, &address).await {
+                    println!("Couldn't send a packet to {}: {}", address, e);
+                }
+            }
+        }
+    }
+
+    /// Collect the packets that are due to be sent, and close the connections of the peers that are done.
+    fn poll_peers(&self,
+                  peers: &Peers,
+                  reply_rx: &mut UnboundedReceiver<(SocketAddr, BytesMut)>,
+                  now: Instant) -> Vec<(SocketAddr, BytesMut)>
+    {
+        let mut context = Context::from_waker(futures::task::noop_waker_ref());
+        let mut packets = Vec::new();
+        while let Poll::Ready(Some(reply)) = reply_rx.poll_recv(&mut context) {
+            packets.push(reply);
+        }
+
+        let mut closed = Vec::new();
+        {
+            let mut peers = peers.lock().expect("To get a lock on the UDP peers");
+            for (address, peer) in peers.iter_mut() {
+                let due: Vec<BytesMut> = match &mut peer.links {
+                    Some(links) => std::iter::from_fn(|| links.inbound.pop(now)).collect(),
+                    None => Vec::new()
+                };
+                if due.iter().any(|contents| peer.receive(contents, now)) {
+                    closed.push((*address, DisconnectReason::Kicked));
+                    continue;
+                }
+                if now.duration_since(peer.connection.last_received()) >= self.config.timeout {
+                    println!("Client with ID {} timed out", peer.id);
+                    closed.push((*address, DisconnectReason::TimedOut));
+                    continue;
+                }
+                peer.inbound.state().touch(peer.connection.last_received());
+                peer.inbound.state().set_rtt(peer.connection.rtt());
+                loop {
+                    if peer.connection.unacknowledged() >= self.config.max_unacknowledged {
+                        if peer.outbound.overflowed() {
+                            println!("Client with ID {} fell too far behind", peer.id);
+                            closed.push((*address, DisconnectReason::SlowConsumer));
+                        }
+                        break;
+                    }
+                    match peer.outbound.poll_recv(&mut context) {
+                        Poll::Ready(Some(message)) => {
+                            let message = compression::encode(message, peer.compression.as_ref());
+                            if let Err(e) = message.and_then(|message| peer.connection.send(message)) {
+                                println!("Dropping message to client with ID {}: {}", peer.id, e);
+                            }
+                        },
+                        Poll::Ready(None) if peer.outbound.overflowed() => {
+                            println!("Client with ID {} fell too far behind", peer.id);
+                            closed.push((*address, DisconnectReason::SlowConsumer));
+                            break;
+                        },
+                        // The client was removed from the client map, so disconnect them
+                        Poll::Ready(None) => {
+                            closed.push((*address, DisconnectReason::Removed));
+                            break;
+                        },
+                        Poll::Pending => break
+                    }
+                }
+                let outgoing = peer.connection.poll(now);
+                match &mut peer.links {
+                    Some(links) => {
+                        for packet in outgoing {
+                            let size = packet.len();
+                            links.outbound.push(packet, size, now);
+                        }
+                        packets.extend(std::iter::from_fn(|| links.outbound.pop(now)).map(|packet| (*address, packet)));
+                    },
+                    None => packets.extend(outgoing.into_iter().map(|packet| (*address, packet)))
+                }
+            }
+            for (address, reason) in closed {
+                if let Some(peer) = peers.remove(&address) {
+                    packets.push((address, packet::begin_packet(PacketKind::Disconnect, 0)));
+                    self.remove_client(address, peer, reason);
+                }
+            }
+        }
+        packets
+    }
+
+    /// Remove a peer's client from the client map. Only clients that timed out can resume their session.
+    fn remove_client(&self, address: SocketAddr, peer: Peer, reason: DisconnectReason) {
+        let sessions = self.sessions.as_ref().filter(|_| reason == DisconnectReason::TimedOut);
+        // The peers are locked, so the manager is left to finish this on its own
+        drop(self.connections.unregister(sessions, &self.events, peer.id, address, peer.outbound, reason));
+    }
+}
+
+fn reject_handshake(reason: RejectReason) -> BytesMut {
+    let mut reject = packet::begin_packet(PacketKind::Reject, 32);
+    reject.put_u8(0);
+    reject.extend_from_slice(&ServerHello::Rejected(reason).encode());
+    reject
+}
+
+fn reject_auth(error: AuthError) -> BytesMut {
+    let mut reject = packet::begin_packet(PacketKind::Reject, 5);
+    reject.put_u8(1);
+    reject.put_u8(error.code());
+    reject
+}
\ No newline at end of file
@@ -0,0 +1,199 @@
+//! The layout of UDP packets.
+//!
+//! Every packet starts with a 2 byte protocol ID and a 1 byte `PacketKind`. Integers are big-endian.
+//! The `Connect`, `Data` and `Disconnect` packets a client sends follow that with the 8 byte salt from the
+//! server's `Challenge`, which proves the client can receive packets at the address it sends from.
+//! `Data` packets continue with a header used for acknowledgements:
+//! - 2 bytes: the sequence number of this packet
+//! - 1 byte: flags (`HAS_ACK` once anything has been received from the other side)
+//! - 2 bytes: the sequence number of the latest packet received from the other side, or 0 without `HAS_ACK`
+//! - 4 bytes: a bitfield of the 32 packets received before that one
+//!
+//! After the header come any number of segments, each of which holds a whole message or a fragment of one:
+//! - 1 byte: the `Channel`
+//! - 2 bytes: the sequence number of the message on its channel
+//! - 1 byte: the index of this fragment
+//! - 1 byte: the number of fragments in the message
+//! - 2 bytes: the length of the payload, followed by the payload
+
+use bytes::{Bytes, BytesMut, BufMut, Buf, IntoBuf};
+use crate::network::Channel;
+
+/// Identifies packets that belong to the engine, so that stray datagrams are dropped early.
+pub const PROTOCOL_ID: u16 = 0x5354;
+
+/// The size of the protocol ID and packet kind.
+pub const PREFIX_SIZE: usize = 3;
+/// The size of the salt that clients echo back.
+pub const SALT_SIZE: usize = 8;
+/// The size of the acknowledgement header of a `Data` packet.
+pub const DATA_HEADER_SIZE: usize = 9;
+/// Set in a `Data` packet's flags when it acknowledges anything.
+pub const HAS_ACK: u8 = 1;
+/// The size of a segment, not including its payload.
+pub const SEGMENT_HEADER_SIZE: usize = 7;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacketKind {
+    /// Client to server: the handshake and credentials.
+    Connect,
+    /// Server to client: the salt to send with the next `Connect`, and every packet after it.
+    Challenge,
+    /// Server to client: the handshake was accepted.
+    Accept,
+    /// Server to client: the handshake or authentication failed.
+    Reject,
+    /// Either way: messages and acknowledgements.
+    Data,
+    /// Either way: the connection is being closed.
+    Disconnect,
+}
+
+/// A whole message or a fragment of one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub channel: Channel,
+    pub sequence: u16,
+    pub fragment: u8,
+    pub fragment_count: u8,
+    pub payload: Bytes,
+}
+
+/// The acknowledgement header of a `Data` packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DataHeader {
+    pub sequence: u16,
+    /// The latest packet received from the other side, if any has been.
+    pub ack: Option<u16>,
+    pub ack_bits: u32,
+}
+
+/// Whether sequence number `a` comes after `b`, taking wrapping into account.
+pub fn sequence_greater_than(a: u16, b: u16) -> bool {
+    a != b && a.wrapping_sub(b) < 0x8000
+}
+
+impl PacketKind {
+    fn to_u8(self) -> u8 {
+        match self {
+            PacketKind::Connect => 0,
+            PacketKind::Accept => 1,
+            PacketKind::Reject => 2,
+            PacketKind::Data => 3,
+            PacketKind::Disconnect => 4,
+            PacketKind::Challenge => 5
+        }
+    }
+    fn from_u8(kind: u8) -> Option<PacketKind> {
+        match kind {
+            0 => Some(PacketKind::Connect),
+            1 => Some(PacketKind::Accept),
+            2 => Some(PacketKind::Reject),
+            3 => Some(PacketKind::Data),
+            4 => Some(PacketKind::Disconnect),
+            5 => Some(PacketKind::Challenge),
+            _ => None
+        }
+    }
+}
+
+fn channel_to_u8(channel: Channel) -> u8 {
+    match channel {
+        Channel::ReliableOrdered => 0,
+        Channel::ReliableUnordered => 1,
+        Channel::UnreliableSequenced => 2
+    }
+}
+
+fn channel_from_u8(channel: u8) -> Option<Channel> {
+    match channel {
+        0 => Some(Channel::ReliableOrdered),
+        1 => Some(Channel::ReliableUnordered),
+        2 => Some(Channel::UnreliableSequenced),
+        _ => None
+    }
+}
+
+/// Start a packet of the given kind.
+pub fn begin_packet(kind: PacketKind, capacity: usize) -> BytesMut {
+    let mut bytes = BytesMut::with_capacity(capacity.max(PREFIX_SIZE));
+    bytes.put_u16_be(PROTOCOL_ID);
+    bytes.put_u8(kind.to_u8());
+    bytes
+}
+
+/// Split a packet into its kind and the rest of its contents.
+pub fn parse_packet(bytes: &[u8]) -> Option<(PacketKind, &[u8])> {
+    if bytes.len() < PREFIX_SIZE || u16::from_be_bytes([bytes[0], bytes[1]]) != PROTOCOL_ID {
+        return None;
+    }
+    PacketKind::from_u8(bytes[2]).map(|kind| (kind, &bytes[PREFIX_SIZE..]))
+}
+
+/// Split the salt off the contents of a packet from a client.
+pub fn split_salt(contents: &[u8]) -> Option<(u64, &[u8])> {
+    if contents.len() < SALT_SIZE {
+        return None;
+    }
+    let mut salt = [0u8; SALT_SIZE];
+    salt.copy_from_slice(&contents[..SALT_SIZE]);
+    Some((u64::from_be_bytes(salt), &contents[SALT_SIZE..]))
+}
+
+impl DataHeader {
+    pub fn encode(&self, bytes: &mut BytesMut) {
+        bytes.put_u16_be(self.sequence);
+        bytes.put_u8(if self.ack.is_some() { HAS_ACK } else { 0 });
+        bytes.put_u16_be(self.ack.unwrap_or(0));
+        bytes.put_u32_be(self.ack_bits);
+    }
+}
+
+impl Segment {
+    pub fn encoded_size(&self) -> usize {
+        SEGMENT_HEADER_SIZE + self.payload.len()
+    }
+
+    pub fn encode(&self, bytes: &mut BytesMut) {
+        bytes.put_u8(channel_to_u8(self.channel));
+        bytes.put_u16_be(self.sequence);
+        bytes.put_u8(self.fragment);
+        bytes.put_u8(self.fragment_count);
+        bytes.put_u16_be(self.payload.len() as u16);
+        bytes.put_slice(&self.payload);
+    }
+}
+
+/// Decode the contents of a `Data` packet (everything after the prefix).
+pub fn decode_data(bytes: &[u8]) -> Option<(DataHeader, Vec<Segment>)> {
+    if bytes.len() < DATA_HEADER_SIZE {
+        return None;
+    }
+    let mut buf = bytes.into_buf();
+    let sequence = buf.get_u16_be();
+    let flags = buf.get_u8();
+    let ack = buf.get_u16_be();
+    let header = DataHeader {
+        sequence,
+        ack: if flags & HAS_ACK != 0 { Some(ack) } else { None },
+        ack_bits: buf.get_u32_be()
+    };
+    let mut segments = Vec::new();
+    while buf.remaining() > 0 {
+        if buf.remaining() < SEGMENT_HEADER_SIZE {
+            return None;
+        }
+        let channel = channel_from_u8(buf.get_u8())?;
+        let sequence = buf.get_u16_be();
+        let fragment = buf.get_u8();
+        let fragment_count = buf.get_u8();
+        let len = buf.get_u16_be() as usize;
+        if buf.remaining() < len || fragment >= fragment_count {
+            return None;
+        }
+        let mut payload = vec![0u8; len];
+        buf.copy_to_slice(&mut payload);
+        segments.push(Segment { channel, sequence, fragment, fragment_count, payload: Bytes::from(payload) });
+    }
+    Some((header, segments))
+}
@@ -0,0 +1,591 @@
+//! A WebSocket transport, so that browser clients can connect to a real server.
+//!
+//! After the HTTP upgrade, the client sends its `ClientHello` in a binary frame and the server answers
+//! with the `ServerHello` in a binary frame. If the server requires authentication, the client then sends
+//! its credentials in a binary frame and gets the authentication result back the same way. From then on,
+//! each binary frame is one `Message`. Text frames are not used, and are ignored.
+//! The upgrade, the handshake and authentication have to be done within `HandshakeConfig::timeout`.
+//!
+//! The server pings every client regularly, and drops clients that haven't sent anything (including
+//! the pongs browsers send automatically) within `WebSocketConfig::idle_timeout`. Each ping carries a
+//! `heartbeat` timestamp, so that the pongs give the client's round trip time. Clients that close the
+//! WebSocket are done, and only clients whose connection drops can resume their session.
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::*;
+use tokio::sync::mpsc::{UnboundedSender, UnboundedReceiver};
+use tokio::timer::Interval;
+use bytes::{Bytes, BytesMut, BufMut};
+use futures::future;
+use sha1::{Sha1, Digest};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
+use crate::network::{ClientID, Message};
+use crate::network::manager::ConnectionManager;
+use crate::network::handshake::{Capabilities, ClientHello, Handshake, HandshakeConfig, ServerHello};
+use crate::network::compression::{self, CompressionConfig};
+use crate::network::ratelimit::RateLimitConfig;
+use crate::network::queue::{self, OutboundReceiver, QueueConfig};
+use crate::network::inbound::InboundSender;
+use crate::network::session::SessionStore;
+use crate::network::lifecycle::{ClientEvents, DisconnectReason};
+use crate::network::conditions::{self, Direction, Link, NetworkSimulator};
+use crate::network::heartbeat::{self, PongReceiver, PongSender};
+use crate::network::auth::{self, Account, AuthError, Authenticator, Credentials};
+use crate::network::frame::MAX_FRAME_SIZE;
+
+/// The GUID that the WebSocket protocol appends to the client's key.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_REQUEST_SIZE: usize = 8192;
+/// The largest payload of a control frame (close, ping or pong).
+const MAX_CONTROL_SIZE: usize = 125;
+
+#[derive(Clone, Debug)]
+pub struct WebSocketConfig {
+    pub address: SocketAddr,
+    /// How often clients are pinged.
+    pub ping_interval: Duration,
+    /// Clients that haven't sent anything for this long are disconnected.
+    pub idle_timeout: Duration,
+}
+
+impl WebSocketConfig {
+    pub fn new(address: SocketAddr) -> WebSocketConfig {
+        WebSocketConfig {
+            address,
+            ping_interval: Duration::from_secs(5),
+            idle_timeout: Duration::from_secs(15)
+        }
+    }
+}
+
+/// A WebSocket frame, after any fragments have been put together.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Frame {
+    Binary(Bytes),
+    Text(Bytes),
+    Ping(Bytes),
+    Pong(Bytes),
+    Close,
+}
+
+impl Frame {
+    fn opcode(&self) -> u8 {
+        match self {
+            Frame::Text(_) => 0x1,
+            Frame::Binary(_) => 0x2,
+            Frame::Close => 0x8,
+            Frame::Ping(_) => 0x9,
+            Frame::Pong(_) => 0xA
+        }
+    }
+
+    fn payload(&self) -> &[u8] {
+        match self {
+            Frame::Binary(bytes) | Frame::Text(bytes) | Frame::Ping(bytes) | Frame::Pong(bytes) => bytes,
+            Frame::Close => &[]
+        }
+    }
+
+    /// Encode a frame. Clients must mask their frames, and servers must not.
+    pub fn encode(&self, mask: Option<[u8; 4]>) -> BytesMut {
+        let payload = self.payload();
+        let mut bytes = BytesMut::with_capacity(14 + payload.len());
+        bytes.put_u8(0x80 | self.opcode());
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        if payload.len() < 126 {
+            bytes.put_u8(mask_bit | payload.len() as u8);
+        } else if payload.len() <= u16::MAX as usize {
+            bytes.put_u8(mask_bit | 126);
+            bytes.put_u16_be(payload.len() as u16);
+        } else {
+            bytes.put_u8(mask_bit | 127);
+            bytes.put_u64_be(payload.len() as u64);
+        }
+        match mask {
+            Some(mask) => {
+                bytes.put_slice(&mask);
+                bytes.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+            },
+            None => bytes.put_slice(payload)
+        }
+        bytes
+    }
+}
+
+/// Compute the `Sec-WebSocket-Accept` header for a client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.input(key.trim().as_bytes());
+    hasher.input(WEBSOCKET_GUID.as_bytes());
+    base64::encode(&hasher.result())
+}
+
+fn invalid_data(msg: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Reads what a client sends through a buffer: the HTTP upgrade request, then its frames. A fragmented message
+/// is kept between frames, so that the control frames which arrive in the middle of it don't lose it.
+#[derive(Default)]
+pub struct FrameReader {
+    buffer: BytesMut,
+    message: Option<(u8, BytesMut)>,
+}
+
+impl FrameReader {
+    pub fn new() -> FrameReader {
+        FrameReader::default()
+    }
+
+    /// Read until the buffer holds at least `needed` bytes. Returns false if the stream closed first.
+    async fn fill<R>(&mut self, reader: &mut R, needed: usize) -> Result<bool, std::io::Error>
+    where R: AsyncRead + Unpin {
+        while self.buffer.len() < needed {
+            self.buffer.reserve((needed - self.buffer.len()).max(4096));
+            let read = future::poll_fn(|cx| Pin::new(&mut *reader).poll_read_buf(cx, &mut self.buffer)).await?;
+            if read == 0 {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    async fn fill_frame<R>(&mut self, reader: &mut R, needed: usize) -> Result<(), std::io::Error>
+    where R: AsyncRead + Unpin {
+        if self.fill(reader, needed).await? {
+            Ok(())
+        } else {
+            Err(std::io::Error::new(ErrorKind::UnexpectedEof, "The stream closed in the middle of a WebSocket frame"))
+        }
+    }
+
+    /// Read the HTTP request, up to the blank line that ends it.
+    async fn read_request<R>(&mut self, reader: &mut R) -> Result<BytesMut, std::io::Error>
+    where R: AsyncRead + Unpin {
+        loop {
+            if let Some(end) = self.buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+                return Ok(self.buffer.split_to(end + 4));
+            }
+            if self.buffer.len() >= MAX_REQUEST_SIZE {
+                return Err(invalid_data("WebSocket upgrade request is too large"));
+            }
+            let needed = self.buffer.len() + 1;
+            if !self.fill(reader, needed).await? {
+                return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "The stream closed during the upgrade request"));
+            }
+        }
+    }
+
+    /// Read a single frame, putting together fragmented messages. Client frames must be masked.
+    /// Returns `None` if the stream was closed cleanly between frames.
+    pub async fn read_frame<R>(&mut self, reader: &mut R) -> Result<Option<Frame>, std::io::Error>
+    where R: AsyncRead + Unpin {
+        loop {
+            if !self.fill(reader, 2).await? {
+                if self.buffer.is_empty() && self.message.is_none() {
+                    return Ok(None);
+                }
+                return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "The stream closed in the middle of a WebSocket message"));
+            }
+            let fin = self.buffer[0] & 0x80 != 0;
+            let opcode = self.buffer[0] & 0x0F;
+            if self.buffer[1] & 0x80 == 0 {
+                return Err(invalid_data("Client WebSocket frames must be masked"));
+            }
+            let (len_size, header_size) = match self.buffer[1] & 0x7F {
+                126 => (2, 8),
+                127 => (8, 14),
+                _ => (0, 6)
+            };
+            self.fill_frame(reader, 2 + len_size).await?;
+            let len = match len_size {
+                2 => u64::from(u16::from_be_bytes([self.buffer[2], self.buffer[3]])),
+                8 => {
+                    let mut len = [0u8; 8];
+                    len.copy_from_slice(&self.buffer[2..10]);
+                    let len = u64::from_be_bytes(len);
+                    if len & (1 << 63) != 0 {
+                        return Err(invalid_data("WebSocket frame length has its most significant bit set"));
+                    }
+                    len
+                },
+                _ => u64::from(self.buffer[1] & 0x7F)
+            };
+            let control = opcode & 0x8 != 0;
+            if control && (!fin || len > MAX_CONTROL_SIZE as u64) {
+                return Err(invalid_data("WebSocket control frames must be unfragmented, and at most 125 bytes"));
+            }
+            // Check the length before doing anything with it, since it comes straight from the client
+            let buffered = self.message.as_ref().filter(|_| !control).map(|(_, bytes)| bytes.len()).unwrap_or(0);
+            if len > MAX_FRAME_SIZE.saturating_sub(buffered) as u64 {
+                return Err(invalid_data("WebSocket frame is too large"));
+            }
+            let len = len as usize;
+            self.fill_frame(reader, header_size + len).await?;
+            let mut mask = [0u8; 4];
+            mask.copy_from_slice(&self.buffer[header_size - 4..header_size]);
+            self.buffer.advance(header_size);
+            let mut payload = self.buffer.split_to(len);
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+
+            // Control frames can arrive in the middle of a fragmented message, which carries on after them
+            match opcode {
+                0x8 => return Ok(Some(Frame::Close)),
+                0x9 => return Ok(Some(Frame::Ping(payload.freeze()))),
+                0xA => return Ok(Some(Frame::Pong(payload.freeze()))),
+                0x0 => match self.message.as_mut() {
+                    Some((_, bytes)) => bytes.extend_from_slice(&payload),
+                    None => return Err(invalid_data("WebSocket continuation frame without a message"))
+                },
+                0x1 | 0x2 if self.message.is_none() => self.message = Some((opcode, payload)),
+                _ => return Err(invalid_data("Unexpected WebSocket opcode"))
+            }
+            if fin {
+                let (opcode, bytes) = self.message.take().unwrap();
+                let bytes = bytes.freeze();
+                return Ok(Some(if opcode == 0x1 { Frame::Text(bytes) } else { Frame::Binary(bytes) }));
+            }
+        }
+    }
+}
+
+/// Read the client's HTTP upgrade request, and switch the connection over to WebSockets.
+pub async fn upgrade<S>(stream: &mut S, frames: &mut FrameReader) -> Result<(), std::io::Error>
+where S: AsyncRead + AsyncWrite + Unpin {
+    let request = frames.read_request(stream).await?;
+    let request = String::from_utf8_lossy(&request);
+    let header = |name: &str| request.lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            Some((parts.next()?.trim(), parts.next()?.trim()))
+        })
+        .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.to_string());
+
+    let is_upgrade = header("Upgrade").map(|value| value.eq_ignore_ascii_case("websocket")).unwrap_or(false);
+    match (is_upgrade, header("Sec-WebSocket-Key")) {
+        (true, Some(key)) => {
+            let response = format!("HTTP/1.1 101 Switching Protocols\r\n\
+                                    Upgrade: websocket\r\n\
+                                    Connection: Upgrade\r\n\
+                                    Sec-WebSocket-Accept: {}\r\n\r\n", accept_key(&key));
+            stream.write_all(response.as_bytes()).await
+        },
+        _ => {
+            stream.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n").await?;
+            Err(invalid_data("Not a WebSocket upgrade request"))
+        }
+    }
+}
+
+/// Read frames until a binary one arrives, answering pings along the way. Used during the handshake.
+async fn read_binary<S>(stream: &mut S, frames: &mut FrameReader) -> Result<Bytes, std::io::Error>
+where S: AsyncRead + AsyncWrite + Unpin {
+    loop {
+        match frames.read_frame(stream).await? {
+            Some(Frame::Binary(bytes)) => return Ok(bytes),
+            Some(Frame::Ping(bytes)) => stream.write_all(&Frame::Pong(bytes).encode(None)).await?,
+            Some(Frame::Close) | None => return Err(std::io::Error::new(ErrorKind::ConnectionAborted,
+                                                                        "WebSocket closed during the handshake")),
+            Some(_) => {}
+        }
+    }
+}
+
+/// Where a client's frames come from: straight from the socket, or out of a simulated link.
+enum Incoming<R> {
+    Socket(R, FrameReader),
+    Simulated(UnboundedReceiver<Frame>),
+}
+
+impl<R> Incoming<R> where R: AsyncRead + Unpin {
+    async fn next(&mut self) -> Result<Option<Frame>, std::io::Error> {
+        match self {
+            Incoming::Socket(r_socket, frames) => frames.read_frame(r_socket).await,
+            Incoming::Simulated(link_rx) => Ok(link_rx.recv().await)
+        }
+    }
+}
+
+/// Where the frames for a client go: straight to the socket, or into a simulated link.
+enum Outgoing<W> {
+    Socket(W),
+    Simulated(UnboundedSender<Frame>),
+}
+
+impl<W> Outgoing<W> where W: AsyncWrite + Unpin {
+    async fn send(&mut self, frame: Frame) -> Result<(), std::io::Error> {
+        match self {
+            Outgoing::Socket(w_socket) => w_socket.write_all(&frame.encode(None)).await,
+            Outgoing::Simulated(link_tx) => link_tx.try_send(frame)
+                .map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "The simulated link has closed"))
+        }
+    }
+}
+
+/// Everything the WebSocket listener needs from the server.
+pub struct WebSocketListener {
+    pub config: WebSocketConfig,
+    pub handshake_config: HandshakeConfig,
+    pub authenticator: Option<Arc<Authenticator>>,
+    pub compression: Option<CompressionConfig>,
+    pub rate_limit: Option<RateLimitConfig>,
+    pub queues: QueueConfig,
+    pub sessions: Option<SessionStore>,
+    pub events: ClientEvents,
+    pub simulator: Option<NetworkSimulator>,
+    pub connections: ConnectionManager,
+}
+
+impl WebSocketListener {
+    pub async fn serve(self) -> Result<(), std::io::Error> {
+        let mut listener = TcpListener::bind(self.config.address).await?;
+        let listener_config = Arc::new(self);
+        loop {
+            let (stream, address) = listener.accept().await?;
+            let config = listener_config.clone();
+            tokio::spawn(async move {
+                if let Err(e) = config.process(stream, address).await {
+                    println!("WebSocket client at {} disconnected: {}", address, e);
+                }
+            });
+        }
+    }
+
+    async fn process(&self, mut stream: TcpStream, address: SocketAddr) -> Result<(), std::io::Error> {
+        let mut frames = FrameReader::new();
+        // The client is pinged once it's registered, so until then it has a deadline instead
+        let (handshake, account) = self.admit(&mut stream, &mut frames).timeout(self.handshake_config.timeout).await
+            .map_err(|_| std::io::Error::new(ErrorKind::TimedOut, "Took too long to connect"))??;
+
+        let compression = self.compression.filter(|_| handshake.capabilities.contains(Capabilities::COMPRESSION));
+        let (id, server_tx, mut server_rx) = self.connections.register(address, handshake, account, self.rate_limit.clone(),
+                                                                       self.queues, self.sessions.as_ref(), &self.events).await?;
+        let result = self.run(&mut stream, frames, id, server_tx, &mut server_rx, compression).await;
+        let reason = match &result {
+            Ok(()) => DisconnectReason::Closed,
+            Err(e) => DisconnectReason::from_error(e)
+        };
+        let sessions = self.sessions.as_ref().filter(|_| {
+            result.is_err() && reason != DisconnectReason::Kicked && reason != DisconnectReason::SlowConsumer
+        });
+        let _ = self.connections.unregister(sessions, &self.events, id, address, server_rx, reason).await;
+        result
+    }
+
+    /// Upgrade the connection, then run the handshake and authentication.
+    async fn admit(&self, stream: &mut TcpStream, frames: &mut FrameReader)
+        -> Result<(Handshake, Option<Account>), std::io::Error>
+    {
+        upgrade(stream, frames).await?;
+
+        let hello = ClientHello::decode(&read_binary(stream, frames).await?);
+        let handshake = hello.and_then(|hello| self.handshake_config.negotiate(&hello));
+        let response = match &handshake {
+            Ok(handshake) => ServerHello::Accepted {
+                protocol_version: handshake.protocol_version,
+                capabilities: handshake.capabilities
+            },
+            Err(reason) => ServerHello::Rejected(reason.clone())
+        };
+        stream.write_all(&Frame::Binary(response.encode().freeze()).encode(None)).await?;
+        let handshake = handshake.map_err(|reason| invalid_data(&format!("Failed the handshake: {}", reason)))?;
+
+        let account = match &self.authenticator {
+            Some(authenticator) => {
+                let result = match Credentials::decode(&read_binary(stream, frames).await?) {
+                    Some((credentials, _)) => auth::authenticate_in_background(authenticator.clone(), credentials).await,
+                    None => Err(AuthError::Malformed)
+                };
+                let mut response = BytesMut::with_capacity(9);
+                match &result {
+                    Ok(account) => {
+                        response.put_u8(0);
+                        response.put_u64_be(account.id);
+                    },
+                    Err(e) => response.put_u8(e.code())
+                }
+                stream.write_all(&Frame::Binary(response.freeze()).encode(None)).await?;
+                Some(result.map_err(|e| invalid_data(&format!("Failed to authenticate: {}", e)))?)
+            },
+            None => None
+        };
+        Ok((handshake, account))
+    }
+
+    /// Pass messages back and forth until either side closes the connection.
+    async fn run(&self,
+                 stream: &mut TcpStream,
+                 frames: FrameReader,
+                 id: ClientID,
+                 server_tx: InboundSender,
+                 server_rx: &mut OutboundReceiver,
+                 compression: Option<CompressionConfig>) -> Result<(), std::io::Error>
+    {
+        let start = Instant::now();
+        let frame_size = |frame: &Frame| frame.payload().len();
+        let (pong_tx, pong_rx) = heartbeat::pongs();
+        let (result, closed_by_client) = {
+            let (r_socket, w_socket) = stream.split();
+            // With simulated conditions, the read half reads from the inbound link, which is fed from the socket,
+            // and the write half writes to the outbound link, which feeds the socket
+            let (incoming, outgoing, links) = match &self.simulator {
+                Some(simulator) => {
+                    let (inbound_tx, inbound_rx) = conditions::spawn_link(
+                        Link::new(simulator.clone(), id, Direction::Inbound, true, start), frame_size);
+                    let (outbound_tx, outbound_rx) = conditions::spawn_link(
+                        Link::new(simulator.clone(), id, Direction::Outbound, true, start), frame_size);
+                    (Incoming::Simulated(inbound_rx), Outgoing::Simulated(outbound_tx),
+                     Some(WebSocketListener::simulate(r_socket, frames, inbound_tx, w_socket, outbound_rx)))
+                },
+                None => (Incoming::Socket(r_socket, frames), Outgoing::Socket(w_socket), None)
+            };
+
+            let read = WebSocketListener::read(incoming, server_tx, pong_tx, self.config.idle_timeout, compression.is_some(), start);
+            let write = WebSocketListener::write(outgoing, server_rx, pong_rx, self.config.ping_interval, start, compression);
+            let mut links = links.map(Box::pin);
+            let connection = future::select(Box::pin(read), Box::pin(write));
+            let (result, closed_by_client, drain) = match &mut links {
+                Some(links) => match future::select(connection, links.as_mut()).await {
+                    future::Either::Left((future::Either::Left((result, _)), _)) => {
+                        let closed = result.is_ok();
+                        (result, closed, false)
+                    },
+                    future::Either::Left((future::Either::Right((result, _)), _)) => (result, false, true),
+                    future::Either::Right((result, _)) => (result, false, false)
+                },
+                None => match connection.await {
+                    future::Either::Left((result, _)) => {
+                        let closed = result.is_ok();
+                        (result, closed, false)
+                    },
+                    future::Either::Right((result, _)) => (result, false, false)
+                }
+            };
+            // The write half is done, but what it sent last, such as the Close frame, still has to get through its link
+            if let (true, Some(links)) = (drain, links) {
+                let _ = links.await;
+            }
+            (result, closed_by_client)
+        };
+        // The read half only finishes cleanly when the client closes the WebSocket, which it expects to be answered
+        if closed_by_client {
+            let _ = stream.write_all(&Frame::Close.encode(None)).await;
+        }
+        result
+    }
+
+    async fn read<R>(mut incoming: Incoming<R>,
+                     mut server_tx: InboundSender,
+                     mut pong_tx: PongSender<Bytes>,
+                     idle_timeout: Duration,
+                     compressed: bool,
+                     start: Instant) -> Result<(), std::io::Error>
+    where R: AsyncRead + Unpin {
+        loop {
+            let frame = incoming.next().timeout(idle_timeout).await
+                .map_err(|_| std::io::Error::new(ErrorKind::TimedOut, "WebSocket client went idle"))??;
+            let now = Instant::now();
+            server_tx.state().touch(now);
+            match frame {
+                Some(Frame::Binary(bytes)) => {
+                    server_tx.send(compression::decode(Message::new(bytes), compressed)?).await?;
+                },
+                // The frames that never reach the game still count towards the rate limits, since they cost the server
+                // as much to read, and pings have to be answered as well
+                Some(Frame::Ping(bytes)) => if server_tx.admit(bytes.len())? {
+                    pong_tx.send(bytes);
+                },
+                Some(Frame::Close) => return Ok(()),
+                // The connection dropped without the client closing the WebSocket
+                None => return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "WebSocket connection dropped")),
+                Some(Frame::Pong(bytes)) => if server_tx.admit(bytes.len())? {
+                    let rtt = heartbeat::decode(&Message::new(bytes))
+                        .and_then(|(_, timestamp)| heartbeat::round_trip(timestamp, start, now));
+                    if let Some(rtt) = rtt {
+                        server_tx.state().record_rtt(rtt);
+                    }
+                },
+                Some(Frame::Text(bytes)) => {
+                    server_tx.admit(bytes.len())?;
+                }
+            }
+        }
+    }
+
+    /// Feed the frames from the socket into the inbound link.
+    /// Only finishes early if the socket fails, or once the link is gone.
+    async fn receive<R>(mut r_socket: R, mut frames: FrameReader, mut inbound_tx: UnboundedSender<Frame>) -> Result<(), std::io::Error>
+    where R: AsyncRead + Unpin {
+        while let Some(frame) = frames.read_frame(&mut r_socket).await? {
+            if inbound_tx.try_send(frame).is_err() {
+                return Ok(());
+            }
+        }
+        // The connection dropped, but what's still in the link has to arrive first
+        drop(inbound_tx);
+        future::pending().await
+    }
+
+    /// Move frames between the socket and the simulated links.
+    /// Only finishes early if the socket fails, or once the links are gone.
+    async fn simulate<R, W>(r_socket: R,
+                            frames: FrameReader,
+                            inbound_tx: UnboundedSender<Frame>,
+                            mut w_socket: W,
+                            mut outbound_rx: UnboundedReceiver<Frame>) -> Result<(), std::io::Error>
+    where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+        let transmit = async move {
+            while let Some(frame) = outbound_rx.recv().await {
+                w_socket.write_all(&frame.encode(None)).await?;
+            }
+            Ok::<(), std::io::Error>(())
+        };
+        match future::select(Box::pin(WebSocketListener::receive(r_socket, frames, inbound_tx)), Box::pin(transmit)).await {
+            future::Either::Left((result, _)) | future::Either::Right((result, _)) => result
+        }
+    }
+
+    /// Send the client its messages, the answers to its pings and pings of our own, one frame at a time.
+    /// Messages are only taken out of the client's queue once the last frame has been written, so the queue
+    /// fills up when the client doesn't keep up, and its `OverflowPolicy` applies.
+    async fn write<W>(mut outgoing: Outgoing<W>,
+                      server_rx: &mut OutboundReceiver,
+                      mut pong_rx: PongReceiver<Bytes>,
+                      ping_interval: Duration,
+                      start: Instant,
+                      compression: Option<CompressionConfig>) -> Result<(), std::io::Error>
+    where W: AsyncWrite + Unpin {
+        let mut pings = Interval::new_interval(ping_interval);
+        loop {
+            let heartbeat = future::select(Box::pin(pong_rx.recv()), Box::pin(pings.next()));
+            let frame = match future::select(Box::pin(server_rx.recv()), heartbeat).await {
+                future::Either::Left((Some(message), _)) => match compression::encode(message, compression.as_ref()) {
+                    Ok(message) => Frame::Binary(message.bytes),
+                    Err(e) => {
+                        println!("Dropping message to WebSocket client: {}", e);
+                        continue;
+                    }
+                },
+                // The client was removed from the client map, or fell too far behind
+                future::Either::Left((None, _)) => break,
+                future::Either::Right((future::Either::Left((Some(pong), _)), _)) => Frame::Pong(pong),
+                // The read half is done, so the connection is closing anyway
+                future::Either::Right((future::Either::Left((None, _)), _)) => return Ok(()),
+                future::Either::Right((future::Either::Right(_), _)) => Frame::Ping(heartbeat::ping(start, Instant::now()).bytes)
+            };
+            outgoing.send(frame).await?;
+        }
+        let closed = outgoing.send(Frame::Close).await;
+        if server_rx.overflowed() {
+            return Err(queue::slow_consumer());
+        }
+        closed
+    }
+}
\ No newline at end of file
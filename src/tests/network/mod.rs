@@ -1,43 +1,2022 @@
 //! The tests here involve networking. Since these tests cannot actually create another computer with a client,
 //! it will replicate a client's interactions with the server.
 
+use crate::network::handshake::*;
+use crate::network::auth::*;
+use crate::network::outbox::*;
+use crate::network::replication::*;
+use crate::network::bitpack::{self, BitReader, BitWriter};
+use crate::network::interest::*;
+use crate::network::opcode;
+use crate::network::input::*;
+use crate::network::udp::connection::*;
+use crate::network::udp::packet;
+use crate::network::websocket;
+use crate::network::transport::*;
+use crate::network::tls::{self, TlsAcceptor, TlsConfig};
+use crate::network::compression::{self, CompressionConfig};
+use crate::network::ratelimit::*;
+use crate::network::inbound::*;
+use crate::network::heartbeat::{self, HeartbeatConfig};
+use crate::network::inbox::Inbox;
+use crate::network::session::{self, SessionStore};
+use crate::network::lifecycle::*;
+use crate::network::client::{self, ClientConfig, Mirror};
+use crate::network::conditions::*;
+use crate::network::queue::{self, OutboundReceiver, OverflowPolicy, QueueConfig, SendError};
+use crate::network::manager::{ConnectionManager, Route, RoutingTable};
+use crate::network::frame::FrameReader;
+use crate::network::pool::BufferPool;
+use crate::network::codec::{self, DecodeError, DecodeErrorKind, GameMessage, SerdeCodec};
+use crate::network::schema::{Description, Field, Layout, Schema, TraceError, TypeLayout, Variant};
+use crate::network::rpc::{self, Envelope, Payload, Procedure, ProcedureID, Procedures, RpcCalls, RpcError};
+use crate::ecs::{Game, Updater};
+use crate::ecs::network::sync_clients;
+use crate::ecs::notifier::NotifierQueue;
+use crate::ecs::event::{force_downcast_event_ref, is};
+use specs::{Builder, World};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::network::*;
+use tokio::sync::mpsc::unbounded_channel;
+use bytes::{Bytes, BytesMut};
+use futures::executor::block_on;
+use serde::{Deserialize, Serialize};
+
+/// Create a client entry as if the client had just connected, along with the
+/// receiving end of the messages that would be written to it.
+fn dummy_client() -> (ClientEntry, OutboundReceiver) {
+    let (tx, rx) = queue::outbound(QueueConfig::default());
+    let (_, rx2) = unbounded_channel();
+    let entry = ClientEntry {
+        address: "127.0.0.1:4343".parse().unwrap(),
+        sender: tx,
+        receiver: Arc::new(Mutex::new(rx2)),
+        handshake: Handshake {
+            protocol_version: PROTOCOL_VERSION,
+            client_build: "test-client".to_string(),
+            capabilities: Capabilities::NONE
+        },
+        account: None,
+        inbound: Default::default()
+    };
+    (entry, rx)
+}
+
+fn message(bytes: &[u8]) -> Message {
+    Message::new(BytesMut::from(bytes))
+}
+
+/// Connect a client to a loopback server and run the handshake.
+fn connect_client(connector: &LoopbackConnector) -> LoopbackStream {
+    let mut stream = connector.connect().unwrap();
+    block_on(handshake::connect(&mut stream, &ClientHello::new("test-client 1.0", Capabilities::NONE))).unwrap();
+    stream
+}
+
 #[test]
 fn can_connect_with_dummy_client() {
-
+    let (connections, connector) = loopback_server();
+    let _stream = connect_client(&connector);
+    let id = wait_for_clients(&connections, 1)[0];
+    assert_eq!(connections.routes()[&id].handshake.client_build, "test-client 1.0");
 }
 
 #[test]
 fn can_send_message_and_get_response() {
+    let (connections, connector) = loopback_server();
+    let mut stream = connect_client(&connector);
+    let id = wait_for_clients(&connections, 1)[0];
 
+    block_on(frame::write_frame(&mut stream, &message(b"hello"))).unwrap();
+    let entry = block_on(connections.remove(id)).unwrap().unwrap();
+    assert_eq!(&block_on(entry.receiver.lock().unwrap().recv()).unwrap().bytes[..], b"hello");
+    entry.sender.try_send(message(b"welcome")).unwrap();
+    assert_eq!(&read_frame_after_heartbeats(&mut stream, false).bytes[..], b"welcome");
 }
 
 #[test]
 fn server_handles_invalid_address() {
-
+    // A documentation address, which is never assigned to this machine
+    let mut transport = TcpTransport::new("192.0.2.1:4343".parse().unwrap());
+    let result = tokio::runtime::Runtime::new().unwrap().block_on(async move {
+        transport.accept().await.map(|_| ())
+    });
+    assert!(result.is_err());
 }
 
 #[test]
 fn server_handles_invalid_port() {
-
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut transport = TcpTransport::new(taken.local_addr().unwrap());
+    let result = tokio::runtime::Runtime::new().unwrap().block_on(async move {
+        transport.accept().await.map(|_| ())
+    });
+    assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::AddrInUse);
 }
 
 #[test]
 fn can_connect_with_multiple_clients() {
-
+    let (connections, connector) = loopback_server();
+    let _streams: Vec<LoopbackStream> = (0..3).map(|_| connect_client(&connector)).collect();
+    let mut ids = wait_for_clients(&connections, 3);
+    ids.sort();
+    ids.dedup();
+    assert_eq!(ids.len(), 3);
 }
 
 #[test]
 fn can_disconnect_and_have_updated_client_list() {
+    let (connections, connector) = loopback_server();
+    let first = connect_client(&connector);
+    let before = wait_for_clients(&connections, 1);
+    let _second = connect_client(&connector);
+    wait_for_clients(&connections, 2);
+
+    drop(first);
+    let after = wait_for_clients(&connections, 1);
+    assert_ne!(before, after);
+}
+
+#[test]
+fn handshake_round_trips() {
+    let hello = ClientHello::new("test-client 1.0", Capabilities(0b101));
+    assert_eq!(ClientHello::decode(&hello.encode()), Ok(hello));
+
+    let accepted = ServerHello::Accepted { protocol_version: PROTOCOL_VERSION, capabilities: Capabilities(1) };
+    assert_eq!(ServerHello::decode(&accepted.encode()), Ok(accepted));
+
+    let rejected = ServerHello::Rejected(RejectReason::Other("server is full".to_string()));
+    assert_eq!(ServerHello::decode(&rejected.encode()), Ok(rejected));
+
+    // Long build strings are cut short between characters
+    let long = ClientHello::new(&"é".repeat(200), Capabilities::NONE);
+    assert_eq!(ClientHello::decode(&long.encode()).unwrap().client_build, "é".repeat(127));
+}
+
+#[test]
+fn handshake_rejects_mismatched_version() {
+    let config = HandshakeConfig { capabilities: Capabilities(0b001), ..HandshakeConfig::default() };
+    let mut hello = ClientHello::new("test-client 1.0", Capabilities(0b011));
+    assert_eq!(config.negotiate(&hello).unwrap().capabilities, Capabilities(0b001));
+
+    hello.protocol_version = PROTOCOL_VERSION + 1;
+    match config.negotiate(&hello) {
+        Err(RejectReason::UnsupportedVersion { client, .. }) => assert_eq!(client, PROTOCOL_VERSION + 1),
+        other => panic!("Expected a version mismatch, got {:?}", other)
+    }
+    assert_eq!(ClientHello::decode(b"NOPE\0\0\0\0\0\0\0"), Err(RejectReason::Malformed));
+}
+
+#[test]
+fn password_store_checks_salted_passwords() {
+    let mut store = PasswordStore::new().with_rounds(16);
+    let alice = store.register("alice", "hunter2").unwrap();
+    let bob = store.register("bob", "hunter2").unwrap();
+    assert_ne!(alice.id, bob.id);
+    assert_eq!(store.register("alice", "password"), Err(AuthError::AccountExists));
+
+    // Accounts from other providers never share an ID with the store's
+    let tokens = TokenProvider::new();
+    let carol = Account::new("carol");
+    assert!(carol.id != alice.id && carol.id != bob.id);
+    let token = tokens.issue(carol.clone());
+
+    let authenticator = Arc::new(Authenticator::new().with_provider(store).with_provider(tokens));
+    let login = |username: &str, password: &str| block_on(authenticate_in_background(authenticator.clone(), Credentials::Password {
+        username: username.to_string(),
+        password: password.to_string()
+    }));
+    assert_eq!(login("alice", "hunter2"), Ok(alice));
+    assert_eq!(login("alice", "hunter3"), Err(AuthError::InvalidCredentials));
+    // A username that doesn't exist looks just like a wrong password
+    assert_eq!(login("carol", "hunter2"), Err(AuthError::InvalidCredentials));
+    assert_eq!(authenticator.authenticate(&Credentials::Token("abc".to_string())), Err(AuthError::UnknownAccount));
+    assert_eq!(authenticator.authenticate(&Credentials::Token(token)), Ok(carol));
+}
+
+#[test]
+fn token_provider_accepts_issued_tokens() {
+    let tokens = TokenProvider::new();
+    let account = Account { id: 7, name: "alice".to_string() };
+    let token = tokens.issue(account.clone());
+    assert_eq!(tokens.authenticate(&Credentials::Token(token.clone())), Ok(account));
+    tokens.revoke(&token);
+    assert_eq!(tokens.authenticate(&Credentials::Token(token)), Err(AuthError::UnknownAccount));
+}
+
+#[test]
+fn outbox_routes_messages_to_recipients() {
+    let (a, mut a_rx) = dummy_client();
+    let (b, mut b_rx) = dummy_client();
+    let mut routes = RoutingTable::new();
+    routes.insert(0, Arc::new(Route::new(&a)));
+    routes.insert(1, Arc::new(Route::new(&b)));
+    drop((a, b));
+
+    let mut outbox = Outbox::new();
+    outbox.join_group("red", 1);
+    outbox.send(0, message(b"one"));
+    outbox.multicast("red", message(b"two"));
+    outbox.broadcast(message(b"three"));
+    outbox.flush(&routes);
+    assert!(outbox.is_empty());
+    drop(routes);
+
+    assert_eq!(&block_on(a_rx.recv()).unwrap().bytes[..], b"one");
+    assert_eq!(&block_on(a_rx.recv()).unwrap().bytes[..], b"three");
+    assert!(block_on(a_rx.recv()).is_none());
+    assert_eq!(&block_on(b_rx.recv()).unwrap().bytes[..], b"two");
+    assert_eq!(&block_on(b_rx.recv()).unwrap().bytes[..], b"three");
+    assert!(block_on(b_rx.recv()).is_none());
+}
+
+#[test]
+fn broadcasts_share_their_payload() {
+    let (a, mut a_rx) = dummy_client();
+    let (b, mut b_rx) = dummy_client();
+    let mut routes = RoutingTable::new();
+    routes.insert(0, Arc::new(Route::new(&a)));
+    routes.insert(1, Arc::new(Route::new(&b)));
+
+    let mut outbox = Outbox::new();
+    outbox.broadcast(message(&[3u8; 1024]));
+    outbox.flush(&routes);
+    let (to_a, to_b) = (block_on(a_rx.recv()).unwrap(), block_on(b_rx.recv()).unwrap());
+    assert_eq!(to_a.bytes.as_ptr(), to_b.bytes.as_ptr());
+}
+
+#[test]
+fn frame_reader_splits_frames_out_of_pooled_slabs() {
+    let mut stream = Vec::new();
+    for payload in &[&[1u8; 40][..], &[2u8; 40][..], &[3u8; 200][..]] {
+        stream.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        stream.extend_from_slice(payload);
+    }
+    let pool = BufferPool::new().with_slab_size(128);
+    let mut frames = FrameReader::new(pool.clone());
+    let mut reader = &stream[..];
+    let first = block_on(frames.read_frame(&mut reader)).unwrap().unwrap();
+    let second = block_on(frames.read_frame(&mut reader)).unwrap().unwrap();
+    let third = block_on(frames.read_frame(&mut reader)).unwrap().unwrap();
+    assert_eq!((&first.bytes[..], &second.bytes[..]), (&[1u8; 40][..], &[2u8; 40][..]));
+    assert_eq!(&third.bytes[..], &[3u8; 200][..]);
+    // The first two frames were read into the same slab, right after each other
+    assert_eq!(second.bytes.as_ptr() as usize, first.bytes.as_ptr() as usize + 44);
+
+    // The third didn't fit, so the full slab went back to the pool, and is reused once its messages are gone
+    let slab = first.bytes.as_ptr() as usize - 4;
+    drop((first, second));
+    assert_eq!(pool.len(), 1);
+    assert_eq!(pool.slab(0).as_ptr() as usize, slab);
+    let oversized = third.bytes.as_ptr() as usize - 4;
+    assert!(block_on(frames.read_frame(&mut reader)).unwrap().is_none());
+    // The third frame got a slab of its own, which the pool doesn't keep, only the usual slab read into after it
+    drop((third, frames));
+    assert_eq!(pool.len(), 1);
+    assert_ne!(pool.slab(0).as_ptr() as usize, oversized);
+
+    let truncated = &stream[..60];
+    let mut frames = FrameReader::new(pool);
+    let mut reader = truncated;
+    assert!(block_on(frames.read_frame(&mut reader)).unwrap().is_some());
+    assert_eq!(block_on(frames.read_frame(&mut reader)).unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn detached_messages_dont_keep_their_slab() {
+    let mut stream = Vec::new();
+    for payload in &[&[1u8; 40][..], &[2u8; 8000][..]] {
+        stream.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        stream.extend_from_slice(payload);
+    }
+    let mut frames = FrameReader::new(BufferPool::new());
+    let mut reader = &stream[..];
+    let small = block_on(frames.read_frame(&mut reader)).unwrap().unwrap();
+    let large = block_on(frames.read_frame(&mut reader)).unwrap().unwrap();
+    let (small_ptr, large_ptr) = (small.bytes.as_ptr(), large.bytes.as_ptr());
+    let (small, large) = (small.detach(), large.detach());
+    assert_ne!(small.bytes.as_ptr(), small_ptr);
+    assert_eq!(&small.bytes[..], &[1u8; 40][..]);
+    // Large payloads stay in their slab
+    assert_eq!(large.bytes.as_ptr(), large_ptr);
+}
 
+fn entity_state(components: &[(ComponentTypeID, &[u8])]) -> EntityState {
+    components.iter().map(|(type_id, bytes)| (*type_id, bytes::Bytes::from(*bytes))).collect()
 }
 
-use std::time::Duration;
+#[test]
+fn snapshot_delta_only_contains_changes() {
+    let mut baseline = WorldState::new();
+    baseline.insert(1, entity_state(&[(0, b"pos a"), (1, b"health")]));
+    baseline.insert(2, entity_state(&[(0, b"pos b")]));
+    let mut current = WorldState::new();
+    current.insert(1, entity_state(&[(0, b"pos a2"), (1, b"health")]));
+    current.insert(3, entity_state(&[]));
+
+    let delta = SnapshotDelta::between(&baseline, &current, 4, 5);
+    assert_eq!(delta.removed, vec![2]);
+    assert_eq!(delta.entities.len(), 2);
+    assert_eq!(delta.entities[0].changed, vec![(0, bytes::Bytes::from(&b"pos a2"[..]))]);
+    assert!(delta.entities[1].changed.is_empty());
+    assert_eq!(delta.apply(&baseline), current);
+    assert!(SnapshotDelta::between(&current, &current, 5, 6).entities.is_empty());
+}
+
+#[test]
+fn snapshot_delta_survives_being_split_into_messages() {
+    let mut current = WorldState::new();
+    for id in 0..50 {
+        current.insert(id, entity_state(&[(0, &[id as u8; 20]), (3, b"name")]));
+    }
+    let delta = SnapshotDelta::between(&WorldState::new(), &current, 0, 1);
+    let messages = delta.encode(256);
+    assert!(messages.len() > 1);
+    assert!(messages.iter().all(|message| message.bytes.len() <= 256));
+
+    let mut decoded = SnapshotDelta::default();
+    for (i, message) in messages.iter().enumerate() {
+        let part = SnapshotDelta::decode(message).unwrap();
+        assert_eq!((part.index as usize, part.count as usize), (i, messages.len()));
+        assert_eq!(part.delta.sequence, 1);
+        decoded.sequence = part.delta.sequence;
+        decoded.merge(part.delta);
+    }
+    assert_eq!(decoded, delta);
+    assert_eq!(decode_ack(&encode_ack(1234)), Some(1234));
+}
+
+#[derive(Debug, PartialEq)]
+struct Transform {
+    x: f32,
+    angle: f32,
+    frame: u8,
+    health: i32,
+}
+
+crate::packed!(Transform {
+    x: quantized(-1024.0, 1024.0, 0.01),
+    angle: quantized(-std::f32::consts::PI, std::f32::consts::PI, 0.001),
+    frame: bits(3),
+    health: packed
+});
+
+#[test]
+fn packed_snapshots_are_smaller_and_decode_the_same() {
+    let mut writer = BitWriter::new();
+    writer.write_bool(true);
+    writer.write_varint(300);
+    writer.write_signed_varint(-1);
+    writer.write_varint(u64::MAX);
+    let bits = writer.finish();
+    let mut reader = BitReader::new(&bits);
+    assert_eq!(reader.read_bool(), Some(true));
+    assert_eq!(reader.read_varint(), Some(300));
+    assert_eq!(reader.read_signed_varint(), Some(-1));
+    assert_eq!(reader.read_varint(), Some(u64::MAX));
+    assert_eq!(reader.read_varint(), None);
+
+    // 18 bits for x, 13 for the angle, 3 for the frame and 8 for the health
+    let transform = Transform { x: 12.345, angle: -1.5, frame: 5, health: -20 };
+    let mut bytes = BytesMut::new();
+    bitpack::encode(&transform, &mut bytes);
+    assert_eq!(bytes.len(), 6);
+    let decoded: Transform = bitpack::decode(&bytes).unwrap();
+    assert!((decoded.x - transform.x).abs() <= 0.005);
+    assert!((decoded.angle - transform.angle).abs() <= 0.0005);
+    assert_eq!((decoded.frame, decoded.health), (5, -20));
+    assert_eq!(bitpack::decode::<Transform>(&bytes[..5]), None);
+
+    let mut baseline = WorldState::new();
+    for index in 1000..1010 {
+        baseline.insert((2 << 32) | index, entity_state(&[]));
+    }
+    let mut current = WorldState::new();
+    for index in 0..50 {
+        let mut bytes = BytesMut::new();
+        bitpack::encode(&Transform { x: index as f32, angle: 0.0, frame: 0, health: 100 }, &mut bytes);
+        current.insert((1 << 32) | (index * 3), entity_state(&[(0, &bytes[..]), (3, b"name")]));
+    }
+    let delta = SnapshotDelta::between(&baseline, &current, 1, 2);
+    let packed = delta.encode_packed(128);
+    assert!(packed.len() > 1);
+    assert!(packed.iter().all(|message| message.bytes.len() <= 128));
+    let size = |messages: &[Message]| messages.iter().map(|message| message.bytes.len()).sum::<usize>();
+    assert!(size(&packed) < size(&delta.encode(128)) * 2 / 3);
+
+    let mut decoded = SnapshotDelta::default();
+    for (i, message) in packed.iter().enumerate() {
+        let part = SnapshotDelta::decode(message).unwrap();
+        assert_eq!((part.index as usize, part.count as usize), (i, packed.len()));
+        decoded.sequence = part.delta.sequence;
+        decoded.baseline = part.delta.baseline;
+        decoded.merge(part.delta);
+    }
+    assert_eq!(decoded, delta);
+}
+
+#[test]
+fn packed_structs_are_described_with_their_precision() {
+    let mut schema = Schema::new();
+    let description = bitpack::describe::<Transform>(&mut schema).unwrap();
+    assert_eq!(description, Description::bit_packed(Layout::Named("Transform".to_string())));
+    match &schema.types["Transform"] {
+        TypeLayout::Struct(fields) => {
+            match fields[0].layout {
+                Layout::Quantized { min, max, bits, step } => {
+                    assert_eq!((min, max, bits), (-1024.0, 1024.0, 18));
+                    assert!(step <= 0.01);
+                },
+                ref other => panic!("expected x to be quantized, got {:?}", other)
+            }
+            assert_eq!(fields[2], Field::new("frame", Layout::Bits(3)));
+            assert_eq!(fields[3], Field::new("health", Layout::SignedVarint));
+        },
+        other => panic!("expected a struct, got {:?}", other)
+    }
+}
+
+#[test]
+fn packed_bits_are_clamped() {
+    let mut bytes = BytesMut::new();
+    bitpack::encode(&Transform { x: 0.0, angle: 0.0, frame: 200, health: 0 }, &mut bytes);
+    assert_eq!(bitpack::decode::<Transform>(&bytes).unwrap().frame, 7);
+}
+
+/// A component whose quantization has an empty range.
+struct Backwards {
+    x: f32,
+}
+
+crate::packed!(Backwards {
+    x: quantized(1.0, -1.0, 0.01)
+});
+
+impl specs::Component for Backwards {
+    type Storage = specs::VecStorage<Self>;
+}
+
+impl Replicated for Backwards {
+    const TYPE_ID: ComponentTypeID = 9;
+
+    fn encode(&self, bytes: &mut BytesMut) {
+        bitpack::encode(self, bytes);
+    }
+    fn decode(bytes: &[u8]) -> Option<Backwards> {
+        bitpack::decode(bytes)
+    }
+    fn describe(schema: &mut Schema) -> Result<Description, TraceError> {
+        bitpack::describe::<Self>(schema)
+    }
+}
+
+#[test]
+fn invalid_packed_encodings_are_caught_at_registration() {
+    assert_eq!(bitpack::Quantization::try_new(0.0, 1.0, 0.0), Err(bitpack::PackError::Precision(0.0)));
+    assert_eq!(bitpack::Quantization::try_new(0.0, 1.0, 1e-12), Err(bitpack::PackError::Bits(40)));
+    assert_eq!(bitpack::Quantization::try_with_bits(1.0, 1.0, 8), Err(bitpack::PackError::EmptyRange(1.0, 1.0)));
+    assert!(bitpack::Quantization::try_new(f32::NAN, 1.0, 0.1).is_err());
+
+    let error = Game::new_builder().with_replicated::<Backwards>().err().unwrap();
+    assert_eq!(error, TraceError::Invalid("the range 1..-1 is empty".to_string()));
+}
+
+#[test]
+fn interest_policy_filters_distant_entities() {
+    let mut state = WorldState::new();
+    let mut positions = Positions::new();
+    for (id, position) in [(1, [0.0, 0.0]), (2, [3.0, 4.0]), (3, [10.0, 0.0])] {
+        state.insert(id, EntityState::new());
+        positions.insert(id, position);
+    }
+    // An entity without a position, such as the round timer
+    state.insert(4, EntityState::new());
+
+    let radius = InterestPolicy::Radius(5.0);
+    let relevant = radius.relevant(Some(1), &state, &positions);
+    assert_eq!(relevant, vec![1, 2, 4].into_iter().collect());
+    let rect = InterestPolicy::ViewRect { half_width: 10.0, half_height: 1.0 };
+    assert_eq!(rect.relevant(Some(1), &state, &positions), vec![1, 3, 4].into_iter().collect());
+    assert_eq!(radius.relevant(None, &state, &positions), vec![4].into_iter().collect());
+
+    let message = encode_view_change(opcode::VIEW_LEAVE, &[2, 3]);
+    assert_eq!(decode_view_change(&message), Some((opcode::VIEW_LEAVE, vec![2, 3])));
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Walk(u8);
+
+impl Input for Walk {
+    fn encode(&self, bytes: &mut BytesMut) {
+        bytes.extend_from_slice(&[self.0]);
+    }
+    fn decode(bytes: &[u8]) -> Option<Walk> {
+        bytes.first().map(|direction| Walk(*direction))
+    }
+    fn validate(&self, _: ClientID) -> bool {
+        self.0 < 4
+    }
+}
+
+#[test]
+fn input_queue_applies_inputs_on_their_tick() {
+    let commands = vec![
+        InputCommand { sequence: 1, tick: 10, input: Walk(0) },
+        InputCommand { sequence: 2, tick: 12, input: Walk(1) },
+        InputCommand { sequence: 3, tick: 10, input: Walk(7) },
+    ];
+    let decoded = decode_inputs::<Walk>(&encode_inputs(&commands)).unwrap();
+    assert_eq!(decoded, commands);
+
+    let mut queue = InputQueue::<Walk>::new().with_window(2, 5);
+    let mut results = decoded.into_iter().map(|command| queue.submit(0, command, 10));
+    assert_eq!(results.next(), Some(Ok(())));
+    assert_eq!(results.next(), Some(Ok(())));
+    assert_eq!(results.next(), Some(Err(InputRejection::Invalid)));
+    assert_eq!(queue.submit(0, InputCommand { sequence: 2, tick: 12, input: Walk(1) }, 10), Err(InputRejection::Duplicate));
+    assert_eq!(queue.submit(0, InputCommand { sequence: 4, tick: 7, input: Walk(1) }, 10), Err(InputRejection::TooLate));
+    assert_eq!(queue.submit(0, InputCommand { sequence: 5, tick: 16, input: Walk(1) }, 10), Err(InputRejection::TooEarly));
+    assert_eq!(queue.submit(0, InputCommand { sequence: 5000, tick: 10, input: Walk(1) }, 10), Err(InputRejection::OutOfWindow));
+
+    queue.advance(10, &[0]);
+    assert_eq!(queue.current().collect::<Vec<_>>(), vec![(0, &Walk(0))]);
+    // Input 2 is still waiting for its tick
+    assert_eq!(queue.processed(0), 1);
+    queue.advance(11, &[0]);
+    assert_eq!(queue.current().count(), 0);
+    queue.advance(12, &[0]);
+    assert_eq!(queue.current().collect::<Vec<_>>(), vec![(0, &Walk(1))]);
+    assert_eq!(queue.processed(0), 5);
+
+    // Inputs that arrive out of order are neither duplicates nor processed until the gap is filled
+    assert_eq!(queue.submit(0, InputCommand { sequence: 7, tick: 12, input: Walk(2) }, 12), Ok(()));
+    queue.advance(12, &[0]);
+    assert_eq!(queue.processed(0), 5);
+    assert_eq!(queue.submit(0, InputCommand { sequence: 6, tick: 13, input: Walk(3) }, 12), Ok(()));
+    queue.advance(13, &[0]);
+    assert_eq!(queue.current().collect::<Vec<_>>(), vec![(0, &Walk(3))]);
+    assert_eq!(queue.processed(0), 7);
+}
+
+#[derive(Debug, PartialEq)]
+struct Slot(u8);
+
+impl Payload for Slot {
+    fn encode(&self, bytes: &mut BytesMut) {
+        bytes.extend_from_slice(&[self.0]);
+    }
+    fn decode(bytes: &[u8]) -> Option<Slot> {
+        bytes.first().map(|slot| Slot(*slot))
+    }
+}
+
+struct Lookup;
+
+impl Procedure for Lookup {
+    const ID: ProcedureID = 1;
+    type Request = Slot;
+    type Response = Slot;
+}
+
+struct Unregistered;
+
+impl Procedure for Unregistered {
+    const ID: ProcedureID = 99;
+    type Request = ();
+    type Response = ();
+}
+
+#[test]
+fn procedures_answer_calls_in_both_directions() {
+    let mut world = World::new();
+    world.add_resource(Inbox::new());
+    world.add_resource(Outbox::new());
+    world.add_resource(RpcCalls::new());
+    let mut procedures = Procedures::new();
+    procedures.register::<Lookup, _>(|_, _, Slot(slot)| match slot {
+        0..=3 => Ok(Slot(slot * 2)),
+        _ => Err(RpcError::Failed("empty slot".to_string()))
+    });
+    let (client, mut rx) = dummy_client();
+    let mut routes = RoutingTable::new();
+    routes.insert(0, Arc::new(Route::new(&client)));
+
+    // The server calls the client, which only answers the first call in time
+    let answered = world.write_resource::<RpcCalls>().call::<Lookup>(0, &Slot(1), Duration::from_secs(60));
+    let lost = world.write_resource::<RpcCalls>().call::<Lookup>(0, &Slot(2), Duration::from_secs(0));
+    rpc::flush(&world);
+    world.write_resource::<Outbox>().flush(&routes);
+    let request = rpc::decode(&block_on(rx.recv()).unwrap());
+    assert!(rpc::decode(&block_on(rx.recv()).unwrap()).is_some());
+    let correlation = match request {
+        Some(Envelope::Request { procedure, correlation, payload }) => {
+            assert_eq!((procedure, Slot::decode(&payload)), (Lookup::ID, Some(Slot(1))));
+            correlation
+        },
+        other => panic!("expected a request, got {:?}", other)
+    };
+    {
+        let mut inbox = world.write_resource::<Inbox>();
+        inbox.push(0, rpc::encode_response(correlation, Ok(BytesMut::from(&[2u8][..]))));
+        inbox.push(0, rpc::encode_request::<Lookup>(7, &Slot(3)));
+        inbox.push(0, rpc::encode_request::<Lookup>(8, &Slot(9)));
+        inbox.push(0, rpc::encode_request::<Unregistered>(9, &()));
+    }
+    rpc::update(&procedures, &mut world, &[0]);
+    assert_eq!(world.write_resource::<RpcCalls>().result(answered), Some(Ok(Slot(2))));
+    assert_eq!(world.write_resource::<RpcCalls>().result(lost), Some(Err(RpcError::TimedOut)));
+    assert_eq!(world.read_resource::<RpcCalls>().pending(), 0);
+
+    // The client's calls are answered in order, errors included
+    world.write_resource::<Outbox>().flush(&routes);
+    let responses: Vec<_> = (0..3).map(|_| rpc::decode(&block_on(rx.recv()).unwrap()).unwrap()).collect();
+    assert_eq!(responses, vec![
+        Envelope::Response { correlation: 7, result: Ok(Bytes::from(&[6u8][..])) },
+        Envelope::Response { correlation: 8, result: Err(RpcError::Failed("empty slot".to_string())) },
+        Envelope::Response { correlation: 9, result: Err(RpcError::UnknownProcedure) },
+    ]);
+    // Every kind of error survives the trip back to the caller
+    for error in [RpcError::TimedOut, RpcError::Disconnected, RpcError::Malformed] {
+        let response = rpc::decode(&rpc::encode_response(1, Err(error.clone()))).unwrap();
+        assert_eq!(response, Envelope::Response { correlation: 1, result: Err(error) });
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+enum Chat {
+    Say(String),
+    Emote { id: u16 },
+}
+
+impl GameMessage for Chat {
+    const OPCODE: opcode::Opcode = 0x10;
+}
+
+#[derive(Default)]
+struct ChatLog(Vec<(ClientID, Chat)>);
+
+#[test]
+fn serde_codec_routes_messages_and_reports_decode_errors() {
+    let mut world = World::new();
+    world.add_resource(ChatLog::default());
+    let mut chat = SerdeCodec::new().with_handler(|world: &mut World, client, message: Chat| {
+        world.write_resource::<ChatLog>().0.push((client, message));
+    });
+    let say = codec::encode(&Chat::Say("hello".to_string())).unwrap();
+    assert_eq!(codec::decode::<Chat>(&say), Ok(Chat::Say("hello".to_string())));
+
+    // Only the messages for the game are taken out of the inbox
+    let mut inbox = Inbox::new();
+    inbox.push(0, say);
+    inbox.push(0, message(&[opcode::SNAPSHOT_ACK, 0, 0, 0, 1]));
+    inbox.push(0, message(&[Chat::OPCODE, 7]));
+    inbox.push(0, codec::encode(&Chat::Emote { id: 3 }).unwrap());
+    inbox.push(1, message(&[0x11]));
+    inbox.push(1, message(&[]));
+    let messages = inbox.take_game();
+    assert_eq!(inbox.take(opcode::SNAPSHOT_ACK).len(), 1);
+    assert!(inbox.is_empty());
+
+    let decoded = chat.process_messages(messages, &ClientHandshakes::new());
+    assert_eq!(decoded.len(), 2);
+    decoded.update_world(&mut world);
+    assert_eq!(world.read_resource::<ChatLog>().0, vec![(0, Chat::Say("hello".to_string())), (0, Chat::Emote { id: 3 })]);
+
+    // Bad messages are reported for their client without holding up the others
+    let notifier = world.read_resource::<NotifierQueue>();
+    let mut errors: Vec<&DecodeError> = notifier.iter()
+        .map(|event| force_downcast_event_ref::<DecodeError>(&**event))
+        .collect();
+    errors.sort_by_key(|error| error.client);
+    assert_eq!(errors.len(), 2);
+    match &errors[0].kind {
+        DecodeErrorKind::Malformed(opcode, _) => assert_eq!((errors[0].client, *opcode), (0, Chat::OPCODE)),
+        other => panic!("expected a malformed message, got {:?}", other)
+    }
+    assert_eq!(errors[0].count, 1);
+    // Each client gets one event a tick, no matter how many of its messages were bad
+    assert_eq!(errors[1], &DecodeError { client: 1, kind: DecodeErrorKind::UnknownOpcode(0x11), count: 2 });
+}
+
+#[derive(Serialize, Deserialize)]
+struct Trade {
+    with: ClientID,
+    offer: Vec<(u16, u8)>,
+    note: Option<String>,
+    chat: Chat,
+}
+
+impl GameMessage for Trade {
+    const OPCODE: opcode::Opcode = 0x11;
+}
+
+#[derive(Deserialize)]
+enum List {
+    Cons(u8, Box<List>),
+    Nil,
+}
+
+mod other {
+    /// A different type with the same name as the `Trade` message.
+    #[derive(serde::Deserialize)]
+    pub struct Trade(pub u8);
+}
+
+#[test]
+fn schema_describes_messages_and_components() {
+    let messages = SerdeCodec::new()
+        .with_handler(|_: &mut World, _, _: Chat| {})
+        .with_handler(|_: &mut World, _, _: Trade| {});
+    let game = Game::new_builder().with_replicated::<Health>().unwrap().build();
+    let mut schema = game.schema(&messages).unwrap();
+    assert_eq!(schema.messages.iter().map(|message| message.opcode).collect::<Vec<_>>(), vec![0x10, 0x11]);
+    assert_eq!(schema.messages[1].description, Description::bincode(Layout::Named("Trade".to_string())));
+    assert_eq!(schema.components[0].type_id, Health::TYPE_ID);
+    assert_eq!(schema.components[0].description, Description::opaque());
+    assert_eq!(schema.types["Trade"], TypeLayout::Struct(vec![
+        Field::new("with", Layout::U32),
+        Field::new("offer", Layout::Seq(Box::new(Layout::Tuple(vec![Layout::U16, Layout::U8])))),
+        Field::new("note", Layout::Option(Box::new(Layout::String))),
+        Field::new("chat", Layout::Named("Chat".to_string())),
+    ]));
+    // Every variant is described, including those of enums that contain themselves
+    assert_eq!(schema.types["Chat"], TypeLayout::Enum(vec![
+        Variant { name: "Say".to_string(), layout: TypeLayout::Newtype(Layout::String) },
+        Variant { name: "Emote".to_string(), layout: TypeLayout::Struct(vec![Field::new("id", Layout::U16)]) },
+    ]));
+    assert_eq!(schema.trace::<List>(), Ok(Layout::Named("List".to_string())));
+    assert_eq!(schema.types["List"], TypeLayout::Enum(vec![
+        Variant { name: "Cons".to_string(), layout: TypeLayout::Tuple(vec![Layout::U8, Layout::Named("List".to_string())]) },
+        Variant { name: "Nil".to_string(), layout: TypeLayout::Unit },
+    ]));
+
+    // The engine's messages are described as well
+    let snapshot = schema.engine_opcodes.iter().find(|engine| engine.opcode == opcode::SNAPSHOT).unwrap();
+    match &snapshot.description.layout {
+        Layout::Struct(fields) => {
+            let names: Vec<&str> = fields.iter().map(|field| field.name.as_str()).collect();
+            assert_eq!(names, vec!["sequence", "baseline", "last_input", "flags", "part_index", "part_count", "delta"]);
+            // The entities are laid out according to the flags
+            match &fields[6].layout {
+                Layout::Switch { field, cases, .. } => {
+                    assert_eq!(field, "flags");
+                    assert_eq!(cases[0].name, "packed");
+                },
+                other => panic!("expected the snapshot's delta to depend on its flags, got {:?}", other)
+            }
+        },
+        other => panic!("expected the snapshot's fields, got {:?}", other)
+    }
+
+    let json: serde_json::Value = serde_json::from_str(&schema.to_json()).unwrap();
+    assert_eq!(json["protocol_version"], PROTOCOL_VERSION);
+    assert_eq!(json["messages"][0]["encoding"], "bincode");
+    assert_eq!(json["types"]["Trade"]["struct"][2]["layout"]["option"], "string");
+    // So is everything sent around them
+    let formats: Vec<&str> = json["formats"].as_array().unwrap().iter().map(|format| format["name"].as_str().unwrap()).collect();
+    assert_eq!(formats, vec!["client_hello", "server_hello", "credentials", "auth_result", "frame", "compressed_message"]);
+    assert_eq!(json["formats"][0]["layout"]["struct"][0]["layout"]["magic"], "STAR");
+}
+
+#[test]
+fn schema_refuses_types_with_the_same_name() {
+    let mut schema = Schema::new();
+    schema.trace::<Trade>().unwrap();
+    assert_eq!(schema.trace::<Trade>(), Ok(Layout::Named("Trade".to_string())));
+    assert_eq!(schema.trace::<other::Trade>(), Err(TraceError::Collision("Trade".to_string())));
+    assert_eq!(schema.define("Chat", TypeLayout::Unit), Err(TraceError::Collision("Chat".to_string())));
+}
+
+/// Deliver UDP packets from one connection to another, skipping the ones for which `drop` returns true.
+fn deliver(packets: Vec<BytesMut>, to: &mut Connection, now: Instant, mut drop: impl FnMut(usize) -> bool) -> Vec<Message> {
+    let mut delivered = Vec::new();
+    for (i, bytes) in packets.into_iter().enumerate() {
+        if drop(i) {
+            continue;
+        }
+        let (kind, contents) = packet::parse_packet(&bytes).unwrap();
+        assert_eq!(kind, packet::PacketKind::Data);
+        delivered.extend(to.receive(contents, now));
+    }
+    delivered
+}
+
+#[test]
+fn udp_connection_resends_lost_reliable_messages_in_order() {
+    let start = Instant::now();
+    let mut a = Connection::new(ConnectionConfig::default(), start);
+    let mut b = Connection::new(ConnectionConfig::default(), start);
+
+    let large = vec![7u8; DEFAULT_MTU * 3];
+    a.send(message(b"first")).unwrap();
+    a.send(message(&large)).unwrap();
+    a.send(message(b"last")).unwrap();
+    let packets = a.poll(start);
+    assert!(packets.len() >= 4);
+    assert!(packets.iter().all(|packet| packet.len() <= DEFAULT_MTU));
+
+    // Losing the first packet holds back everything after it
+    let delivered = deliver(packets, &mut b, start, |i| i == 0);
+    assert!(delivered.is_empty());
+    let acks = b.poll(start);
+    deliver(acks, &mut a, start, |_| false);
+
+    let later = start + Duration::from_secs(1);
+    let delivered = deliver(a.poll(later), &mut b, later, |_| false);
+    let delivered: Vec<&[u8]> = delivered.iter().map(|m| &m.bytes[..]).collect();
+    assert_eq!(delivered, vec![&b"first"[..], &large[..], &b"last"[..]]);
+
+    deliver(b.poll(later), &mut a, later, |_| false);
+    assert_eq!(a.unacknowledged(), 0);
+    assert!(a.rtt().is_some());
+}
+
+#[test]
+fn udp_connection_resends_when_the_first_packet_is_lost() {
+    let start = Instant::now();
+    let mut a = Connection::new(ConnectionConfig::default(), start);
+    let mut b = Connection::new(ConnectionConfig::default(), start);
+
+    a.send(message(b"hello")).unwrap();
+    assert!(deliver(a.poll(start), &mut b, start, |i| i == 0).is_empty());
+    // B hasn't received anything yet, so its packets mustn't acknowledge A's packet 0
+    b.send(message(b"hi")).unwrap();
+    assert_eq!(&deliver(b.poll(start), &mut a, start, |_| false)[0].bytes[..], b"hi");
+    assert_eq!(a.unacknowledged(), 1);
+
+    let later = start + Duration::from_secs(1);
+    assert_eq!(&deliver(a.poll(later), &mut b, later, |_| false)[0].bytes[..], b"hello");
+    deliver(b.poll(later), &mut a, later, |_| false);
+    assert_eq!(a.unacknowledged(), 0);
+}
+
+#[test]
+fn udp_connection_drops_stale_sequenced_messages() {
+    let start = Instant::now();
+    let mut a = Connection::new(ConnectionConfig::default(), start);
+    let mut b = Connection::new(ConnectionConfig::default(), start);
+
+    a.send(Message::on(Channel::UnreliableSequenced, BytesMut::from(&b"old"[..]))).unwrap();
+    let old = a.poll(start);
+    a.send(Message::on(Channel::UnreliableSequenced, BytesMut::from(&b"new"[..]))).unwrap();
+    let new = a.poll(start);
+
+    assert_eq!(&deliver(new, &mut b, start, |_| false)[0].bytes[..], b"new");
+    assert!(deliver(old, &mut b, start, |_| false).is_empty());
+    // Unreliable messages are never sent twice
+    assert!(a.poll(start + Duration::from_secs(1)).iter().all(|packet| packet.len() == packet::PREFIX_SIZE + packet::DATA_HEADER_SIZE));
+}
+
+#[test]
+fn udp_connection_limits_unfinished_messages() {
+    let start = Instant::now();
+    let mut a = Connection::new(ConnectionConfig::default(), start);
+    let fragment_count = 3;
+    let large = vec![7u8; a.max_message_size() / MAX_FRAGMENTS * (fragment_count - 1) + 1];
+    // Room for one unfinished message
+    let config = ConnectionConfig { max_pending_bytes: a.max_message_size() / MAX_FRAGMENTS * fragment_count, ..ConnectionConfig::default() };
+    let mut b = Connection::new(config, start);
+
+    a.send(message(&large)).unwrap();
+    a.send(message(&large)).unwrap();
+    let packets = a.poll(start);
+    assert_eq!(packets.len(), fragment_count * 2);
+    // The first message is missing a fragment, so the second one can't start
+    assert!(deliver(packets, &mut b, start, |i| i == 0).is_empty());
+    deliver(b.poll(start), &mut a, start, |_| false);
+    assert_eq!(a.unacknowledged(), fragment_count + 1);
+
+    let later = start + Duration::from_secs(1);
+    let delivered = deliver(a.poll(later), &mut b, later, |_| false);
+    assert_eq!(delivered.len(), 2);
+    assert!(delivered.iter().all(|m| m.bytes[..] == large[..]));
+}
+
+#[test]
+fn udp_connection_gives_up_on_old_sequenced_messages() {
+    let start = Instant::now();
+    let mut a = Connection::new(ConnectionConfig::default(), start);
+    let large = vec![7u8; a.max_message_size() / MAX_FRAGMENTS + 1];
+    let config = ConnectionConfig { max_pending_bytes: a.max_message_size() / MAX_FRAGMENTS * 2, ..ConnectionConfig::default() };
+    let mut b = Connection::new(config, start);
+    let mut send = |bytes: &[u8], drop: fn(usize) -> bool| {
+        a.send(Message::on(Channel::UnreliableSequenced, BytesMut::from(bytes))).unwrap();
+        deliver(a.poll(start), &mut b, start, drop)
+    };
+
+    assert!(send(&large, |i| i == 1).is_empty());
+    // The unfinished message takes up all the room
+    assert!(send(&large, |_| false).is_empty());
+    for _ in 0..32 {
+        assert_eq!(send(b"small", |_| false).len(), 1);
+    }
+    assert_eq!(send(&large, |_| false).len(), 1);
+}
+
+#[test]
+fn websocket_accept_key() {
+    // The example from RFC 6455
+    assert_eq!(websocket::accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+}
+
+#[test]
+fn websocket_frames_round_trip() {
+    let large = Bytes::from(vec![7u8; 70000]);
+    let mut bytes = BytesMut::new();
+    bytes.extend_from_slice(&websocket::Frame::Binary(message(b"hello").bytes).encode(Some([1, 2, 3, 4])));
+    bytes.extend_from_slice(&websocket::Frame::Ping(Bytes::new()).encode(Some([5, 6, 7, 8])));
+    bytes.extend_from_slice(&websocket::Frame::Binary(large.clone()).encode(Some([9, 10, 11, 12])));
+
+    let mut reader = &bytes[..];
+    let mut frames = websocket::FrameReader::new();
+    assert_eq!(block_on(frames.read_frame(&mut reader)).unwrap(), Some(websocket::Frame::Binary(message(b"hello").bytes)));
+    assert_eq!(block_on(frames.read_frame(&mut reader)).unwrap(), Some(websocket::Frame::Ping(Bytes::new())));
+    assert_eq!(block_on(frames.read_frame(&mut reader)).unwrap(), Some(websocket::Frame::Binary(large)));
+    assert_eq!(block_on(frames.read_frame(&mut reader)).unwrap(), None);
+
+    // Servers don't mask their frames, and clients must
+    let unmasked = websocket::Frame::Binary(message(b"hello").bytes).encode(None);
+    assert!(block_on(websocket::FrameReader::new().read_frame(&mut &unmasked[..])).is_err());
+}
+
+#[test]
+fn websocket_control_frames_interrupt_fragmented_messages() {
+    let mask = [1, 2, 3, 4];
+    let masked = |payload: &[u8]| payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]).collect::<Vec<u8>>();
+    let mut bytes = BytesMut::new();
+    // "hel" without FIN, a ping, then "lo" as the final continuation
+    bytes.extend_from_slice(&[0x02, 0x83]);
+    bytes.extend_from_slice(&mask);
+    bytes.extend_from_slice(&masked(b"hel"));
+    bytes.extend_from_slice(&websocket::Frame::Ping(Bytes::from(&b"ping"[..])).encode(Some(mask)));
+    bytes.extend_from_slice(&[0x80, 0x82]);
+    bytes.extend_from_slice(&mask);
+    bytes.extend_from_slice(&masked(b"lo"));
+
+    let mut reader = &bytes[..];
+    let mut frames = websocket::FrameReader::new();
+    assert_eq!(block_on(frames.read_frame(&mut reader)).unwrap(), Some(websocket::Frame::Ping(Bytes::from(&b"ping"[..]))));
+    assert_eq!(block_on(frames.read_frame(&mut reader)).unwrap(), Some(websocket::Frame::Binary(message(b"hello").bytes)));
+    assert_eq!(block_on(frames.read_frame(&mut reader)).unwrap(), None);
+
+    // Control frames can't be fragmented, or longer than 125 bytes
+    let long = websocket::Frame::Ping(Bytes::from(vec![0u8; 126])).encode(Some(mask));
+    assert_eq!(block_on(websocket::FrameReader::new().read_frame(&mut &long[..])).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    let mut fragmented = websocket::Frame::Ping(Bytes::new()).encode(Some(mask));
+    fragmented[0] &= 0x7F;
+    assert_eq!(block_on(websocket::FrameReader::new().read_frame(&mut &fragmented[..])).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn websocket_rejects_oversized_frames() {
+    // A short fragment, followed by a continuation that claims to be almost 2^64 bytes long
+    let mut bytes = BytesMut::new();
+    bytes.extend_from_slice(&[0x02, 0x81, 0, 0, 0, 0, 7]);
+    bytes.extend_from_slice(&[0x80, 0xFF]);
+    bytes.extend_from_slice(&(u64::MAX >> 1).to_be_bytes());
+    assert_eq!(block_on(websocket::FrameReader::new().read_frame(&mut &bytes[..])).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+
+    let mut bytes = BytesMut::from(&[0x82, 0xFF][..]);
+    bytes.extend_from_slice(&u64::MAX.to_be_bytes());
+    assert_eq!(block_on(websocket::FrameReader::new().read_frame(&mut &bytes[..])).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+}
+
+/// Read the next frame a WebSocket server sent. Server frames aren't masked, so a `websocket::FrameReader` doesn't take them.
+fn read_server_frame(stream: &mut std::net::TcpStream) -> (u8, Vec<u8>) {
+    use std::io::Read;
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).unwrap();
+    let len = match header[1] & 0x7F {
+        126 => {
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len).unwrap();
+            u16::from_be_bytes(len) as usize
+        },
+        127 => {
+            let mut len = [0u8; 8];
+            stream.read_exact(&mut len).unwrap();
+            u64::from_be_bytes(len) as usize
+        },
+        len => len as usize
+    };
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).unwrap();
+    (header[0] & 0x0F, payload)
+}
+
+#[test]
+fn websocket_clients_exchange_messages_with_a_real_server() {
+    use std::io::{Read, Write};
+    let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let (connections, _connector) = loopback_server_with(|server| server.with_websocket(websocket::WebSocketConfig::new(address)));
+
+    let start = Instant::now();
+    let mut stream = loop {
+        match std::net::TcpStream::connect(address) {
+            Ok(stream) => break stream,
+            Err(_) => {
+                assert!(start.elapsed() < Duration::from_secs(5), "The WebSocket listener never started");
+                std::thread::yield_now();
+            }
+        }
+    };
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                       Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+    let mut response = Vec::new();
+    let mut byte = [0u8; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).unwrap();
+        response.push(byte[0]);
+    }
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 101"));
+    assert!(response.contains("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+    let mask = Some([1, 2, 3, 4]);
+    let hello = ClientHello::new("test-client 1.0", Capabilities::NONE).encode().freeze();
+    stream.write_all(&websocket::Frame::Binary(hello).encode(mask)).unwrap();
+    let (opcode, server_hello) = read_server_frame(&mut stream);
+    assert_eq!(opcode, 0x2);
+    assert!(matches!(ServerHello::decode(&server_hello), Ok(ServerHello::Accepted { .. })));
+
+    stream.write_all(&websocket::Frame::Binary(message(b"hello").bytes).encode(mask)).unwrap();
+    let id = wait_for_clients(&connections, 1)[0];
+    let entry = block_on(connections.remove(id)).unwrap().unwrap();
+    assert_eq!(&block_on(entry.receiver.lock().unwrap().recv()).unwrap().bytes[..], b"hello");
+
+    // Pings are answered, and the server's own pings are skipped
+    stream.write_all(&websocket::Frame::Ping(Bytes::from(&b"are you there"[..])).encode(mask)).unwrap();
+    loop {
+        match read_server_frame(&mut stream) {
+            (0xA, payload) => {
+                assert_eq!(payload, b"are you there");
+                break;
+            },
+            (0x9, _) => continue,
+            (opcode, _) => panic!("Unexpected frame with opcode {:#x}", opcode)
+        }
+    }
+
+    entry.sender.try_send(message(b"welcome")).unwrap();
+    loop {
+        match read_server_frame(&mut stream) {
+            (0x2, payload) => {
+                assert_eq!(payload, b"welcome");
+                break;
+            },
+            (0x9, _) => continue,
+            (opcode, _) => panic!("Unexpected frame with opcode {:#x}", opcode)
+        }
+    }
+}
+
+#[test]
+fn websocket_clients_that_never_upgrade_are_dropped() {
+    use std::io::{Read, Write};
+    let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let handshake_config = HandshakeConfig { timeout: Duration::from_millis(50), ..HandshakeConfig::default() };
+    let _server = loopback_server_with(|server| {
+        server.with_handshake_config(handshake_config).with_websocket(websocket::WebSocketConfig::new(address))
+    });
+
+    let start = Instant::now();
+    let mut stream = loop {
+        match std::net::TcpStream::connect(address) {
+            Ok(stream) => break stream,
+            Err(_) => {
+                assert!(start.elapsed() < Duration::from_secs(5), "The WebSocket listener never started");
+                std::thread::yield_now();
+            }
+        }
+    };
+    // Half of a request, and then nothing
+    stream.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0);
+}
+
+/// Send a UDP packet to the server until it answers with one of the given kind, and return the answer's contents.
+fn udp_exchange(socket: &std::net::UdpSocket, request: &[u8], kind: packet::PacketKind) -> Vec<u8> {
+    let start = Instant::now();
+    let mut buffer = [0u8; 2048];
+    loop {
+        assert!(start.elapsed() < Duration::from_secs(5), "The server never answered with {:?}", kind);
+        socket.send(request).unwrap();
+        if let Ok(len) = socket.recv(&mut buffer) {
+            match packet::parse_packet(&buffer[..len]) {
+                Some((received, contents)) if received == kind => return contents.to_vec(),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// A packet from a UDP client, with the salt in front of the contents.
+fn salted(kind: packet::PacketKind, salt: &[u8], contents: &[u8]) -> BytesMut {
+    let mut bytes = packet::begin_packet(kind, 64);
+    bytes.extend_from_slice(salt);
+    bytes.extend_from_slice(contents);
+    bytes
+}
+
+#[test]
+fn udp_clients_answer_a_challenge_to_connect() {
+    let address = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let (connections, _connector) = loopback_server_with(|server| server.with_udp(udp::UdpConfig::new(address)));
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.connect(address).unwrap();
+    socket.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+
+    let hello = ClientHello::new("test-client 1.0", Capabilities::NONE).encode();
+    let salt = udp_exchange(&socket, &salted(packet::PacketKind::Connect, &[0; 8], &hello), packet::PacketKind::Challenge);
+    assert_eq!(salt.len(), packet::SALT_SIZE);
+    // The wrong salt gets another challenge, and the right one gets in
+    let wrong: Vec<u8> = salt.iter().map(|byte| !byte).collect();
+    assert_eq!(udp_exchange(&socket, &salted(packet::PacketKind::Connect, &wrong, &hello), packet::PacketKind::Challenge), salt);
+    let server_hello = udp_exchange(&socket, &salted(packet::PacketKind::Connect, &salt, &hello), packet::PacketKind::Accept);
+    assert!(matches!(ServerHello::decode(&server_hello), Ok(ServerHello::Accepted { .. })));
+    let id = wait_for_clients(&connections, 1)[0];
+
+    // Packets without the salt can't end the connection
+    socket.send(&salted(packet::PacketKind::Disconnect, &wrong, &[])).unwrap();
+    let now = Instant::now();
+    let mut connection = Connection::new(ConnectionConfig::default(), now);
+    connection.send(message(b"hello")).unwrap();
+    for data in connection.poll(now) {
+        socket.send(&salted(packet::PacketKind::Data, &salt, &data[packet::PREFIX_SIZE..])).unwrap();
+    }
+    let messages = wait_for_messages(&connections, id);
+    assert_eq!(&messages[0].bytes[..], b"hello");
+    assert_eq!(connections.routes().len(), 1);
+}
+
+/// Start a server on the loopback transport, and return its connection manager and a way to connect to it.
+fn loopback_server() -> (ConnectionManager, LoopbackConnector) {
+    loopback_server_with(|server| server)
+}
+
+fn loopback_server_with<F>(configure: F) -> (ConnectionManager, LoopbackConnector)
+where F: FnOnce(Server<BlankCodec, ()>) -> Server<BlankCodec, ()> {
+    let (transport, connector) = LoopbackTransport::new();
+    let server = configure(Server::new(BlankCodec).with_transport(transport));
+    let connections = server.connections();
+    server.spawn();
+    (connections, connector)
+}
+
+/// Wait for the server to register or remove clients, until the routing table has `count` entries.
+fn wait_for_clients(connections: &ConnectionManager, count: usize) -> Vec<ClientID> {
+    let start = Instant::now();
+    loop {
+        let routes = connections.routes();
+        if routes.len() == count {
+            return routes.keys().cloned().collect();
+        }
+        assert!(start.elapsed() < Duration::from_secs(5), "The server never got to {} clients", count);
+        std::thread::yield_now();
+    }
+}
+
+/// Wait for a client's messages to reach the connection manager, and take them like the game would.
+fn wait_for_messages(connections: &ConnectionManager, id: ClientID) -> Vec<Message> {
+    let start = Instant::now();
+    loop {
+        if let Some(messages) = connections.receive().messages.remove(&id) {
+            return messages;
+        }
+        assert!(start.elapsed() < Duration::from_secs(5), "The client's messages never arrived");
+        std::thread::yield_now();
+    }
+}
+
+/// Read the next frame the server sent that isn't a heartbeat.
+fn read_frame_after_heartbeats<S>(stream: &mut S, compressed: bool) -> Message
+where S: tokio::io::AsyncRead + Unpin {
+    loop {
+        let received = block_on(frame::read_frame(stream)).unwrap().unwrap();
+        if heartbeat::decode(&compression::decode(received.clone(), compressed).unwrap()).is_none() {
+            return received;
+        }
+    }
+}
+
+#[test]
+fn silent_connections_are_dropped_before_the_handshake() {
+    let handshake_config = HandshakeConfig { timeout: Duration::from_millis(50), ..HandshakeConfig::default() };
+    let (_connections, connector) = loopback_server_with(|server| server.with_handshake_config(handshake_config));
+    let mut stream = connector.connect().unwrap();
+    // The server gives up on the client and closes the connection without answering
+    let mut byte = [0u8; 1];
+    assert_eq!(block_on(tokio::io::AsyncReadExt::read_exact(&mut stream, &mut byte)).unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn loopback_clients_connect_and_disconnect() {
+    let (connections, connector) = loopback_server();
+    let mut stream = connector.connect().unwrap();
+    block_on(handshake::connect(&mut stream, &ClientHello::new("test-client 1.0", Capabilities::NONE))).unwrap();
+    let id = wait_for_clients(&connections, 1)[0];
+
+    block_on(frame::write_frame(&mut stream, &message(b"first"))).unwrap();
+    block_on(frame::write_frame(&mut stream, &message(b"second"))).unwrap();
+    let receiver = block_on(connections.remove(id)).unwrap().unwrap().receiver;
+    assert_eq!(&block_on(receiver.lock().unwrap().recv()).unwrap().bytes[..], b"first");
+    assert_eq!(&block_on(receiver.lock().unwrap().recv()).unwrap().bytes[..], b"second");
+
+    let mut other = connector.connect().unwrap();
+    block_on(handshake::connect(&mut other, &ClientHello::new("test-client 1.0", Capabilities::NONE))).unwrap();
+    wait_for_clients(&connections, 1);
+    drop(other);
+    wait_for_clients(&connections, 0);
+}
+
+#[test]
+fn tls_connections_are_encrypted() {
+    let certificate = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let (certificate, key) = (certificate.serialize_pem().unwrap(), certificate.serialize_private_key_pem());
+    let acceptor = TlsAcceptor::from_pem(certificate.as_bytes(), key.as_bytes(), false).unwrap();
+    let (connections, connector) = loopback_server_with(|server| server.with_tls(acceptor));
+    let hello = ClientHello::new("test-client 1.0", Capabilities::NONE);
+
+    let stream: BoxedStream = Box::new(connector.connect().unwrap());
+    let mut stream = block_on(tls::connect(stream, "localhost", Some(certificate.as_bytes()))).unwrap();
+    block_on(handshake::connect(&mut stream, &hello)).unwrap();
+    let id = wait_for_clients(&connections, 1)[0];
+    block_on(frame::write_frame(&mut stream, &message(b"secret"))).unwrap();
+    let receiver = block_on(connections.remove(id)).unwrap().unwrap().receiver;
+    assert_eq!(&block_on(receiver.lock().unwrap().recv()).unwrap().bytes[..], b"secret");
+
+    // The server requires TLS, so plaintext clients are turned away
+    let mut plaintext = connector.connect().unwrap();
+    assert!(block_on(handshake::connect(&mut plaintext, &hello)).is_err());
+}
+
+#[test]
+fn tls_config_can_allow_plaintext() {
+    let certificate = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let directory = std::env::temp_dir();
+    let certificate_path = directory.join(format!("star-engine-test-{}.crt", std::process::id()));
+    let key_path = directory.join(format!("star-engine-test-{}.key", std::process::id()));
+    std::fs::write(&certificate_path, certificate.serialize_pem().unwrap()).unwrap();
+    std::fs::write(&key_path, certificate.serialize_private_key_pem()).unwrap();
+    let acceptor = TlsConfig::new(&certificate_path, &key_path).with_plaintext(true).load().unwrap();
+    let _ = std::fs::remove_file(certificate_path);
+    let _ = std::fs::remove_file(key_path);
+
+    let (connections, connector) = loopback_server_with(|server| server.with_tls(acceptor));
+    let mut plaintext = connector.connect().unwrap();
+    block_on(handshake::connect(&mut plaintext, &ClientHello::new("test-client 1.0", Capabilities::NONE))).unwrap();
+    wait_for_clients(&connections, 1);
+}
+
+#[test]
+fn compression_round_trip() {
+    let config = CompressionConfig::default().with_threshold(64);
+    let small = message(b"tiny");
+    let large = Message::on(Channel::UnreliableSequenced, BytesMut::from(vec![42u8; 4096]));
+
+    let encoded = compression::encode(small.clone(), Some(&config)).unwrap();
+    assert_eq!(encoded.bytes.len(), small.bytes.len() + 1);
+    assert_eq!(compression::decode(encoded, true).unwrap().bytes, small.bytes);
+
+    let encoded = compression::encode(large.clone(), Some(&config)).unwrap();
+    assert!(encoded.bytes.len() < large.bytes.len() / 10);
+    let decoded = compression::decode(encoded, true).unwrap();
+    assert_eq!(decoded.bytes, large.bytes);
+    assert_eq!(decoded.channel, Channel::UnreliableSequenced);
+    assert!(!decoded.compressed);
+
+    // Messages compressed ahead of time are decompressed for clients without compression
+    let precompressed = compression::compress(large.clone());
+    assert!(precompressed.compressed);
+    assert_eq!(compression::encode(precompressed, None).unwrap().bytes, large.bytes);
+    assert!(compression::decode(message(&[7, 1, 2]), true).is_err());
+}
+
+#[test]
+fn loopback_clients_can_negotiate_compression() {
+    let (connections, connector) = loopback_server_with(|server| server.with_compression(CompressionConfig::default()));
+    let mut stream = connector.connect().unwrap();
+    let handshake = block_on(handshake::connect(&mut stream, &ClientHello::new("test-client 1.0", Capabilities::COMPRESSION))).unwrap();
+    assert!(handshake.capabilities.contains(Capabilities::COMPRESSION));
+    let id = wait_for_clients(&connections, 1)[0];
+    let config = CompressionConfig::default();
+
+    let large = Message::new(BytesMut::from(vec![1u8; 2048]));
+    block_on(frame::write_frame(&mut stream, &compression::encode(large.clone(), Some(&config)).unwrap())).unwrap();
+    let entry = block_on(connections.remove(id)).unwrap().unwrap();
+    assert_eq!(block_on(entry.receiver.lock().unwrap().recv()).unwrap().bytes, large.bytes);
+
+    entry.sender.try_send(large.clone()).unwrap();
+    let received = read_frame_after_heartbeats(&mut stream, true);
+    assert!(received.bytes.len() < large.bytes.len());
+    assert_eq!(compression::decode(received, true).unwrap().bytes, large.bytes);
+}
+
+#[test]
+fn token_bucket_refills_up_to_capacity() {
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(10.0, 5.0, start);
+    assert!(bucket.has(5.0, start));
+    bucket.take(5.0, start);
+    assert!(!bucket.has(1.0, start));
+    let wait = bucket.time_until(1.0, start);
+    assert!(wait > Duration::from_millis(99) && wait < Duration::from_millis(101));
+    assert!(bucket.has(1.0, start + Duration::from_millis(150)));
+    assert_eq!(bucket.available(start + Duration::from_secs(60)), 5.0);
+}
+
+#[test]
+fn rate_limited_clients_emit_violations() {
+    let connections = ConnectionManager::new();
+    let handshake = dummy_client().0.handshake;
+    let address = "127.0.0.1:4343".parse().unwrap();
+    let config = RateLimitConfig::default()
+        .with_messages_per_second(1.0, 2.0)
+        .with_policy(RatePolicy::Drop);
+    let (id, mut sender, _) = block_on(connections.register(address, handshake.clone(), None, Some(config), QueueConfig::default(), None, &ClientEvents::new())).unwrap();
+    for _ in 0..5 {
+        sender.try_send(message(b"spam")).unwrap();
+    }
+
+    let mut world = World::new();
+    sync_clients(&connections, &mut world);
+    assert_eq!(world.write_resource::<Inbox>().take(b's').len(), 2);
+    let notifier = world.read_resource::<NotifierQueue>();
+    let violations: Vec<&RateLimitViolation> = notifier.iter()
+        .map(|event| force_downcast_event_ref::<RateLimitViolation>(&**event))
+        .collect();
+    assert_eq!(violations, vec![&RateLimitViolation { client: id, limit: RateLimit::MessageRate, policy: RatePolicy::Drop, count: 3 }]);
+    assert_eq!(connections.routes()[&id].inbound.queued(), 0);
+    drop(notifier);
+
+    // The count starts over every tick, and the last tick's events are gone
+    sender.try_send(message(b"spam")).unwrap();
+    sync_clients(&connections, &mut world);
+    let notifier = world.read_resource::<NotifierQueue>();
+    let violations: Vec<usize> = notifier.iter()
+        .map(|event| force_downcast_event_ref::<RateLimitViolation>(&**event).count)
+        .collect();
+    assert_eq!(violations, vec![1]);
+    drop(notifier);
+
+    // Clients that let their queue fill up get kicked
+    let config = RateLimitConfig::default()
+        .with_max_queue_depth(1)
+        .with_policy(RatePolicy::Kick);
+    let (_, mut sender, _) = block_on(connections.register(address, handshake, None, Some(config), QueueConfig::default(), None, &ClientEvents::new())).unwrap();
+    sender.try_send(message(b"first")).unwrap();
+    assert!(sender.try_send(message(b"second")).is_err());
+}
+
+fn unreliable(bytes: &[u8]) -> Message {
+    Message::on(Channel::UnreliableSequenced, BytesMut::from(bytes))
+}
+
+#[test]
+fn full_queues_drop_unreliable_messages_then_disconnect() {
+    let (sender, mut receiver) = queue::outbound(QueueConfig::default().with_capacity(2));
+    sender.try_send(unreliable(b"a")).unwrap();
+    sender.try_send(message(b"b")).unwrap();
+    sender.try_send(message(b"c")).unwrap();
+    assert_eq!(sender.len(), 2);
+    // There's nothing left to drop but the new message
+    assert_eq!(sender.try_send(unreliable(b"d")), Err(SendError::Dropped));
+    assert_eq!(sender.try_send(message(b"e")), Err(SendError::Overflowed));
+    assert_eq!(block_on(receiver.recv()).map(|message| message.bytes), None);
+    assert!(receiver.overflowed());
+    assert_eq!(DisconnectReason::from_error(&queue::slow_consumer()), DisconnectReason::SlowConsumer);
+
+    let (sender, mut receiver) = queue::outbound(QueueConfig::default().with_capacity(1).with_policy(OverflowPolicy::Disconnect));
+    sender.try_send(unreliable(b"a")).unwrap();
+    assert_eq!(sender.try_send(unreliable(b"b")), Err(SendError::Overflowed));
+    assert!(block_on(receiver.recv()).is_none());
+}
+
+#[test]
+fn coalescing_queues_replace_stale_state_updates() {
+    let snapshot = |sequence: u8| unreliable(&[opcode::SNAPSHOT, 0, 0, 0, sequence]);
+    let (sender, mut receiver) = queue::outbound(QueueConfig::default().with_capacity(2).with_policy(OverflowPolicy::Coalesce));
+    sender.try_send(snapshot(1)).unwrap();
+    sender.try_send(unreliable(b"x1")).unwrap();
+    sender.try_send(snapshot(2)).unwrap();
+    sender.try_send(unreliable(b"x2")).unwrap();
+    assert_eq!(block_on(receiver.recv()).unwrap().bytes, snapshot(2).bytes);
+    assert_eq!(&block_on(receiver.recv()).unwrap().bytes[..], b"x2");
+
+    // The parts of one snapshot don't replace each other, and once one doesn't fit, the whole snapshot is dropped
+    sender.try_send(snapshot(3)).unwrap();
+    sender.try_send(snapshot(3)).unwrap();
+    assert_eq!(sender.try_send(snapshot(3)), Err(SendError::Dropped));
+    assert_eq!(receiver.len(), 0);
+    assert_eq!(sender.try_send(snapshot(3)), Err(SendError::Dropped));
+    sender.try_send(snapshot(4)).unwrap();
+    assert_eq!(receiver.len(), 1);
+}
+
+#[test]
+fn dropping_a_snapshot_part_drops_the_whole_snapshot() {
+    let part = |sequence: u8| unreliable(&[opcode::SNAPSHOT, 0, 0, 0, sequence]);
+    let (sender, mut receiver) = queue::outbound(QueueConfig::default().with_capacity(3));
+    sender.try_send(part(1)).unwrap();
+    sender.try_send(part(1)).unwrap();
+    sender.try_send(message(b"a")).unwrap();
+    // Making room for the new part drops both parts of the first snapshot, and its last part won't be queued
+    sender.try_send(part(2)).unwrap();
+    assert_eq!(sender.len(), 2);
+    assert_eq!(sender.try_send(part(1)), Err(SendError::Dropped));
+    sender.try_send(part(2)).unwrap();
+    assert_eq!(&block_on(receiver.recv()).unwrap().bytes[..], b"a");
+    assert_eq!(block_on(receiver.recv()).unwrap().bytes, part(2).bytes);
+    assert_eq!(block_on(receiver.recv()).unwrap().bytes, part(2).bytes);
+}
+
+#[test]
+fn clients_cant_fill_the_inbound_queue() {
+    let connections = ConnectionManager::new();
+    let queues = QueueConfig::default().with_inbound_capacity(2);
+    let (id, mut sender, _) = block_on(connections.register("127.0.0.1:4343".parse().unwrap(),
+                                              dummy_client().0.handshake, None, None, queues, None, &ClientEvents::new())).unwrap();
+    for _ in 0..5 {
+        sender.try_send(message(b"spam")).unwrap();
+    }
+    assert_eq!(connections.routes()[&id].inbound.queued(), 2);
+
+    let mut world = World::new();
+    sync_clients(&connections, &mut world);
+    let connections = world.read_resource::<heartbeat::ClientConnections>();
+    assert_eq!(connections.get(id).map(|stats| stats.inbound_queue), Some(2));
+    assert_eq!(world.write_resource::<Inbox>().take(b's').len(), 2);
+
+    // Rate limits that only warn about a deep queue still can't let it grow past its capacity
+    let connections = ConnectionManager::new();
+    let warn = RateLimitConfig::default().with_max_queue_depth(1).with_policy(RatePolicy::Warn);
+    let (id, mut sender, _) = block_on(connections.register("127.0.0.1:4343".parse().unwrap(),
+                                              dummy_client().0.handshake, None, Some(warn), queues, None, &ClientEvents::new())).unwrap();
+    for _ in 0..5 {
+        sender.try_send(message(b"spam")).unwrap();
+    }
+    assert_eq!(connections.routes()[&id].inbound.queued(), 2);
+}
+
+#[test]
+fn connection_manager_handles_concurrent_clients() {
+    let connections = ConnectionManager::new();
+    let events = ClientEvents::new();
+    let address = "127.0.0.1:4343".parse().unwrap();
+    let threads: Vec<_> = (0..8).map(|_| {
+        let (connections, events) = (connections.clone(), events.clone());
+        std::thread::spawn(move || (0..50).map(|_| {
+            let (id, mut sender, outbound) = block_on(connections.register(address, dummy_client().0.handshake, None, None,
+                                                                           QueueConfig::default(), None, &events)).unwrap();
+            sender.try_send(message(b"hello")).unwrap();
+            (id, outbound)
+        }).collect::<Vec<_>>())
+    }).collect();
+    let clients: Vec<(ClientID, OutboundReceiver)> = threads.into_iter().flat_map(|thread| thread.join().unwrap()).collect();
+    let mut ids = connections.clients();
+    ids.sort();
+    ids.dedup();
+    assert_eq!(ids.len(), 400);
+
+    let received = connections.receive();
+    assert_eq!(received.messages.len(), 400);
+    assert!(received.messages.values().all(|messages| messages.len() == 1));
+    let first = clients[0].0;
+    for (id, outbound) in clients.into_iter().take(100) {
+        drop(connections.unregister(None, &events, id, address, outbound, DisconnectReason::Closed));
+    }
+    // Commands are handled in order, so everything before this one is done once it replies
+    assert!(block_on(connections.remove(first)).unwrap().is_none());
+    assert_eq!(connections.len(), 300);
+}
+
+#[test]
+fn heartbeat_round_trip() {
+    let start = Instant::now();
+    let ping = heartbeat::ping(start, start + Duration::from_millis(5));
+    let pong = heartbeat::pong(&ping);
+    assert_eq!(heartbeat::decode(&ping), Some((opcode::PING, 5000)));
+    assert_eq!(heartbeat::decode(&pong), Some((opcode::PONG, 5000)));
+    assert_eq!(heartbeat::round_trip(5000, start, start + Duration::from_millis(8)), Some(Duration::from_millis(3)));
+    assert_eq!(heartbeat::decode(&message(&[opcode::PING, 1, 2])), None);
+}
+
+#[test]
+fn heartbeats_measure_rtt_and_drop_silent_clients() {
+    let config = HeartbeatConfig::default()
+        .with_interval(Duration::from_millis(10))
+        .with_timeout(Duration::from_millis(200));
+    let (connections, connector) = loopback_server_with(|server| server.with_heartbeat(config));
+    let mut stream = connector.connect().unwrap();
+    block_on(handshake::connect(&mut stream, &ClientHello::new("test-client 1.0", Capabilities::NONE))).unwrap();
+    let id = wait_for_clients(&connections, 1)[0];
+
+    let ping = block_on(frame::read_frame(&mut stream)).unwrap().unwrap();
+    assert_eq!(heartbeat::decode(&ping).map(|(opcode, _)| opcode), Some(opcode::PING));
+    block_on(frame::write_frame(&mut stream, &heartbeat::pong(&ping))).unwrap();
+    let start = Instant::now();
+    while connections.routes().get(&id).and_then(|route| route.inbound.rtt()).is_none() {
+        assert!(start.elapsed() < Duration::from_secs(5), "The server never measured the round trip time");
+        std::thread::yield_now();
+    }
+
+    // The connection is still open, but the client has stopped answering
+    wait_for_clients(&connections, 0);
+    drop(stream);
+}
+
+#[test]
+fn unsent_pongs_are_replaced() {
+    let (mut pong_tx, mut pong_rx) = heartbeat::pongs();
+    for i in 0..3u8 {
+        pong_tx.send(i);
+    }
+    assert_eq!(block_on(pong_rx.recv()), Some(2));
+    pong_tx.send(3);
+    drop(pong_tx);
+    assert_eq!(block_on(pong_rx.recv()), Some(3));
+    assert_eq!(block_on(pong_rx.recv()), None);
+}
+
+#[test]
+fn pings_count_towards_the_rate_limits() {
+    let config = RateLimitConfig::default()
+        .with_messages_per_second(1.0, 2.0)
+        .with_policy(RatePolicy::Kick);
+    let (connections, connector) = loopback_server_with(|server| server.with_rate_limit(config));
+    let mut stream = connector.connect().unwrap();
+    block_on(handshake::connect(&mut stream, &ClientHello::new("test-client 1.0", Capabilities::NONE))).unwrap();
+    wait_for_clients(&connections, 1);
+
+    let start = Instant::now();
+    for _ in 0..5 {
+        block_on(frame::write_frame(&mut stream, &heartbeat::ping(start, Instant::now()))).unwrap();
+    }
+    wait_for_clients(&connections, 0);
+}
+
+#[test]
+fn suspended_sessions_keep_their_id_and_pending_messages() {
+    let connections = ConnectionManager::new();
+    let sessions = SessionStore::new();
+    let events = ClientEvents::new();
+    let handshake = dummy_client().0.handshake;
+    let address = "127.0.0.1:4343".parse().unwrap();
+    let account = Account { id: 7, name: "alice".to_string() };
+
+    let (id, _, mut outbound) = block_on(connections.register(address, handshake.clone(), Some(account.clone()), None, QueueConfig::default(),
+                                                Some(&sessions), &events)).unwrap();
+    let token = session::decode_token(&block_on(outbound.recv()).unwrap()).unwrap();
+    assert_eq!(sessions.authenticate(&Credentials::Token(token.clone())), Ok(account.clone()));
+    connections.routes()[&id].sender.try_send(message(b"pending")).unwrap();
+    block_on(connections.unregister(Some(&sessions), &events, id, address, outbound, DisconnectReason::TimedOut)).unwrap();
+    assert!(connections.is_empty());
+    assert_eq!(sessions.suspended(), vec![id]);
+
+    let (resumed, _, mut outbound) = block_on(connections.register(address, handshake.clone(), Some(account.clone()), None, QueueConfig::default(),
+                                                     Some(&sessions), &events)).unwrap();
+    assert_eq!(resumed, id);
+    assert_eq!(&block_on(outbound.recv()).unwrap().bytes[..], b"pending");
+    // Every resume rotates the token
+    let rotated = session::decode_token(&block_on(outbound.recv()).unwrap()).unwrap();
+    assert_ne!(rotated, token);
+    assert_eq!(sessions.authenticate(&Credentials::Token(token)), Err(AuthError::Unsupported));
+    assert_eq!(sessions.authenticate(&Credentials::Token(rotated)), Ok(account.clone()));
+    assert!(sessions.suspended().is_empty());
+
+    // Clients without an account can't resume anything
+    let (anonymous, _, outbound) = block_on(connections.register(address, handshake, None, None, QueueConfig::default(), Some(&sessions), &events)).unwrap();
+    block_on(connections.unregister(Some(&sessions), &events, anonymous, address, outbound, DisconnectReason::TimedOut)).unwrap();
+    assert!(sessions.suspended().is_empty());
+}
+
+#[test]
+fn logging_in_again_replaces_the_old_connection() {
+    let connections = ConnectionManager::new();
+    let sessions = SessionStore::new();
+    let events = ClientEvents::new();
+    let handshake = dummy_client().0.handshake;
+    let address = "127.0.0.1:4343".parse().unwrap();
+    let account = Account { id: 7, name: "alice".to_string() };
+
+    let (id, mut old_sender, old_outbound) = block_on(connections.register(address, handshake.clone(), Some(account.clone()), None,
+                                                                   QueueConfig::default(), Some(&sessions), &events)).unwrap();
+    block_on(old_sender.send(message(b"unread"))).unwrap();
+    let (new_id, _, mut outbound) = block_on(connections.register(address, handshake, Some(account), None, QueueConfig::default(),
+                                                          Some(&sessions), &events)).unwrap();
+    assert_eq!(new_id, id);
+    assert_eq!(connections.len(), 1);
+    assert!(session::decode_token(&block_on(outbound.recv()).unwrap()).is_some());
+    let connected: Vec<bool> = events.take().into_iter()
+        .filter_map(|event| match event {
+            ClientEvent::Connected(event) => Some(event.resumed),
+            _ => None
+        })
+        .collect();
+    assert_eq!(connected, vec![false, true]);
+
+    // The old connection closing doesn't take the new one with it
+    block_on(connections.unregister(Some(&sessions), &events, id, address, old_outbound, DisconnectReason::Removed)).unwrap();
+    assert_eq!(connections.clients(), vec![id]);
+    assert!(sessions.suspended().is_empty());
+    assert!(events.take().is_empty());
+    let entry = block_on(connections.remove(id)).unwrap().unwrap();
+    assert_eq!(&block_on(entry.receiver.lock().unwrap().recv()).unwrap().bytes[..], b"unread");
+}
+
+#[test]
+fn logging_in_after_the_grace_period_keeps_the_new_token() {
+    let connections = ConnectionManager::new();
+    let sessions = SessionStore::new().with_grace_period(Duration::from_secs(0));
+    let events = ClientEvents::new();
+    let handshake = dummy_client().0.handshake;
+    let address = "127.0.0.1:4343".parse().unwrap();
+    let account = Account { id: 7, name: "alice".to_string() };
+    let (id, _, outbound) = block_on(connections.register(address, handshake.clone(), Some(account.clone()), None, QueueConfig::default(),
+                                            Some(&sessions), &events)).unwrap();
+    block_on(connections.unregister(Some(&sessions), &events, id, address, outbound, DisconnectReason::TimedOut)).unwrap();
+
+    // The old session is over, but hasn't been dropped yet when the account logs in again
+    let (new_id, _, mut outbound) = block_on(connections.register(address, handshake, Some(account), None, QueueConfig::default(),
+                                                    Some(&sessions), &events)).unwrap();
+    assert_ne!(new_id, id);
+    let token = session::decode_token(&block_on(outbound.recv()).unwrap()).unwrap();
+    assert_eq!(sessions.expire(Instant::now()), vec![(id, address)]);
+    assert!(sessions.authenticate(&Credentials::Token(token)).is_ok());
+}
+
+#[test]
+fn expired_sessions_release_their_entity() {
+    let connections = ConnectionManager::new();
+    let sessions = SessionStore::new().with_grace_period(Duration::from_secs(0));
+    let events = ClientEvents::new();
+    let handshake = dummy_client().0.handshake;
+    let address = "127.0.0.1:4343".parse().unwrap();
+    let account = Account { id: 7, name: "alice".to_string() };
+    let (id, _, outbound) = block_on(connections.register(address, handshake.clone(), Some(account.clone()), None, QueueConfig::default(),
+                                            Some(&sessions), &events)).unwrap();
+    block_on(connections.unregister(Some(&sessions), &events, id, address, outbound, DisconnectReason::TimedOut)).unwrap();
+
+    let mut world = World::new();
+    let entity = world.create_entity().build();
+    let mut controlled = ControlledEntities::new();
+    controlled.control(id, entity);
+    world.add_resource(controlled);
+    world.add_resource(sessions.clone());
+    world.add_resource(events.clone());
+    sync_clients(&connections, &mut world);
+    assert_eq!(world.read_resource::<ControlledEntities>().get(id), None);
+    let reasons: Vec<(DisconnectReason, bool)> = world.read_resource::<NotifierQueue>().iter()
+        .filter(|event| is::<ClientDisconnected>(&***event))
+        .map(|event| force_downcast_event_ref::<ClientDisconnected>(&**event))
+        .map(|event| (event.reason.clone(), event.resumable))
+        .collect();
+    assert_eq!(reasons, vec![(DisconnectReason::TimedOut, true), (DisconnectReason::SessionExpired, false)]);
+    assert!(sessions.suspended().is_empty());
+
+    let (new_id, _, _) = block_on(connections.register(address, handshake, Some(account), None, QueueConfig::default(), Some(&sessions), &events)).unwrap();
+    assert_ne!(new_id, id);
+}
+
+#[test]
+fn loopback_clients_resume_with_their_token() {
+    let mut passwords = PasswordStore::new().with_rounds(1);
+    passwords.register("alice", "hunter2").unwrap();
+    let sessions = SessionStore::new();
+    let authenticator = Authenticator::new()
+        .with_provider(passwords)
+        .with_provider(sessions.clone());
+    let (connections, connector) = loopback_server_with(|server| server.with_authenticator(authenticator).with_sessions(sessions));
+
+    let mut stream = connect_client(&connector);
+    let credentials = Credentials::Password { username: "alice".to_string(), password: "hunter2".to_string() };
+    block_on(auth::login(&mut stream, &credentials)).unwrap().unwrap();
+    let id = wait_for_clients(&connections, 1)[0];
+    let token = session::decode_token(&read_frame_after_heartbeats(&mut stream, false)).unwrap();
+    drop(stream);
+    wait_for_clients(&connections, 0);
+
+    let mut stream = connect_client(&connector);
+    block_on(auth::login(&mut stream, &Credentials::Token(token))).unwrap().unwrap();
+    assert_eq!(wait_for_clients(&connections, 1), vec![id]);
+}
+
+#[test]
+fn client_events_keep_the_latest_when_nothing_takes_them() {
+    let events = ClientEvents::new().with_capacity(2);
+    let address = "127.0.0.1:4343".parse().unwrap();
+    for client in 0..5 {
+        events.push(ClientEvent::Connected(ClientConnected { client, address, resumed: false }));
+    }
+    let clients: Vec<ClientID> = events.take().into_iter()
+        .filter_map(|event| match event {
+            ClientEvent::Connected(event) => Some(event.client),
+            _ => None
+        })
+        .collect();
+    assert_eq!(clients, vec![3, 4]);
+    assert!(events.take().is_empty());
+}
+
+#[test]
+fn servers_return_the_error_that_stopped_them() {
+    let (transport, connector) = LoopbackTransport::new();
+    let mut game = Game::new_builder().build();
+    let server = game.run_server(Server::new(BlankCodec).with_transport(transport));
+    drop(connector);
+    let error = server.join().unwrap().unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::NotConnected);
+}
+
+#[test]
+fn lifecycle_events_reach_the_world() {
+    let (transport, connector) = LoopbackTransport::new();
+    let server = Server::new(BlankCodec).with_transport(transport);
+    let (connections, events) = (server.connections(), server.client_events());
+    server.spawn();
+
+    let stream = connect_client(&connector);
+    let id = wait_for_clients(&connections, 1)[0];
+    drop(stream);
+    wait_for_clients(&connections, 0);
+
+    let mut world = World::new();
+    world.add_resource(events);
+    sync_clients(&connections, &mut world);
+    let notifier = world.read_resource::<NotifierQueue>();
+    let mut queued = notifier.iter();
+    let connected = force_downcast_event_ref::<ClientConnected>(&**queued.next().unwrap());
+    assert_eq!((connected.client, connected.resumed), (id, false));
+    let disconnected = force_downcast_event_ref::<ClientDisconnected>(&**queued.next().unwrap());
+    assert_eq!(disconnected.client, id);
+    assert_eq!(disconnected.address, connected.address);
+    assert_eq!(disconnected.reason, DisconnectReason::Closed);
+    assert!(queued.next().is_none());
+    drop(notifier);
+
+    // They only last for a tick
+    sync_clients(&connections, &mut world);
+    assert_eq!(world.read_resource::<NotifierQueue>().iter().count(), 0);
+}
+
+#[test]
+fn disconnect_reasons_come_from_errors() {
+    let timed_out = std::io::Error::new(std::io::ErrorKind::TimedOut, "The client stopped responding");
+    assert_eq!(DisconnectReason::from_error(&timed_out), DisconnectReason::TimedOut);
+    let kicked = std::io::Error::new(std::io::ErrorKind::ConnectionAborted, Kicked);
+    assert_eq!(DisconnectReason::from_error(&kicked), DisconnectReason::Kicked);
+    let reset = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+    assert_eq!(DisconnectReason::from_error(&reset), DisconnectReason::Error("reset".to_string()));
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Health(u16);
+
+impl specs::Component for Health {
+    type Storage = specs::VecStorage<Self>;
+}
+
+impl Replicated for Health {
+    const TYPE_ID: ComponentTypeID = 1;
+
+    fn encode(&self, bytes: &mut BytesMut) {
+        bytes.extend_from_slice(&self.0.to_be_bytes());
+    }
+    fn decode(bytes: &[u8]) -> Option<Health> {
+        if bytes.len() != 2 {
+            return None;
+        }
+        Some(Health(u16::from_be_bytes([bytes[0], bytes[1]])))
+    }
+}
+
+/// A component that encodes to as many bytes as it holds.
+struct Blob(usize);
+
+impl specs::Component for Blob {
+    type Storage = specs::VecStorage<Self>;
+}
+
+impl Replicated for Blob {
+    const TYPE_ID: ComponentTypeID = 2;
+
+    fn encode(&self, bytes: &mut BytesMut) {
+        bytes.extend_from_slice(&vec![0; self.0]);
+    }
+    fn decode(bytes: &[u8]) -> Option<Blob> {
+        Some(Blob(bytes.len()))
+    }
+}
+
+#[test]
+fn oversized_components_are_not_replicated() {
+    let mut world = World::new();
+    world.register::<Networked>();
+    world.register::<Blob>();
+    let mut replication = Replication::new();
+    replication.register::<Blob>().unwrap();
+    let small = world.create_entity().with(Networked).with(Blob(10)).build();
+    let large = world.create_entity().with(Networked).with(Blob(Replication::MAX_COMPONENT_SIZE + 1)).build();
+
+    let state = replication.capture(&world);
+    assert_eq!(state[&network_id(small)][&Blob::TYPE_ID].len(), 10);
+    // The entity itself is still replicated
+    assert!(state[&network_id(large)].is_empty());
+}
+
+#[test]
+fn mirror_follows_replicated_state() {
+    let mut world = World::new();
+    world.register::<Networked>();
+    world.register::<Health>();
+    let mut replication = Replication::new();
+    replication.register::<Health>().unwrap();
+    let player = world.create_entity().with(Networked).with(Health(100)).build();
+    let barrel = world.create_entity().with(Networked).with(Health(5)).build();
+
+    let mut mirror = Mirror::new().with_component::<Health>();
+    fn sync(world: &World, replication: &mut Replication, mirror: &mut Mirror) {
+        for (client, message) in replication.update(world, &[1]) {
+            if let Some(ack) = mirror.apply(&message) {
+                replication.handle_ack(client, &ack);
+            }
+        }
+    }
+    sync(&world, &mut replication, &mut mirror);
+    let mirrored = mirror.entity(network_id(player)).unwrap();
+    assert_eq!(mirror.world().read_storage::<Health>().get(mirrored), Some(&Health(100)));
+    assert!(mirror.entity(network_id(barrel)).is_some());
+
+    world.write_storage::<Health>().insert(player, Health(40)).unwrap();
+    world.delete_entity(barrel).unwrap();
+    world.maintain();
+    sync(&world, &mut replication, &mut mirror);
+    assert_eq!(mirror.world().read_storage::<Health>().get(mirrored), Some(&Health(40)));
+    assert!(mirror.entity(network_id(barrel)).is_none());
+    assert_eq!(mirror.sequence(), 2);
+
+    // Stale snapshots are ignored
+    let old = SnapshotDelta { sequence: 1, ..Default::default() };
+    assert!(mirror.apply(&old.encode(1024)[0]).is_none());
+}
+
+#[test]
+fn mirror_only_applies_complete_snapshots() {
+    let mut state = WorldState::new();
+    for id in 0..20 {
+        state.insert(id, entity_state(&[(1, &[0, id as u8])]));
+    }
+    let first = SnapshotDelta::between(&WorldState::new(), &state, 0, 1).encode(64);
+    let second = SnapshotDelta::between(&WorldState::new(), &state, 0, 2).encode(64);
+    assert!(first.len() > 2);
+
+    // A lone last part isn't the whole snapshot
+    let mut mirror = Mirror::new().with_component::<Health>();
+    assert!(mirror.apply(first.last().unwrap()).is_none());
+    assert_eq!(mirror.sequence(), 0);
+
+    // Parts of two snapshots can arrive interleaved and out of order
+    let last = first.len() - 1;
+    for i in (1..last).rev() {
+        assert!(mirror.apply(&first[i]).is_none());
+        assert!(mirror.apply(&second[i]).is_none());
+    }
+    assert!(mirror.apply(&second[0]).is_none());
+    assert_eq!(mirror.apply(&first[0]).and_then(|ack| decode_ack(&ack)), Some(1));
+    assert_eq!(mirror.apply(&second[last]).and_then(|ack| decode_ack(&ack)), Some(2));
+    assert_eq!(mirror.state(), &state);
+    use specs::Join;
+    assert_eq!(mirror.world().read_storage::<Health>().join().count(), 20);
+}
+
+#[test]
+fn client_connection_exchanges_messages() {
+    let (connections, connector) = loopback_server_with(|server| server.with_compression(CompressionConfig::default()));
+    let config = ClientConfig::new("test-client 1.0").with_compression(CompressionConfig::default());
+    let mut connection = block_on(client::Connection::connect(Box::new(connector.connect().unwrap()), &config)).unwrap();
+    assert!(connection.handshake().capabilities.contains(Capabilities::COMPRESSION));
+    let id = wait_for_clients(&connections, 1)[0];
+
+    block_on(connection.send(message(b"hello"))).unwrap();
+    assert_eq!(&wait_for_messages(&connections, id)[0].bytes[..], b"hello");
+
+    // Heartbeats are answered without being returned, and compressed messages arrive decompressed
+    let large = message(&[b'x'; 4096]);
+    connections.routes()[&id].sender.try_send(large.clone()).unwrap();
+    assert_eq!(block_on(connection.recv()).unwrap().unwrap().bytes, large.bytes);
+}
+
+#[test]
+fn simulated_links_add_latency_and_cap_bandwidth() {
+    let link = LinkConditions::new().with_latency(Duration::from_millis(50), Duration::from_secs(0)).with_bandwidth(1000.0);
+    let simulator = NetworkSimulator::new().with_default(NetworkConditions::new(link));
+    let now = Instant::now();
+    let mut link = Link::new(simulator, 1, Direction::Outbound, true, now);
+    link.push(1, 100, now);
+    link.push(2, 100, now);
+    assert_eq!(link.pop(now), None);
+    assert_eq!(link.next_due(), Some(now + Duration::from_millis(150)));
+    assert_eq!(link.pop(now + Duration::from_millis(150)), Some(1));
+    // The second message had to wait for the first to go through
+    assert_eq!(link.pop(now + Duration::from_millis(200)), None);
+    assert_eq!(link.pop(now + Duration::from_millis(250)), Some(2));
+    assert!(link.is_empty());
+}
+
+#[test]
+fn only_datagram_links_lose_duplicate_and_reorder() {
+    let link = LinkConditions::new()
+        .with_latency(Duration::from_millis(20), Duration::from_millis(20))
+        .with_loss(0.3)
+        .with_duplication(0.3)
+        .with_reordering(true);
+    let simulator = NetworkSimulator::new().with_default(NetworkConditions::new(link)).with_seed(7);
+    let now = Instant::now();
+    let later = now + Duration::from_secs(3600);
+    let received = |stream: bool| {
+        let mut link = Link::new(simulator.clone(), 1, Direction::Inbound, stream, now);
+        for i in 0..100 {
+            link.push(i, 10, now + Duration::from_millis(i as u64));
+        }
+        std::iter::from_fn(|| link.pop(later)).collect::<Vec<_>>()
+    };
+    assert_eq!(received(true), (0..100).collect::<Vec<_>>());
+    let datagrams = received(false);
+    assert!((0..100).any(|i| !datagrams.contains(&i)));
+    assert!(datagrams.windows(2).any(|pair| pair[0] >= pair[1]));
+}
+
+#[test]
+fn stalled_stream_links_keep_resending_until_they_give_up() {
+    let lossy = NetworkConditions::new(LinkConditions::new().with_loss(1.0));
+    let simulator = NetworkSimulator::new().with_default(lossy).with_seed(7);
+    let now = Instant::now();
+    let mut link = Link::new(simulator.clone(), 1, Direction::Inbound, true, now);
+    link.push(1, 10, now);
+    link.push(2, 10, now);
+    assert_eq!(link.pop(now + Duration::from_secs(1)), None);
+    assert!(!link.is_empty());
+
+    // Once the link recovers, everything that was held back arrives, in order
+    simulator.set_default(None);
+    let later = link.next_due().unwrap();
+    assert_eq!(std::iter::from_fn(|| link.pop(later)).collect::<Vec<_>>(), vec![1, 2]);
+    assert!(link.is_empty() && !link.is_closed());
+
+    // A link that never recovers closes the connection instead of losing anything
+    simulator.set_default(Some(lossy));
+    link.push(3, 10, later);
+    while let Some(at) = link.next_due() {
+        assert_eq!(link.pop(at), None);
+    }
+    assert!(link.is_closed());
+}
+
+#[test]
+fn clients_time_out_when_their_link_loses_everything() {
+    let config = HeartbeatConfig::default()
+        .with_interval(Duration::from_millis(10))
+        .with_timeout(Duration::from_millis(200));
+    let simulator = NetworkSimulator::new();
+    let (connections, connector) = loopback_server_with(|server| {
+        server.with_heartbeat(config).with_network_conditions(simulator.clone())
+    });
+    let mut stream = connect_client(&connector);
+    let id = wait_for_clients(&connections, 1)[0];
+
+    // Only this client loses everything it sends, from now on
+    simulator.set(id, NetworkConditions::default().with_inbound(LinkConditions::new().with_loss(1.0)));
+    let start = Instant::now();
+    while !connections.is_empty() {
+        assert!(start.elapsed() < Duration::from_secs(5), "The client never timed out");
+        let _ = block_on(frame::write_frame(&mut stream, &message(b"still here")));
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
 
 
 // 5 microseconds is pretty fast, so adjust this if your computer is slow.
 const LATENCY_CAP: Duration = Duration::from_micros(5);
 
 #[test]
+#[ignore] // Measures wall-clock time across threads, so run it on purpose with `cargo test -- --ignored`
 fn server_latency_below_threshold() {
+    let (connections, connector) = loopback_server();
+    let mut stream = connector.connect().unwrap();
+    block_on(handshake::connect(&mut stream, &ClientHello::new("test-client 1.0", Capabilities::NONE))).unwrap();
+    let id = wait_for_clients(&connections, 1)[0];
+    let entry = block_on(connections.remove(id)).unwrap().unwrap();
 
-}
+    const ROUND_TRIPS: u32 = 1000;
+    let start = Instant::now();
+    for _ in 0..ROUND_TRIPS {
+        block_on(frame::write_frame(&mut stream, &message(b"ping"))).unwrap();
+        let ping = block_on(entry.receiver.lock().unwrap().recv()).unwrap();
+        entry.sender.try_send(ping).unwrap();
+        assert_eq!(&read_frame_after_heartbeats(&mut stream, false).bytes[..], b"ping");
+    }
+    let latency = start.elapsed() / ROUND_TRIPS;
+    assert!(latency < LATENCY_CAP, "Average round trip took {:?}", latency);
+}
\ No newline at end of file